@@ -1,76 +1,218 @@
 use clap::Parser;
-use std::net::SocketAddrV4;
+use p384::ecdsa::SigningKey;
+use proxima_centauri::client::CommandBuilder;
+use proxima_centauri::ProxyCommand;
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{
     atomic::{AtomicBool, AtomicU32, Ordering},
     Arc, Mutex,
 };
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
     time::sleep,
 };
 
+/// Asks `ping-server` to answer every ping together with the time it received it
+const TIMESTAMPS_HELLO: u32 = 0;
+/// The answer of a `ping-server` that timestamps pings, an older one echoes the hello
+const TIMESTAMPS_ACK: u32 = u32::MAX;
+
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
+    let args = Arc::new(Args::parse());
+
+    let addr = SocketAddrV4::from_str(&args.address).unwrap();
+
+    // The tunnel to ping through, created on the port of `addr`
+    let tunnel = match (&args.control_url, &args.key, args.destination) {
+        (Some(control_url), Some(key), Some(destination)) => {
+            let key = read_key(key);
+            let id = uuid::Uuid::new_v4();
+            let mut create = CommandBuilder::create(id, addr.port(), destination);
+            if args.pool {
+                create = create.pool();
+            }
+            if let Some(accept_loops) = args.accept_loops {
+                create = create.accept_loops(accept_loops);
+            }
+            let create = create.sign(&key);
+            send_command(control_url, &create).await;
+            if !args.csv {
+                println!(
+                    "Created tunnel {id} from port {} to {destination}",
+                    addr.port()
+                );
+            }
+            Some((control_url, key, id))
+        }
+        _ => None,
+    };
+
+    if args.reconnect {
+        if !args.csv {
+            println!("Ping {addr}, connecting for every ping");
+        } else if !args.no_header {
+            println!("seq,rtt_us,jitter_us,timestamp");
+        }
+        let start = Instant::now();
+        let pingers: Vec<_> = (0..args.parallel)
+            .map(|_| tokio::spawn(ping_reconnecting(addr, args.clone())))
+            .collect();
+        for pinger in pingers {
+            pinger.await.unwrap();
+        }
+        if !args.csv {
+            let connections = args.count * args.parallel;
+            let elapsed = start.elapsed();
+            println!(
+                "{connections} connections in {elapsed:?}, {:.0} per second",
+                f64::from(connections) / elapsed.as_secs_f64()
+            );
+        }
+    } else {
+        ping(addr, &args).await;
+    }
 
+    if let Some((control_url, key, id)) = tunnel {
+        send_command(control_url, &ProxyCommand::delete(id).sign(&key)).await;
+        if !args.csv {
+            println!("Deleted tunnel {id}");
+        }
+    }
+}
+
+/// Sends the pings over a single connection.
+async fn ping(addr: SocketAddrV4, args: &Args) {
     let in_transit = Arc::new(AtomicBool::new(false));
     let in_transit2 = in_transit.clone();
-    let out_timestamp = Arc::new(Mutex::new(Instant::now()));
-    let out_timestamp2 = out_timestamp.clone();
+    // Send times of the pings that haven't been answered yet, by sequence number
+    let out_timestamps = Arc::new(Mutex::new(HashMap::<u32, (Instant, SystemTime)>::new()));
+    let out_timestamps2 = out_timestamps.clone();
     let count = AtomicU32::new(args.count);
 
-    let addr = SocketAddrV4::from_str(&args.address).unwrap();
-
-    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    if args.one_way {
+        stream.write_u32(TIMESTAMPS_HELLO).await.unwrap();
+        if stream.read_u32().await.ok() != Some(TIMESTAMPS_ACK) {
+            eprintln!("{addr} doesn't timestamp pings, `--one-way` needs a newer ping-server");
+            std::process::exit(1);
+        }
+    }
     if !args.csv {
         println!("Ping {addr}");
+    } else if !args.no_header {
+        if args.one_way {
+            println!("seq,rtt_us,jitter_us,timestamp,forward_us,back_us");
+        } else {
+            println!("seq,rtt_us,jitter_us,timestamp");
+        }
     }
 
     let (mut si, mut so) = stream.into_split();
 
     let ping_in = async move {
-        let mut read_buf = [0; 1024];
+        let mut one_way = OneWay {
+            synced_clocks: args.synced_clocks,
+            fastest: None,
+        };
+        let mut previous_rtt = None;
+        let mut highest_seq = 0;
+        let mut answered = HashSet::new();
+        let mut out_of_order = 0;
+        let mut duplicates = 0;
         while count.load(Ordering::Relaxed) > 0 {
-            let bytes = si.read(&mut read_buf).await.unwrap();
-            if bytes > 0 {
-                let in_timestamp = Instant::now();
-                // println!("Received {bytes} bytes");
-
-                let duration = in_timestamp.duration_since(*out_timestamp.lock().unwrap());
-                let i = u32::from_be_bytes(read_buf[0..bytes].try_into().unwrap());
-                let rtt = duration.as_micros();
+            let Ok(i) = si.read_u32().await else {
+                break;
+            };
+            let in_timestamp = Instant::now();
+            let received_at = if args.one_way {
+                let Ok(received_at) = si.read_u64().await else {
+                    break;
+                };
+                Some(received_at)
+            } else {
+                None
+            };
+
+            let Some((sent, sent_at)) = out_timestamps.lock().unwrap().remove(&i) else {
+                if answered.contains(&i) {
+                    duplicates += 1;
+                    if !args.csv {
+                        println!("Ping {i} arrived again (duplicate)");
+                    }
+                } else if !args.csv {
+                    println!("Ping {i} arrived but was never sent");
+                }
+                continue;
+            };
+            answered.insert(i);
+            if i < highest_seq {
+                out_of_order += 1;
                 if !args.csv {
-                    println!("Ping {i} arrived with RTT of {rtt}us");
-                } else {
-                    println!("{rtt},");
+                    println!("Ping {i} arrived out of order, after {highest_seq}");
+                }
+            }
+            highest_seq = highest_seq.max(i);
+
+            let duration = in_timestamp.duration_since(sent);
+            let rtt = duration.as_micros();
+            // Jitter is the difference with the RTT of the previous ping
+            let jitter = previous_rtt.map_or(0, |previous: u128| rtt.abs_diff(previous));
+            previous_rtt = Some(rtt);
+            let timestamp = sent_at.duration_since(UNIX_EPOCH).unwrap().as_micros();
+            match (
+                received_at.map(|at| one_way.split(timestamp, rtt, at)),
+                args.csv,
+            ) {
+                (None, false) => println!("Ping {i} arrived with RTT of {rtt}us"),
+                (None, true) => println!("{i},{rtt},{jitter},{timestamp}"),
+                (Some((forward, back)), false) => println!(
+                    "Ping {i} arrived with RTT of {rtt}us, {forward}us forward and {back}us back"
+                ),
+                (Some((forward, back)), true) => {
+                    println!("{i},{rtt},{jitter},{timestamp},{forward},{back}")
                 }
-                // let old = count.fetch_sub(1, Ordering::Relaxed);
-                // println!("Old count value: {old}");
-                count.fetch_sub(1, Ordering::Relaxed);
-                in_transit2.store(false, Ordering::Relaxed);
             }
+            count.fetch_sub(1, Ordering::Relaxed);
+            in_transit2.store(false, Ordering::Relaxed);
         }
         if !args.csv {
-            println!("Done receiving");
+            println!(
+                "Done receiving, {out_of_order} out of order and {duplicates} duplicate replies"
+            );
+            if let Some((rtt, offset)) = one_way.fastest {
+                println!(
+                    "The clock of the server is estimated to be off by {offset}us, from the \
+                     fastest ping with an RTT of {rtt}us"
+                );
+            }
         }
     };
 
     let ping_out = async move {
         for i in 1..=args.count {
-            if let Ok(false) =
-                in_transit.compare_exchange_weak(false, true, Ordering::Relaxed, Ordering::Relaxed)
+            // Flood mode doesn't wait for the previous ping to be answered
+            if args.flood
+                || in_transit
+                    .compare_exchange_weak(false, true, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
             {
-                *out_timestamp2.lock().unwrap() = Instant::now();
+                out_timestamps2
+                    .lock()
+                    .unwrap()
+                    .insert(i, (Instant::now(), SystemTime::now()));
                 so.write_u32(i).await.unwrap();
             }
 
             if !args.csv {
                 println!("Sending ping {i}");
             }
-            sleep(Duration::from_millis(args.time)).await;
+            sleep(Duration::from_millis(args.interval)).await;
         }
         if !args.csv {
             println!("Done sending pings");
@@ -80,6 +222,73 @@ async fn main() {
     tokio::join!(ping_out, ping_in);
 }
 
+/// Splits the RTT of pings into the delay forward and back, from the time the server received
+/// them
+struct OneWay {
+    /// Use the clocks as they are, instead of estimating their difference
+    synced_clocks: bool,
+    /// The RTT of the fastest ping so far, and how far the clock of the server was ahead by
+    /// it. The delay there and back are taken to be the same for this ping, like NTP does, so
+    /// the estimate is off by half the difference between them.
+    fastest: Option<(i128, i128)>,
+}
+
+impl OneWay {
+    /// Returns the delay forward and back in microseconds, of a ping sent at `sent_at` and
+    /// received by the server at `received_at`, both in microseconds since the unix epoch.
+    fn split(&mut self, sent_at: u128, rtt: u128, received_at: u64) -> (i128, i128) {
+        let (sent_at, rtt) = (sent_at as i128, rtt as i128);
+        let forward = i128::from(received_at) - sent_at;
+        if !self.synced_clocks && self.fastest.is_none_or(|(fastest, _)| rtt < fastest) {
+            self.fastest = Some((rtt, forward - rtt / 2));
+        }
+        let offset = self.fastest.map_or(0, |(_, offset)| offset);
+        (forward - offset, rtt - forward + offset)
+    }
+}
+
+/// Sends every ping over a new connection, closed once the ping is answered. The RTT includes
+/// connecting, which shows what a tunnel that `pool`s its connections saves.
+async fn ping_reconnecting(addr: SocketAddrV4, args: Arc<Args>) {
+    let mut previous_rtt = None;
+    for i in 1..=args.count {
+        let (sent, sent_at) = (Instant::now(), SystemTime::now());
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_u32(i).await.unwrap();
+        if stream.read_u32().await.ok() != Some(i) {
+            if !args.csv {
+                println!("Ping {i} wasn't answered");
+            }
+            continue;
+        }
+        let rtt = sent.elapsed().as_micros();
+        stream.shutdown().await.unwrap();
+        let jitter = previous_rtt.map_or(0, |previous: u128| rtt.abs_diff(previous));
+        previous_rtt = Some(rtt);
+        if !args.csv {
+            println!("Ping {i} arrived with RTT of {rtt}us");
+        } else {
+            let timestamp = sent_at.duration_since(UNIX_EPOCH).unwrap().as_micros();
+            println!("{i},{rtt},{jitter},{timestamp}");
+        }
+        sleep(Duration::from_millis(args.interval)).await;
+    }
+}
+
+/// Reads the key to sign commands with from a PKCS#8 PEM file.
+fn read_key(path: &Path) -> SigningKey {
+    let pem = std::fs::read_to_string(path).unwrap();
+    SigningKey::from_str(&pem).expect("invalid signing key")
+}
+
+/// Posts `command` to the control plane at `control_url`, exiting when it fails.
+async fn send_command(control_url: &str, command: &ProxyCommand) {
+    if let Err(err) = command.send(control_url).await {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+}
+
 #[derive(Parser, Debug)]
 struct Args {
     /// Socket address to ping
@@ -90,11 +299,66 @@ struct Args {
     #[arg(short, long)]
     count: u32,
 
-    /// Time between pings
-    #[arg(short, long)]
-    time: u64,
+    /// Time between pings in milliseconds
+    #[arg(short = 't', long, visible_alias = "time", default_value_t = 1)]
+    interval: u64,
+
+    /// Keep sending pings without waiting for the previous one to be answered
+    #[arg(long)]
+    flood: bool,
 
-    /// CSV mode
+    /// Open a new connection for every ping, and count the time to connect in its RTT
+    #[arg(long, conflicts_with = "flood")]
+    reconnect: bool,
+
+    /// Ping over this many connections at the same time with `--reconnect`, each sending
+    /// `count` pings, and print how many connections per second the tunnel accepted
+    #[arg(long, requires = "reconnect", default_value_t = 1)]
+    parallel: u32,
+
+    /// CSV mode, prints a `seq,rtt_us,jitter_us,timestamp` row per ping. The timestamp is the
+    /// send time in microseconds since the unix epoch. `--one-way` adds `forward_us,back_us`
     #[arg(long)]
     csv: bool,
+
+    /// Leave out the CSV header, for appending to an existing file
+    #[arg(long, requires = "csv")]
+    no_header: bool,
+
+    /// Have the server timestamp every ping, to split the RTT into the delay forward and back,
+    /// like to see which way a tunnel holds on to small writes. Needs a `ping-server` that
+    /// supports it.
+    ///
+    /// Without `--synced-clocks` the difference between the clocks is estimated from the
+    /// fastest ping, which is assumed to take as long forward as back. That keeps how the
+    /// delays vary over the pings accurate, but an asymmetric path shows up as symmetric.
+    #[arg(long, conflicts_with = "reconnect")]
+    one_way: bool,
+
+    /// Trust that the clocks of this host and the server are in sync, like through PTP, and
+    /// split the RTT by them as they are
+    #[arg(long, requires = "one_way")]
+    synced_clocks: bool,
+
+    /// URL of the control plane of a proxy, like `http://127.0.0.1:14000`. A tunnel from the
+    /// port of `address` to `destination` is created there before pinging, and deleted after.
+    #[arg(long, requires_all = ["key", "destination"])]
+    control_url: Option<String>,
+
+    /// PKCS#8 PEM file with the key to sign the commands to the proxy with
+    #[arg(long)]
+    key: Option<PathBuf>,
+
+    /// Socket address for the tunnel to forward the pings to, such as a `ping-server`
+    #[arg(long)]
+    destination: Option<SocketAddr>,
+
+    /// Create the tunnel with `pool`, so it reuses its connections to `destination`. Pair with
+    /// `--reconnect` to measure what that saves
+    #[arg(long, requires = "control_url")]
+    pool: bool,
+
+    /// Create the tunnel with this many `accept_loops`
+    #[arg(long, requires = "control_url")]
+    accept_loops: Option<usize>,
 }
@@ -1,9 +1,23 @@
 use clap::Parser;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::error::Error;
 use std::net::SocketAddrV4;
 use std::str::FromStr;
-use tokio::io::AsyncWriteExt;
-use tokio::{io, net::TcpListener};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+use tokio::{
+    io,
+    net::{TcpListener, TcpStream},
+};
+
+/// Sent by `ping-client --one-way` before its pings, which start at 1
+const TIMESTAMPS_HELLO: u32 = 0;
+/// The answer to [`TIMESTAMPS_HELLO`], where an older server would echo the hello
+const TIMESTAMPS_ACK: u32 = u32::MAX;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -15,14 +29,123 @@ async fn main() -> Result<(), Box<dyn Error>> {
     println!("Listening on: {addr}");
 
     loop {
-        let (mut socket, _) = listener.accept().await?;
+        let (socket, _) = listener.accept().await?;
+        // Every connection starts from the same seed, so runs can be repeated exactly
+        let rng = match args.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let delay = Duration::from_millis(args.delay_ms);
+        tokio::spawn(serve(socket, delay, args.drop_rate, rng));
+    }
+}
+
+/// Echoes the pings of a connection. When it starts with [`TIMESTAMPS_HELLO`], every ping is
+/// answered together with the time it was received.
+async fn serve(socket: TcpStream, delay: Duration, drop_rate: f64, rng: StdRng) -> io::Result<()> {
+    let (mut si, mut so) = socket.into_split();
+    let Ok(start) = read_hello(&mut si).await else {
+        return so.shutdown().await;
+    };
+    let timestamps = start == TIMESTAMPS_HELLO.to_be_bytes();
+    if timestamps {
+        so.write_u32(TIMESTAMPS_ACK).await?;
+    } else if delay.is_zero() && drop_rate == 0.0 {
+        // Anything is echoed as it arrives, not only whole pings
+        so.write_all(&start).await?;
+        io::copy(&mut si, &mut so).await?;
+        return so.shutdown().await;
+    }
+    let first = if timestamps {
+        None
+    } else {
+        let mut first = [0; 4];
+        first[..start.len()].copy_from_slice(&start);
+        if si.read_exact(&mut first[start.len()..]).await.is_err() {
+            return so.shutdown().await;
+        }
+        Some(u32::from_be_bytes(first))
+    };
+    echo_pings(si, so, first, timestamps, delay, drop_rate, rng).await
+}
+
+/// Reads the start of a connection only for as long as it can still be [`TIMESTAMPS_HELLO`],
+/// so that a client that sends anything else is echoed without waiting for 4 bytes. Returns
+/// the bytes read, which are all of the hello when it was sent.
+async fn read_hello(si: &mut OwnedReadHalf) -> io::Result<Vec<u8>> {
+    let hello = TIMESTAMPS_HELLO.to_be_bytes();
+    let mut start = [0; 4];
+    let mut len = 0;
+    while len < start.len() && start[..len] == hello[..len] {
+        match si.read(&mut start[len..]).await? {
+            0 => break,
+            read => len += read,
+        }
+    }
+    Ok(start[..len].to_vec())
+}
 
-        tokio::spawn(async move {
-            let (mut si, mut so) = socket.split();
-            io::copy(&mut si, &mut so).await?;
-            so.shutdown().await
-        });
+/// Echoes every ping after `delay`, dropping a `drop_rate` fraction of them. With `timestamps`
+/// every ping is followed by the time it was received, in microseconds since the unix epoch.
+///
+/// Pings are handled as the 4 byte sequence numbers `ping-client` sends, so a dropped ping
+/// doesn't corrupt the ones after it.
+async fn echo_pings(
+    mut si: OwnedReadHalf,
+    mut so: OwnedWriteHalf,
+    mut first: Option<u32>,
+    timestamps: bool,
+    delay: Duration,
+    drop_rate: f64,
+    mut rng: StdRng,
+) -> io::Result<()> {
+    // The writer waits for the deadline of each ping, so delays don't add up when pings are
+    // sent faster than the delay
+    let (tx, mut rx) = mpsc::unbounded_channel::<(Instant, u32, u64)>();
+    let writer = tokio::spawn(async move {
+        while let Some((deadline, ping, received_at)) = rx.recv().await {
+            // Even a deadline that has passed waits for the next tick of the timer
+            if !delay.is_zero() {
+                sleep_until(deadline).await;
+            }
+            if timestamps {
+                // In one write, as Nagle's algorithm would hold back the time until the ping
+                // is acknowledged
+                let mut reply = [0; 12];
+                reply[..4].copy_from_slice(&ping.to_be_bytes());
+                reply[4..].copy_from_slice(&received_at.to_be_bytes());
+                so.write_all(&reply).await?;
+            } else {
+                so.write_u32(ping).await?;
+            }
+        }
+        so.shutdown().await
+    });
+
+    loop {
+        let ping = match first.take() {
+            Some(ping) => ping,
+            None => match si.read_u32().await {
+                Ok(ping) => ping,
+                Err(_) => break,
+            },
+        };
+        let received_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64;
+        if rng.gen_bool(drop_rate) {
+            continue;
+        }
+        if tx
+            .send((Instant::now() + delay, ping, received_at))
+            .is_err()
+        {
+            break;
+        }
     }
+    drop(tx);
+    writer.await?
 }
 
 #[derive(Parser, Debug)]
@@ -30,4 +153,25 @@ struct Args {
     /// Socket address to listen on
     #[arg(long)]
     address: String,
+
+    /// Time to hold on to every ping before echoing it
+    #[arg(long, default_value_t = 0)]
+    delay_ms: u64,
+
+    /// Fraction of pings to drop, between 0 and 1
+    #[arg(long, default_value_t = 0.0, value_parser = parse_rate)]
+    drop_rate: f64,
+
+    /// Seed for the random number generator that picks the dropped pings
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+fn parse_rate(rate: &str) -> Result<f64, String> {
+    let rate: f64 = rate.parse().map_err(|err| format!("{err}"))?;
+    if (0.0..=1.0).contains(&rate) {
+        Ok(rate)
+    } else {
+        Err(format!("{rate} is not between 0 and 1"))
+    }
 }
@@ -1,41 +1,423 @@
-use axum::{
-    routing::{get, post},
-    Router,
+use clap::{Parser, ValueEnum};
+use p384::ecdsa::{SigningKey, VerifyingKey};
+#[cfg(unix)]
+use proxima_centauri::unix;
+use proxima_centauri::{
+    reload_tunnels, serve, tls, AllowedDestination, ControlPlaneConfig, GlobalState, Heartbeat,
+    UnreachablePolicy, DEFAULT_BANNER, DEFAULT_COMMAND_TIMEOUT, DEFAULT_MAX_BODY_SIZE,
 };
-use proxima_centauri::{process_command, root, GlobalState};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use tracing::Level;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
 
 #[tokio::main]
 async fn main() {
-    let addr: std::net::SocketAddr = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:14000".to_string())
-        .parse()
-        .unwrap();
+    let args = Args::parse();
 
     // initialize tracing
-    let subscriber = tracing_subscriber::FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
-        .finish();
+    init_tracing(&args);
+
+    let shared_state = Arc::new(
+        GlobalState::new(args.verifying_key.as_ref())
+            .with_signing_key(
+                args.signing_key
+                    .as_ref()
+                    .map(|key| SigningKey::from_str(key).expect("invalid signing key")),
+            )
+            .with_admin_token(args.admin_token.clone())
+            .with_payload_logging(args.log_payloads)
+            .with_max_tunnels(args.max_tunnels)
+            .with_key_quotas(args.key_quota.iter().cloned().collect())
+            .with_observer_keys(args.observer_key.iter().cloned().collect())
+            .with_allowed_destinations(args.allow_destination.clone())
+            .with_max_connections(args.max_connections)
+            .with_privileged_ports(args.allow_privileged_ports)
+            .with_reuse_address(!args.no_reuse_address)
+            .with_reuse_port(reuse_port(&args))
+            .with_buffered_status(args.buffered_status)
+            .with_command_timeout(Duration::from_millis(args.command_timeout_ms)),
+    );
+    let config = ControlPlaneConfig {
+        tcp_keepalive: (args.tcp_keepalive_secs > 0)
+            .then(|| Duration::from_secs(args.tcp_keepalive_secs)),
+        http2: args.http2,
+        http2_max_concurrent_streams: args.http2_max_concurrent_streams,
+        max_body_size: args.max_body_size,
+        banner: (!args.no_banner).then(|| args.banner.clone()),
+    };
 
-    tracing::subscriber::set_global_default(subscriber).unwrap();
+    if let Some(path) = &args.tunnels_file {
+        match reload_tunnels(&shared_state, path).await {
+            Ok(reconciled) => tracing::info!("tunnels file loaded: {reconciled}"),
+            Err(err) => {
+                tracing::error!("{err:#}");
+                std::process::exit(1);
+            }
+        }
+        #[cfg(unix)]
+        tokio::spawn(reload_on_hangup(shared_state.clone(), path.clone()));
+    }
 
-    let verifying_key = std::env::args().nth(1);
+    if let Some(url) = &args.heartbeat_url {
+        let heartbeat = Heartbeat {
+            url: url.clone(),
+            interval: Duration::from_secs(args.heartbeat_interval_secs),
+            max_missed: args.heartbeat_max_missed,
+            policy: args.heartbeat_policy,
+        };
+        tokio::spawn(heartbeat.run(shared_state.clone()));
+    }
 
-    let shared_state = Arc::new(GlobalState::new(verifying_key.as_ref()));
-    // build our application with a route
-    let app = Router::new()
-        // `GET /` goes to `root`
-        .route("/", get(root))
-        // `POST /command` goes to `process_command`
-        .route("/command", post(process_command))
-        .with_state(shared_state);
+    // Only listen on TCP by default when there is no unix socket to listen on
+    #[cfg(unix)]
+    let address = args
+        .address
+        .or(args.control_socket.is_none().then_some(DEFAULT_ADDRESS));
+    #[cfg(not(unix))]
+    let address = args.address.or(Some(DEFAULT_ADDRESS));
 
     // run our app with hyper
-    tracing::debug!("listening  on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    let tcp = async {
+        let Some(address) = address else {
+            return;
+        };
+        tracing::debug!("listening  on {}", address);
+        if let (Some(cert), Some(key), Some(client_ca)) =
+            (&args.tls_cert, &args.tls_key, &args.tls_client_ca)
+        {
+            let acceptor = tls::acceptor(cert, key, client_ca).unwrap();
+            let listener = tokio::net::TcpListener::bind(&address).await.unwrap();
+            tls::serve(listener, shared_state.clone(), &config, acceptor)
+                .await
+                .unwrap();
+        } else {
+            serve(&address, shared_state.clone(), &config)
+                .unwrap()
+                .await
+                .unwrap();
+        }
+    };
+
+    #[cfg(unix)]
+    let unix = async {
+        let Some(path) = &args.control_socket else {
+            return;
+        };
+        tracing::debug!("listening  on {}", path.display());
+        let listener = unix::bind(path).unwrap();
+        unix::serve(listener, shared_state.clone(), &config)
+            .await
+            .unwrap();
+    };
+    #[cfg(not(unix))]
+    let unix = async {};
+
+    tokio::select! {
+        _ = async { tokio::join!(tcp, unix) } => {}
+        () = shutdown_signal() => {}
+    }
+
+    tracing::info!(
+        "shutting down, connections get {}s to finish",
+        args.shutdown_grace_secs
+    );
+    let stats = shared_state
+        .shutdown(Duration::from_secs(args.shutdown_grace_secs))
+        .await;
+    for (id, tunnel) in &stats.tunnels {
+        tracing::info!(
+            tunnel = %id,
+            port = tunnel.incoming_port,
+            connections = tunnel.served.connections,
+            bytes_sent = tunnel.served.bytes_sent,
+            bytes_received = tunnel.served.bytes_received,
+            "tunnel served"
+        );
+    }
+    tracing::info!(
+        uptime_secs = stats.uptime_secs,
+        connections = stats.served.connections,
+        bytes_sent = stats.served.bytes_sent,
+        bytes_received = stats.served.bytes_received,
+        "proxy served"
+    );
+    if let Some(path) = &args.shutdown_stats_file {
+        let json = serde_json::to_vec_pretty(&stats).unwrap();
+        if let Err(err) = std::fs::write(path, json) {
+            tracing::error!(
+                "writing the shutdown stats to {} failed: {err}",
+                path.display()
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Resolves on `SIGTERM` or `SIGINT`.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("handling SIGTERM failed");
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Reloads the tunnels file on every `SIGHUP`, keeping the current tunnels when it fails.
+#[cfg(unix)]
+async fn reload_on_hangup(state: Arc<GlobalState>, path: PathBuf) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup()).expect("handling SIGHUP failed");
+    while hangups.recv().await.is_some() {
+        match reload_tunnels(&state, &path).await {
+            Ok(reconciled) => tracing::info!("tunnels file reloaded: {reconciled}"),
+            Err(err) => tracing::error!("{err:#}"),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn reuse_port(args: &Args) -> bool {
+    args.reuse_port
+}
+
+#[cfg(not(unix))]
+fn reuse_port(_: &Args) -> bool {
+    false
+}
+
+/// Parses a `--key-quota` like `tenant-a=10`.
+fn parse_key_quota(quota: &str) -> Result<(String, usize), String> {
+    let (name, max) = quota
+        .split_once('=')
+        .ok_or_else(|| format!("`{quota}` is not in the form NAME=MAX"))?;
+    let max = max
+        .parse()
+        .map_err(|err| format!("invalid maximum `{max}`: {err}"))?;
+    Ok((name.to_string(), max))
+}
+
+/// Parses an `--observer-key` like `dashboard=-----BEGIN PUBLIC KEY-----...`.
+fn parse_observer_key(observer_key: &str) -> Result<(String, VerifyingKey), String> {
+    let (name, pem) = observer_key
+        .split_once('=')
+        .ok_or_else(|| "the observer key is not in the form NAME=PEM".to_string())?;
+    let key = VerifyingKey::from_str(pem)
+        .map_err(|err| format!("invalid verifying key for `{name}`: {err}"))?;
+    Ok((name.to_string(), key))
+}
+
+const DEFAULT_ADDRESS: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 14000));
+
+/// Installs the global subscriber. `RUST_LOG` takes precedence over `--log-level` when set,
+/// for the logs as well as the exported spans.
+fn init_tracing(args: &Args) {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(args.log_level.as_str().to_lowercase()));
+    let fmt = tracing_subscriber::fmt::layer();
+    let fmt = match args.log_format {
+        LogFormat::Full => fmt.boxed(),
+        LogFormat::Pretty => fmt.pretty().boxed(),
+        LogFormat::Json => fmt.json().boxed(),
+        LogFormat::Compact => fmt.compact().boxed(),
+    };
+    let subscriber = tracing_subscriber::registry().with(filter).with(fmt);
+
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(args.otlp_endpoint.as_deref().map(|endpoint| {
+        proxima_centauri::otel::layer(endpoint).expect("setting up the OTLP exporter failed")
+    }));
+    subscriber.init();
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LogFormat {
+    Full,
+    Pretty,
+    Json,
+    Compact,
+}
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Socket address for the control plane to listen on, defaults to 127.0.0.1:14000 unless
+    /// only `--control-socket` is given
+    address: Option<SocketAddr>,
+
+    /// Path of a unix socket for the control plane to listen on, next to `address` if that is
+    /// given as well
+    #[cfg(unix)]
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
+
+    /// Public key to verify command signatures with
+    #[arg(long)]
+    verifying_key: Option<String>,
+
+    /// Token to accept in an `Authorization: Bearer` header instead of a signature, for local
+    /// testing. Ignored when `--verifying-key` is given.
+    #[arg(long)]
+    admin_token: Option<String>,
+
+    /// Private key in PKCS#8 PEM to sign the responses to commands with, which also answers
+    /// `Status` as a single JSON document like `--buffered-status`
+    #[arg(long)]
+    signing_key: Option<String>,
+
+    /// Maximum level of the logs, overridden by `RUST_LOG`
+    #[arg(long, default_value_t = Level::INFO)]
+    log_level: Level,
+
+    /// Output format of the logs
+    #[arg(long, value_enum, default_value_t = LogFormat::Full)]
+    log_format: LogFormat,
+
+    /// OTLP collector to export the spans of commands and connections to over gRPC, like
+    /// `http://localhost:4317`. Only connections that are logged have a span.
+    #[cfg(feature = "otel")]
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Log the complete payload of every command, signatures included, at the trace level
+    #[arg(long)]
+    log_payloads: bool,
+
+    /// Maximum amount of tunnels that can exist at the same time
+    #[arg(long)]
+    max_tunnels: Option<usize>,
+
+    /// Maximum amount of tunnels that commands signed by a verifying key can create, as
+    /// NAME=MAX with the name of the key, like `tenant-a=10`. Can be given several times. A key
+    /// with a quota only sees and changes its own tunnels and can't rotate keys
+    #[arg(long, value_parser = parse_key_quota)]
+    key_quota: Vec<(String, usize)>,
+
+    /// Public key that may only run `Status`, as NAME=PEM with a name for the key. Can be
+    /// given several times. Commands have to be signed once any key is given
+    #[arg(long, value_parser = parse_observer_key)]
+    observer_key: Vec<(String, VerifyingKey)>,
+
+    /// Network that tunnels may forward to, like `10.0.0.0/8` or `10.1.2.3/32:5432` or
+    /// `fd00::/8:8000-8999`. Can be given several times, tunnels may forward anywhere without it.
+    #[arg(long)]
+    allow_destination: Vec<AllowedDestination>,
+
+    /// Maximum amount of connections through all tunnels together, further connections are
+    /// closed right after they are accepted
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Allow tunnels to listen on ports below 1024, requires the `CAP_NET_BIND_SERVICE`
+    /// capability
+    #[arg(long)]
+    allow_privileged_ports: bool,
+
+    /// Don't set `SO_REUSEADDR` on the listeners of tunnels, unless a tunnel asks for it.
+    /// Without it, the port of a deleted tunnel can't be reused while its connections are in
+    /// `TIME_WAIT`
+    #[arg(long)]
+    no_reuse_address: bool,
+
+    /// Set `SO_REUSEPORT` on the listeners of tunnels, unless a tunnel asks otherwise, so
+    /// several proxies can share a port
+    #[cfg(unix)]
+    #[arg(long)]
+    reuse_port: bool,
+
+    /// JSON file with an array of `create` commands, without signatures, for tunnels that
+    /// always exist. The tunnels are changed to match the file again on `SIGHUP`
+    #[arg(long)]
+    tunnels_file: Option<PathBuf>,
+
+    /// Plain HTTP URL of the orchestrator to post a heartbeat to every
+    /// `--heartbeat-interval-secs`, with the amount of tunnels and the version of `Status`
+    #[arg(long)]
+    heartbeat_url: Option<hyper::Uri>,
+
+    /// Seconds between heartbeats, a heartbeat without an answer within it is missed
+    #[arg(long, default_value_t = 10, requires = "heartbeat_url",
+          value_parser = clap::value_parser!(u64).range(1..))]
+    heartbeat_interval_secs: u64,
+
+    /// Heartbeats missed in a row after which the orchestrator is unreachable
+    #[arg(long, default_value_t = 3, requires = "heartbeat_url",
+          value_parser = clap::value_parser!(u32).range(1..))]
+    heartbeat_max_missed: u32,
+
+    /// What happens to the tunnels while the orchestrator is unreachable: `fail-open` keeps
+    /// them, `fail-closed` closes all of them along with their connections
+    #[arg(long, default_value_t = UnreachablePolicy::FailOpen, requires = "heartbeat_url")]
+    heartbeat_policy: UnreachablePolicy,
+
+    /// Seconds that the connections get to finish on `SIGTERM` or `SIGINT` before they are
+    /// closed and the proxy exits
+    #[arg(long, default_value_t = 30)]
+    shutdown_grace_secs: u64,
+
+    /// File to write what the proxy served to on exit, as JSON: the uptime, the connections
+    /// and bytes of all tunnels and those of each tunnel open at the time
+    #[arg(long)]
+    shutdown_stats_file: Option<PathBuf>,
+
+    /// Answer `Status` with a single JSON document instead of a JSON line per tunnel, for
+    /// clients from before it was streamed
+    #[arg(long)]
+    buffered_status: bool,
+
+    /// Idle time in seconds before TCP keepalive probes are sent on control plane
+    /// connections, 0 disables keepalive
+    #[arg(long, default_value_t = 60)]
+    tcp_keepalive_secs: u64,
+
+    /// Also accept HTTP/2 with prior knowledge on the control plane
+    #[arg(long)]
+    http2: bool,
+
+    /// Maximum amount of concurrent HTTP/2 streams per control plane connection
+    #[arg(long, requires = "http2")]
+    http2_max_concurrent_streams: Option<u32>,
+
+    /// Longest time in milliseconds that a command may take, after which it is undone and
+    /// answered with `504 Gateway Timeout`
+    #[arg(long, default_value_t = DEFAULT_COMMAND_TIMEOUT.as_millis() as u64,
+          value_parser = clap::value_parser!(u64).range(1..))]
+    command_timeout_ms: u64,
+
+    /// Largest request body in bytes that the control plane accepts
+    #[arg(long, default_value_t = DEFAULT_MAX_BODY_SIZE)]
+    max_body_size: usize,
+
+    /// Answer to `GET /` on the control plane, can be empty
+    #[arg(long, default_value = DEFAULT_BANNER)]
+    banner: String,
+
+    /// Answer `GET /` on the control plane with `404 Not Found`, like any unknown route
+    #[arg(long, conflicts_with = "banner")]
+    no_banner: bool,
+
+    /// Certificate chain to serve the control plane over TLS with, in PEM format. Requires
+    /// clients to present a certificate signed by `--tls-client-ca`
+    #[arg(long, requires_all = ["tls_key", "tls_client_ca"])]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key of `--tls-cert`, in PEM format
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// CA certificates that client certificates must be signed by, in PEM format
+    #[arg(long, requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
 }
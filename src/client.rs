@@ -0,0 +1,321 @@
+//! Building, signing and sending commands to the control plane of a proxy
+
+use crate::{
+    Command, ErrorBody, ErrorDetail, ProxyCommand, ProxyResponse, REQUEST_NONCE_HEADER,
+    REQUEST_SIGNATURE_HEADER, REQUEST_TIMESTAMP_HEADER, STATUS_LINES_CONTENT_TYPE,
+};
+use hyper::{header, Body, Client, Method, Request, StatusCode};
+use p384::ecdsa::SigningKey;
+use std::fmt;
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+/// Builds the commands of the control plane, without writing their JSON by hand. Finish with
+/// [`CommandBuilder::sign`] and send the command with [`ProxyCommand::send`].
+#[derive(Debug)]
+pub struct CommandBuilder {
+    command: Command,
+}
+
+impl CommandBuilder {
+    /// Creates the tunnel `id` from `incoming_port` to `destination`.
+    pub fn create(id: Uuid, incoming_port: u16, destination: SocketAddr) -> Self {
+        Self {
+            command: Command::create(id, incoming_port, destination),
+        }
+    }
+
+    /// Creates `count` tunnels from `start_port` onwards, to the ports from `destination`
+    /// onwards.
+    pub fn create_range(start_port: u16, count: u16, destination: SocketAddr) -> Self {
+        Self {
+            command: Command::CreateRange {
+                start_port,
+                count,
+                destination_ip: destination.ip(),
+                destination_port_start: destination.port(),
+            },
+        }
+    }
+
+    /// Creates the router `id` on `incoming_port`, which connects every client to the route
+    /// its first bytes ask for.
+    pub fn create_router(
+        id: Uuid,
+        incoming_port: u16,
+        routes: impl IntoIterator<Item = (String, SocketAddr)>,
+    ) -> Self {
+        Self {
+            command: Command::CreateRouter {
+                id,
+                incoming_port,
+                routes: routes.into_iter().collect(),
+            },
+        }
+    }
+
+    /// Points the tunnel `id` at `destination`.
+    pub fn modify(id: Uuid, destination: SocketAddr) -> Self {
+        Self {
+            command: Command::Modify {
+                destination_port: Some(destination.port()),
+                destination_ip: Some(destination.ip()),
+                destination_uds: None,
+                id,
+                source_address: None,
+                ttl_secs: None,
+                drain_on_modify: false,
+                label: None,
+            },
+        }
+    }
+
+    /// Points the tunnel `id` at `port` on the address it already forwards to.
+    pub fn modify_port(id: Uuid, port: u16) -> Self {
+        Self {
+            command: Command::Modify {
+                destination_port: Some(port),
+                destination_ip: None,
+                destination_uds: None,
+                id,
+                source_address: None,
+                ttl_secs: None,
+                drain_on_modify: false,
+                label: None,
+            },
+        }
+    }
+
+    /// Points the tunnel `id` at `destination` for `revert_after_secs` seconds, after which it
+    /// goes back to its current destination.
+    pub fn temporary_modify(id: Uuid, destination: SocketAddr, revert_after_secs: u64) -> Self {
+        Self {
+            command: Command::TemporaryModify {
+                id,
+                destination,
+                revert_after_secs,
+            },
+        }
+    }
+
+    /// Moves the incoming port of tunnel `from_id` to tunnel `to_id` and drains `from_id`.
+    pub fn handover(from_id: Uuid, to_id: Uuid) -> Self {
+        Self {
+            command: Command::Handover { from_id, to_id },
+        }
+    }
+
+    /// Deletes the tunnel `id` right away.
+    pub fn delete(id: Uuid) -> Self {
+        Self {
+            command: Command::Delete { id, drain: false },
+        }
+    }
+
+    /// Deletes the tunnel `id` once its connections have finished.
+    pub fn drain(id: Uuid) -> Self {
+        Self {
+            command: Command::Delete { id, drain: true },
+        }
+    }
+
+    /// Lists the tunnels.
+    pub fn status() -> Self {
+        Self {
+            command: Command::Status,
+        }
+    }
+
+    /// Audits the state of the proxy, and with `repair` fixes what it can.
+    pub fn self_check(repair: bool) -> Self {
+        Self {
+            command: Command::SelfCheck { repair },
+        }
+    }
+
+    /// Labels the tunnel, only used by `create` and `modify`.
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        match &mut self.command {
+            Command::Create { label: l, .. } => *l = Some(label.into()),
+            Command::Modify { label: l, .. } => *l = Some(Some(label.into())),
+            _ => {}
+        }
+        self
+    }
+
+    /// Removes the label of the tunnel, only used by `modify`. Without it a `modify` keeps the
+    /// label of the tunnel.
+    pub fn clear_label(mut self) -> Self {
+        if let Command::Modify { label, .. } = &mut self.command {
+            *label = Some(None);
+        }
+        self
+    }
+
+    /// Deletes the tunnel after `ttl_secs` seconds, only used by `create` and `modify`.
+    pub fn ttl_secs(mut self, ttl_secs: u64) -> Self {
+        match &mut self.command {
+            Command::Create { ttl_secs: t, .. } => *t = Some(ttl_secs),
+            Command::Modify { ttl_secs: t, .. } => *t = Some(Some(ttl_secs)),
+            _ => {}
+        }
+        self
+    }
+
+    /// Keeps the tunnel until it is deleted, only used by `modify`. Without it a `modify` keeps
+    /// the time to live of the tunnel.
+    pub fn clear_ttl(mut self) -> Self {
+        if let Command::Modify { ttl_secs, .. } = &mut self.command {
+            *ttl_secs = Some(None);
+        }
+        self
+    }
+
+    /// Routes TLS connections asking for `server_name` to `destination`, only used by
+    /// `create`.
+    pub fn sni_route(mut self, server_name: impl Into<String>, destination: SocketAddr) -> Self {
+        if let Command::Create { sni_map, .. } = &mut self.command {
+            sni_map.insert(server_name.into(), destination);
+        }
+        self
+    }
+
+    /// Reuses the connections to the destination for later clients, only used by `create`. See
+    /// the `pool` field of the command for the protocols this works with.
+    pub fn pool(mut self) -> Self {
+        if let Command::Create { pool, .. } = &mut self.command {
+            *pool = true;
+        }
+        self
+    }
+
+    /// Accepts connections with `accept_loops` tasks at the same time, only used by `create`.
+    pub fn accept_loops(mut self, accept_loops: usize) -> Self {
+        if let Command::Create {
+            accept_loops: a, ..
+        } = &mut self.command
+        {
+            *a = Some(accept_loops);
+        }
+        self
+    }
+
+    /// Connects to `destination` while the destinations before it are unreachable, only used
+    /// by `create`.
+    pub fn failover_to(mut self, destination: SocketAddr) -> Self {
+        if let Command::Create {
+            failover_destinations,
+            ..
+        } = &mut self.command
+        {
+            failover_destinations.push(destination);
+        }
+        self
+    }
+
+    /// The command without a signature, for a proxy that doesn't verify commands.
+    pub fn unsigned(self) -> ProxyCommand {
+        ProxyCommand::unsigned(self.command)
+    }
+
+    /// The command signed with `signing_key`, at the current time and with a random nonce.
+    pub fn sign(self, signing_key: &SigningKey) -> ProxyCommand {
+        self.unsigned().sign(signing_key)
+    }
+}
+
+impl ProxyCommand {
+    /// The headers that authenticate a `GET` request to the control plane, like
+    /// `GET /diagnostics`, with this command, which must be a signed `status`.
+    pub fn read_headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = Vec::new();
+        if let Some(timestamp) = self.timestamp {
+            headers.push((REQUEST_TIMESTAMP_HEADER, timestamp.to_string()));
+        }
+        if let Some(nonce) = self.nonce {
+            let nonce = nonce.iter().map(|byte| format!("{byte:02x}")).collect();
+            headers.push((REQUEST_NONCE_HEADER, nonce));
+        }
+        if let Some(signature) = &self.signature {
+            headers.push((REQUEST_SIGNATURE_HEADER, signature.to_string()));
+        }
+        headers
+    }
+
+    /// Posts the command to the control plane at `control_url`, like `http://127.0.0.1:14000`.
+    ///
+    /// Only plain HTTP is supported.
+    pub async fn send(&self, control_url: &str) -> Result<ProxyResponse, ClientError> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}/command", control_url.trim_end_matches('/')))
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(self).unwrap()))
+            .map_err(|err| ClientError::InvalidUrl(err.to_string()))?;
+        let response = Client::new().request(request).await?;
+        let status = response.status();
+        let streamed = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .is_some_and(|content_type| content_type == STATUS_LINES_CONTENT_TYPE);
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        if status.is_success() {
+            let response = if streamed {
+                ProxyResponse::from_status_lines(&body)
+            } else {
+                serde_json::from_slice(&body)
+            };
+            response.map_err(|_| ClientError::InvalidResponse {
+                status,
+                body: String::from_utf8_lossy(&body).into_owned(),
+            })
+        } else {
+            match serde_json::from_slice::<ErrorBody>(&body) {
+                Ok(ErrorBody { error }) => Err(ClientError::Api { status, error }),
+                Err(_) => Err(ClientError::InvalidResponse {
+                    status,
+                    body: String::from_utf8_lossy(&body).into_owned(),
+                }),
+            }
+        }
+    }
+}
+
+/// Why sending a command failed
+#[derive(Debug)]
+pub enum ClientError {
+    /// The URL of the control plane can't be used
+    InvalidUrl(String),
+    /// The command couldn't be sent or its response couldn't be read
+    Http(hyper::Error),
+    /// The control plane refused the command
+    Api {
+        status: StatusCode,
+        error: ErrorDetail,
+    },
+    /// The response isn't one that the control plane sends, maybe it isn't a proxy
+    InvalidResponse { status: StatusCode, body: String },
+}
+
+impl From<hyper::Error> for ClientError {
+    fn from(err: hyper::Error) -> Self {
+        ClientError::Http(err)
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::InvalidUrl(err) => write!(f, "invalid control plane URL: {err}"),
+            ClientError::Http(err) => write!(f, "sending the command failed: {err}"),
+            ClientError::Api { status, error } => {
+                write!(f, "command failed with {status}: {}", error.message)
+            }
+            ClientError::InvalidResponse { status, body } => {
+                write!(f, "unexpected response with {status}: {body}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
@@ -0,0 +1,529 @@
+//! Bookkeeping of the live connections of a tunnel
+
+use crate::fanout::FanoutStats;
+use crate::mirror::MirrorStats;
+use crate::talkers::{ClientCount, TopClients};
+use crate::Destination;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time;
+use tokio::io::{self, AsyncRead, ReadBuf};
+
+/// Connect latencies that the 99th percentile is taken over
+const LATENCY_WINDOW: usize = 1024;
+
+/// How often the throughput of a connection is sampled
+pub(crate) const THROUGHPUT_SAMPLE_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+/// The live connections of a tunnel
+#[derive(Debug, Default)]
+pub(crate) struct Connections {
+    next_id: AtomicU64,
+    active: Mutex<HashMap<u64, Arc<Connection>>>,
+    /// Connections that have ended, per [`CloseReason`]
+    closed: [AtomicU64; CloseReason::ALL.len()],
+    /// The most recent failure to connect to or copy from the destination, cleared once a
+    /// connection reaches the destination again
+    last_error: Mutex<Option<(time::SystemTime, String)>>,
+    /// Connections accepted on the listener of the tunnel, rejected ones included
+    accepted: AtomicU64,
+    /// Set once the listener of the tunnel broke and stopped accepting connections
+    listener_failed: AtomicBool,
+    accept_rate: Mutex<AcceptRate>,
+    /// Connections accepted per client address, bounded for floods
+    top_clients: Mutex<TopClients>,
+    connect_latency: Mutex<ConnectLatencies>,
+    mirror: MirrorStats,
+    fanout: FanoutStats,
+    /// The connections of the tunnel that have ended
+    served: ServedCounter,
+    /// The connections of every tunnel of the proxy that have ended, deleted tunnels included
+    proxy_served: Arc<ServedCounter>,
+}
+
+/// Connections that have ended and the bytes they copied
+#[derive(Debug, Default)]
+pub(crate) struct ServedCounter {
+    connections: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl ServedCounter {
+    fn add(&self, connection: &Connection) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        let sent = connection.bytes_sent.load(Ordering::Relaxed);
+        self.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+        let received = connection.bytes_received.load(Ordering::Relaxed);
+        self.bytes_received.fetch_add(received, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> Served {
+        Served {
+            connections: self.connections.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What connections that have ended copied, see [`crate::ShutdownStats`]
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Served {
+    pub connections: u64,
+    /// Bytes copied from clients to destinations
+    pub bytes_sent: u64,
+    /// Bytes copied from destinations to clients
+    pub bytes_received: u64,
+}
+
+/// How long connecting to the destination took
+#[derive(Debug, Default)]
+struct ConnectLatencies {
+    count: u32,
+    total: time::Duration,
+    /// The most recent ones, newest last
+    recent: VecDeque<time::Duration>,
+}
+
+/// Connections accepted during the current and the previous second since the unix epoch
+#[derive(Debug, Default)]
+struct AcceptRate {
+    second: u64,
+    current: u64,
+    previous: u64,
+}
+
+/// Why a connection through a tunnel ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CloseReason {
+    /// Both sides shut down their half of the connection
+    Completed,
+    /// Copying between the client and the destination failed
+    Error,
+    /// The tunnel was deleted or expired
+    TunnelClosed,
+    /// The connection was dropped before it reached the destination
+    Rejected,
+    /// The connection was open for the `max_lifetime_secs` of the tunnel
+    MaxLifetime,
+}
+
+impl CloseReason {
+    pub(crate) const ALL: [CloseReason; 5] = [
+        CloseReason::Completed,
+        CloseReason::Error,
+        CloseReason::TunnelClosed,
+        CloseReason::Rejected,
+        CloseReason::MaxLifetime,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            CloseReason::Completed => "completed",
+            CloseReason::Error => "error",
+            CloseReason::TunnelClosed => "tunnel_closed",
+            CloseReason::Rejected => "rejected",
+            CloseReason::MaxLifetime => "max_lifetime",
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Connections {
+    /// Counts the connections that end in `proxy_served` as well as in the tunnel.
+    pub(crate) fn new(proxy_served: Arc<ServedCounter>) -> Self {
+        Self {
+            proxy_served,
+            ..Self::default()
+        }
+    }
+
+    /// Adds a connection from `client`, which is removed again once the returned guard is
+    /// dropped.
+    pub(crate) fn register(self: &Arc<Self>, client: SocketAddr) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let connection = Arc::new(Connection {
+            client,
+            destination: Mutex::new(None),
+            started: time::Instant::now(),
+            started_at: time::SystemTime::now(),
+            last_activity_ms: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            sample: Mutex::new(Sample {
+                at: time::Instant::now(),
+                bytes: 0,
+                throughput: None,
+            }),
+        });
+        self.active.lock().unwrap().insert(id, connection.clone());
+        Registration {
+            connections: self.clone(),
+            id,
+            connection,
+        }
+    }
+
+    /// Counts a connection from `client` accepted on the listener of the tunnel.
+    pub(crate) fn accepted(&self, client: SocketAddr) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.top_clients.lock().unwrap().record(client.ip());
+        let now = unix_secs(time::SystemTime::now());
+        let mut rate = self.accept_rate.lock().unwrap();
+        if now != rate.second {
+            rate.previous = if now == rate.second + 1 {
+                rate.current
+            } else {
+                0
+            };
+            rate.second = now;
+            rate.current = 0;
+        }
+        rate.current += 1;
+    }
+
+    /// The `limit` client addresses that opened the most connections
+    pub(crate) fn top_clients(&self, limit: usize) -> Vec<ClientCount> {
+        self.top_clients.lock().unwrap().top(limit)
+    }
+
+    /// How many connections were accepted in total
+    pub(crate) fn accepted_total(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    /// How many connections were accepted during the last complete second, to correlate with
+    /// connections dropped by the kernel when the backlog is full
+    pub(crate) fn accepted_last_sec(&self) -> u64 {
+        let now = unix_secs(time::SystemTime::now());
+        let rate = self.accept_rate.lock().unwrap();
+        if now == rate.second {
+            rate.previous
+        } else if now == rate.second + 1 {
+            rate.current
+        } else {
+            0
+        }
+    }
+
+    /// Records that connecting to the destination took `latency`.
+    pub(crate) fn connect_took(&self, latency: time::Duration) {
+        let mut latencies = self.connect_latency.lock().unwrap();
+        latencies.count = latencies.count.saturating_add(1);
+        latencies.total = latencies.total.saturating_add(latency);
+        if latencies.recent.len() == LATENCY_WINDOW {
+            latencies.recent.pop_front();
+        }
+        latencies.recent.push_back(latency);
+    }
+
+    /// The latest, average and 99th percentile connect latency, `None` before the first
+    /// connection reaches the destination
+    pub(crate) fn connect_latency(&self) -> Option<ConnectLatency> {
+        let latencies = self.connect_latency.lock().unwrap();
+        let current = *latencies.recent.back()?;
+        let mut sorted: Vec<_> = latencies.recent.iter().copied().collect();
+        sorted.sort_unstable();
+        let p99 = sorted[(sorted.len() * 99).div_ceil(100) - 1];
+        Some(ConnectLatency {
+            current_us: current.as_micros() as u64,
+            avg_us: (latencies.total / latencies.count).as_micros() as u64,
+            p99_us: p99.as_micros() as u64,
+        })
+    }
+
+    /// Counts a connection that ended because of `reason`.
+    pub(crate) fn closed(&self, reason: CloseReason) {
+        self.closed[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// How many connections ended because of each reason
+    pub(crate) fn closed_counts(&self) -> impl Iterator<Item = (CloseReason, u64)> + '_ {
+        CloseReason::ALL
+            .into_iter()
+            .map(|reason| (reason, self.closed[reason as usize].load(Ordering::Relaxed)))
+    }
+
+    /// Remembers `error` as the reason the tunnel is unhealthy.
+    pub(crate) fn failed(&self, error: String) {
+        *self.last_error.lock().unwrap() = Some((time::SystemTime::now(), error));
+    }
+
+    /// Remembers that the listener of the tunnel broke because of `error`.
+    pub(crate) fn listener_failed(&self, error: String) {
+        self.listener_failed.store(true, Ordering::Relaxed);
+        self.failed(error);
+    }
+
+    pub(crate) fn has_failed_listener(&self) -> bool {
+        self.listener_failed.load(Ordering::Relaxed)
+    }
+
+    /// Forgets the last error, as the destination is reachable again.
+    pub(crate) fn connected(&self) {
+        *self.last_error.lock().unwrap() = None;
+    }
+
+    pub(crate) fn last_error(&self) -> Option<LastError> {
+        self.last_error
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(at, message)| LastError {
+                at: unix_millis(*at),
+                message: message.clone(),
+            })
+    }
+
+    pub(crate) fn mirror_stats(&self) -> &MirrorStats {
+        &self.mirror
+    }
+
+    pub(crate) fn fanout_stats(&self) -> &FanoutStats {
+        &self.fanout
+    }
+
+    /// What the connections of the tunnel that have ended copied
+    pub(crate) fn served(&self) -> Served {
+        self.served.get()
+    }
+
+    /// How many connections are alive
+    pub(crate) fn active(&self) -> usize {
+        self.active.lock().unwrap().len()
+    }
+
+    /// The lowest, average and highest throughput of the live connections, `None` until one
+    /// of them has been sampled
+    pub(crate) fn throughput(&self) -> Option<Throughput> {
+        let active = self.active.lock().unwrap();
+        let samples: Vec<u64> = active
+            .values()
+            .filter_map(|connection| connection.sample.lock().unwrap().throughput)
+            .collect();
+        Some(Throughput {
+            min: *samples.iter().min()?,
+            avg: samples.iter().sum::<u64>() / samples.len() as u64,
+            max: *samples.iter().max()?,
+        })
+    }
+
+    pub(crate) fn status(&self) -> Vec<ConnectionStatus> {
+        self.active
+            .lock()
+            .unwrap()
+            .values()
+            .map(|connection| connection.status())
+            .collect()
+    }
+}
+
+/// Keeps a connection in the registry for as long as it is alive
+pub(crate) struct Registration {
+    connections: Arc<Connections>,
+    id: u64,
+    connection: Arc<Connection>,
+}
+
+impl std::ops::Deref for Registration {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        // Counted before the connection leaves `active`, so a connection is never missing from
+        // both
+        self.connections.served.add(&self.connection);
+        self.connections.proxy_served.add(&self.connection);
+        self.connections.active.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Connection {
+    client: SocketAddr,
+    destination: Mutex<Option<Destination>>,
+    started: time::Instant,
+    started_at: time::SystemTime,
+    /// Milliseconds after `started` that data was last copied
+    last_activity_ms: AtomicU64,
+    /// Bytes copied from the client to the destination
+    bytes_sent: AtomicU64,
+    /// Bytes copied from the destination to the client
+    bytes_received: AtomicU64,
+    sample: Mutex<Sample>,
+}
+
+/// The bytes a connection had copied at its last throughput sample
+#[derive(Debug)]
+struct Sample {
+    at: time::Instant,
+    bytes: u64,
+    /// Bytes per second in both directions since the sample before, `None` until the first
+    throughput: Option<u64>,
+}
+
+impl Connection {
+    pub(crate) fn set_destination(&self, destination: &Destination) {
+        *self.destination.lock().unwrap() = Some(destination.clone());
+    }
+
+    /// Counts the bytes read from `reader` as sent to the destination
+    pub(crate) fn track_sent<R>(&self, reader: R) -> Tracked<'_, R> {
+        Tracked {
+            inner: reader,
+            connection: self,
+            counter: &self.bytes_sent,
+        }
+    }
+
+    /// Counts the bytes read from `reader` as received from the destination
+    pub(crate) fn track_received<R>(&self, reader: R) -> Tracked<'_, R> {
+        Tracked {
+            inner: reader,
+            connection: self,
+            counter: &self.bytes_received,
+        }
+    }
+
+    pub(crate) fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    /// Updates the throughput with the bytes copied since the previous sample.
+    pub(crate) fn sample_throughput(&self) {
+        let bytes =
+            self.bytes_sent.load(Ordering::Relaxed) + self.bytes_received.load(Ordering::Relaxed);
+        let now = time::Instant::now();
+        let mut sample = self.sample.lock().unwrap();
+        let elapsed = now.duration_since(sample.at).as_secs_f64();
+        if elapsed > 0.0 {
+            sample.throughput = Some(((bytes - sample.bytes) as f64 / elapsed) as u64);
+        }
+        sample.at = now;
+        sample.bytes = bytes;
+    }
+
+    fn status(&self) -> ConnectionStatus {
+        let since_start =
+            time::Duration::from_millis(self.last_activity_ms.load(Ordering::Relaxed));
+        let last_activity = self.started_at + since_start;
+        ConnectionStatus {
+            client: self.client,
+            destination: self.destination.lock().unwrap().clone(),
+            started_at: unix_millis(self.started_at),
+            last_activity: unix_millis(last_activity),
+            idle_ms: self
+                .started
+                .elapsed()
+                .saturating_sub(since_start)
+                .as_millis() as u64,
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            throughput: self.sample.lock().unwrap().throughput,
+        }
+    }
+}
+
+fn unix_secs(t: time::SystemTime) -> u64 {
+    t.duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn unix_millis(t: time::SystemTime) -> u64 {
+    t.duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// A connection as reported by `GET /tunnels/{id}/connections`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConnectionStatus {
+    pub client: SocketAddr,
+    /// `None` while still connecting to the destination
+    pub destination: Option<Destination>,
+    /// Milliseconds since the unix epoch
+    pub started_at: u64,
+    /// Milliseconds since the unix epoch
+    pub last_activity: u64,
+    pub idle_ms: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Bytes per second in both directions, `None` until it is first sampled
+    pub throughput: Option<u64>,
+}
+
+/// Throughput in bytes per second across the live connections of a tunnel
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Throughput {
+    pub min: u64,
+    pub avg: u64,
+    pub max: u64,
+}
+
+/// How long connecting to the destination of a tunnel took, in microseconds. The average is
+/// over all connections, the 99th percentile over the most recent ones.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConnectLatency {
+    pub current_us: u64,
+    pub avg_us: u64,
+    pub p99_us: u64,
+}
+
+/// The most recent error of a tunnel
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LastError {
+    /// Milliseconds since the unix epoch
+    pub at: u64,
+    pub message: String,
+}
+
+/// A reader that records the data read through it on its [`Connection`]
+pub(crate) struct Tracked<'a, R> {
+    inner: R,
+    connection: &'a Connection,
+    counter: &'a AtomicU64,
+}
+
+impl<R> Tracked<'_, R> {
+    pub(crate) fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Tracked<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - filled;
+        if read > 0 {
+            self.counter.fetch_add(read as u64, Ordering::Relaxed);
+            let since_start = self.connection.started.elapsed().as_millis() as u64;
+            self.connection
+                .last_activity_ms
+                .store(since_start, Ordering::Relaxed);
+        }
+        result
+    }
+}
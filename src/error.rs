@@ -0,0 +1,170 @@
+//! The error responses of the control plane
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// What went wrong, stable for clients to match on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The body isn't a well-formed command
+    InvalidJson,
+    /// The signature, timestamp or nonce of the command was rejected
+    InvalidSignature,
+    /// The admin token of the request is missing or wrong
+    InvalidToken,
+    /// A field of the command has a value that can't be used
+    InvalidCommand,
+    /// The incoming port is below 1024 and the proxy doesn't allow that
+    PrivilegedPort,
+    /// A destination of the command is outside of the ones the proxy allows
+    DestinationNotAllowed,
+    /// A tunnel with the id already exists
+    IdConflict,
+    /// The incoming port is used by another tunnel or process
+    PortInUse,
+    /// The proxy already has its maximum amount of tunnels
+    TunnelLimit,
+    /// The key that signed the command already has its maximum amount of tunnels
+    QuotaExceeded,
+    /// The key that signed the command has a quota, and the tunnel belongs to another key or
+    /// the command is only for keys without one
+    NotPermitted,
+    /// No tunnel or route exists with the given id or path
+    NotFound,
+    /// The tunnel is draining and can no longer be changed
+    Draining,
+    /// The proxy lacks the permission to listen on the incoming port
+    PermissionDenied,
+    /// Listening on the incoming port failed for another reason
+    ListenFailed,
+    /// Removing the verifying keys would leave the proxy without any
+    LastVerifyingKey,
+    /// The route doesn't support the method of the request
+    MethodNotAllowed,
+    /// The command didn't finish within the command timeout of the proxy and was undone
+    CommandTimeout,
+}
+
+impl ErrorCode {
+    /// The HTTP status that goes with the code
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidJson | ErrorCode::InvalidCommand => StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidSignature | ErrorCode::InvalidToken => StatusCode::UNAUTHORIZED,
+            ErrorCode::PrivilegedPort
+            | ErrorCode::DestinationNotAllowed
+            | ErrorCode::PermissionDenied
+            | ErrorCode::NotPermitted => StatusCode::FORBIDDEN,
+            ErrorCode::IdConflict
+            | ErrorCode::PortInUse
+            | ErrorCode::Draining
+            | ErrorCode::LastVerifyingKey => StatusCode::CONFLICT,
+            ErrorCode::TunnelLimit => StatusCode::INSUFFICIENT_STORAGE,
+            ErrorCode::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::ListenFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::CommandTimeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+}
+
+/// A failed request, answered as `{"error": {"code": ..., "message": ...}}`
+#[derive(Debug)]
+pub struct ApiError {
+    /// Overrides the status of the code, for errors that carry their own
+    status: Option<StatusCode>,
+    body: ErrorBody,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorDetail {
+    pub code: ErrorCode,
+    /// Explanation for humans, which may change between versions
+    pub message: String,
+    /// Every problem found when the command was validated, listed even when there is only one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<Violation>,
+}
+
+/// One of the problems with a command that failed validation
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Violation {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            status: None,
+            body: ErrorBody {
+                error: ErrorDetail {
+                    code,
+                    message: message.into(),
+                    violations: Vec::new(),
+                },
+            },
+        }
+    }
+
+    /// Answers with `status` instead of the status of `code`.
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Answers the violations found by validating a command with a single error: a violation
+    /// of its own keeps its code, several are an `invalid_command` that joins their messages.
+    pub(crate) fn from_violations(mut violations: Vec<ApiError>) -> Result<(), ApiError> {
+        let listed: Vec<_> = violations
+            .iter()
+            .map(|violation| Violation {
+                code: violation.code(),
+                message: violation.to_string(),
+            })
+            .collect();
+        let mut error = match violations.len() {
+            0 => return Ok(()),
+            1 => violations.pop().unwrap(),
+            count => {
+                let messages: Vec<_> = listed.iter().map(|v| v.message.as_str()).collect();
+                ApiError::new(
+                    ErrorCode::InvalidCommand,
+                    format!("The command has {count} problems: {}", messages.join("; ")),
+                )
+            }
+        };
+        error.body.error.violations = listed;
+        Err(error)
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.body.error.code
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status.unwrap_or(self.body.error.code.status())
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.body.error.message)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status(), Json(self.body)).into_response()
+    }
+}
@@ -0,0 +1,185 @@
+//! Fallback destinations that a tunnel connects to while its destination is unreachable, and
+//! the probes that move it back once the destination recovers
+
+use crate::{Destination, Outbound, TunnelConfig};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time;
+use tokio::io;
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+/// Most fallback destinations that a tunnel may have, a client may wait for the connect timeout
+/// of every one of them
+pub const MAX_FAILOVER_DESTINATIONS: usize = 8;
+/// Time between the probes of the destination of a tunnel while it uses a fallback
+pub const FAILOVER_PROBE_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+/// Which of the destination of a tunnel and its fallbacks new connections go to
+#[derive(Debug)]
+pub(crate) struct Failover {
+    fallbacks: Vec<SocketAddr>,
+    /// The destination of the tunnel and the address to connect from, as of the last
+    /// connection. Probed while a fallback is active.
+    primary: Mutex<(Destination, Option<IpAddr>)>,
+    /// 0 for the destination of the tunnel, otherwise 1 + the index of the fallback
+    active: AtomicUsize,
+    /// Times that new connections moved to another destination, back included
+    switches: AtomicU64,
+    connect_timeout: time::Duration,
+}
+
+/// The failover of a tunnel as reported by the `Status` command
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FailoverStatus {
+    /// Where new connections go, the destination of the tunnel unless it is unreachable
+    pub active_destination: Destination,
+    pub fallbacks: Vec<SocketAddr>,
+    /// Times that new connections moved to another destination, back included
+    pub switches: u64,
+}
+
+impl Failover {
+    /// Starts out with `primary` and probes it every [`FAILOVER_PROBE_INTERVAL`] while a
+    /// fallback is active, until the failover is dropped.
+    pub(crate) fn new(
+        primary: Destination,
+        source_address: Option<IpAddr>,
+        fallbacks: Vec<SocketAddr>,
+        connect_timeout: time::Duration,
+    ) -> Arc<Self> {
+        let failover = Arc::new(Self {
+            fallbacks,
+            primary: Mutex::new((primary, source_address)),
+            active: AtomicUsize::new(0),
+            switches: AtomicU64::new(0),
+            connect_timeout,
+        });
+        tokio::spawn(probe_primary(Arc::downgrade(&failover)));
+        failover
+    }
+
+    /// Most destinations that a connection may try
+    pub(crate) fn attempts(&self) -> u32 {
+        self.fallbacks.len() as u32 + 1
+    }
+
+    /// Connects to the active destination, or to the others in order when it fails, each
+    /// within the connect timeout of the tunnel. Returns the destination that accepted.
+    ///
+    /// A `primary` other than the last one, after a `Modify`, starts over with it.
+    pub(crate) async fn connect(
+        &self,
+        primary: &Destination,
+        source_address: Option<IpAddr>,
+        config: &TunnelConfig,
+    ) -> io::Result<(Destination, Box<dyn Outbound>)> {
+        let active = {
+            let mut current = self.primary.lock().unwrap();
+            if *current != (primary.clone(), source_address) {
+                *current = (primary.clone(), source_address);
+                self.active.store(0, Ordering::Relaxed);
+            }
+            self.active.load(Ordering::Relaxed)
+        };
+        let candidates = std::iter::once(active)
+            .chain((0..self.attempts() as usize).filter(|index| *index != active));
+        let mut errors = Vec::new();
+        let mut kind = io::ErrorKind::Other;
+        for index in candidates {
+            let destination = self.destination(index, primary);
+            let connect = crate::connect(&destination, source_address, config);
+            let err = match tokio::time::timeout(self.connect_timeout, connect).await {
+                Ok(Ok(outbound)) => {
+                    self.switch_to(index, &destination);
+                    return Ok((destination, outbound));
+                }
+                Ok(Err(err)) => err,
+                Err(_) => io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("timed out after {:?}", self.connect_timeout),
+                ),
+            };
+            kind = err.kind();
+            errors.push(format!("{destination}: {err}"));
+        }
+        Err(io::Error::new(
+            kind,
+            format!("every destination failed ({})", errors.join(", ")),
+        ))
+    }
+
+    pub(crate) fn fallbacks(&self) -> &[SocketAddr] {
+        &self.fallbacks
+    }
+
+    pub(crate) fn status(&self) -> FailoverStatus {
+        let primary = self.primary.lock().unwrap().0.clone();
+        FailoverStatus {
+            active_destination: self.destination(self.active.load(Ordering::Relaxed), &primary),
+            fallbacks: self.fallbacks.clone(),
+            switches: self.switches.load(Ordering::Relaxed),
+        }
+    }
+
+    fn destination(&self, index: usize, primary: &Destination) -> Destination {
+        match index {
+            0 => primary.clone(),
+            _ => Destination::Tcp(self.fallbacks[index - 1]),
+        }
+    }
+
+    /// Sends new connections to the destination at `index` from now on.
+    fn switch_to(&self, index: usize, destination: &Destination) {
+        if self.active.swap(index, Ordering::Relaxed) != index {
+            self.switches.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("failing over to {destination}");
+        }
+    }
+}
+
+/// Moves the connections of `failover` back to the destination of the tunnel once it accepts
+/// connections again, while the failover exists.
+async fn probe_primary(failover: Weak<Failover>) {
+    let mut interval = tokio::time::interval(FAILOVER_PROBE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(failover) = failover.upgrade() else {
+            return;
+        };
+        if failover.active.load(Ordering::Relaxed) == 0 {
+            continue;
+        }
+        let (primary, source_address) = failover.primary.lock().unwrap().clone();
+        let probe = tokio::time::timeout(failover.connect_timeout, probe(&primary, source_address));
+        if let Ok(Ok(())) = probe.await {
+            failover.switch_to(0, &primary);
+        }
+    }
+}
+
+/// Connects to `destination` and closes the connection right away.
+async fn probe(destination: &Destination, source_address: Option<IpAddr>) -> io::Result<()> {
+    match destination {
+        Destination::Tcp(addr) => {
+            let socket = match addr {
+                SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+            };
+            if let Some(source_address) = source_address {
+                socket.bind(SocketAddr::new(source_address, 0))?;
+            }
+            let _: TcpStream = socket.connect(*addr).await?;
+        }
+        #[cfg(unix)]
+        Destination::Unix(path) => {
+            UnixStream::connect(path).await?;
+        }
+        #[cfg(not(unix))]
+        Destination::Unix(_) => return Err(io::Error::from(io::ErrorKind::Unsupported)),
+    }
+    Ok(())
+}
@@ -0,0 +1,151 @@
+//! Copies of the complete data that clients send through a tunnel, for replication
+
+use crate::connections::Connections;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time;
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// Reads of a connection that may wait for a fan-out destination, the client has to wait for
+/// a destination that is further behind
+const FANOUT_QUEUE_LEN: usize = 64;
+/// Longest time a write to a fan-out destination may take, after which it is left out, so one
+/// that stops reading holds up the client at most this long
+pub const FANOUT_WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+/// What happened to the copies of a tunnel's data for its fan-out destinations
+#[derive(Debug, Default)]
+pub(crate) struct FanoutStats {
+    sent_bytes: AtomicU64,
+    /// Connections to fan-out destinations that couldn't be made or broke off
+    failed_connections: AtomicU64,
+}
+
+impl FanoutStats {
+    pub(crate) fn status(&self, destinations: &[SocketAddr]) -> FanoutStatus {
+        FanoutStatus {
+            destinations: destinations.to_vec(),
+            sent_bytes: self.sent_bytes.load(Ordering::Relaxed),
+            failed_connections: self.failed_connections.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The fan-out destinations of a tunnel as reported by the `Status` command
+#[derive(Debug, Deserialize, Serialize)]
+pub struct FanoutStatus {
+    pub destinations: Vec<SocketAddr>,
+    /// Over all fan-out destinations together
+    pub sent_bytes: u64,
+    pub failed_connections: u64,
+}
+
+/// Connects to the fan-out destination `to` for one connection and returns where to send its
+/// data.
+///
+/// The connection is shut down once every sender is dropped, and whatever `to` answers is
+/// discarded. A write that takes longer than [`FANOUT_WRITE_TIMEOUT`] fails the connection,
+/// which closes the channel and so stops [`copy_buf`] from waiting for it. Failures are counted
+/// and only logged when the connection is `logged`, they never affect the connection to the
+/// destination of the tunnel.
+pub(crate) fn spawn(
+    to: SocketAddr,
+    connect_timeout: time::Duration,
+    connections: Arc<Connections>,
+    logged: bool,
+) -> mpsc::Sender<Vec<u8>> {
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(FANOUT_QUEUE_LEN);
+    tokio::spawn(async move {
+        let stats = connections.fanout_stats();
+        let fail = |message: String| {
+            if logged {
+                tracing::warn!("{message}");
+            }
+            stats.failed_connections.fetch_add(1, Ordering::Relaxed);
+        };
+        let stream = match tokio::time::timeout(connect_timeout, TcpStream::connect(to)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(err)) => return fail(format!("connecting to fan-out {to} failed: {err}")),
+            Err(_) => return fail(format!("connecting to fan-out {to} timed out")),
+        };
+        let (mut reader, mut writer) = stream.into_split();
+        let forward = async {
+            while let Some(data) = rx.recv().await {
+                write_in_time(writer.write_all(&data)).await?;
+                stats
+                    .sent_bytes
+                    .fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+            write_in_time(writer.shutdown()).await
+        };
+        // Doesn't decide when the connection is done, so it never finishes
+        let discard = async {
+            let _ = io::copy(&mut reader, &mut io::sink()).await;
+            std::future::pending::<()>().await
+        };
+        tokio::select! {
+            result = forward => {
+                if let Err(err) = result {
+                    fail(format!("writing to fan-out {to} failed: {err}"));
+                }
+            }
+            () = discard => unreachable!(),
+        }
+    });
+    tx
+}
+
+/// Fails `write` when it takes longer than [`FANOUT_WRITE_TIMEOUT`].
+async fn write_in_time(write: impl std::future::Future<Output = io::Result<()>>) -> io::Result<()> {
+    match tokio::time::timeout(FANOUT_WRITE_TIMEOUT, write).await {
+        Ok(written) => written,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the destination stopped reading",
+        )),
+    }
+}
+
+/// Copies `reader` to `primary` like `io::copy_buf`, and every read to the fan-out
+/// destinations at the same time. Destinations that failed are left out from then on.
+pub(crate) async fn copy_buf<R, W>(
+    reader: &mut R,
+    primary: &mut W,
+    fanout: &mut Vec<mpsc::Sender<Vec<u8>>>,
+) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut copied = 0;
+    loop {
+        let data = reader.fill_buf().await?;
+        if data.is_empty() {
+            primary.flush().await?;
+            return Ok(copied);
+        }
+        let copy = data.to_vec();
+        let send = async {
+            let mut failed = Vec::new();
+            for (i, destination) in fanout.iter().enumerate() {
+                // Only closed once the destination failed, which was counted then
+                if destination.send(copy.clone()).await.is_err() {
+                    failed.push(i);
+                }
+            }
+            failed
+        };
+        let (written, failed) = tokio::join!(primary.write_all(data), send);
+        for i in failed.into_iter().rev() {
+            fanout.swap_remove(i);
+        }
+        written?;
+        let len = copy.len();
+        copied += len as u64;
+        reader.consume(len);
+    }
+}
@@ -0,0 +1,123 @@
+//! Heartbeats to the orchestrator of the proxy, which close the tunnels when it can't be reached
+//! for a deployment that has to fail closed
+
+use crate::GlobalState;
+use hyper::{Body, Client, Method, Request, Uri};
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time;
+
+/// What happens to the tunnels once the orchestrator missed `max_missed` heartbeats in a row
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnreachablePolicy {
+    /// The tunnels keep forwarding
+    #[default]
+    FailOpen,
+    /// Every tunnel is closed, like with `Delete`, and tunnels created while the orchestrator
+    /// is still unreachable are closed at the next missed heartbeat. They aren't created again
+    /// once it is back.
+    FailClosed,
+}
+
+impl FromStr for UnreachablePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail-open" => Ok(Self::FailOpen),
+            "fail-closed" => Ok(Self::FailClosed),
+            _ => Err(format!("`{s}` is neither `fail-open` nor `fail-closed`")),
+        }
+    }
+}
+
+impl fmt::Display for UnreachablePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::FailOpen => "fail-open",
+            Self::FailClosed => "fail-closed",
+        })
+    }
+}
+
+/// Where and how often the proxy posts its heartbeats, see [`Heartbeat::run`]
+#[derive(Clone, Debug)]
+pub struct Heartbeat {
+    /// Plain HTTP URL that the heartbeats are posted to, like `http://orchestrator:8080/beat`
+    pub url: Uri,
+    /// Time between heartbeats, a heartbeat without a response within it is missed
+    pub interval: time::Duration,
+    /// Heartbeats missed in a row after which the orchestrator is unreachable, at least 1
+    pub max_missed: u32,
+    pub policy: UnreachablePolicy,
+}
+
+/// The body of a heartbeat
+#[derive(Debug, Serialize)]
+struct Beat {
+    tunnels: usize,
+    /// Incremented on every change to the tunnels, like the ETag of `Status`
+    version: u64,
+}
+
+impl Heartbeat {
+    /// Posts a heartbeat every `interval` for as long as the proxy runs, applying the policy
+    /// while the orchestrator doesn't answer them with a success.
+    pub async fn run(self, state: Arc<GlobalState>) {
+        let client = Client::new();
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut missed = 0u32;
+        loop {
+            interval.tick().await;
+            let beat = Beat {
+                tunnels: state.proxies.lock().unwrap().len(),
+                version: *state.version.borrow(),
+            };
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(self.url.clone())
+                .header("content-type", "application/json")
+                .body(Body::from(serde_json::to_vec(&beat).unwrap()))
+                .unwrap();
+            let error = match tokio::time::timeout(self.interval, client.request(request)).await {
+                Ok(Ok(response)) if response.status().is_success() => None,
+                Ok(Ok(response)) => Some(format!("answered {}", response.status())),
+                Ok(Err(err)) => Some(err.to_string()),
+                Err(_) => Some(format!("no answer within {:?}", self.interval)),
+            };
+            let Some(error) = error else {
+                if missed >= self.max_missed {
+                    tracing::info!("the orchestrator at {} is reachable again", self.url);
+                }
+                missed = 0;
+                continue;
+            };
+            missed = missed.saturating_add(1);
+            tracing::warn!("heartbeat to {} missed: {error}", self.url);
+            if missed < self.max_missed {
+                continue;
+            }
+            if missed == self.max_missed {
+                tracing::error!(
+                    "the orchestrator at {} missed {missed} heartbeats, failing {}",
+                    self.url,
+                    match self.policy {
+                        UnreachablePolicy::FailOpen => "open",
+                        UnreachablePolicy::FailClosed => "closed",
+                    }
+                );
+            }
+            if self.policy == UnreachablePolicy::FailClosed {
+                let closed = state.close_all_tunnels();
+                if closed > 0 {
+                    tracing::error!(
+                        "closed {closed} tunnels while the orchestrator is unreachable"
+                    );
+                }
+            }
+        }
+    }
+}
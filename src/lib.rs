@@ -1,353 +1,4487 @@
-use axum::extract::State;
-use axum::{http::StatusCode, Json};
-use p384::ecdsa::signature::Verifier;
-use p384::ecdsa::{Signature, VerifyingKey};
+use axum::body::{self, Body, Full, StreamBody};
+use axum::extract::rejection::{JsonRejection, QueryRejection};
+use axum::extract::{DefaultBodyLimit, Extension, Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode, Uri};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, IntoMakeService};
+use axum::{Json, Router, Server};
+use hyper::server::conn::AddrIncoming;
+use p384::ecdsa::signature::{Signer, Verifier};
+use p384::ecdsa::{Signature, SigningKey, VerifyingKey};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use std::net::{IpAddr, SocketAddr};
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{self, Write as _};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::time;
-use tokio::io::{self, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{
+    self, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    BufReader,
+};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
 use tokio::sync::watch::{self, Receiver, Sender};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::task::{AbortHandle, JoinSet};
+use tokio_stream::wrappers::WatchStream;
+use tokio_stream::{Stream, StreamExt};
+use tracing::Instrument;
 use uuid::Uuid;
 
+pub mod client;
+mod connections;
+mod error;
+mod failover;
+mod fanout;
+mod heartbeat;
+mod mirror;
+pub mod msgpack;
+#[cfg(feature = "otel")]
+pub mod otel;
+mod policy;
+mod pool;
+mod port;
+mod queue;
+mod rejections;
+mod reload;
+mod self_check;
+mod shutdown;
+mod sni;
+mod talkers;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod tls;
+#[cfg(unix)]
+pub mod unix;
+mod validate;
+
+use connections::{CloseReason, Connections, ServedCounter, THROUGHPUT_SAMPLE_INTERVAL};
+pub use connections::{ConnectLatency, ConnectionStatus, LastError, Served, Throughput};
+pub use error::{ApiError, ErrorBody, ErrorCode, ErrorDetail, Violation};
+use failover::Failover;
+pub use failover::{FailoverStatus, FAILOVER_PROBE_INTERVAL, MAX_FAILOVER_DESTINATIONS};
+pub use fanout::{FanoutStatus, FANOUT_WRITE_TIMEOUT};
+pub use heartbeat::{Heartbeat, UnreachablePolicy};
+use mirror::Mirrored;
+pub use mirror::{MirrorStatus, MIRROR_GZIP_LEVELS, MIRROR_WRITE_TIMEOUT};
+use msgpack::CommandBody;
+pub use policy::AllowedDestination;
+use pool::OutboundPool;
+pub use pool::{PoolStatus, MAX_POOLED_PER_DESTINATION, POOL_IDLE_TIMEOUT, POOL_SETTLE_TIME};
+pub use queue::OverflowPolicy;
+use queue::{ConnectionQueue, Pending};
+use rejections::{RejectReason, Rejections};
+pub use reload::{reload_tunnels, Reconciled};
+pub use self_check::{Finding, Inconsistency};
+pub use shutdown::{ShutdownStats, TunnelServed};
+pub use talkers::{ClientCount, MAX_TRACKED_CLIENTS};
+
+/// How old the timestamp of a signed command may be
+pub const MAX_COMMAND_AGE: time::Duration = time::Duration::from_secs(60);
+/// How far the timestamp of a signed command may be in the future, to allow for clock skew
+pub const MAX_COMMAND_FUTURE: time::Duration = time::Duration::from_secs(30);
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct ProxyCommand {
     #[serde(flatten)]
     command: Command,
     timestamp: Option<u64>,
+    /// Random bytes picked by the client, so that every signed command is unique
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nonce: Option<[u8; 16]>,
     signature: Option<Signature>,
 }
 
 impl ProxyCommand {
-    fn verify_signature(&self, verifying_key: &Option<VerifyingKey>) -> bool {
-        match (verifying_key, &self.signature) {
-            (Some(key), Some(signature)) => {
-                let mut message = serde_json::to_string(&self.command).unwrap();
-
-                let timestamp = if let Some(timestamp) = self.timestamp {
-                    message.push_str(&timestamp.to_string());
-                    time::Duration::from_secs(timestamp)
-                } else {
+    /// An unsigned command that creates the tunnel `id` from `incoming_port` to `destination`,
+    /// with the defaults for everything else.
+    pub fn create(id: Uuid, incoming_port: u16, destination: SocketAddr) -> Self {
+        Self::unsigned(Command::create(id, incoming_port, destination))
+    }
+
+    /// An unsigned command that deletes the tunnel `id` right away.
+    pub fn delete(id: Uuid) -> Self {
+        Self::unsigned(Command::Delete { id, drain: false })
+    }
+
+    fn unsigned(command: Command) -> Self {
+        Self {
+            command,
+            timestamp: None,
+            nonce: None,
+            signature: None,
+        }
+    }
+
+    /// Signs the command with `signing_key`, at the current time and with a random nonce.
+    pub fn sign(mut self, signing_key: &SigningKey) -> Self {
+        let timestamp = unix_timestamp();
+        let nonce: [u8; 16] = rand::random();
+        self.signature = Some(signing_key.sign(self.signed_message(timestamp, &nonce).as_bytes()));
+        self.timestamp = Some(timestamp);
+        self.nonce = Some(nonce);
+        self
+    }
+
+    /// What the signature is over: the command serialized as JSON, followed by the timestamp in
+    /// decimal and the nonce in hex.
+    fn signed_message(&self, timestamp: u64, nonce: &[u8; 16]) -> String {
+        let mut message = serde_json::to_string(&self.command).unwrap();
+        message.push_str(&timestamp.to_string());
+        for byte in nonce {
+            message.push_str(&format!("{byte:02x}"));
+        }
+        message
+    }
+
+    /// A view of the command that is safe to log: it only shows the kind of command, the
+    /// tunnel id and a truncated signature.
+    fn redacted(&self) -> Redacted<'_> {
+        Redacted(self)
+    }
+
+    /// Checks the signature over the command, its timestamp and its nonce.
+    ///
+    /// A signature by any of `verifying_keys` is accepted, and the name of the key that made it
+    /// is returned. Without keys, every command is. Unless `record_nonce` is false, the nonce is
+    /// remembered so the command can't be used again.
+    fn verify_signature<'k>(
+        &self,
+        verifying_keys: &'k HashMap<String, VerifyingKey>,
+        nonces: &Nonces,
+        record_nonce: bool,
+    ) -> Result<Option<&'k str>, VerifyError> {
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap();
+        self.verify_signature_at(verifying_keys, nonces, record_nonce, now)
+    }
+
+    /// Like `verify_signature`, at `now` since the unix epoch.
+    fn verify_signature_at<'k>(
+        &self,
+        verifying_keys: &'k HashMap<String, VerifyingKey>,
+        nonces: &Nonces,
+        record_nonce: bool,
+        now: time::Duration,
+    ) -> Result<Option<&'k str>, VerifyError> {
+        match (verifying_keys.is_empty(), &self.signature) {
+            (false, Some(signature)) => {
+                let Some(timestamp) = self.timestamp else {
                     tracing::debug!("timestamp missing while signature is present");
-                    return false; // timestamp missing with signature present
+                    return Err(VerifyError::MissingTimestamp);
                 };
+                let Some(nonce) = self.nonce else {
+                    tracing::debug!("nonce missing while signature is present");
+                    return Err(VerifyError::MissingNonce);
+                };
+                let message = self.signed_message(timestamp, &nonce);
+                let timestamp = time::Duration::from_secs(timestamp);
 
-                if !key.verify(message.as_bytes(), signature).is_ok() {
+                let Some(signer) = verifying_keys
+                    .iter()
+                    .find(|(_, key)| key.verify(message.as_bytes(), signature).is_ok())
+                    .map(|(name, _)| name.as_str())
+                else {
                     tracing::debug!("signature does not match message");
-                    return false; // signature doesn't match
-                }
+                    return Err(VerifyError::SignatureMismatch);
+                };
 
-                let now = time::SystemTime::now()
-                    .duration_since(time::UNIX_EPOCH)
-                    .unwrap();
-                if timestamp > (now + time::Duration::from_secs(30)) {
-                    tracing::warn!("command is more than 30s from the future");
-                    false
-                } else if now - timestamp <= time::Duration::from_secs(60) {
-                    // less than a minute old
-                    true
+                // A command from the near future has an age of zero, instead of one that
+                // underflows
+                if timestamp > (now + MAX_COMMAND_FUTURE) {
+                    tracing::warn!("command is more than {MAX_COMMAND_FUTURE:?} from the future");
+                    Err(VerifyError::FromTheFuture { now: now.as_secs() })
+                } else if now.saturating_sub(timestamp) <= MAX_COMMAND_AGE {
+                    let fresh = if record_nonce {
+                        nonces.insert(nonce, timestamp.as_secs(), now.as_secs())
+                    } else {
+                        !nonces.contains(&nonce)
+                    };
+                    if fresh {
+                        Ok(Some(signer))
+                    } else {
+                        tracing::warn!("command reuses a nonce");
+                        Err(VerifyError::ReplayedNonce)
+                    }
                 } else {
-                    tracing::warn!("command is more than a minute old");
-                    false
+                    tracing::warn!("command is more than {MAX_COMMAND_AGE:?} old");
+                    Err(VerifyError::Stale { now: now.as_secs() })
                 }
             }
-            (Some(_), None) => false,
-            (None, _) => true,
+            (false, None) => Err(VerifyError::MissingSignature),
+            (true, _) => Ok(None),
+        }
+    }
+}
+
+/// Why the signature of a command was rejected
+#[derive(Debug, PartialEq, Eq)]
+enum VerifyError {
+    MissingSignature,
+    MissingTimestamp,
+    MissingNonce,
+    SignatureMismatch,
+    ReplayedNonce,
+    Stale { now: u64 },
+    FromTheFuture { now: u64 },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::MissingSignature => write!(f, "Signature missing"),
+            VerifyError::MissingTimestamp => {
+                write!(f, "Timestamp missing while signature is present")
+            }
+            VerifyError::MissingNonce => write!(f, "Nonce missing while signature is present"),
+            VerifyError::SignatureMismatch => write!(f, "Signature does not match the command"),
+            VerifyError::ReplayedNonce => write!(f, "Nonce has already been used"),
+            VerifyError::Stale { now } => write!(
+                f,
+                "Command is more than {}s old (server time: {now})",
+                MAX_COMMAND_AGE.as_secs()
+            ),
+            VerifyError::FromTheFuture { now } => write!(
+                f,
+                "Command is more than {}s in the future (server time: {now})",
+                MAX_COMMAND_FUTURE.as_secs()
+            ),
+        }
+    }
+}
+
+/// Header with the time at which the proxy signed a response, in seconds since the unix epoch
+pub const RESPONSE_TIMESTAMP_HEADER: &str = "x-proxima-timestamp";
+/// Header with the signature of the proxy over the body of a response and its timestamp
+pub const RESPONSE_SIGNATURE_HEADER: &str = "x-proxima-signature";
+
+/// Header with the timestamp of the `status` command that authenticates a `GET` request, see
+/// [`ProxyCommand::read_headers`]
+pub const REQUEST_TIMESTAMP_HEADER: &str = "x-proxima-request-timestamp";
+/// Header with the nonce of the `status` command that authenticates a `GET` request, in hex
+pub const REQUEST_NONCE_HEADER: &str = "x-proxima-request-nonce";
+/// Header with the signature of the `status` command that authenticates a `GET` request
+pub const REQUEST_SIGNATURE_HEADER: &str = "x-proxima-request-signature";
+
+/// Checks that a response of `POST /command` was signed by the proxy, the inverse of the
+/// signature over commands.
+///
+/// The signature is over the body followed by the timestamp in decimal. Like commands, the
+/// timestamp has to be at most [`MAX_COMMAND_AGE`] old, so an old response can't be replayed.
+pub fn verify_response(
+    verifying_key: &VerifyingKey,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<(), ResponseVerifyError> {
+    let signature = headers
+        .get(RESPONSE_SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| Signature::from_str(value).ok())
+        .ok_or(ResponseVerifyError::MissingSignature)?;
+    let timestamp = headers
+        .get(RESPONSE_TIMESTAMP_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .ok_or(ResponseVerifyError::MissingTimestamp)?;
+
+    let mut message = body.to_vec();
+    message.extend_from_slice(timestamp.to_string().as_bytes());
+    if verifying_key.verify(&message, &signature).is_err() {
+        return Err(ResponseVerifyError::SignatureMismatch);
+    }
+
+    let now = unix_timestamp();
+    if timestamp > now + MAX_COMMAND_FUTURE.as_secs() {
+        Err(ResponseVerifyError::FromTheFuture { now })
+    } else if now.saturating_sub(timestamp) > MAX_COMMAND_AGE.as_secs() {
+        Err(ResponseVerifyError::Stale { now })
+    } else {
+        Ok(())
+    }
+}
+
+/// Why the signature of a response was rejected
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResponseVerifyError {
+    MissingSignature,
+    MissingTimestamp,
+    SignatureMismatch,
+    Stale { now: u64 },
+    FromTheFuture { now: u64 },
+}
+
+impl fmt::Display for ResponseVerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseVerifyError::MissingSignature => write!(f, "Signature missing"),
+            ResponseVerifyError::MissingTimestamp => write!(f, "Timestamp missing"),
+            ResponseVerifyError::SignatureMismatch => {
+                write!(f, "Signature does not match the response")
+            }
+            ResponseVerifyError::Stale { now } => write!(
+                f,
+                "Response is more than {}s old (local time: {now})",
+                MAX_COMMAND_AGE.as_secs()
+            ),
+            ResponseVerifyError::FromTheFuture { now } => write!(
+                f,
+                "Response is more than {}s in the future (local time: {now})",
+                MAX_COMMAND_FUTURE.as_secs()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ResponseVerifyError {}
+
+/// Nonces of the recently accepted commands, to reject replays of them
+#[derive(Debug, Default)]
+struct Nonces(Mutex<HashMap<[u8; 16], u64>>);
+
+impl Nonces {
+    /// Records the `nonce` of a command signed at `timestamp`, returns `false` if it has been
+    /// used before.
+    fn insert(&self, nonce: [u8; 16], timestamp: u64, now: u64) -> bool {
+        let mut nonces = self.0.lock().unwrap();
+        // Replays of commands that are older than this are rejected as stale anyway
+        nonces.retain(|_, seen| *seen + MAX_COMMAND_AGE.as_secs() >= now);
+        nonces.insert(nonce, timestamp).is_none()
+    }
+
+    /// Whether `nonce` has been used by a recent command
+    fn contains(&self, nonce: &[u8; 16]) -> bool {
+        self.0.lock().unwrap().contains_key(nonce)
+    }
+}
+
+struct Redacted<'a>(&'a ProxyCommand);
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("ProxyCommand");
+        debug.field("command", &self.0.command.kind());
+        if let Some(id) = self.0.command.id() {
+            debug.field("id", &id);
         }
+        debug.field("timestamp", &self.0.timestamp);
+        debug.field(
+            "signature",
+            &self.0.signature.map(|signature| {
+                let mut hex = signature.to_string();
+                hex.truncate(8);
+                format!("{hex}...")
+            }),
+        );
+        debug.finish()
     }
 }
 
+// A command only lives for one request, so the size of `Create` doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(rename_all = "snake_case")]
 enum Command {
     Create {
+        #[serde(deserialize_with = "port::incoming_port")]
         incoming_port: u16,
-        destination_port: u16,
-        destination_ip: IpAddr,
+        #[serde(
+            default,
+            deserialize_with = "port::destination_port",
+            skip_serializing_if = "Option::is_none"
+        )]
+        destination_port: Option<u16>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        destination_ip: Option<IpAddr>,
+        /// Path of a unix socket to forward to, instead of `destination_ip` and
+        /// `destination_port`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        destination_uds: Option<PathBuf>,
         id: Uuid,
+        /// Time to wait for the destination to accept a connection, defaults to
+        /// [`DEFAULT_CONNECT_TIMEOUT`]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        connect_timeout_ms: Option<u64>,
+        /// Local address to connect to the destination from, instead of letting the OS pick one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        source_address: Option<IpAddr>,
+        /// Delete the tunnel automatically after this many seconds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl_secs: Option<u64>,
+        /// Size of the buffer used per direction of every connection, defaults to
+        /// [`DEFAULT_BUFFER_SIZE`] and may be at most [`MAX_BUFFER_SIZE`]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        buffer_size: Option<usize>,
+        /// Bound the data of every connection that the proxy holds per direction, for tunnels
+        /// with many connections between fast clients and slow destinations or the other way
+        /// around. Caps the copy buffers and the socket buffers of both sides at this many
+        /// bytes, which the kernel may round up, and only reads more once the previous data
+        /// was written. Costs some throughput, at most [`MAX_BUFFER_SIZE`].
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_in_flight_bytes: Option<usize>,
+        /// Disable Nagle's algorithm on both sides of every connection
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        tcp_nodelay: bool,
+        /// Send TCP keepalive probes on both sides of every connection after it has been idle
+        /// for this many seconds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tcp_keepalive_secs: Option<u64>,
+        /// Mark the packets of both sides of every connection with this DSCP class, at most
+        /// [`MAX_DSCP`], like 46 for expedited forwarding on networks that honor it. Ignored
+        /// where the platform can't set it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dscp: Option<u8>,
+        /// Close every connection this many seconds after it was accepted, however busy it is,
+        /// so clients have to reconnect now and then, like for rebalancing. A connection that
+        /// switches to a new destination keeps its deadline.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_lifetime_secs: Option<u64>,
+        /// Set `SO_REUSEADDR` on the listener, defaults to the setting of the proxy
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reuse_address: Option<bool>,
+        /// Set `SO_REUSEPORT` on the listener, defaults to the setting of the proxy
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reuse_port: Option<bool>,
+        /// Fraction of the connections between 0 and 1 that log their details, picked by
+        /// client address. Defaults to 1, logging every connection.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        log_sample_rate: Option<f64>,
+        /// Log nothing about the connections of the tunnel, not even errors, for tunnels whose
+        /// connection details may not be recorded. They are still counted in the metrics.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        quiet: bool,
+        /// Free-form description for humans, at most [`MAX_LABEL_LEN`] bytes
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        label: Option<String>,
+        /// Length of the queue of connections that wait to be accepted, defaults to
+        /// [`DEFAULT_BACKLOG`]. The kernel drops connections beyond it and may cap it, to
+        /// `net.core.somaxconn` on Linux.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        backlog: Option<u32>,
+        /// Tasks that accept connections from the listener at the same time, at most
+        /// [`MAX_ACCEPT_LOOPS`]. Defaults to 1, which keeps up unless the tunnel gets many
+        /// thousands of connections per second.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        accept_loops: Option<usize>,
+        /// Destinations by the server name that TLS clients ask for in their ClientHello,
+        /// without terminating TLS. Connections without a server name or with one that isn't
+        /// listed go to the destination of the tunnel. Server names are matched ignoring case,
+        /// so each may only be listed once.
+        ///
+        /// Sorted, so that the command is signed the same way on both ends.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        sni_map: BTreeMap<String, SocketAddr>,
+        /// Read the responses for the client from this address instead of from the
+        /// destination, which then only receives the data of the client and whose answers are
+        /// discarded. Only useful for setups like traffic capture, where two independent
+        /// servers handle the directions of one protocol.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        response_destination: Option<SocketAddr>,
+        /// Also send the data of every client to this address, on a best-effort basis: data
+        /// that the mirror can't keep up with is dropped, and a failing mirror never affects
+        /// the connection to the destination.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mirror_to: Option<SocketAddr>,
+        /// Gzip the data sent to `mirror_to` with this level, from 1 for the fastest to 9 for
+        /// the smallest, for mirrors with little bandwidth. Every connection is a gzip stream
+        /// of its own. The connection to the destination is never compressed.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mirror_gzip_level: Option<u32>,
+        /// Also send the complete data of every client to each of these, at most
+        /// [`MAX_FANOUT_DESTINATIONS`]. Unlike a mirror nothing is dropped, so the client is
+        /// slowed down to the slowest of them. Their answers are discarded, and one that fails,
+        /// also by not taking data for [`FANOUT_WRITE_TIMEOUT`], is left out for the rest of
+        /// the connection without affecting it.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        fanout_destinations: Vec<SocketAddr>,
+        /// Connect to these in order while the destination is unreachable, at most
+        /// [`MAX_FAILOVER_DESTINATIONS`]. New connections stay with the first one that accepts
+        /// until the destination accepts connections again, which is probed every
+        /// [`FAILOVER_PROBE_INTERVAL`].
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        failover_destinations: Vec<SocketAddr>,
+        /// Sent to clients whose connection is refused because the proxy is at its maximum of
+        /// connections, like an HTTP 503 response, before closing it. Without it the connection
+        /// is closed right away.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        overflow_response: Option<Vec<u8>>,
+        /// Queue up to this many accepted connections for a fixed pool of `queue_workers`,
+        /// instead of handling every connection as soon as it is accepted. Bounds the
+        /// connections and memory of a tunnel during bursts, at most [`MAX_QUEUE_LEN`].
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        queue_len: Option<usize>,
+        /// What happens to connections that arrive while the queue is full, only used with a
+        /// `queue_len`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        overflow_policy: Option<OverflowPolicy>,
+        /// Connections of a queued tunnel that are handled at the same time, defaults to
+        /// [`DEFAULT_QUEUE_WORKERS`]. Only used with a `queue_len`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        queue_workers: Option<usize>,
+        /// Keep the connection to the destination open once a client has sent everything, and
+        /// hand it to the next client instead of connecting again. Only for request and
+        /// response protocols without state per connection: the connection is done when the
+        /// client has stopped sending and the destination has then been quiet for
+        /// [`POOL_SETTLE_TIME`]. It is only kept when both sides ended cleanly and the
+        /// destination sent nothing after the client stopped, as the answer may not be
+        /// complete otherwise. A destination that pauses within an answer for longer than that
+        /// while the client reads it still hands the rest to the next client. At most
+        /// [`MAX_POOLED_PER_DESTINATION`] connections are kept, each for up to
+        /// [`POOL_IDLE_TIMEOUT`].
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pool: bool,
     },
-    Modify {
-        destination_port: u16,
+    /// Creates a tunnel that picks the destination of every connection by a route key that the
+    /// client sends first: one byte with the length of the key, followed by the key in UTF-8.
+    /// Only the data after the key is passed on, connections with an unknown key are closed.
+    CreateRouter {
+        id: Uuid,
+        #[serde(deserialize_with = "port::incoming_port")]
+        incoming_port: u16,
+        /// Sorted, so that the command is signed the same way on both ends
+        routes: BTreeMap<String, SocketAddr>,
+    },
+    /// Creates `count` tunnels with the defaults, from `start_port + i` to
+    /// `destination_port_start + i` on `destination_ip`. The tunnels get random ids, and none
+    /// of them are kept if one can't be created.
+    CreateRange {
+        #[serde(deserialize_with = "port::start_port")]
+        start_port: u16,
+        count: u16,
         destination_ip: IpAddr,
+        #[serde(deserialize_with = "port::destination_port_start")]
+        destination_port_start: u16,
+    },
+    /// Changes a tunnel. The fields that are left out keep their current value, and `null`
+    /// clears the ones that can be cleared.
+    Modify {
+        /// Keeps the current port of the destination when only `destination_ip` is given
+        #[serde(
+            default,
+            deserialize_with = "port::destination_port",
+            skip_serializing_if = "Option::is_none"
+        )]
+        destination_port: Option<u16>,
+        /// Keeps the current address of the destination when only `destination_port` is given
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        destination_ip: Option<IpAddr>,
+        /// Path of a unix socket to forward to, instead of `destination_ip` and
+        /// `destination_port`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        destination_uds: Option<PathBuf>,
         id: Uuid,
+        /// Replaces the source address of the tunnel, while `null` goes back to the default
+        /// routing. Left out, the current source address is kept.
+        #[serde(
+            default,
+            deserialize_with = "present",
+            skip_serializing_if = "Option::is_none"
+        )]
+        source_address: Option<Option<IpAddr>>,
+        /// Replaces the time to live of the tunnel, counting from now, while `null` keeps the
+        /// tunnel until it is deleted. Left out, the current time to live keeps running.
+        #[serde(
+            default,
+            deserialize_with = "present",
+            skip_serializing_if = "Option::is_none"
+        )]
+        ttl_secs: Option<Option<u64>>,
+        /// Let established connections keep using the old destination until they close,
+        /// instead of reconnecting them to the new one
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        drain_on_modify: bool,
+        /// Replaces the label of the tunnel, while `null` removes it. Left out, the current label
+        /// is kept.
+        #[serde(
+            default,
+            deserialize_with = "present",
+            skip_serializing_if = "Option::is_none"
+        )]
+        label: Option<Option<String>>,
+    },
+    /// Points the tunnel at `destination` for `revert_after_secs` seconds, after which it
+    /// goes back to the destination from before, like for trying a canary. A `Modify` in the
+    /// meantime cancels the revert, another `TemporaryModify` replaces its time but still goes
+    /// back to the destination from before the first one.
+    TemporaryModify {
+        id: Uuid,
+        destination: SocketAddr,
+        revert_after_secs: u64,
     },
     Delete {
         id: Uuid,
+        /// Stop accepting connections, but only remove the tunnel once the
+        /// established connections have finished
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        drain: bool,
+    },
+    /// Moves the incoming port of tunnel `from_id` to tunnel `to_id`, which stops listening on
+    /// its own port, like for migrating a service to a new tunnel.
+    ///
+    /// `from_id` drains like a `Delete` with `drain`: its established connections keep using
+    /// its destination until they close, after which it is removed, while the connections
+    /// accepted from then on go to the destination of `to_id`. The listener itself is handed
+    /// on, so clients that connect in the meantime wait in its backlog instead of being
+    /// refused. The connections of `to_id` are not affected.
+    Handover {
+        from_id: Uuid,
+        to_id: Uuid,
     },
     Status,
+    /// Changes the keys that commands may be signed with, without restarting the proxy. The
+    /// command itself has to be signed by one of the keys from before the change.
+    RotateKey {
+        /// Keys to add, replacing keys with the same name
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        add: Vec<NamedKey>,
+        /// Names of the keys to remove
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        remove: Vec<String>,
+    },
+    /// Audits the tunnels against the ports they reserve, to catch state that leaked, like a
+    /// port that stays reserved after its tunnel is gone
+    SelfCheck {
+        /// Remove dead tunnels and make the reserved ports match the tunnels
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        repair: bool,
+    },
+}
+
+/// A verifying key in PEM format, with a name to remove it by later
+#[derive(Deserialize, Serialize, Debug)]
+struct NamedKey {
+    name: String,
+    key: String,
+    #[serde(default, skip_serializing_if = "KeyRole::is_admin")]
+    role: KeyRole,
+}
+
+/// What the commands signed by a verifying key may do
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyRole {
+    /// Run every command
+    #[default]
+    Admin,
+    /// Only run `Status`, for parties that may watch the tunnels but not change them
+    Observer,
+}
+
+impl KeyRole {
+    fn is_admin(&self) -> bool {
+        *self == KeyRole::Admin
+    }
+}
+
+/// Reads a field that is there as `Some`, also when it is `null`, so a `Modify` can tell a
+/// field that it leaves out, which keeps the current value, from one that clears it.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+impl Command {
+    /// Creates the tunnel `id` from `incoming_port` to `destination`, with the defaults for
+    /// everything else.
+    fn create(id: Uuid, incoming_port: u16, destination: SocketAddr) -> Self {
+        Command::Create {
+            incoming_port,
+            destination_port: Some(destination.port()),
+            destination_ip: Some(destination.ip()),
+            destination_uds: None,
+            id,
+            connect_timeout_ms: None,
+            source_address: None,
+            ttl_secs: None,
+            buffer_size: None,
+            max_in_flight_bytes: None,
+            tcp_nodelay: false,
+            tcp_keepalive_secs: None,
+            dscp: None,
+            max_lifetime_secs: None,
+            reuse_address: None,
+            reuse_port: None,
+            log_sample_rate: None,
+            quiet: false,
+            label: None,
+            backlog: None,
+            accept_loops: None,
+            sni_map: BTreeMap::new(),
+            response_destination: None,
+            mirror_to: None,
+            mirror_gzip_level: None,
+            fanout_destinations: Vec::new(),
+            failover_destinations: Vec::new(),
+            overflow_response: None,
+            queue_len: None,
+            overflow_policy: None,
+            queue_workers: None,
+            pool: false,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Command::Create { .. } => "create",
+            Command::CreateRange { .. } => "create_range",
+            Command::CreateRouter { .. } => "create_router",
+            Command::Modify { .. } => "modify",
+            Command::TemporaryModify { .. } => "temporary_modify",
+            Command::Delete { .. } => "delete",
+            Command::Handover { .. } => "handover",
+            Command::Status => "status",
+            Command::RotateKey { .. } => "rotate_key",
+            Command::SelfCheck { .. } => "self_check",
+        }
+    }
+
+    /// The id of the tunnel the command applies to
+    fn id(&self) -> Option<Uuid> {
+        match self {
+            Command::Create { id, .. }
+            | Command::CreateRouter { id, .. }
+            | Command::Modify { id, .. }
+            | Command::TemporaryModify { id, .. }
+            | Command::Delete { id, .. }
+            | Command::Handover { to_id: id, .. } => Some(*id),
+            Command::CreateRange { .. }
+            | Command::Status
+            | Command::RotateKey { .. }
+            | Command::SelfCheck { .. } => None,
+        }
+    }
+}
+
+/// Where a tunnel forwards its connections to
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Destination {
+    Tcp(SocketAddr),
+    /// A unix domain socket on the host of the proxy
+    Unix(PathBuf),
+}
+
+impl Destination {
+    /// The destination given by the `destination_*` fields of a command
+    fn from_fields(
+        ip: Option<IpAddr>,
+        port: Option<u16>,
+        uds: Option<PathBuf>,
+    ) -> Result<Self, String> {
+        match (ip, port, uds) {
+            (Some(ip), Some(port), None) => Ok(Destination::Tcp(SocketAddr::new(ip, port))),
+            #[cfg(unix)]
+            (None, None, Some(path)) => Ok(Destination::Unix(path)),
+            #[cfg(not(unix))]
+            (None, None, Some(_)) => {
+                Err("`destination_uds` is not supported on this platform".to_string())
+            }
+            _ => Err(
+                "Either `destination_ip` and `destination_port`, or `destination_uds` must be given"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Tcp(addr) => write!(f, "{addr}"),
+            Destination::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub enum ProxyResponse {
     Message(String),
     Status {
-        tunnels: HashMap<Uuid, (u16, SocketAddr)>,
+        tunnels: HashMap<Uuid, TunnelStatus>,
+        /// All tunnels, including the ones a filter left out
+        tunnel_count: usize,
+        /// `None` when the amount of tunnels isn't limited
+        max_tunnels: Option<usize>,
+        /// Open connections through all tunnels
+        active_connections: usize,
+        /// `None` when the amount of connections isn't limited
+        max_connections: Option<usize>,
+    },
+    /// The clock of the proxy and the window in which it accepts signed commands
+    Time {
+        timestamp: u64,
+        max_age_secs: u64,
+        max_future_secs: u64,
+    },
+    /// The build of the proxy, to check that hosts run compatible versions
+    Version {
+        version: String,
+        /// Commit the proxy was built from, when `PROXIMA_GIT_HASH` was set during the build
+        git_hash: Option<String>,
+        /// The optional parts of the proxy that this build supports
+        features: Vec<String>,
+    },
+    /// Resource usage per tunnel, to find the tunnel that exhausts the file descriptors
+    Diagnostics {
+        tunnels: HashMap<Uuid, TunnelDiagnostics>,
+    },
+    /// The answer to a `Modify` command
+    Modified {
+        message: String,
+        /// Of the tunnel after this command, a higher one means that another `Modify` was
+        /// applied after it
+        generation: u64,
+        /// Of the tunnel after this command, including the fields that the command left out
+        destination: Destination,
+    },
+    /// The ids of the tunnels created by a `CreateRange` command, by incoming port
+    Created {
+        ids: Vec<Uuid>,
+    },
+    /// The live connections of a tunnel
+    Connections {
+        connections: Vec<ConnectionStatus>,
+        last_error: Option<LastError>,
+        /// The `create` command of the tunnel that every connection belongs to, with its
+        /// original destination and limits
+        #[serde(default)]
+        created_with: serde_json::Value,
+    },
+    /// The client addresses that opened the most connections through a tunnel
+    TopClients {
+        clients: Vec<ClientCount>,
+        /// Connections accepted from all clients together
+        accepted: u64,
+    },
+    /// The settings in effect for a tunnel
+    Config {
+        config: Box<TunnelSettings>,
+    },
+    /// The inconsistencies found by a `SelfCheck`, none when the state of the proxy is sound
+    SelfCheck {
+        findings: Vec<Finding>,
+        /// All tunnels that were checked
+        tunnel_count: usize,
+    },
+}
+
+/// Content type of a streamed `Status` response, one [`StatusLine`] per line
+pub const STATUS_LINES_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// A line of a streamed `Status` response. The summary comes first, followed by a line per
+/// tunnel.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum StatusLine {
+    Summary {
+        /// All tunnels, including the ones a filter left out
+        tunnel_count: usize,
+        max_tunnels: Option<usize>,
+        active_connections: usize,
+        max_connections: Option<usize>,
+    },
+    Tunnel {
+        id: Uuid,
+        status: Box<TunnelStatus>,
     },
 }
 
+impl ProxyResponse {
+    /// Collects the lines of a streamed `Status` response into a single `Status`.
+    pub fn from_status_lines(lines: &[u8]) -> Result<Self, serde_json::Error> {
+        let mut tunnels = HashMap::new();
+        let mut summary = None;
+        for line in serde_json::Deserializer::from_slice(lines).into_iter() {
+            match line? {
+                StatusLine::Summary {
+                    tunnel_count,
+                    max_tunnels,
+                    active_connections,
+                    max_connections,
+                } => {
+                    summary = Some((
+                        tunnel_count,
+                        max_tunnels,
+                        active_connections,
+                        max_connections,
+                    ))
+                }
+                StatusLine::Tunnel { id, status } => {
+                    tunnels.insert(id, *status);
+                }
+            }
+        }
+        let Some((tunnel_count, max_tunnels, active_connections, max_connections)) = summary else {
+            return Err(serde::de::Error::missing_field("Summary"));
+        };
+        Ok(ProxyResponse::Status {
+            tunnels,
+            tunnel_count,
+            max_tunnels,
+            active_connections,
+            max_connections,
+        })
+    }
+}
+
+/// The resource usage of a tunnel as reported by `GET /diagnostics`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TunnelDiagnostics {
+    pub active_connections: usize,
+    /// Two per connection plus the listener, only reported on Unix
+    pub estimated_fds: Option<usize>,
+}
+
+/// A tunnel as reported by the `Status` command
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TunnelStatus {
+    pub state: TunnelState,
+    pub incoming_port: u16,
+    pub label: Option<String>,
+    pub destination: Destination,
+    /// Seconds since the unix epoch
+    pub created_at: u64,
+    /// Seconds since the unix epoch, equal to `created_at` until the tunnel is modified
+    pub last_modified: u64,
+    /// How many times the tunnel was modified
+    pub generation: u64,
+    pub age_secs: u64,
+    /// Seconds until the tunnel expires, `None` if it has no time to live
+    pub ttl_remaining_secs: Option<u64>,
+    /// Why the last connection through the tunnel failed, `None` once one succeeds again
+    pub last_error: Option<LastError>,
+    pub backlog: u32,
+    pub accept_loops: usize,
+    /// Seconds after which connections are closed, `None` without a maximum lifetime
+    pub max_lifetime_secs: Option<u64>,
+    /// Whether the connections of the tunnel are left out of the logs
+    pub quiet: bool,
+    /// Connections accepted during the last complete second
+    pub accepted_last_sec: u64,
+    pub sni_map: BTreeMap<String, SocketAddr>,
+    /// Destinations by route key when the tunnel is a router, whose `destination` is then the
+    /// unspecified address `0.0.0.0:0`
+    pub routes: BTreeMap<String, SocketAddr>,
+    /// Where the responses come from, `None` when it is the destination
+    pub response_destination: Option<Destination>,
+    pub mirror: Option<MirrorStatus>,
+    /// `None` when the tunnel has no fan-out destinations
+    pub fanout: Option<FanoutStatus>,
+    /// `None` when the tunnel has no failover destinations
+    pub failover: Option<FailoverStatus>,
+    /// `None` when the tunnel doesn't pool its connections
+    pub pool: Option<PoolStatus>,
+    /// Throughput of the live connections, `None` until one has been sampled
+    pub throughput: Option<Throughput>,
+    /// Time to connect to the destination, without the time spent reading a ClientHello
+    pub connect_latency: Option<ConnectLatency>,
+    /// Set while a `TemporaryModify` is in effect
+    pub pending_revert: Option<PendingRevert>,
+}
+
+/// The destination that a tunnel goes back to after a `TemporaryModify`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PendingRevert {
+    pub destination: Destination,
+    pub remaining_secs: u64,
+}
+
+/// Every setting in effect for a tunnel, with the defaults filled in, as answered by
+/// `GET /tunnels/:id/config`. Unlike [`TunnelStatus`] it holds no statistics, only what the
+/// `Create` and later commands set.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TunnelSettings {
+    pub incoming_port: u16,
+    /// Address the listener of the tunnel is bound to
+    pub bind_address: SocketAddr,
+    pub label: Option<String>,
+    pub destination: Destination,
+    pub source_address: Option<IpAddr>,
+    /// Seconds until the tunnel expires, `None` if it has no time to live
+    pub ttl_remaining_secs: Option<u64>,
+    /// Set while a `TemporaryModify` is in effect
+    pub pending_revert: Option<PendingRevert>,
+    pub generation: u64,
+    /// The tenant that created the tunnel, `None` without key quotas
+    pub owner: Option<String>,
+    pub connect_timeout_ms: u64,
+    pub buffer_size: usize,
+    pub max_in_flight_bytes: Option<usize>,
+    pub tcp_nodelay: bool,
+    pub tcp_keepalive_secs: Option<u64>,
+    pub dscp: Option<u8>,
+    pub max_lifetime_secs: Option<u64>,
+    pub reuse_address: bool,
+    pub reuse_port: bool,
+    pub backlog: u32,
+    pub accept_loops: usize,
+    pub log_sample_rate: f64,
+    pub quiet: bool,
+    pub sni_map: BTreeMap<String, SocketAddr>,
+    pub routes: BTreeMap<String, SocketAddr>,
+    pub response_destination: Option<Destination>,
+    pub mirror_to: Option<SocketAddr>,
+    pub mirror_gzip_level: Option<u32>,
+    pub fanout_destinations: Vec<SocketAddr>,
+    pub failover_destinations: Vec<SocketAddr>,
+    pub overflow_response: Option<Vec<u8>>,
+    /// `None` along with `overflow_policy` and `queue_workers` when the tunnel has no queue
+    pub queue_len: Option<usize>,
+    pub overflow_policy: Option<OverflowPolicy>,
+    pub queue_workers: Option<usize>,
+    pub pool: bool,
+}
+
+/// Name of the verifying key that the proxy is started with
+pub const DEFAULT_KEY_NAME: &str = "default";
+
 #[derive(Debug)]
 pub struct GlobalState {
-    proxies: Mutex<HashMap<Uuid, ProxyState>>,
-    ports: RwLock<HashSet<u16>>,
-    verifying_key: Option<VerifyingKey>,
+    /// The tunnels and their ports share one lock, so checking and reserving a port can't
+    /// race with another command
+    proxies: Mutex<Tunnels>,
+    /// Commands have to be signed by one of these, by name
+    verifying_keys: RwLock<HashMap<String, VerifyingKey>>,
+    /// Roles of the verifying keys that aren't admins, by name. Only changed while holding the
+    /// lock of `verifying_keys`, so a key and its role are always seen together.
+    key_roles: RwLock<HashMap<String, KeyRole>>,
+    /// Signs the responses to commands when set
+    signing_key: Option<SigningKey>,
+    /// Accepted in place of a signature while there are no verifying keys
+    admin_token: Option<String>,
+    nonces: Nonces,
+    log_payloads: bool,
+    max_tunnels: Option<usize>,
+    /// Maximum amount of tunnels by the name of the verifying key that created them. Keys with
+    /// a quota only see and change their own tunnels, see [`GlobalState::with_key_quotas`]
+    key_quotas: HashMap<String, usize>,
+    /// Tunnels may only forward to these when there are any
+    allowed_destinations: Vec<AllowedDestination>,
+    max_connections: Option<usize>,
+    /// One permit per connection through any tunnel, when `max_connections` is set
+    connection_permits: Option<Arc<Semaphore>>,
+    allow_privileged_ports: bool,
+    /// Default of `reuse_address` for tunnels that don't set it
+    reuse_address: bool,
+    /// Default of `reuse_port` for tunnels that don't set it
+    reuse_port: bool,
+    /// Answer `Status` with a single JSON document instead of streaming it
+    buffered_status: bool,
+    /// Longest time `POST /command` may take, see [`GlobalState::with_command_timeout`]
+    command_timeout: time::Duration,
+    /// The addresses of this host that tunnels listen on, see [`local_addresses`]
+    local_addresses: HashSet<IpAddr>,
+    /// The tunnels from the tunnels file, see [`reload_tunnels`]
+    declared: tokio::sync::Mutex<reload::Declared>,
+    /// Incremented on every change to the tunnels, used as the ETag of `Status` and to push
+    /// changes to `GET /status/stream`
+    version: watch::Sender<u64>,
+    rejections: Arc<Rejections>,
+    started: time::Instant,
+    /// Counts the connections of every tunnel for the [`ShutdownStats`]
+    served: Arc<ServedCounter>,
 }
 
 impl GlobalState {
     pub fn new<S: AsRef<str>>(verifying_key: Option<S>) -> Self {
         Self {
-            proxies: Mutex::new(HashMap::new()),
-            ports: RwLock::new(HashSet::new()),
-            verifying_key: verifying_key.and_then(|key| VerifyingKey::from_str(key.as_ref()).ok()),
+            proxies: Mutex::default(),
+            verifying_keys: RwLock::new(
+                verifying_key
+                    .and_then(|key| VerifyingKey::from_str(key.as_ref()).ok())
+                    .map(|key| (DEFAULT_KEY_NAME.to_string(), key))
+                    .into_iter()
+                    .collect(),
+            ),
+            key_roles: RwLock::default(),
+            signing_key: None,
+            admin_token: None,
+            nonces: Nonces::default(),
+            log_payloads: false,
+            max_tunnels: None,
+            key_quotas: HashMap::new(),
+            allowed_destinations: Vec::new(),
+            max_connections: None,
+            connection_permits: None,
+            allow_privileged_ports: false,
+            reuse_address: true,
+            reuse_port: false,
+            buffered_status: false,
+            command_timeout: DEFAULT_COMMAND_TIMEOUT,
+            local_addresses: local_addresses(),
+            declared: tokio::sync::Mutex::default(),
+            version: watch::Sender::new(0),
+            rejections: Arc::default(),
+            started: time::Instant::now(),
+            served: Arc::default(),
+        }
+    }
+
+    /// Also log the complete payload of every command, including its signature, at the trace
+    /// level
+    pub fn with_payload_logging(mut self, log_payloads: bool) -> Self {
+        self.log_payloads = log_payloads;
+        self
+    }
+
+    /// Sign the responses to commands with `signing_key`, see [`verify_response`]. The
+    /// signature covers the whole body, so `Status` is answered as a single JSON document like
+    /// with [`GlobalState::with_buffered_status`].
+    pub fn with_signing_key(mut self, signing_key: Option<SigningKey>) -> Self {
+        self.signing_key = signing_key;
+        self
+    }
+
+    /// Accept commands with `Authorization: Bearer <admin_token>` instead of a signature. Only
+    /// used without a verifying key, signatures are required whenever there is one.
+    pub fn with_admin_token(mut self, admin_token: Option<String>) -> Self {
+        self.admin_token = admin_token;
+        self
+    }
+
+    /// Refuse to create more than `max_tunnels` tunnels at the same time
+    pub fn with_max_tunnels(mut self, max_tunnels: Option<usize>) -> Self {
+        self.max_tunnels = max_tunnels;
+        self
+    }
+
+    /// Limit the tunnels that commands signed by a key can create, by the name of the key.
+    ///
+    /// A key with a quota belongs to a tenant: its `Status` only lists the tunnels it created,
+    /// it can't change or delete other tunnels and it can't rotate keys. Keys without a quota
+    /// manage every tunnel like before.
+    pub fn with_key_quotas(mut self, key_quotas: HashMap<String, usize>) -> Self {
+        self.key_quotas = key_quotas;
+        self
+    }
+
+    /// Also accept commands signed by `observer_keys`, by name, but only let them run
+    /// `Status`
+    pub fn with_observer_keys(mut self, observer_keys: HashMap<String, VerifyingKey>) -> Self {
+        let roles = self.key_roles.get_mut().unwrap();
+        roles.extend(
+            observer_keys
+                .keys()
+                .map(|name| (name.clone(), KeyRole::Observer)),
+        );
+        self.verifying_keys.get_mut().unwrap().extend(observer_keys);
+        self
+    }
+
+    /// The tenant that signed a command, when the key named `signer` has a quota
+    fn tenant(&self, signer: Option<String>) -> Option<String> {
+        signer.filter(|name| self.key_quotas.contains_key(name))
+    }
+
+    /// Refuse commands that point tunnels anywhere outside of `allowed_destinations`, however
+    /// they are signed. Tunnels may forward anywhere when it is empty.
+    pub fn with_allowed_destinations(
+        mut self,
+        allowed_destinations: Vec<AllowedDestination>,
+    ) -> Self {
+        self.allowed_destinations = allowed_destinations;
+        self
+    }
+
+    /// Checks that `destination` isn't the port of a tunnel on this host, which would connect
+    /// the tunnel to itself or another tunnel over and over. `incoming_port` is the port of a
+    /// tunnel that is about to be created.
+    fn check_self_loop(
+        &self,
+        proxies: &Tunnels,
+        incoming_port: Option<u16>,
+        destination: SocketAddr,
+    ) -> Result<(), ApiError> {
+        let port = destination.port();
+        let listening = incoming_port == Some(port) || proxies.port_in_use(Protocol::Tcp, port);
+        let ip = destination.ip().to_canonical();
+        let local = ip.is_loopback() || ip.is_unspecified() || self.local_addresses.contains(&ip);
+        if listening && local {
+            Err(ApiError::new(
+                ErrorCode::InvalidCommand,
+                format!("{destination} is a tunnel of this proxy, which would loop back to it"),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks that tunnels may forward to `destination`.
+    fn check_destination(&self, destination: &Destination) -> Result<(), ApiError> {
+        if self.allowed_destinations.is_empty() {
+            return Ok(());
+        }
+        let allowed = match destination {
+            Destination::Tcp(addr) => self
+                .allowed_destinations
+                .iter()
+                .any(|allowed| allowed.allows(*addr)),
+            // Only networks are allowed, so none of the sockets on the host
+            Destination::Unix(_) => false,
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(ApiError::new(
+                ErrorCode::DestinationNotAllowed,
+                format!("The proxy doesn't allow tunnels to {destination}"),
+            ))
+        }
+    }
+
+    /// Refuse connections through any tunnel while `max_connections` connections are open, to
+    /// protect the host
+    pub fn with_max_connections(mut self, max_connections: Option<usize>) -> Self {
+        self.max_connections = max_connections;
+        self.connection_permits = max_connections.map(|max| Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    /// How many connections are open through all tunnels
+    fn active_connections(&self, proxies: &Tunnels) -> usize {
+        match (&self.connection_permits, self.max_connections) {
+            (Some(permits), Some(max)) => max - permits.available_permits(),
+            _ => proxies
+                .iter()
+                .map(|(_, proxy)| proxy.connections.active())
+                .sum(),
+        }
+    }
+
+    /// Deletes every tunnel and closes its connections, returns how many there were.
+    pub fn close_all_tunnels(&self) -> usize {
+        let mut proxies = self.proxies.lock().unwrap();
+        let ids: Vec<Uuid> = proxies.iter().map(|(id, _)| *id).collect();
+        for id in &ids {
+            let proxy = proxies.remove(id).unwrap();
+            // A draining tunnel may already have no receivers left
+            let _ = proxy.control.send(ProxyControlMessage::Close);
+        }
+        if !ids.is_empty() {
+            self.changed();
+        }
+        ids.len()
+    }
+
+    /// Marks the tunnels as changed, for clients that poll `Status`
+    fn changed(&self) {
+        self.version.send_modify(|version| *version += 1);
+    }
+
+    /// Answer `POST /command` with `504 Gateway Timeout` when a command takes longer than
+    /// `command_timeout`, after undoing what it changed so far. Defaults to
+    /// [`DEFAULT_COMMAND_TIMEOUT`].
+    pub fn with_command_timeout(mut self, command_timeout: time::Duration) -> Self {
+        self.command_timeout = command_timeout;
+        self
+    }
+
+    /// Allow tunnels to listen on ports below 1024, which requires the proxy to run with
+    /// `CAP_NET_BIND_SERVICE`
+    pub fn with_privileged_ports(mut self, allow_privileged_ports: bool) -> Self {
+        self.allow_privileged_ports = allow_privileged_ports;
+        self
+    }
+
+    /// Set `SO_REUSEADDR` on the listeners of tunnels that don't say otherwise, so a port can
+    /// be bound again right away while connections to it are in `TIME_WAIT`. On by default.
+    pub fn with_reuse_address(mut self, reuse_address: bool) -> Self {
+        self.reuse_address = reuse_address;
+        self
+    }
+
+    /// Set `SO_REUSEPORT` on the listeners of tunnels that don't say otherwise, so several
+    /// proxies can listen on the same port and share its connections. Only supported on Unix.
+    pub fn with_reuse_port(mut self, reuse_port: bool) -> Self {
+        self.reuse_port = reuse_port;
+        self
+    }
+
+    /// Answer `Status` with a single JSON document like before it was streamed, for clients
+    /// that can't read [`StatusLine`]s. The lock on the tunnels is held while listing all of
+    /// them.
+    pub fn with_buffered_status(mut self, buffered_status: bool) -> Self {
+        self.buffered_status = buffered_status;
+        self
+    }
+}
+
+/// The transport protocol that a tunnel listens with. Ports of different protocols are
+/// separate, so only tunnels of the same protocol can conflict over a port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Protocol {
+    Tcp,
+}
+
+/// The tunnels by id, together with the ports they listen on
+#[derive(Debug, Default)]
+struct Tunnels {
+    by_id: HashMap<Uuid, ProxyState>,
+    ports: HashSet<(Protocol, u16)>,
+}
+
+impl Tunnels {
+    fn get(&self, id: &Uuid) -> Option<&ProxyState> {
+        self.by_id.get(id)
+    }
+
+    fn get_mut(&mut self, id: &Uuid) -> Option<&mut ProxyState> {
+        self.by_id.get_mut(id)
+    }
+
+    fn contains_key(&self, id: &Uuid) -> bool {
+        self.by_id.contains_key(id)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&Uuid, &ProxyState)> {
+        self.by_id.iter()
+    }
+
+    fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    fn port_in_use(&self, protocol: Protocol, port: u16) -> bool {
+        self.ports.contains(&(protocol, port))
+    }
+
+    /// Adds a tunnel and reserves its port, which must not be in use.
+    fn insert(&mut self, id: Uuid, proxy: ProxyState) {
+        let reserved = self.ports.insert((proxy.protocol, proxy.incoming_port));
+        debug_assert!(reserved, "port {} is already in use", proxy.incoming_port);
+        self.by_id.insert(id, proxy);
+    }
+
+    /// Removes a tunnel and frees its port, unless it handed the port over to another tunnel.
+    fn remove(&mut self, id: &Uuid) -> Option<ProxyState> {
+        let proxy = self.by_id.remove(id)?;
+        let port = (proxy.protocol, proxy.incoming_port);
+        if !self
+            .by_id
+            .values()
+            .any(|other| (other.protocol, other.incoming_port) == port)
+        {
+            self.ports.remove(&port);
         }
+        Some(proxy)
+    }
+
+    /// Gives the port of tunnel `from` to tunnel `to`, freeing the port of `to`. Both must
+    /// exist.
+    fn hand_over_port(&mut self, from: &Uuid, to: &Uuid) -> u16 {
+        let port = self.by_id[from].incoming_port;
+        let to = self.by_id.get_mut(to).unwrap();
+        self.ports.remove(&(to.protocol, to.incoming_port));
+        to.incoming_port = port;
+        port
+    }
+
+    /// Undoes [`Tunnels::hand_over_port`], where `port` is the one `to` had before
+    fn give_back_port(&mut self, to: &Uuid, port: u16) {
+        let to = self.by_id.get_mut(to).unwrap();
+        to.incoming_port = port;
+        self.ports.insert((to.protocol, port));
     }
 }
 
 #[derive(Debug)]
 struct ProxyState {
+    protocol: Protocol,
     incoming_port: u16,
-    destination: SocketAddr,
-    control: Sender<ProxyControlMessage>,
+    label: Option<String>,
+    destination: Destination,
+    source_address: Option<IpAddr>,
+    /// Every `proxy` and `transfer` task of the tunnel holds a receiver, so the sender is
+    /// closed once all of them have exited
+    control: Arc<Sender<ProxyControlMessage>>,
+    /// Replaces the listener of the tunnel, see `Command::Handover`
+    listeners: mpsc::Sender<TcpListener>,
+    draining: bool,
+    /// The tenant that created the tunnel, see [`GlobalState::with_key_quotas`]
+    owner: Option<String>,
+    created_at: time::SystemTime,
+    last_modified: time::SystemTime,
+    /// Starts at 0 and counts the `Modify` commands applied to the tunnel
+    generation: u64,
+    expiry: Option<Expiry>,
+    /// Pending while the tunnel has a temporary destination
+    revert: Option<Revert>,
+    connections: Arc<Connections>,
+    config: Arc<TunnelConfig>,
+    /// The `Create` command as it was received, or as a `CreateRange` or `CreateRouter`
+    /// expanded to. Later commands don't change it.
+    created_with: serde_json::Value,
 }
 
-pub async fn root() -> &'static str {
-    "Hello, World!"
+/// The timer that deletes a tunnel once its time to live has passed
+#[derive(Debug)]
+struct Expiry {
+    at: tokio::time::Instant,
+    timer: AbortHandle,
 }
 
-pub async fn process_command(
-    State(state): State<Arc<GlobalState>>,
-    Json(payload): Json<ProxyCommand>,
-) -> (StatusCode, Json<ProxyResponse>) {
-    tracing::info!("Received payload: {:?}", payload);
-
-    if !payload.verify_signature(&state.verifying_key) {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(ProxyResponse::Message("Invalid signature".to_string())),
-        );
+/// The timer that puts back the destination from before a `TemporaryModify`
+#[derive(Debug)]
+struct Revert {
+    at: tokio::time::Instant,
+    timer: AbortHandle,
+    destination: Destination,
+    source_address: Option<IpAddr>,
+}
+
+impl Drop for Revert {
+    fn drop(&mut self) {
+        self.timer.abort();
     }
-    match payload.command {
-        Command::Create {
-            incoming_port,
-            destination_port,
-            destination_ip,
-            id,
-        } => {
-            // Check if ID or incoming_port already exists
-            if state.proxies.lock().unwrap().get(&id).is_some() {
-                return (
-                    StatusCode::CONFLICT,
-                    Json(ProxyResponse::Message(
-                        "Id already exists. Use the modify command instead.".to_string(),
-                    )),
-                );
-            }
-            if !state.ports.write().unwrap().insert(incoming_port) {
-                return (
-                    StatusCode::CONFLICT,
-                    Json(ProxyResponse::Message(format!(
-                        "The `incoming_port` already in use: {incoming_port}"
-                    ))),
-                );
-            }
+}
 
-            let addr = SocketAddr::new(destination_ip, destination_port);
-            let (tx, rx) = watch::channel(ProxyControlMessage::Open { destination: addr });
-            state.proxies.lock().unwrap().insert(
-                id,
-                ProxyState {
-                    incoming_port,
-                    destination: addr,
-                    control: tx,
-                },
-            );
-            add_proxy(incoming_port, rx).await.unwrap(); // TODO: error propagation??
-            (
-                StatusCode::ACCEPTED,
-                Json(ProxyResponse ::
-                    Message( format!(
-                        "Created tunnel {id} on port {incoming_port} to use {destination_ip}:{destination_port}"
-                    ),
-                )),
-            )
+impl Drop for ProxyState {
+    fn drop(&mut self) {
+        // A tunnel that is deleted before it expires must not be deleted again
+        if let Some(expiry) = &self.expiry {
+            expiry.timer.abort();
         }
-        Command::Modify {
-            destination_port,
-            destination_ip,
-            id,
-        } => {
-            if let Some(proxy) = state.proxies.lock().unwrap().get_mut(&id) {
-                proxy.destination.set_port(destination_port);
-                proxy.destination.set_ip(destination_ip);
-                proxy
-                    .control
-                    .send(ProxyControlMessage::Open {
-                        destination: proxy.destination,
-                    })
-                    .unwrap();
-                (
-                    StatusCode::ACCEPTED,
-                    Json(ProxyResponse::Message(format!(
-                        "Changed tunnel {id} to use {destination_ip}:{destination_port}"
-                    ))),
-                )
-            } else {
-                (
-                    StatusCode::NOT_FOUND,
-                    Json(ProxyResponse::Message(format!("Id not found: {id}"))),
-                )
-            }
+    }
+}
+
+impl ProxyState {
+    fn state(&self) -> TunnelState {
+        if self.connections.has_failed_listener() {
+            TunnelState::Failed
+        } else if self.draining {
+            TunnelState::Draining
+        } else {
+            TunnelState::Active
         }
-        Command::Delete { id } => {
-            if let Some(proxy) = state.proxies.lock().unwrap().remove(&id) {
-                proxy.control.send(ProxyControlMessage::Close).unwrap();
-                state.ports.write().unwrap().remove(&proxy.incoming_port);
-                (
-                    StatusCode::ACCEPTED,
-                    Json(ProxyResponse::Message(format!("Deleted tunnel: {id}"))),
+    }
+
+    fn status(&self) -> TunnelStatus {
+        let since_epoch = |t: time::SystemTime| {
+            t.duration_since(time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        };
+        TunnelStatus {
+            state: self.state(),
+            incoming_port: self.incoming_port,
+            label: self.label.clone(),
+            destination: self.destination.clone(),
+            created_at: since_epoch(self.created_at),
+            last_modified: since_epoch(self.last_modified),
+            generation: self.generation,
+            age_secs: self.created_at.elapsed().unwrap_or_default().as_secs(),
+            ttl_remaining_secs: self.ttl_remaining_secs(),
+            last_error: self.connections.last_error(),
+            backlog: self.config.backlog,
+            max_lifetime_secs: self.config.max_lifetime.map(|lifetime| lifetime.as_secs()),
+            accept_loops: self.config.accept_loops,
+            quiet: self.config.quiet,
+            accepted_last_sec: self.connections.accepted_last_sec(),
+            sni_map: self.config.sni_map.clone(),
+            routes: self.config.routes.clone(),
+            response_destination: self.config.response_destination.clone(),
+            mirror: self.config.mirror_to.map(|to| {
+                let gzip_level = self.config.mirror_gzip_level;
+                self.connections.mirror_stats().status(to, gzip_level)
+            }),
+            fanout: (!self.config.fanout_destinations.is_empty()).then(|| {
+                self.connections
+                    .fanout_stats()
+                    .status(&self.config.fanout_destinations)
+            }),
+            failover: self
+                .config
+                .failover
+                .as_ref()
+                .map(|failover| failover.status()),
+            pool: self.config.pool.as_ref().map(|pool| pool.status()),
+            throughput: self.connections.throughput(),
+            connect_latency: self.connections.connect_latency(),
+            pending_revert: self.pending_revert(),
+        }
+    }
+
+    fn settings(&self) -> TunnelSettings {
+        let config = &self.config;
+        TunnelSettings {
+            incoming_port: self.incoming_port,
+            bind_address: listen_address(self.incoming_port),
+            label: self.label.clone(),
+            destination: self.destination.clone(),
+            source_address: self.source_address,
+            ttl_remaining_secs: self.ttl_remaining_secs(),
+            pending_revert: self.pending_revert(),
+            generation: self.generation,
+            owner: self.owner.clone(),
+            connect_timeout_ms: config.connect_timeout.as_millis() as u64,
+            buffer_size: config.buffer_size,
+            max_in_flight_bytes: config.max_in_flight_bytes,
+            tcp_nodelay: config.tcp_nodelay,
+            tcp_keepalive_secs: config.tcp_keepalive.map(|keepalive| keepalive.as_secs()),
+            dscp: config.dscp,
+            max_lifetime_secs: config.max_lifetime.map(|lifetime| lifetime.as_secs()),
+            reuse_address: config.reuse_address,
+            reuse_port: config.reuse_port,
+            backlog: config.backlog,
+            accept_loops: config.accept_loops,
+            log_sample_rate: config.log_sample_rate,
+            quiet: config.quiet,
+            sni_map: config.sni_map.clone(),
+            routes: config.routes.clone(),
+            response_destination: config.response_destination.clone(),
+            mirror_to: config.mirror_to,
+            mirror_gzip_level: config.mirror_gzip_level,
+            fanout_destinations: config.fanout_destinations.clone(),
+            failover_destinations: config
+                .failover
+                .as_ref()
+                .map(|failover| failover.fallbacks().to_vec())
+                .unwrap_or_default(),
+            overflow_response: config.overflow_response.clone(),
+            queue_len: config.queue.as_ref().map(|queue| queue.capacity()),
+            overflow_policy: config.queue.as_ref().map(|queue| queue.policy()),
+            queue_workers: config.queue.as_ref().map(|_| config.queue_workers),
+            pool: config.pool.is_some(),
+        }
+    }
+
+    fn ttl_remaining_secs(&self) -> Option<u64> {
+        self.expiry.as_ref().map(|expiry| {
+            expiry
+                .at
+                .saturating_duration_since(tokio::time::Instant::now())
+                .as_secs()
+        })
+    }
+
+    fn pending_revert(&self) -> Option<PendingRevert> {
+        self.revert.as_ref().map(|revert| PendingRevert {
+            destination: revert.destination.clone(),
+            remaining_secs: revert
+                .at
+                .saturating_duration_since(tokio::time::Instant::now())
+                .as_secs(),
+        })
+    }
+}
+
+/// The addresses that this host can be reached on from itself, besides the loopback and
+/// unspecified ones. Found by asking which address the default routes use, which doesn't send
+/// anything, so interfaces without a route elsewhere are missing.
+fn local_addresses() -> HashSet<IpAddr> {
+    let routes: [SocketAddr; 2] = [
+        (Ipv4Addr::new(192, 0, 2, 1), 9).into(),
+        (Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 9).into(),
+    ];
+    routes
+        .into_iter()
+        .filter_map(|route| {
+            let bind: SocketAddr = match route {
+                SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            };
+            let socket = std::net::UdpSocket::bind(bind).ok()?;
+            socket.connect(route).ok()?;
+            Some(socket.local_addr().ok()?.ip())
+        })
+        .collect()
+}
+
+/// Time to wait for a destination to accept a connection when the tunnel doesn't specify one
+pub const DEFAULT_CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// Size of the copy buffers of a connection when the tunnel doesn't specify one
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+/// Largest copy buffer a tunnel may ask for, every connection allocates two of them
+pub const MAX_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Length of the queue of pending connections of a tunnel that doesn't specify one
+pub const DEFAULT_BACKLOG: u32 = 1024;
+/// Most accept loops that a tunnel may ask for
+pub const MAX_ACCEPT_LOOPS: usize = 64;
+/// Highest DSCP class, which has six bits
+pub const MAX_DSCP: u8 = 63;
+
+/// Most fan-out destinations that a tunnel may have, every connection makes one more
+/// connection per destination
+pub const MAX_FANOUT_DESTINATIONS: usize = 16;
+
+/// Longest queue of accepted connections that a tunnel may ask for
+pub const MAX_QUEUE_LEN: usize = 64 * 1024;
+/// Workers of a queued tunnel that doesn't specify how many
+pub const DEFAULT_QUEUE_WORKERS: usize = 16;
+/// Most workers that a queued tunnel may ask for
+pub const MAX_QUEUE_WORKERS: usize = 1024;
+
+/// Longest route key of a router, which has to fit the length byte in front of it
+pub const MAX_ROUTE_KEY_LEN: usize = u8::MAX as usize;
+
+/// The destination of a router in its status, as its connections only go to its routes
+const ROUTER_DESTINATION: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// Longest label a tunnel may have, in bytes
+pub const MAX_LABEL_LEN: usize = 128;
+
+/// Settings of a tunnel that are fixed when it is created
+#[derive(Debug)]
+struct TunnelConfig {
+    /// The tunnel that these settings belong to
+    id: Uuid,
+    /// The span of the command that created the tunnel, linked from the span of every
+    /// connection
+    #[cfg(feature = "otel")]
+    created_by: opentelemetry::trace::SpanContext,
+    connect_timeout: time::Duration,
+    /// At most `max_in_flight_bytes`
+    buffer_size: usize,
+    max_in_flight_bytes: Option<usize>,
+    tcp_nodelay: bool,
+    tcp_keepalive: Option<time::Duration>,
+    dscp: Option<u8>,
+    /// Time after which connections are closed, `None` keeps them as long as they are used
+    max_lifetime: Option<time::Duration>,
+    log_sample_rate: f64,
+    /// Overrides `log_sample_rate` and also silences the errors of connections
+    quiet: bool,
+    reuse_address: bool,
+    reuse_port: bool,
+    backlog: u32,
+    accept_loops: usize,
+    /// Server names in lowercase, see `Command::Create`
+    sni_map: BTreeMap<String, SocketAddr>,
+    response_destination: Option<Destination>,
+    mirror_to: Option<SocketAddr>,
+    mirror_gzip_level: Option<u32>,
+    fanout_destinations: Vec<SocketAddr>,
+    /// `None` when the tunnel has no failover destinations
+    failover: Option<Arc<Failover>>,
+    overflow_response: Option<Vec<u8>>,
+    /// Destinations by route key, only for routers
+    routes: BTreeMap<String, SocketAddr>,
+    /// Accepted connections that wait for one of `queue_workers`, when the tunnel has a queue
+    queue: Option<Arc<ConnectionQueue>>,
+    queue_workers: usize,
+    /// Idle connections to the destinations, when the tunnel reuses them
+    pool: Option<Arc<OutboundPool>>,
+}
+
+impl TunnelConfig {
+    /// Whether every connection goes to the route of its key, see `Command::CreateRouter`
+    fn is_router(&self) -> bool {
+        !self.routes.is_empty()
+    }
+
+    /// Applies the socket options of the tunnel to one side of a connection.
+    fn configure(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.tcp_nodelay)?;
+        if let Some(keepalive) = self.tcp_keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))?;
+        }
+        // Fixed sizes also keep the kernel from growing the buffers of busy connections
+        if let Some(max) = self.max_in_flight_bytes {
+            let socket = SockRef::from(stream);
+            socket.set_recv_buffer_size(max)?;
+            socket.set_send_buffer_size(max)?;
+        }
+        if let Some(dscp) = self.dscp {
+            // Only a hint to the network, so the connection works without it
+            if let Err(err) = set_dscp(stream, dscp) {
+                tracing::debug!("marking a connection of tunnel {} failed: {err}", self.id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the connection from `client` logs its details, which is decided by a hash of
+    /// the address so every decision about the same client is the same.
+    fn logs(&self, client: SocketAddr) -> bool {
+        if self.quiet {
+            return false;
+        }
+        if self.log_sample_rate >= 1.0 {
+            return true;
+        }
+        let mut hasher = DefaultHasher::new();
+        client.hash(&mut hasher);
+        (hasher.finish() as f64 / u64::MAX as f64) < self.log_sample_rate
+    }
+}
+
+/// Sets the DSCP class of the packets that `stream` sends, in the upper six bits of the TOS or
+/// traffic class byte.
+fn set_dscp(stream: &TcpStream, dscp: u8) -> io::Result<()> {
+    let socket = SockRef::from(stream);
+    let class = u32::from(dscp) << 2;
+    match stream.local_addr()? {
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "linux",
+            target_os = "macos"
+        ))]
+        SocketAddr::V6(addr) if addr.ip().to_ipv4_mapped().is_none() => socket.set_tclass_v6(class),
+        #[cfg(not(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "linux",
+            target_os = "macos"
+        )))]
+        SocketAddr::V6(addr) if addr.ip().to_ipv4_mapped().is_none() => {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+        #[cfg(not(any(target_os = "illumos", target_os = "solaris")))]
+        _ => socket.set_tos(class),
+        #[cfg(any(target_os = "illumos", target_os = "solaris"))]
+        _ => Err(io::ErrorKind::Unsupported.into()),
+    }
+}
+
+/// Builds the control plane around `state`.
+pub fn app(state: Arc<GlobalState>, config: &ControlPlaneConfig) -> Router {
+    let router = Router::new();
+    // `GET /` answers with the banner, unless there is none
+    let router = match &config.banner {
+        Some(banner) => {
+            let banner = banner.clone();
+            router.route("/", get(|| async move { banner }).fallback(allow_get))
+        }
+        None => router,
+    };
+    // These read the tunnels, so they are authenticated like a `status` command
+    let readers = Router::new()
+        // `GET /diagnostics` goes to `diagnostics`
+        .route("/diagnostics", get(diagnostics).fallback(allow_get))
+        // `GET /tunnels/:id/connections` goes to `tunnel_connections`
+        .route(
+            "/tunnels/:id/connections",
+            get(tunnel_connections).fallback(allow_get),
+        )
+        // `GET /tunnels/:id/top-clients` goes to `tunnel_top_clients`
+        .route(
+            "/tunnels/:id/top-clients",
+            get(tunnel_top_clients).fallback(allow_get),
+        )
+        // `GET /tunnels/:id/config` goes to `tunnel_config`
+        .route(
+            "/tunnels/:id/config",
+            get(tunnel_config).fallback(allow_get),
+        )
+        // `GET /status/stream` goes to `status_stream`
+        .route("/status/stream", get(status_stream).fallback(allow_get))
+        // `GET /metrics` goes to `metrics`
+        .route("/metrics", get(metrics).fallback(allow_get))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_reader,
+        ));
+    router
+        // `POST /command` goes to `process_command`
+        .route("/command", post(process_command).fallback(allow_post))
+        // `POST /verify` goes to `verify_command`
+        .route("/verify", post(verify_command).fallback(allow_post))
+        // `GET /time` goes to `server_time`
+        .route("/time", get(server_time).fallback(allow_get))
+        // `GET /version` goes to `version`
+        .route("/version", get(version).fallback(allow_get))
+        .merge(readers)
+        .fallback(not_found)
+        // Responses are indented and converted to MessagePack before they are signed, so the
+        // signature covers what is sent
+        .layer(middleware::from_fn(pretty_json))
+        .layer(middleware::from_fn(msgpack::msgpack_responses))
+        .layer(middleware::from_fn_with_state(state.clone(), sign_response))
+        // Larger bodies are answered with `413 Payload Too Large`
+        .layer(DefaultBodyLimit::max(config.max_body_size))
+        .with_state(state)
+}
+
+/// Answer to `GET /` when not configured otherwise
+pub const DEFAULT_BANNER: &str = "Hello, World!";
+
+/// Largest request body the control plane accepts when not configured otherwise
+pub const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Longest time a command may take when not configured otherwise
+pub const DEFAULT_COMMAND_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// Tuning of the HTTP server of the control plane
+#[derive(Debug)]
+pub struct ControlPlaneConfig {
+    /// Time a control plane connection may be idle before TCP keepalive probes are sent,
+    /// `None` disables keepalive
+    pub tcp_keepalive: Option<time::Duration>,
+    /// Also accept HTTP/2 with prior knowledge next to HTTP/1.1
+    pub http2: bool,
+    /// Maximum amount of concurrent HTTP/2 streams per connection, `None` uses hyper's default
+    pub http2_max_concurrent_streams: Option<u32>,
+    /// Largest request body in bytes, defaults to [`DEFAULT_MAX_BODY_SIZE`]
+    pub max_body_size: usize,
+    /// Answer to `GET /`, defaults to [`DEFAULT_BANNER`]. `None` answers `404 Not Found` like
+    /// any other route that doesn't exist, so scanners can't recognize the proxy by it
+    pub banner: Option<String>,
+}
+
+impl Default for ControlPlaneConfig {
+    fn default() -> Self {
+        Self {
+            tcp_keepalive: None,
+            http2: false,
+            http2_max_concurrent_streams: None,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            banner: Some(DEFAULT_BANNER.to_string()),
+        }
+    }
+}
+
+/// Binds the control plane around `state` to `addr`.
+pub fn serve(
+    addr: &SocketAddr,
+    state: Arc<GlobalState>,
+    config: &ControlPlaneConfig,
+) -> hyper::Result<Server<AddrIncoming, IntoMakeService<Router>>> {
+    let server = Server::try_bind(addr)?
+        .tcp_keepalive(config.tcp_keepalive)
+        .http1_only(!config.http2)
+        .http2_max_concurrent_streams(config.http2_max_concurrent_streams)
+        .serve(app(state, config).into_make_service());
+    Ok(server)
+}
+
+/// Answers a request for a route that doesn't exist.
+async fn not_found(uri: Uri) -> ApiError {
+    ApiError::new(ErrorCode::NotFound, format!("No route for {}", uri.path()))
+}
+
+/// Answers a request with another method than `GET` to a route that only supports `GET`.
+async fn allow_get(method: Method) -> Response {
+    method_not_allowed(method, "GET,HEAD")
+}
+
+/// Answers a request with another method than `POST` to a route that only supports `POST`.
+async fn allow_post(method: Method) -> Response {
+    method_not_allowed(method, "POST")
+}
+
+fn method_not_allowed(method: Method, allow: &'static str) -> Response {
+    let error = ApiError::new(
+        ErrorCode::MethodNotAllowed,
+        format!("Method {method} not allowed, use {allow}"),
+    );
+    ([(header::ALLOW, allow)], error).into_response()
+}
+
+/// Seconds since the unix epoch according to the clock of the proxy
+fn unix_timestamp() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Lets clients detect clock skew before signing commands.
+pub async fn server_time() -> Json<ProxyResponse> {
+    Json(ProxyResponse::Time {
+        timestamp: unix_timestamp(),
+        max_age_secs: MAX_COMMAND_AGE.as_secs(),
+        max_future_secs: MAX_COMMAND_FUTURE.as_secs(),
+    })
+}
+
+/// Reports the version of the proxy and the features it was built with.
+pub async fn version() -> Json<ProxyResponse> {
+    let mut features = vec!["tls", "http2"];
+    if cfg!(unix) {
+        features.push("unix");
+    }
+    if cfg!(feature = "otel") {
+        features.push("otel");
+    }
+    Json(ProxyResponse::Version {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_hash: option_env!("PROXIMA_GIT_HASH").map(str::to_string),
+        features: features.into_iter().map(str::to_string).collect(),
+    })
+}
+
+/// Reports the connections and file descriptors that every tunnel holds, to correlate running
+/// out of file descriptors with a tunnel.
+pub async fn diagnostics(
+    State(state): State<Arc<GlobalState>>,
+    Extension(reader): Extension<Reader>,
+) -> Json<ProxyResponse> {
+    let tunnels = state
+        .proxies
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, proxy)| reader.sees(proxy))
+        .map(|(id, proxy)| {
+            let active_connections = proxy.connections.active();
+            let diagnostics = TunnelDiagnostics {
+                active_connections,
+                estimated_fds: cfg!(unix).then_some(2 * active_connections + 1),
+            };
+            (*id, diagnostics)
+        })
+        .collect();
+    Json(ProxyResponse::Diagnostics { tunnels })
+}
+
+/// Lists the live connections of a tunnel, to find connections that are stuck.
+pub async fn tunnel_connections(
+    State(state): State<Arc<GlobalState>>,
+    Extension(reader): Extension<Reader>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<ProxyResponse>, ApiError> {
+    match state.proxies.lock().unwrap().get(&id) {
+        Some(proxy) => {
+            check_owner(reader.tenant.as_deref(), id, proxy)?;
+            Ok(Json(ProxyResponse::Connections {
+                connections: proxy.connections.status(),
+                last_error: proxy.connections.last_error(),
+                created_with: proxy.created_with.clone(),
+            }))
+        }
+        None => Err(ApiError::new(
+            ErrorCode::NotFound,
+            format!("Id not found: {id}"),
+        )),
+    }
+}
+
+/// Clients listed by `GET /tunnels/:id/top-clients` without a `limit`
+pub const DEFAULT_TOP_CLIENTS: usize = 10;
+
+/// The query string of `GET /tunnels/:id/top-clients`
+#[derive(Debug, Deserialize)]
+pub struct TopClientsQuery {
+    /// At most [`MAX_TRACKED_CLIENTS`], defaults to [`DEFAULT_TOP_CLIENTS`]
+    limit: Option<usize>,
+}
+
+/// Lists the client addresses that opened the most connections through a tunnel since it was
+/// created, to spot abusive clients.
+pub async fn tunnel_top_clients(
+    State(state): State<Arc<GlobalState>>,
+    Extension(reader): Extension<Reader>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<TopClientsQuery>,
+) -> Result<Json<ProxyResponse>, ApiError> {
+    let limit = query.limit.unwrap_or(DEFAULT_TOP_CLIENTS);
+    if limit == 0 || limit > MAX_TRACKED_CLIENTS {
+        return Err(ApiError::new(
+            ErrorCode::InvalidCommand,
+            format!("The `limit` must be between 1 and {MAX_TRACKED_CLIENTS}"),
+        ));
+    }
+    match state.proxies.lock().unwrap().get(&id) {
+        Some(proxy) => {
+            check_owner(reader.tenant.as_deref(), id, proxy)?;
+            Ok(Json(ProxyResponse::TopClients {
+                clients: proxy.connections.top_clients(limit),
+                accepted: proxy.connections.accepted_total(),
+            }))
+        }
+        None => Err(ApiError::new(
+            ErrorCode::NotFound,
+            format!("Id not found: {id}"),
+        )),
+    }
+}
+
+/// The settings in effect for a tunnel, after its `Create` and any later commands.
+pub async fn tunnel_config(
+    State(state): State<Arc<GlobalState>>,
+    Extension(reader): Extension<Reader>,
+    Path(id): Path<Uuid>,
+) -> Result<Json<ProxyResponse>, ApiError> {
+    match state.proxies.lock().unwrap().get(&id) {
+        Some(proxy) => {
+            check_owner(reader.tenant.as_deref(), id, proxy)?;
+            Ok(Json(ProxyResponse::Config {
+                config: Box::new(proxy.settings()),
+            }))
+        }
+        None => Err(ApiError::new(
+            ErrorCode::NotFound,
+            format!("Id not found: {id}"),
+        )),
+    }
+}
+
+/// Counters for monitoring, in the Prometheus text format.
+pub async fn metrics(
+    State(state): State<Arc<GlobalState>>,
+    Extension(reader): Extension<Reader>,
+) -> impl IntoResponse {
+    // The rejections are counted for the whole proxy, so a tenant only gets its tunnels
+    let mut metrics = match reader.tenant {
+        Some(_) => String::new(),
+        None => state.rejections.metrics(),
+    };
+    // The counters of the tunnels are read without holding the lock, so every metric covers the
+    // same tunnels
+    let tunnels: Vec<(Uuid, Arc<Connections>, Arc<TunnelConfig>)> = state
+        .proxies
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, proxy)| reader.sees(proxy))
+        .map(|(id, proxy)| (*id, proxy.connections.clone(), proxy.config.clone()))
+        .collect();
+    metrics.push_str(
+        "# HELP proxima_closed_connections_total Connections through a tunnel that have ended\n\
+         # TYPE proxima_closed_connections_total counter\n",
+    );
+    for (id, connections, _) in &tunnels {
+        for (reason, count) in connections.closed_counts() {
+            // Writing to a string can't fail
+            let _ = writeln!(
+                metrics,
+                "proxima_closed_connections_total{{tunnel=\"{id}\",reason=\"{reason}\"}} {count}"
+            );
+        }
+    }
+    metrics.push_str(
+        "# HELP proxima_accepted_connections_total Connections accepted on the port of a tunnel\n\
+         # TYPE proxima_accepted_connections_total counter\n",
+    );
+    for (id, connections, _) in &tunnels {
+        let count = connections.accepted_total();
+        let _ = writeln!(
+            metrics,
+            "proxima_accepted_connections_total{{tunnel=\"{id}\"}} {count}"
+        );
+    }
+    metrics.push_str(
+        "# HELP proxima_connection_throughput_bytes_per_second Lowest, average and highest \
+         throughput of the live connections of a tunnel\n\
+         # TYPE proxima_connection_throughput_bytes_per_second gauge\n",
+    );
+    for (id, connections, _) in &tunnels {
+        if let Some(throughput) = connections.throughput() {
+            for (stat, value) in [
+                ("min", throughput.min),
+                ("avg", throughput.avg),
+                ("max", throughput.max),
+            ] {
+                let _ = writeln!(
+                    metrics,
+                    "proxima_connection_throughput_bytes_per_second{{tunnel=\"{id}\",stat=\"{stat}\"}} {value}"
+                );
+            }
+        }
+    }
+    metrics.push_str(
+        "# HELP proxima_connect_latency_seconds Latest, average and 99th percentile time to \
+         connect to the destination of a tunnel\n\
+         # TYPE proxima_connect_latency_seconds gauge\n",
+    );
+    for (id, connections, _) in &tunnels {
+        if let Some(latency) = connections.connect_latency() {
+            for (stat, us) in [
+                ("current", latency.current_us),
+                ("avg", latency.avg_us),
+                ("p99", latency.p99_us),
+            ] {
+                let seconds = us as f64 / 1_000_000.0;
+                let _ = writeln!(
+                    metrics,
+                    "proxima_connect_latency_seconds{{tunnel=\"{id}\",stat=\"{stat}\"}} {seconds}"
+                );
+            }
+        }
+    }
+    metrics.push_str(
+        "# HELP proxima_queued_connections Accepted connections that wait for a worker of a \
+         tunnel with a connection queue\n\
+         # TYPE proxima_queued_connections gauge\n",
+    );
+    for (id, _, config) in &tunnels {
+        if let Some(queue) = &config.queue {
+            let _ = writeln!(
+                metrics,
+                "proxima_queued_connections{{tunnel=\"{id}\"}} {}",
+                queue.len()
+            );
+        }
+    }
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics,
+    )
+}
+
+pub async fn process_command(
+    State(state): State<Arc<GlobalState>>,
+    headers: HeaderMap,
+    filter: Result<Query<StatusFilter>, QueryRejection>,
+    payload: Result<CommandBody, ApiError>,
+) -> Response {
+    let payload = match payload {
+        Ok(CommandBody(payload)) => payload,
+        Err(err) => return err.into_response(),
+    };
+    tracing::info!("Received payload: {:?}", payload.redacted());
+    if state.log_payloads {
+        tracing::trace!("Full payload: {:?}", payload);
+    }
+
+    let tenant = match authenticate(&state, &headers, &payload, true) {
+        Ok(signer) => state.tenant(signer),
+        Err(err) => return err.into_response(),
+    };
+
+    if let Command::Status = payload.command {
+        // The query string only filters the status, other commands ignore it
+        let mut filter = match filter {
+            Ok(Query(filter)) => filter,
+            Err(rejection) => {
+                return ApiError::new(ErrorCode::InvalidCommand, rejection.body_text())
+                    .into_response()
+            }
+        };
+        filter.owner = tenant;
+        // The version is read before the tunnels, so a change in between leads to a new ETag on
+        // the next poll instead of a missed change
+        let etag = format!("\"{}\"", *state.version.borrow());
+        if matches_etag(&headers, &etag) {
+            return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+        }
+        // Only JSON is streamed, and a signed response has to be complete before it is sent
+        if state.buffered_status || state.signing_key.is_some() || msgpack::is_msgpack(&headers) {
+            let response = tunnel_status(&state, &filter);
+            return ([(header::ETAG, etag)], response).into_response();
+        }
+        let headers = [
+            (header::ETAG, etag),
+            (header::CONTENT_TYPE, STATUS_LINES_CONTENT_TYPE.to_string()),
+        ];
+        return (headers, StreamBody::new(status_lines(state, filter))).into_response();
+    }
+    // Dropping the command when it times out undoes the tunnels it was creating
+    let command = execute_command(&state, payload.command, tenant.as_deref(), false);
+    match tokio::time::timeout(state.command_timeout, command).await {
+        Ok(response) => response.into_response(),
+        Err(_) => ApiError::new(
+            ErrorCode::CommandTimeout,
+            format!(
+                "The command didn't finish within {:?} and was undone",
+                state.command_timeout
+            ),
+        )
+        .into_response(),
+    }
+}
+
+/// Adds the signature of the proxy to a response of `POST /command` when it has a signing key,
+/// over the body and the current time.
+async fn sign_response(
+    State(state): State<Arc<GlobalState>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let signed = request.uri().path() == "/command";
+    let response = next.run(request).await;
+    let (Some(signing_key), true) = (&state.signing_key, signed) else {
+        return response;
+    };
+    let (mut parts, response_body) = response.into_parts();
+    let response_body = match hyper::body::to_bytes(response_body).await {
+        Ok(response_body) => response_body,
+        Err(err) => {
+            tracing::error!("reading the response to sign failed: {err}");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let timestamp = unix_timestamp();
+    let mut message = response_body.to_vec();
+    message.extend_from_slice(timestamp.to_string().as_bytes());
+    let signature: Signature = signing_key.sign(&message);
+    parts
+        .headers
+        .insert(RESPONSE_TIMESTAMP_HEADER, HeaderValue::from(timestamp));
+    parts.headers.insert(
+        RESPONSE_SIGNATURE_HEADER,
+        // Hex digits are always a valid header value
+        HeaderValue::from_str(&signature.to_string()).unwrap(),
+    );
+    Response::from_parts(parts, body::boxed(Full::from(response_body)))
+}
+
+/// The query parameter that asks for indented JSON
+#[derive(Debug, Default, Deserialize)]
+struct Format {
+    #[serde(default)]
+    pretty: bool,
+}
+
+/// Indents JSON responses when the request has `?pretty=true`, for humans reading them. Other
+/// clients get compact JSON.
+async fn pretty_json(
+    Query(format): Query<Format>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let response = next.run(request).await;
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/json"));
+    if !format.pretty || !is_json {
+        return response;
+    }
+    let (mut parts, response_body) = response.into_parts();
+    let pretty = hyper::body::to_bytes(response_body)
+        .await
+        .map_err(|err| err.to_string())
+        .and_then(|compact| {
+            serde_json::from_slice::<serde_json::Value>(&compact).map_err(|err| err.to_string())
+        })
+        .and_then(|value| serde_json::to_vec_pretty(&value).map_err(|err| err.to_string()));
+    match pretty {
+        Ok(pretty) => {
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Response::from_parts(parts, body::boxed(Full::from(pretty)))
+        }
+        Err(err) => {
+            tracing::error!("indenting the response failed: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Checks whether a command would be accepted by `POST /command`, without carrying it out.
+///
+/// The response has the status code that the command would get. The nonce isn't recorded, so
+/// the same command can be sent for real afterwards.
+pub async fn verify_command(
+    State(state): State<Arc<GlobalState>>,
+    headers: HeaderMap,
+    CommandBody(payload): CommandBody,
+) -> Result<(StatusCode, Json<ProxyResponse>), ApiError> {
+    tracing::info!("Verifying payload: {:?}", payload.redacted());
+    let tenant = state.tenant(authenticate(&state, &headers, &payload, false)?);
+    execute_command(&state, payload.command, tenant.as_deref(), true).await
+}
+
+/// Checks that a command comes from an operator: by its signature when the proxy has verifying
+/// keys, otherwise by the admin token in the `Authorization` header if the proxy has one.
+///
+/// Returns the name of the key that signed the command, if it was signed, and checks that the
+/// role of the key allows the command.
+fn authenticate(
+    state: &GlobalState,
+    headers: &HeaderMap,
+    payload: &ProxyCommand,
+    record_nonce: bool,
+) -> Result<Option<String>, ApiError> {
+    let verifying_keys = state.verifying_keys.read().unwrap();
+    if let (true, Some(admin_token)) = (verifying_keys.is_empty(), &state.admin_token) {
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        return match token {
+            Some(token) if constant_time_eq(token.as_bytes(), admin_token.as_bytes()) => Ok(None),
+            Some(_) => Err(ApiError::new(ErrorCode::InvalidToken, "Wrong admin token")),
+            None => Err(ApiError::new(
+                ErrorCode::InvalidToken,
+                "Admin token missing, send it as `Authorization: Bearer <token>`",
+            )),
+        };
+    }
+    let signer = payload
+        .verify_signature(&verifying_keys, &state.nonces, record_nonce)
+        .map_err(|err| ApiError::new(ErrorCode::InvalidSignature, err.to_string()))?;
+    let role = signer
+        .and_then(|name| state.key_roles.read().unwrap().get(name).copied())
+        .unwrap_or_default();
+    match (role, &payload.command) {
+        (KeyRole::Observer, command) if !matches!(command, Command::Status) => Err(ApiError::new(
+            ErrorCode::NotPermitted,
+            format!(
+                "Observer keys can only run `status`, not `{}`",
+                command.kind()
+            ),
+        )),
+        _ => Ok(signer.map(str::to_string)),
+    }
+}
+
+/// Compares `a` and `b` in a time that only depends on their lengths, so a token can't be
+/// guessed byte by byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Checks that a `GET` request that reads the tunnels comes from an operator or observer, or
+/// from a tenant, like a `status` command, whose timestamp, nonce and signature it carries in
+/// its headers.
+///
+/// Returns the tenant whose tunnels the request may see, `None` for all of them.
+fn authenticate_read(state: &GlobalState, headers: &HeaderMap) -> Result<Option<String>, ApiError> {
+    fn parse<T>(
+        headers: &HeaderMap,
+        name: &str,
+        parse: impl FnOnce(&str) -> Option<T>,
+    ) -> Result<Option<T>, ApiError> {
+        let Some(value) = headers.get(name) else {
+            return Ok(None);
+        };
+        value
+            .to_str()
+            .ok()
+            .and_then(parse)
+            .map(Some)
+            .ok_or_else(|| {
+                ApiError::new(
+                    ErrorCode::InvalidSignature,
+                    format!("Malformed `{name}` header"),
                 )
+            })
+    }
+    let timestamp = parse(headers, REQUEST_TIMESTAMP_HEADER, |value| {
+        value.parse().ok()
+    })?;
+    let nonce = parse(headers, REQUEST_NONCE_HEADER, |value| {
+        let mut nonce = [0; 16];
+        for (i, byte) in nonce.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(value.get(2 * i..2 * i + 2)?, 16).ok()?;
+        }
+        (value.len() == 32).then_some(nonce)
+    })?;
+    let signature = parse(headers, REQUEST_SIGNATURE_HEADER, |value| {
+        value.parse().ok()
+    })?;
+    let payload = ProxyCommand {
+        command: Command::Status,
+        timestamp,
+        nonce,
+        signature,
+    };
+    let signer = authenticate(state, headers, &payload, true)?;
+    Ok(state.tenant(signer))
+}
+
+/// Who a request that reads the tunnels comes from, see [`require_reader`]
+#[derive(Clone, Debug)]
+pub struct Reader {
+    /// Only the tunnels of this tenant are shown
+    tenant: Option<String>,
+}
+
+impl Reader {
+    fn sees(&self, proxy: &ProxyState) -> bool {
+        self.tenant.is_none() || proxy.owner == self.tenant
+    }
+}
+
+/// Refuses requests that read the tunnels unless they are authenticated like a `status`
+/// command, see [`authenticate_read`], and passes on whose tunnels they may see.
+async fn require_reader(
+    State(state): State<Arc<GlobalState>>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    match authenticate_read(&state, request.headers()) {
+        Ok(tenant) => {
+            request.extensions_mut().insert(Reader { tenant });
+            next.run(request).await
+        }
+        Err(err) => err.into_response(),
+    }
+}
+
+/// Answers a body that isn't a command, including one that is too large.
+pub(crate) fn invalid_json(rejection: JsonRejection) -> ApiError {
+    let message = rejection.body_text();
+    // The JSON is fine, but a port in it is out of range
+    if matches!(rejection, JsonRejection::JsonDataError(_)) && message.contains(port::PORT_RANGE) {
+        return ApiError::new(ErrorCode::InvalidCommand, message);
+    }
+    ApiError::new(ErrorCode::InvalidJson, message).with_status(rejection.status())
+}
+
+/// Whether the `If-None-Match` header of a request matches `etag`
+fn matches_etag(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Checks that `tenant` may change the tunnel `proxy`, which every key without a quota may.
+fn check_owner(tenant: Option<&str>, id: Uuid, proxy: &ProxyState) -> Result<(), ApiError> {
+    match tenant {
+        Some(tenant) if proxy.owner.as_deref() != Some(tenant) => Err(ApiError::new(
+            ErrorCode::NotPermitted,
+            format!("Tunnel {id} wasn't created by the key {tenant}"),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Carries out a `Create`, or with `routes` the one that a `CreateRouter` expands to, whose
+/// connections each go to the route of the key they start with instead of the destination.
+async fn create_tunnel(
+    state: &Arc<GlobalState>,
+    create: Command,
+    routes: BTreeMap<String, SocketAddr>,
+    created_with: serde_json::Value,
+    tenant: Option<&str>,
+    dry_run: bool,
+) -> Result<(StatusCode, Json<ProxyResponse>), ApiError> {
+    let Command::Create {
+        incoming_port,
+        destination_port,
+        destination_ip,
+        destination_uds,
+        id,
+        connect_timeout_ms,
+        source_address,
+        ttl_secs,
+        buffer_size,
+        max_in_flight_bytes,
+        tcp_nodelay,
+        tcp_keepalive_secs,
+        dscp,
+        max_lifetime_secs,
+        reuse_address,
+        reuse_port,
+        log_sample_rate,
+        quiet,
+        label,
+        backlog,
+        accept_loops,
+        sni_map,
+        response_destination,
+        mirror_to,
+        mirror_gzip_level,
+        fanout_destinations,
+        failover_destinations,
+        overflow_response,
+        queue_len,
+        overflow_policy,
+        queue_workers,
+        pool,
+    } = create
+    else {
+        unreachable!("only called with a `Create`");
+    };
+    let kind = if routes.is_empty() {
+        "create"
+    } else {
+        "create_router"
+    };
+    let accepted = |status| {
+        Ok((
+            status,
+            Json(ProxyResponse::Message(format!(
+                "The {kind} command would be accepted"
+            ))),
+        ))
+    };
+    // Checked by the validator
+    let destination =
+        match Destination::from_fields(destination_ip, destination_port, destination_uds) {
+            Ok(destination) => destination,
+            Err(message) => return Err(ApiError::new(ErrorCode::InvalidCommand, message)),
+        };
+    let uses = match routes.len() {
+        0 => destination.to_string(),
+        routes => format!("{routes} routes"),
+    };
+    let reuse_port = reuse_port.unwrap_or(state.reuse_port);
+
+    let connect_timeout = connect_timeout_ms
+        .map(time::Duration::from_millis)
+        .unwrap_or(DEFAULT_CONNECT_TIMEOUT);
+    let config = Arc::new(TunnelConfig {
+        id,
+        #[cfg(feature = "otel")]
+        created_by: otel::current_span_context(),
+        connect_timeout,
+        buffer_size: buffer_size
+            .unwrap_or(DEFAULT_BUFFER_SIZE)
+            .min(max_in_flight_bytes.unwrap_or(usize::MAX)),
+        max_in_flight_bytes,
+        tcp_nodelay,
+        tcp_keepalive: tcp_keepalive_secs.map(time::Duration::from_secs),
+        dscp,
+        max_lifetime: max_lifetime_secs.map(time::Duration::from_secs),
+        log_sample_rate: log_sample_rate.unwrap_or(1.0),
+        quiet,
+        reuse_address: reuse_address.unwrap_or(state.reuse_address),
+        reuse_port,
+        backlog: backlog.unwrap_or(DEFAULT_BACKLOG),
+        accept_loops: accept_loops.unwrap_or(1),
+        sni_map: sni_map
+            .into_iter()
+            .map(|(name, addr)| (name.to_ascii_lowercase(), addr))
+            .collect(),
+        response_destination: response_destination.map(Destination::Tcp),
+        mirror_to,
+        mirror_gzip_level,
+        fanout_destinations,
+        failover: (!failover_destinations.is_empty()).then(|| {
+            Failover::new(
+                destination.clone(),
+                source_address,
+                failover_destinations,
+                connect_timeout,
+            )
+        }),
+        overflow_response,
+        routes,
+        queue: queue_len.map(|len| {
+            Arc::new(ConnectionQueue::new(
+                len,
+                overflow_policy.unwrap_or_default(),
+            ))
+        }),
+        queue_workers: queue_workers.unwrap_or(DEFAULT_QUEUE_WORKERS),
+        pool: pool.then(OutboundPool::new),
+    });
+    let (tx, rx) = watch::channel(ProxyControlMessage::Open {
+        destination: destination.clone(),
+        source_address,
+        reconnect: true,
+    });
+    let now = time::SystemTime::now();
+    let control = Arc::new(tx);
+    let (listeners, listeners_rx) = mpsc::channel(1);
+    let connections = Arc::new(Connections::new(state.served.clone()));
+    {
+        // Everything is checked and reserved under the same lock, so concurrent creates
+        // can't both pass the checks
+        let mut proxies = state.proxies.lock().unwrap();
+        // Check if ID or incoming_port already exists
+        if proxies.get(&id).is_some() {
+            return Err(ApiError::new(
+                ErrorCode::IdConflict,
+                "Id already exists. Use the modify command instead.",
+            ));
+        }
+        if let Some(max_tunnels) = state.max_tunnels {
+            if proxies.len() >= max_tunnels {
+                return Err(ApiError::new(
+                    ErrorCode::TunnelLimit,
+                    format!("The maximum of {max_tunnels} tunnels has been reached"),
+                ));
+            }
+        }
+        if let Some(tenant) = tenant {
+            let quota = state.key_quotas[tenant];
+            let owned = proxies
+                .iter()
+                .filter(|(_, proxy)| proxy.owner.as_deref() == Some(tenant))
+                .count();
+            if owned >= quota {
+                return Err(ApiError::new(
+                    ErrorCode::QuotaExceeded,
+                    format!("The key {tenant} already has its quota of {quota} tunnels"),
+                ));
+            }
+        }
+        if proxies.port_in_use(Protocol::Tcp, incoming_port) {
+            return Err(ApiError::new(
+                ErrorCode::PortInUse,
+                format!("The `incoming_port` already in use: {incoming_port}"),
+            ));
+        }
+        if dry_run {
+            return accepted(StatusCode::ACCEPTED);
+        }
+        proxies.insert(
+            id,
+            ProxyState {
+                protocol: Protocol::Tcp,
+                incoming_port,
+                label,
+                destination: destination.clone(),
+                source_address,
+                control: control.clone(),
+                listeners,
+                draining: false,
+                owner: tenant.map(str::to_string),
+                created_at: now,
+                last_modified: now,
+                generation: 0,
+                expiry: ttl_secs.map(|ttl| expire_after(state, id, &control, ttl)),
+                revert: None,
+                connections: connections.clone(),
+                config: config.clone(),
+                created_with,
+            },
+        );
+        state.changed();
+    }
+    let created = Created::new(state, vec![(id, control.clone())]);
+    if let Err(err) = add_proxy(
+        incoming_port,
+        rx,
+        listeners_rx,
+        config,
+        connections,
+        state.rejections.clone(),
+        state.connection_permits.clone(),
+    )
+    .await
+    {
+        tracing::error!("failed to start tunnel {id} on port {incoming_port}: {err}");
+        drop(created);
+        let code = match err.kind() {
+            io::ErrorKind::AddrInUse => ErrorCode::PortInUse,
+            io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            _ => ErrorCode::ListenFailed,
+        };
+        return Err(ApiError::new(
+            code,
+            format!("Failed to listen on port {incoming_port}: {err}"),
+        ));
+    }
+    created.keep();
+    tokio::spawn(remove_when_stopped(state.clone(), id, control));
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(ProxyResponse::Message(format!(
+            "Created tunnel {id} on port {incoming_port} to use {uses}"
+        ))),
+    ))
+}
+
+/// Carries out `command` for `tenant`, or with `dry_run` only checks that it would succeed.
+#[tracing::instrument(
+    name = "command",
+    skip_all,
+    fields(kind = command.kind(), tunnel = command.id().map(tracing::field::display), dry_run)
+)]
+async fn execute_command(
+    state: &Arc<GlobalState>,
+    command: Command,
+    tenant: Option<&str>,
+    dry_run: bool,
+) -> Result<(StatusCode, Json<ProxyResponse>), ApiError> {
+    let kind = command.kind();
+    let accepted = |status| {
+        Ok((
+            status,
+            Json(ProxyResponse::Message(format!(
+                "The {kind} command would be accepted"
+            ))),
+        ))
+    };
+    // A tunnel keeps the command that created it, for `GET /tunnels/:id/connections`
+    let created_with = match &command {
+        Command::Create { .. } | Command::CreateRouter { .. } => {
+            serde_json::to_value(&command).unwrap_or_default()
+        }
+        _ => serde_json::Value::Null,
+    };
+    // Before anything is changed, so a command with several problems is answered with all of
+    // them at once
+    command.validate(state)?;
+    match command {
+        Command::Create { .. } => {
+            create_tunnel(
+                state,
+                command,
+                BTreeMap::new(),
+                created_with,
+                tenant,
+                dry_run,
+            )
+            .await
+        }
+        Command::CreateRange {
+            start_port,
+            count,
+            destination_ip,
+            destination_port_start,
+        } => {
+            if let Some(max_tunnels) = state.max_tunnels {
+                if state.proxies.lock().unwrap().len() + count as usize > max_tunnels {
+                    return Err(ApiError::new(
+                        ErrorCode::TunnelLimit,
+                        format!("The {count} tunnels would exceed the maximum of {max_tunnels}"),
+                    ));
+                }
+            }
+            // Rolled back when any of them fails, so the range can be retried as a whole
+            let mut created = Created::new(state, Vec::with_capacity(count as usize));
+            for i in 0..count {
+                let id = Uuid::new_v4();
+                let destination = SocketAddr::new(destination_ip, destination_port_start + i);
+                let create = Command::create(id, start_port + i, destination);
+                let _ = Box::pin(execute_command(state, create, tenant, dry_run)).await?;
+                if !dry_run {
+                    let control = state
+                        .proxies
+                        .lock()
+                        .unwrap()
+                        .get(&id)
+                        .map(|proxy| proxy.control.clone());
+                    // A `Delete` may already have removed it
+                    if let Some(control) = control {
+                        created.tunnels.push((id, control));
+                    }
+                }
+            }
+            if dry_run {
+                return accepted(StatusCode::ACCEPTED);
+            }
+            let ids = created.keep();
+            Ok((StatusCode::ACCEPTED, Json(ProxyResponse::Created { ids })))
+        }
+        Command::CreateRouter {
+            id,
+            incoming_port,
+            routes,
+        } => {
+            let create = Command::create(id, incoming_port, ROUTER_DESTINATION);
+            create_tunnel(state, create, routes, created_with, tenant, dry_run).await
+        }
+        Command::Modify {
+            destination_port,
+            destination_ip,
+            destination_uds,
+            id,
+            source_address,
+            ttl_secs,
+            drain_on_modify,
+            label,
+        } => {
+            // Read under the same lock as the change, so the half of the address that isn't
+            // given can't change in between
+            let mut proxies = state.proxies.lock().unwrap();
+            let destination = match (destination_ip, destination_port, destination_uds) {
+                (None, None, None) => Err("A `modify` needs a `destination_ip`, a \
+                                           `destination_port` or a `destination_uds`"
+                    .to_string()),
+                // Only one half of the address changes
+                (ip, port, None) if ip.is_some() != port.is_some() => {
+                    let Some(proxy) = proxies.get(&id) else {
+                        return Err(ApiError::new(
+                            ErrorCode::NotFound,
+                            format!("Id not found: {id}"),
+                        ));
+                    };
+                    check_owner(tenant, id, proxy)?;
+                    match proxy.destination {
+                        Destination::Tcp(addr) => Ok(Destination::Tcp(SocketAddr::new(
+                            ip.unwrap_or(addr.ip()),
+                            port.unwrap_or(addr.port()),
+                        ))),
+                        _ => Err(format!(
+                            "Tunnel {id} doesn't forward to an address, so both \
+                             `destination_ip` and `destination_port` must be given"
+                        )),
+                    }
+                }
+                (ip, port, uds) => Destination::from_fields(ip, port, uds),
+            };
+            let destination = match destination {
+                Ok(destination) => destination,
+                Err(message) => return Err(ApiError::new(ErrorCode::InvalidCommand, message)),
+            };
+            // The validator can't check the half of the address that comes from the tunnel, nor
+            // the source address that is kept
+            let source_address = match source_address {
+                Some(source_address) => source_address,
+                None => proxies.get(&id).and_then(|proxy| proxy.source_address),
+            };
+            if let Some(source_address) = source_address {
+                if let Err(message) = validate_source_address(source_address, &destination) {
+                    return Err(ApiError::new(ErrorCode::InvalidCommand, message));
+                }
+            }
+            state.check_destination(&destination)?;
+            if let Destination::Tcp(addr) = destination {
+                state.check_self_loop(&proxies, None, addr)?;
+            }
+            if let Some(proxy) = proxies.get_mut(&id) {
+                check_owner(tenant, id, proxy)?;
+                if proxy.config.is_router() {
+                    return Err(ApiError::new(
+                        ErrorCode::InvalidCommand,
+                        format!("Tunnel {id} is a router, create a new one to change its routes"),
+                    ));
+                }
+                if proxy.draining {
+                    return Err(ApiError::new(
+                        ErrorCode::Draining,
+                        format!("Tunnel {id} is draining and can no longer be modified"),
+                    ));
+                }
+                if dry_run {
+                    return accepted(StatusCode::ACCEPTED);
+                }
+                proxy.destination = destination.clone();
+                proxy.source_address = source_address;
+                if let Some(label) = label {
+                    proxy.label = label;
+                }
+                proxy.last_modified = time::SystemTime::now();
+                proxy.generation += 1;
+                // The new destination is meant to last
+                proxy.revert = None;
+                state.changed();
+                if let Some(ttl_secs) = ttl_secs {
+                    // Dropping the old expiry cancels its timer
+                    proxy.expiry = ttl_secs.map(|ttl| expire_after(state, id, &proxy.control, ttl));
+                }
+                // A tunnel whose listener failed may already have no receivers left
+                let _ = proxy.control.send(ProxyControlMessage::Open {
+                    destination,
+                    source_address,
+                    reconnect: !drain_on_modify,
+                });
+                Ok((
+                    StatusCode::ACCEPTED,
+                    Json(ProxyResponse::Modified {
+                        message: format!("Changed tunnel {id} to use {}", proxy.destination),
+                        generation: proxy.generation,
+                        destination: proxy.destination.clone(),
+                    }),
+                ))
             } else {
-                (
-                    StatusCode::NOT_FOUND,
-                    Json(ProxyResponse::Message(format!("Id not found: {id}"))),
-                )
+                Err(ApiError::new(
+                    ErrorCode::NotFound,
+                    format!("Id not found: {id}"),
+                ))
+            }
+        }
+        Command::TemporaryModify {
+            id,
+            destination,
+            revert_after_secs,
+        } => {
+            let destination_addr = destination;
+            let destination = Destination::Tcp(destination_addr);
+            let mut proxies = state.proxies.lock().unwrap();
+            let Some(proxy) = proxies.get_mut(&id) else {
+                return Err(ApiError::new(
+                    ErrorCode::NotFound,
+                    format!("Id not found: {id}"),
+                ));
+            };
+            check_owner(tenant, id, proxy)?;
+            if proxy.config.is_router() {
+                return Err(ApiError::new(
+                    ErrorCode::InvalidCommand,
+                    format!("Tunnel {id} is a router, create a new one to change its routes"),
+                ));
+            }
+            if proxy.draining {
+                return Err(ApiError::new(
+                    ErrorCode::Draining,
+                    format!("Tunnel {id} is draining and can no longer be modified"),
+                ));
+            }
+            if let Some(source_address) = proxy.source_address {
+                if let Err(message) = validate_source_address(source_address, &destination) {
+                    return Err(ApiError::new(ErrorCode::InvalidCommand, message));
+                }
+            }
+            if dry_run {
+                return accepted(StatusCode::ACCEPTED);
+            }
+            // Goes back to the destination from before the first of several temporary ones
+            let (previous, source_address) = match proxy.revert.take() {
+                Some(revert) => (revert.destination.clone(), revert.source_address),
+                None => (proxy.destination.clone(), proxy.source_address),
+            };
+            proxy.destination = destination.clone();
+            proxy.last_modified = time::SystemTime::now();
+            proxy.generation += 1;
+            proxy.revert = Some(revert_after(
+                state,
+                id,
+                proxy,
+                previous,
+                source_address,
+                revert_after_secs,
+            ));
+            state.changed();
+            // A tunnel whose listener failed may already have no receivers left
+            let _ = proxy.control.send(ProxyControlMessage::Open {
+                destination,
+                source_address: proxy.source_address,
+                reconnect: true,
+            });
+            Ok((
+                StatusCode::ACCEPTED,
+                Json(ProxyResponse::Modified {
+                    message: format!(
+                        "Changed tunnel {id} to use {destination_addr} for {revert_after_secs}s"
+                    ),
+                    generation: proxy.generation,
+                    destination: proxy.destination.clone(),
+                }),
+            ))
+        }
+        Command::Delete { id, drain: true } => {
+            let mut proxies = state.proxies.lock().unwrap();
+            let Some(proxy) = proxies.get_mut(&id) else {
+                return Err(ApiError::new(
+                    ErrorCode::NotFound,
+                    format!("Id not found: {id}"),
+                ));
+            };
+            check_owner(tenant, id, proxy)?;
+            if dry_run {
+                return accepted(StatusCode::ACCEPTED);
+            }
+            if !proxy.draining {
+                proxy.draining = true;
+                state.changed();
+                // A tunnel whose listener failed may already have no receivers left, it is
+                // removed by `remove_when_stopped` either way
+                let _ = proxy.control.send(ProxyControlMessage::Drain {
+                    destination: proxy.destination.clone(),
+                    source_address: proxy.source_address,
+                    handover: None,
+                });
+            }
+            Ok((
+                StatusCode::ACCEPTED,
+                Json(ProxyResponse::Message(format!("Draining tunnel: {id}"))),
+            ))
+        }
+        Command::Handover { from_id, to_id } => {
+            let (port, listeners, config, mut handing_over) = {
+                let mut proxies = state.proxies.lock().unwrap();
+                for id in [from_id, to_id] {
+                    let Some(proxy) = proxies.get(&id) else {
+                        return Err(ApiError::new(
+                            ErrorCode::NotFound,
+                            format!("Id not found: {id}"),
+                        ));
+                    };
+                    check_owner(tenant, id, proxy)?;
+                    if proxy.draining {
+                        return Err(ApiError::new(
+                            ErrorCode::Draining,
+                            format!("Tunnel {id} is draining and can no longer be modified"),
+                        ));
+                    }
+                }
+                if dry_run {
+                    return accepted(StatusCode::ACCEPTED);
+                }
+                let (handover, handed_over) = oneshot::channel();
+                let from = proxies.get_mut(&from_id).unwrap();
+                from.draining = true;
+                // A tunnel whose listener failed may already have no receivers left
+                let _ = from.control.send(ProxyControlMessage::Drain {
+                    destination: from.destination.clone(),
+                    source_address: from.source_address,
+                    handover: Some(Handover(Mutex::new(Some(handover)))),
+                });
+                let from_control = from.control.clone();
+                let to_port = proxies.get(&to_id).unwrap().incoming_port;
+                let port = proxies.hand_over_port(&from_id, &to_id);
+                let to = proxies.get_mut(&to_id).unwrap();
+                to.last_modified = time::SystemTime::now();
+                state.changed();
+                let handing_over = HandingOver {
+                    state,
+                    from: (from_id, from_control),
+                    to: (to_id, to.control.clone(), to_port),
+                    handed_over,
+                    undo: None,
+                    kept: false,
+                };
+                (port, to.listeners.clone(), to.config.clone(), handing_over)
+            };
+            let Ok(permit) = listeners.reserve().await else {
+                return Err(ApiError::new(
+                    ErrorCode::ListenFailed,
+                    format!("Tunnel {to_id} stopped before it could take over port {port}"),
+                ));
+            };
+            let listener = match handing_over.listener().await {
+                Some(listener) => listener,
+                // The draining tunnel had already stopped listening
+                None => bind(port, &config).map_err(|err| {
+                    ApiError::new(
+                        ErrorCode::ListenFailed,
+                        format!("Failed to listen on port {port}: {err}"),
+                    )
+                })?,
+            };
+            permit.send(listener);
+            handing_over.keep();
+            Ok((
+                StatusCode::ACCEPTED,
+                Json(ProxyResponse::Message(format!(
+                    "Tunnel {to_id} took over port {port} from tunnel {from_id}, which is draining"
+                ))),
+            ))
+        }
+        Command::Delete { id, drain: false } => {
+            let mut proxies = state.proxies.lock().unwrap();
+            if let Some(proxy) = proxies.get(&id) {
+                check_owner(tenant, id, proxy)?;
+            }
+            if dry_run {
+                return if proxies.contains_key(&id) {
+                    accepted(StatusCode::ACCEPTED)
+                } else {
+                    Err(ApiError::new(
+                        ErrorCode::NotFound,
+                        format!("Id not found: {id}"),
+                    ))
+                };
+            }
+            if let Some(proxy) = proxies.remove(&id) {
+                // A draining tunnel may already have no receivers left
+                let _ = proxy.control.send(ProxyControlMessage::Close);
+                state.changed();
+                Ok((
+                    StatusCode::ACCEPTED,
+                    Json(ProxyResponse::Message(format!("Deleted tunnel: {id}"))),
+                ))
+            } else {
+                Err(ApiError::new(
+                    ErrorCode::NotFound,
+                    format!("Id not found: {id}"),
+                ))
             }
         }
-        Command::Status => (
-            StatusCode::OK,
-            Json(ProxyResponse::Status {
-                tunnels: state
-                    .proxies
-                    .lock()
-                    .unwrap()
-                    .iter()
-                    .map(|(key, value)| (*key, (value.incoming_port, value.destination)))
-                    .collect(),
-            }),
-        ),
-    }
+        Command::Status => {
+            let filter = StatusFilter {
+                owner: tenant.map(str::to_string),
+                ..Default::default()
+            };
+            Ok(tunnel_status(state, &filter))
+        }
+        Command::SelfCheck { .. } if tenant.is_some() => Err(ApiError::new(
+            ErrorCode::NotPermitted,
+            "Keys with a quota can't run a self check",
+        )),
+        Command::SelfCheck { repair } => {
+            let findings = state.self_check(repair && !dry_run);
+            let tunnel_count = state.proxies.lock().unwrap().len();
+            Ok((
+                StatusCode::OK,
+                Json(ProxyResponse::SelfCheck {
+                    findings,
+                    tunnel_count,
+                }),
+            ))
+        }
+        Command::RotateKey { .. } if tenant.is_some() => Err(ApiError::new(
+            ErrorCode::NotPermitted,
+            "Keys with a quota can't rotate keys",
+        )),
+        Command::RotateKey { add, remove } => {
+            let mut verifying_keys = state.verifying_keys.write().unwrap();
+            if verifying_keys.is_empty() {
+                return Err(ApiError::new(
+                    ErrorCode::InvalidCommand,
+                    "The proxy doesn't verify commands, start it with `--verifying-key` to \
+                     rotate keys",
+                ));
+            }
+            // The change is made on a copy, so a bad key leaves the current keys in place
+            let mut rotated = verifying_keys.clone();
+            let mut rotated_roles = state.key_roles.read().unwrap().clone();
+            for name in &remove {
+                rotated.remove(name);
+                rotated_roles.remove(name);
+            }
+            for NamedKey { name, key, role } in add {
+                match VerifyingKey::from_str(&key) {
+                    Ok(key) => {
+                        rotated_roles.remove(&name);
+                        if !role.is_admin() {
+                            rotated_roles.insert(name.clone(), role);
+                        }
+                        rotated.insert(name, key);
+                    }
+                    Err(err) => {
+                        return Err(ApiError::new(
+                            ErrorCode::InvalidCommand,
+                            format!("Invalid verifying key {name}: {err}"),
+                        ))
+                    }
+                }
+            }
+            if rotated.is_empty() {
+                return Err(ApiError::new(
+                    ErrorCode::LastVerifyingKey,
+                    "Refusing to remove the last verifying key",
+                ));
+            }
+            // Without an admin key nobody could ever change the tunnels or keys again
+            if rotated.keys().all(|name| rotated_roles.contains_key(name)) {
+                return Err(ApiError::new(
+                    ErrorCode::LastVerifyingKey,
+                    "Refusing to remove the last admin key",
+                ));
+            }
+            if dry_run {
+                return accepted(StatusCode::OK);
+            }
+            *verifying_keys = rotated;
+            *state.key_roles.write().unwrap() = rotated_roles;
+            let mut names: Vec<_> = verifying_keys.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            tracing::info!("verifying keys rotated to {names:?}");
+            Ok((
+                StatusCode::OK,
+                Json(ProxyResponse::Message(format!(
+                    "Verifying keys: {}",
+                    names.join(", ")
+                ))),
+            ))
+        }
+    }
+}
+
+/// Narrows the tunnels listed by `Status`, from the query string of `/command`
+///
+/// Every given field has to match, so `?port=5555&state=draining` only lists the tunnel on port
+/// 5555 if it is draining.
+#[derive(Debug, Default, Deserialize)]
+pub struct StatusFilter {
+    port: Option<u16>,
+    label: Option<String>,
+    state: Option<TunnelState>,
+    /// Only the tunnels of this tenant, which is never taken from the query string
+    #[serde(skip)]
+    owner: Option<String>,
+}
+
+/// Whether a tunnel accepts connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelState {
+    Active,
+    Draining,
+    /// The listener broke, the reason is in the last error of the tunnel. It is removed, which
+    /// frees its port, once its last connection has finished.
+    Failed,
+}
+
+impl StatusFilter {
+    fn matches(&self, proxy: &ProxyState) -> bool {
+        let state = proxy.state();
+        self.port.is_none_or(|port| port == proxy.incoming_port)
+            && self
+                .label
+                .as_ref()
+                .is_none_or(|label| proxy.label.as_ref() == Some(label))
+            && self.state.is_none_or(|filter| filter == state)
+            && self
+                .owner
+                .as_ref()
+                .is_none_or(|owner| proxy.owner.as_ref() == Some(owner))
+    }
+}
+
+/// Lists the tunnels that match `filter`, nothing matching is not an error.
+fn tunnel_status(state: &GlobalState, filter: &StatusFilter) -> (StatusCode, Json<ProxyResponse>) {
+    (StatusCode::OK, Json(status_snapshot(state, filter)))
+}
+
+fn status_snapshot(state: &GlobalState, filter: &StatusFilter) -> ProxyResponse {
+    let proxies = state.proxies.lock().unwrap();
+    ProxyResponse::Status {
+        tunnels: proxies
+            .iter()
+            .filter(|(_, value)| filter.matches(value))
+            .map(|(key, value)| (*key, value.status()))
+            .collect(),
+        tunnel_count: proxies.len(),
+        max_tunnels: state.max_tunnels,
+        active_connections: state.active_connections(&proxies),
+        max_connections: state.max_connections,
+    }
+}
+
+/// Streams the tunnels that match `filter` as [`StatusLine`]s. Only their ids are collected up
+/// front, the lock on the tunnels is taken again for each line so it is never held for long.
+fn status_lines(
+    state: Arc<GlobalState>,
+    filter: StatusFilter,
+) -> impl Stream<Item = Result<Vec<u8>, serde_json::Error>> {
+    let (summary, ids) = {
+        let proxies = state.proxies.lock().unwrap();
+        let summary = StatusLine::Summary {
+            tunnel_count: proxies.len(),
+            max_tunnels: state.max_tunnels,
+            active_connections: state.active_connections(&proxies),
+            max_connections: state.max_connections,
+        };
+        let ids: Vec<Uuid> = proxies
+            .iter()
+            .filter(|(_, value)| filter.matches(value))
+            .map(|(key, _)| *key)
+            .collect();
+        (summary, ids)
+    };
+    let tunnels = tokio_stream::iter(ids).filter_map(move |id| {
+        // Tunnels deleted since the ids were collected are left out
+        let proxies = state.proxies.lock().unwrap();
+        proxies.get(&id).map(|proxy| StatusLine::Tunnel {
+            id,
+            status: Box::new(proxy.status()),
+        })
+    });
+    tokio_stream::once(summary).chain(tunnels).map(|line| {
+        let mut line = serde_json::to_vec(&line)?;
+        line.push(b'\n');
+        Ok(line)
+    })
+}
+
+/// Time between the comments that keep an idle `GET /status/stream` open
+const STATUS_STREAM_HEARTBEAT: time::Duration = time::Duration::from_secs(15);
+
+/// Pushes a `Status` snapshot as a server-sent event whenever the tunnels change, starting
+/// with the current one.
+///
+/// Changes in quick succession lead to a single snapshot, whose event id is the version that
+/// `Status` uses as its ETag. Takes the same filter as `Status`.
+pub async fn status_stream(
+    State(state): State<Arc<GlobalState>>,
+    Extension(reader): Extension<Reader>,
+    Query(mut filter): Query<StatusFilter>,
+) -> Sse<impl Stream<Item = Result<Event, serde_json::Error>>> {
+    filter.owner = reader.tenant;
+    let versions = WatchStream::new(state.version.subscribe());
+    let events = versions.map(move |version| {
+        Event::default()
+            .event("status")
+            .id(version.to_string())
+            .json_data(status_snapshot(&state, &filter))
+    });
+    Sse::new(events).keep_alive(
+        KeepAlive::new()
+            .interval(STATUS_STREAM_HEARTBEAT)
+            .text("heartbeat"),
+    )
+}
+
+/// Tunnels that a command created, deleted again when it is dropped before [`Created::keep`]
+/// because the command failed or timed out
+struct Created<'a> {
+    state: &'a GlobalState,
+    tunnels: Vec<(Uuid, Arc<Sender<ProxyControlMessage>>)>,
+}
+
+impl<'a> Created<'a> {
+    fn new(state: &'a GlobalState, tunnels: Vec<(Uuid, Arc<Sender<ProxyControlMessage>>)>) -> Self {
+        Self { state, tunnels }
+    }
+
+    /// Keeps the tunnels, returns their ids.
+    fn keep(mut self) -> Vec<Uuid> {
+        std::mem::take(&mut self.tunnels)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect()
+    }
+}
+
+impl Drop for Created<'_> {
+    fn drop(&mut self) {
+        if self.tunnels.is_empty() {
+            return;
+        }
+        let mut proxies = self.state.proxies.lock().unwrap();
+        for (id, control) in &self.tunnels {
+            // A `Delete` may have removed the tunnel while it was starting, after which both
+            // the id and the port can belong to a new tunnel
+            if proxies
+                .get(id)
+                .is_some_and(|proxy| Arc::ptr_eq(&proxy.control, control))
+            {
+                proxies.remove(id);
+                let _ = control.send(ProxyControlMessage::Close);
+            }
+        }
+        self.state.changed();
+    }
+}
+
+/// A `Handover` that has started, undone when it is dropped before [`HandingOver::keep`]
+/// because the command failed or timed out: `from` accepts on its port again and `to` goes
+/// back to its own port
+struct HandingOver<'a> {
+    state: &'a GlobalState,
+    from: (Uuid, Arc<Sender<ProxyControlMessage>>),
+    /// Also the port `to` had before
+    to: (Uuid, Arc<Sender<ProxyControlMessage>>, u16),
+    handed_over: oneshot::Receiver<HandedOver>,
+    /// Keeps `from` waiting to hear whether its listener comes back, once it was received
+    undo: Option<oneshot::Sender<TcpListener>>,
+    kept: bool,
+}
+
+impl HandingOver<'_> {
+    /// Waits for the listener of `from`, `None` when it had already stopped listening.
+    async fn listener(&mut self) -> Option<TcpListener> {
+        let handed_over = (&mut self.handed_over).await.ok()?;
+        self.undo = Some(handed_over.undo);
+        Some(handed_over.listener)
+    }
+
+    /// Keeps the handover, which lets `from` finish draining.
+    fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for HandingOver<'_> {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
+        }
+        let mut proxies = self.state.proxies.lock().unwrap();
+        // Either tunnel may have been deleted in the meantime, after which its id and port can
+        // belong to a new tunnel
+        let (to_id, to_control, to_port) = &self.to;
+        if proxies
+            .get(to_id)
+            .is_some_and(|to| Arc::ptr_eq(&to.control, to_control))
+        {
+            proxies.give_back_port(to_id, *to_port);
+        }
+        let (from_id, from_control) = &self.from;
+        if let Some(from) = proxies
+            .get_mut(from_id)
+            .filter(|from| Arc::ptr_eq(&from.control, from_control))
+        {
+            from.draining = false;
+            let _ = from.control.send(ProxyControlMessage::Open {
+                destination: from.destination.clone(),
+                source_address: from.source_address,
+                reconnect: false,
+            });
+        }
+        drop(proxies);
+        // A listener that is still on its way goes back as well, otherwise `from` gets it back
+        // when it finds that nobody waits for it anymore
+        self.handed_over.close();
+        if let Ok(HandedOver { listener, undo }) = self.handed_over.try_recv() {
+            let _ = undo.send(listener);
+        }
+        self.state.changed();
+    }
+}
+
+/// Removes a tunnel from the state, which frees its port, once all of its tasks have exited.
+/// That is when a draining tunnel has no connections left, or when the listener of a tunnel
+/// failed and its last connections have finished.
+async fn remove_when_stopped(
+    state: Arc<GlobalState>,
+    id: Uuid,
+    control: Arc<Sender<ProxyControlMessage>>,
+) {
+    control.closed().await;
+
+    let mut proxies = state.proxies.lock().unwrap();
+    // The tunnel might have been deleted forcefully in the meantime and the id reused
+    if proxies
+        .get(&id)
+        .is_some_and(|proxy| Arc::ptr_eq(&proxy.control, &control))
+    {
+        let proxy = proxies.remove(&id).unwrap();
+        state.changed();
+        match proxy.state() {
+            TunnelState::Failed => {
+                tracing::warn!("tunnel {id} removed after its listener failed");
+            }
+            _ => tracing::info!("tunnel {id} drained"),
+        }
+    }
+}
+
+/// Starts the timer that deletes tunnel `id` after `ttl_secs` seconds.
+fn expire_after(
+    state: &Arc<GlobalState>,
+    id: Uuid,
+    control: &Arc<Sender<ProxyControlMessage>>,
+    ttl_secs: u64,
+) -> Expiry {
+    let at = tokio::time::Instant::now() + time::Duration::from_secs(ttl_secs);
+    let state = state.clone();
+    let control = control.clone();
+    let timer = tokio::spawn(async move {
+        tokio::time::sleep_until(at).await;
+
+        let mut proxies = state.proxies.lock().unwrap();
+        if proxies
+            .get(&id)
+            .is_some_and(|proxy| Arc::ptr_eq(&proxy.control, &control))
+        {
+            let proxy = proxies.remove(&id).unwrap();
+            // A draining tunnel may already have no receivers left
+            let _ = proxy.control.send(ProxyControlMessage::Close);
+            state.changed();
+            tracing::info!("tunnel {id} expired");
+        }
+    });
+    Expiry {
+        at,
+        timer: timer.abort_handle(),
+    }
+}
+
+/// Starts the timer that points tunnel `id` back at `destination` after `secs` seconds, unless
+/// the tunnel is modified again before that.
+fn revert_after(
+    state: &Arc<GlobalState>,
+    id: Uuid,
+    proxy: &ProxyState,
+    destination: Destination,
+    source_address: Option<IpAddr>,
+    secs: u64,
+) -> Revert {
+    let at = tokio::time::Instant::now() + time::Duration::from_secs(secs);
+    let state = state.clone();
+    let control = proxy.control.clone();
+    let generation = proxy.generation;
+    let reverted = destination.clone();
+    let timer = tokio::spawn(async move {
+        tokio::time::sleep_until(at).await;
+
+        let mut proxies = state.proxies.lock().unwrap();
+        // A later command may have taken the lock just before this one, and changed or deleted
+        // the tunnel
+        let Some(proxy) = proxies.get_mut(&id).filter(|proxy| {
+            Arc::ptr_eq(&proxy.control, &control) && proxy.generation == generation
+        }) else {
+            return;
+        };
+        proxy.destination = reverted.clone();
+        proxy.source_address = source_address;
+        proxy.last_modified = time::SystemTime::now();
+        proxy.generation += 1;
+        proxy.revert = None;
+        state.changed();
+        // A draining tunnel may already have no receivers left
+        let _ = proxy.control.send(ProxyControlMessage::Open {
+            destination: reverted,
+            source_address,
+            reconnect: true,
+        });
+        tracing::info!("tunnel {id} reverted to {}", proxy.destination);
+    });
+    Revert {
+        at,
+        timer: timer.abort_handle(),
+        destination,
+        source_address,
+    }
+}
+
+/// The state a tunnel should be in, sent to its tasks over a `watch` channel.
+///
+/// The channel only keeps the latest message, so a task that is slow to look may miss
+/// intermediate ones: after a quick `Modify` and `Delete` it only ever sees `Close`. Every
+/// message therefore describes the complete desired state rather than a change, and tasks act
+/// on whichever message they see last. A closed channel is treated like `Close`.
+#[derive(Debug)]
+enum ProxyControlMessage {
+    Open {
+        destination: Destination,
+        source_address: Option<IpAddr>,
+        /// Whether established connections switch over to the new destination, otherwise only
+        /// new connections use it
+        reconnect: bool,
+    },
+    /// Stop accepting new connections, but let the established ones finish
+    Drain {
+        destination: Destination,
+        source_address: Option<IpAddr>,
+        /// Receives the listener once it is no longer accepted from, see `Command::Handover`
+        handover: Option<Handover>,
+    },
+    Close,
+}
+
+/// Where a draining tunnel sends its listener, for the tunnel that takes over its port
+#[derive(Debug)]
+struct Handover(Mutex<Option<oneshot::Sender<HandedOver>>>);
+
+/// The listener of a draining tunnel on its way to the tunnel that takes over its port
+#[derive(Debug)]
+struct HandedOver {
+    listener: TcpListener,
+    /// Gives the listener back to the draining tunnel when the handover is undone, and is
+    /// dropped once the handover is done
+    undo: oneshot::Sender<TcpListener>,
+}
+
+/// Checks that outbound connections to `destination` can be made from `source_address`.
+fn validate_source_address(
+    source_address: IpAddr,
+    destination: &Destination,
+) -> Result<(), String> {
+    let addr = match destination {
+        Destination::Tcp(addr) => addr,
+        Destination::Unix(_) => {
+            return Err(format!(
+                "The `source_address` {source_address} can't be used with a unix socket \
+                 destination"
+            ))
+        }
+    };
+    if source_address.is_ipv4() != addr.is_ipv4() {
+        return Err(format!(
+            "The `source_address` {source_address} can't be used to connect to {destination}"
+        ));
+    }
+    // Binding only succeeds for addresses that belong to this host
+    std::net::UdpSocket::bind((source_address, 0))
+        .map(|_| ())
+        .map_err(|err| format!("The `source_address` {source_address} is not local: {err}"))
+}
+
+/// Checks that `label` is short and printable, so it is safe to show in dashboards and logs.
+fn validate_label(label: &str) -> Result<(), String> {
+    if label.len() > MAX_LABEL_LEN {
+        return Err(format!(
+            "The `label` may be at most {MAX_LABEL_LEN} bytes long"
+        ));
+    }
+    if label.chars().any(char::is_control) {
+        return Err("The `label` may not contain control characters".to_string());
+    }
+    Ok(())
+}
+
+/// The outbound side of a connection, to either kind of [`Destination`]
+trait Outbound: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Outbound for T {}
+
+/// Reads the route key that a client of a router sends first, see `Command::CreateRouter`.
+async fn read_route_key<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<String> {
+    let len = reader.read_u8().await?;
+    let mut key = vec![0; len as usize];
+    reader.read_exact(&mut key).await?;
+    String::from_utf8(key).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Copies like `io::copy_buf`, but writes and flushes everything that was read before reading
+/// more, so a slow writer holds back the reader instead of data piling up in between.
+async fn copy_bounded<R, W>(reader: &mut R, writer: &mut W) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut copied = 0;
+    loop {
+        let chunk = reader.fill_buf().await?;
+        if chunk.is_empty() {
+            return Ok(copied);
+        }
+        let len = chunk.len();
+        writer.write_all(chunk).await?;
+        writer.flush().await?;
+        reader.consume(len);
+        copied += len as u64;
+    }
+}
+
+/// Like [`copy_bounded`], but keeps `idle` set while it waits for the reader
+async fn copy_tracking_idle<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    idle: &AtomicBool,
+) -> io::Result<u64>
+where
+    R: AsyncBufRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut copied = 0;
+    loop {
+        idle.store(true, Ordering::Relaxed);
+        let chunk = reader.fill_buf().await?;
+        idle.store(false, Ordering::Relaxed);
+        if chunk.is_empty() {
+            return Ok(copied);
+        }
+        let len = chunk.len();
+        writer.write_all(chunk).await?;
+        writer.flush().await?;
+        reader.consume(len);
+        copied += len as u64;
+    }
+}
+
+/// Like [`ignore_disconnect`], but a connection that was cut off is no longer `reusable`, as
+/// the destination may be left with half a request or answer.
+fn note_disconnect(result: io::Result<impl Sized>, reusable: &AtomicBool) -> io::Result<()> {
+    if result.is_err() {
+        reusable.store(false, Ordering::Relaxed);
+    }
+    ignore_disconnect(result)
+}
+
+/// Treats a peer that closes its connection abruptly, by resetting it or by going away before
+/// the other side is done writing, like one that shuts it down.
+fn ignore_disconnect(result: io::Result<impl Sized>) -> io::Result<()> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::UnexpectedEof
+            ) =>
+        {
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Connects to `destination`, from `source_address` if one is given.
+async fn connect(
+    destination: &Destination,
+    source_address: Option<IpAddr>,
+    config: &TunnelConfig,
+) -> io::Result<Box<dyn Outbound>> {
+    let destination = match destination {
+        Destination::Tcp(addr) => *addr,
+        #[cfg(unix)]
+        Destination::Unix(path) => return Ok(Box::new(UnixStream::connect(path).await?)),
+        #[cfg(not(unix))]
+        Destination::Unix(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            ))
+        }
+    };
+    let stream = match source_address {
+        Some(source_address) => {
+            let socket = if destination.is_ipv4() {
+                TcpSocket::new_v4()?
+            } else {
+                TcpSocket::new_v6()?
+            };
+            socket.bind(SocketAddr::new(source_address, 0))?;
+            socket.connect(destination).await?
+        }
+        None => TcpStream::connect(destination).await?,
+    };
+    config.configure(&stream)?;
+    Ok(Box::new(stream))
 }
 
-#[derive(Debug)]
-enum ProxyControlMessage {
-    Open { destination: SocketAddr },
-    Close,
+/// Address that the listener of a tunnel on `in_port` is bound to.
+fn listen_address(in_port: u16) -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::UNSPECIFIED, in_port))
+}
+
+/// Binds the listener of a tunnel with its socket options.
+fn bind(in_port: u16, config: &TunnelConfig) -> io::Result<TcpListener> {
+    let socket = TcpSocket::new_v4()?;
+    // On Windows `SO_REUSEADDR` would let other sockets bind the port while the tunnel
+    // listens on it, while on Unix it only allows rebinding over connections in `TIME_WAIT`
+    #[cfg(unix)]
+    socket.set_reuseaddr(config.reuse_address)?;
+    #[cfg(unix)]
+    socket.set_reuseport(config.reuse_port)?;
+    // Accepted connections start out with the receive buffer of the listener, which also
+    // decides the window they announce
+    if let Some(max) = config.max_in_flight_bytes {
+        socket.set_recv_buffer_size(max.try_into().unwrap_or(u32::MAX))?;
+    }
+    socket.bind(listen_address(in_port))?;
+    socket.listen(config.backlog)
 }
 
-async fn add_proxy(in_port: u16, control: Receiver<ProxyControlMessage>) -> anyhow::Result<()> {
-    let listener = TcpListener::bind(("0.0.0.0", in_port)).await?;
+/// Binds the listener for a tunnel and spawns its accept loop.
+///
+/// Only returns once the accept loop is running, so any error here means the tunnel never
+/// started listening.
+async fn add_proxy(
+    in_port: u16,
+    control: Receiver<ProxyControlMessage>,
+    listeners: mpsc::Receiver<TcpListener>,
+    config: Arc<TunnelConfig>,
+    connections: Arc<Connections>,
+    rejections: Arc<Rejections>,
+    permits: Option<Arc<Semaphore>>,
+) -> io::Result<()> {
+    let listener = bind(in_port, &config)?;
 
     tracing::info!("proxying port {in_port} to {:?}", *control.borrow());
 
-    tokio::spawn(proxy(listener, control));
-    Ok(())
+    let (ready_tx, ready_rx) = oneshot::channel();
+    tokio::spawn(proxy(
+        listener,
+        control,
+        listeners,
+        config,
+        connections,
+        rejections,
+        permits,
+        ready_tx,
+    ));
+    ready_rx
+        .await
+        .map_err(|_| io::Error::other("proxy task exited before it started accepting connections"))
 }
 
-async fn proxy(listener: TcpListener, mut control: Receiver<ProxyControlMessage>) {
+// Everything a tunnel shares between its accept loops and connections
+#[allow(clippy::too_many_arguments)]
+async fn proxy(
+    listener: TcpListener,
+    mut control: Receiver<ProxyControlMessage>,
+    mut listeners: mpsc::Receiver<TcpListener>,
+    config: Arc<TunnelConfig>,
+    connections: Arc<Connections>,
+    rejections: Arc<Rejections>,
+    permits: Option<Arc<Semaphore>>,
+    ready: oneshot::Sender<()>,
+) {
+    // The receiving end only goes away when the handler gave up, in which case it doesn't matter
+    let _ = ready.send(());
+    if let Some(queue) = &config.queue {
+        for _ in 0..config.queue_workers {
+            tokio::spawn(work_queue(
+                queue.clone(),
+                control.clone(),
+                config.clone(),
+                connections.clone(),
+                rejections.clone(),
+                permits.clone(),
+            ));
+        }
+    }
+    let mut port = listener.local_addr().unwrap().port();
+    let mut listener = Arc::new(listener);
+    let accept = |listener: &Arc<TcpListener>, control: &Receiver<ProxyControlMessage>| {
+        // Every loop waits on the same listener and the kernel hands each connection to one of
+        // them
+        let mut loops = JoinSet::new();
+        for _ in 0..config.accept_loops {
+            loops.spawn(accept_loop(
+                listener.clone(),
+                control.clone(),
+                config.clone(),
+                connections.clone(),
+                rejections.clone(),
+                permits.clone(),
+            ));
+        }
+        loops
+    };
+    let mut loops = accept(&listener, &control);
     loop {
-        tokio::select! {
-            l = listener.accept()=> {
-                if let Ok((inbound, _)) = l {
-                    let transfer = transfer(inbound, control.clone());
+        // The tunnel that takes over the port, when draining for a handover
+        let mut handover = None;
+        loop {
+            tokio::select! {
+                // A loop only stops when the listener failed
+                _ = loops.join_next() => break,
+                Some(next) = listeners.recv() => {
+                    loops.shutdown().await;
+                    let previous = port;
+                    port = next.local_addr().unwrap().port();
+                    listener = Arc::new(next);
+                    loops = accept(&listener, &control);
+                    tracing::info!("proxy port {previous} moved to port {port}");
+                }
+                changed = control.changed() => {
+                    if changed.is_err() {
+                        tracing::info!("proxy port {port} lost its tunnel");
+                        break;
+                    }
+                    match *control.borrow() {
+                        ProxyControlMessage::Open { ref destination, .. } => {
+                            tracing::info!("destination for proxy port {port} changed to {destination}");
+                        },
+                        ProxyControlMessage::Drain { handover: ref to, .. } => {
+                            tracing::info!("proxy port {port} draining");
+                            handover = to.as_ref().and_then(|to| to.0.lock().unwrap().take());
+                            break;
+                        },
+                        ProxyControlMessage::Close => {
+                            tracing::info!("destination for proxy port {port} closed");
+                            break;
+                        },
+                    }
+                }
+            }
+        }
+        // Accepting is cancel safe, and the port is released once every loop has let go of it
+        loops.shutdown().await;
+        let Some(handover) = handover else {
+            break;
+        };
+        let Ok(handed_over) = Arc::try_unwrap(listener) else {
+            break;
+        };
+        let (undo, undone) = oneshot::channel();
+        let given_back = match handover.send(HandedOver {
+            listener: handed_over,
+            undo,
+        }) {
+            Ok(()) => undone.await,
+            Err(handed_over) => Ok(handed_over.listener),
+        };
+        // Nothing comes back once the other tunnel took over the listener
+        let Ok(given_back) = given_back else {
+            tracing::info!("proxy port {port} handed over");
+            break;
+        };
+        // The handover was undone, see `HandingOver`, unless the tunnel changed since
+        if !matches!(
+            *control.borrow_and_update(),
+            ProxyControlMessage::Open { .. }
+        ) {
+            break;
+        }
+        tracing::info!("proxy port {port} accepting again after an undone handover");
+        listener = Arc::new(given_back);
+        loops = accept(&listener, &control);
+    }
+    // The workers stop after the connections that were accepted before
+    if let Some(queue) = &config.queue {
+        queue.close();
+    }
+}
 
-                    tokio::spawn(transfer);
+/// Accepts connections from the listener of a tunnel and hands them on, until the listener
+/// fails.
+async fn accept_loop(
+    listener: Arc<TcpListener>,
+    mut control: Receiver<ProxyControlMessage>,
+    config: Arc<TunnelConfig>,
+    connections: Arc<Connections>,
+    rejections: Arc<Rejections>,
+    permits: Option<Arc<Semaphore>>,
+) {
+    // Accept errors in a row that aren't about a single connection, and how long to wait
+    // before accepting again because of them
+    let mut accept_errors = 0;
+    let mut pause = time::Duration::ZERO;
+    loop {
+        if !pause.is_zero() {
+            tokio::time::sleep(pause).await;
+        }
+        match listener.accept().await {
+            Ok((inbound, client)) => {
+                accept_errors = 0;
+                pause = time::Duration::ZERO;
+                connections.accepted(client);
+                // The connection only reacts to changes made after it was accepted
+                control.borrow_and_update();
+                let Some(queue) = &config.queue else {
+                    tokio::spawn(handle_connection(
+                        (inbound, client),
+                        control.clone(),
+                        config.clone(),
+                        connections.clone(),
+                        rejections.clone(),
+                        permits.clone(),
+                    ));
+                    continue;
+                };
+                if let Some((_, client)) = queue.push((inbound, client)) {
+                    let detail = "the connection queue of the tunnel is full";
+                    if config.quiet {
+                        rejections.count(RejectReason::QueueFull);
+                    } else {
+                        rejections.reject(Some(client), RejectReason::QueueFull, &detail);
+                    }
+                    connections.closed(CloseReason::Rejected);
                 }
             }
-            _ = control.changed() => {
-                match *control.borrow() {
-                    ProxyControlMessage::Open { destination } => {
-                        tracing::info!("destination for proxy port {} changed to {}", listener.local_addr().unwrap(), destination);
-                    },
-                    ProxyControlMessage::Close => {
-                        tracing::info!("destination for proxy port {} closed", listener.local_addr().unwrap());
+            Err(err) => {
+                if config.quiet {
+                    rejections.count(RejectReason::AcceptFailed);
+                } else {
+                    let detail = format!("{err} ({:?})", err.kind());
+                    rejections.reject(None, RejectReason::AcceptFailed, &detail);
+                }
+                match accept_backoff(&err, &mut accept_errors) {
+                    Some(backoff) => pause = backoff,
+                    None => {
+                        let port = listener.local_addr().map(|addr| addr.port());
+                        tracing::error!(
+                            ?port,
+                            "listener failed, no longer accepting connections: {err}"
+                        );
+                        connections.listener_failed(format!("accepting connections failed: {err}"));
                         return;
-                    },
+                    }
                 }
             }
         }
     }
 }
 
+/// Handles the queued connections of a tunnel one at a time, until the queue is closed.
+async fn work_queue(
+    queue: Arc<ConnectionQueue>,
+    control: Receiver<ProxyControlMessage>,
+    config: Arc<TunnelConfig>,
+    connections: Arc<Connections>,
+    rejections: Arc<Rejections>,
+    permits: Option<Arc<Semaphore>>,
+) {
+    while let Some(pending) = queue.pop().await {
+        handle_connection(
+            pending,
+            control.clone(),
+            config.clone(),
+            connections.clone(),
+            rejections.clone(),
+            permits.clone(),
+        )
+        .await;
+    }
+}
+
+/// Proxies an accepted connection and counts how it ended.
+async fn handle_connection(
+    (inbound, client): Pending,
+    control: Receiver<ProxyControlMessage>,
+    config: Arc<TunnelConfig>,
+    connections: Arc<Connections>,
+    rejections: Arc<Rejections>,
+    permits: Option<Arc<Semaphore>>,
+) {
+    let logged = config.logs(client);
+    // Connections that aren't logged don't leave a span either
+    let span = if logged {
+        tracing::info_span!("connection", tunnel = %config.id)
+    } else {
+        tracing::Span::none()
+    };
+    #[cfg(feature = "otel")]
+    otel::link(&span, &config.created_by);
+    async move {
+        if logged {
+            tracing::info!(%client, "connection accepted");
+        }
+        let reason = transfer(
+            inbound,
+            client,
+            control,
+            config,
+            connections.clone(),
+            rejections,
+            permits,
+        )
+        .await;
+        // Sampling only applies to the logs, every connection is counted
+        connections.closed(reason);
+        if logged {
+            tracing::info!(%client, %reason, "connection closed");
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Longest wait before accepting again after an accept error
+const MAX_ACCEPT_BACKOFF: time::Duration = time::Duration::from_secs(1);
+
+/// How long to wait before accepting again after `err`, or `None` when the listener is broken.
+/// `consecutive` counts the errors in a row that may be lasting, which are waited out however
+/// long they last.
+fn accept_backoff(err: &io::Error, consecutive: &mut u32) -> Option<time::Duration> {
+    match err.kind() {
+        // The socket is no longer a listener
+        io::ErrorKind::InvalidInput => None,
+        // Only the connection failed, like one that the client reset before it was accepted
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted => Some(time::Duration::ZERO),
+        // The socket was closed, or isn't a socket at all
+        #[cfg(unix)]
+        _ if matches!(err.raw_os_error(), Some(libc::EBADF | libc::ENOTSOCK)) => None,
+        // Like running out of file descriptors or memory, which accepting again right away would
+        // spin on
+        _ => {
+            *consecutive = consecutive.saturating_add(1);
+            let backoff = time::Duration::from_millis(5) * 2u32.pow((*consecutive - 1).min(8));
+            Some(backoff.min(MAX_ACCEPT_BACKOFF))
+        }
+    }
+}
+
 async fn transfer(
     mut inbound: TcpStream,
+    client: SocketAddr,
     mut control: Receiver<ProxyControlMessage>,
-) -> anyhow::Result<()> {
-    loop {
-        let current_destination =
-            if let ProxyControlMessage::Open { destination } = *control.borrow() {
-                Some(destination)
-            } else {
-                break Ok(());
+    config: Arc<TunnelConfig>,
+    connections: Arc<Connections>,
+    rejections: Arc<Rejections>,
+    permits: Option<Arc<Semaphore>>,
+) -> CloseReason {
+    let reject = |reason, detail: &dyn fmt::Display| {
+        if config.quiet {
+            rejections.count(reason);
+        } else {
+            rejections.reject(Some(client), reason, detail);
+        }
+    };
+    // Held until the transfer ends, whichever way it ends
+    let _permit = match permits.map(Semaphore::try_acquire_owned) {
+        Some(Err(_)) => {
+            reject(
+                RejectReason::ConnectionLimit,
+                &"the proxy has reached its maximum of connections",
+            );
+            if let Some(overflow_response) = &config.overflow_response {
+                // A client that doesn't read it can't keep the connection open
+                let _ = tokio::time::timeout(config.connect_timeout, async {
+                    inbound.write_all(overflow_response).await?;
+                    inbound.shutdown().await
+                })
+                .await;
+            }
+            return CloseReason::Rejected;
+        }
+        permit => permit,
+    };
+    if let Err(err) = config.configure(&inbound) {
+        reject(RejectReason::SocketOptions, &err);
+        return CloseReason::Rejected;
+    }
+    let logged = config.logs(client);
+    // Unregisters the connection when the transfer ends, whichever way it ends
+    let connection = connections.register(client);
+
+    // The start of the connection that was read to find the server name, which is sent ahead
+    // of the rest
+    let mut prefix = Vec::new();
+    // Picked for this connection by its server name or route key, which modifying the
+    // destination of the tunnel doesn't change
+    let mut routed_destination = None;
+    if !config.sni_map.is_empty() {
+        let read = sni::read_client_hello(&mut inbound, &mut prefix);
+        match tokio::time::timeout(config.connect_timeout, read).await {
+            Ok(Ok(server_name)) => {
+                routed_destination = server_name
+                    .and_then(|name| config.sni_map.get(&name))
+                    .map(|addr| Destination::Tcp(*addr));
+            }
+            Ok(Err(err)) => {
+                if logged {
+                    tracing::debug!(%client, "reading the ClientHello failed: {err}");
+                }
+                return CloseReason::Error;
+            }
+            // Clients of protocols where the server speaks first keep waiting
+            Err(_) => {}
+        }
+    }
+    if config.is_router() {
+        match tokio::time::timeout(config.connect_timeout, read_route_key(&mut inbound)).await {
+            Ok(Ok(key)) => match config.routes.get(&key) {
+                Some(addr) => routed_destination = Some(Destination::Tcp(*addr)),
+                None => {
+                    reject(
+                        RejectReason::UnknownRoute,
+                        &format!("unknown route {key:?}"),
+                    );
+                    return CloseReason::Rejected;
+                }
+            },
+            Ok(Err(err)) => {
+                reject(RejectReason::UnknownRoute, &format!("no route key: {err}"));
+                return CloseReason::Rejected;
+            }
+            Err(_) => {
+                let error = format!("no route key within {:?}", config.connect_timeout);
+                reject(RejectReason::UnknownRoute, &error);
+                return CloseReason::Rejected;
+            }
+        }
+    }
+
+    let mirror = config.mirror_to.map(|to| {
+        let gzip_level = config.mirror_gzip_level;
+        mirror::spawn(
+            to,
+            gzip_level,
+            config.connect_timeout,
+            connections.clone(),
+            logged,
+        )
+    });
+    let mut fanout: Vec<_> = config
+        .fanout_destinations
+        .iter()
+        .map(|to| fanout::spawn(*to, config.connect_timeout, connections.clone(), logged))
+        .collect();
+    // Counts from here, so switching to a new destination doesn't extend it
+    let lifetime = async {
+        match config.max_lifetime {
+            Some(max_lifetime) => tokio::time::sleep(max_lifetime).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(lifetime);
+
+    'connection: loop {
+        let (current_destination, source_address) = match *control.borrow() {
+            ProxyControlMessage::Open {
+                ref destination,
+                source_address,
+                ..
+            }
+            | ProxyControlMessage::Drain {
+                ref destination,
+                source_address,
+                ..
+            } => (destination.clone(), source_address),
+            ProxyControlMessage::Close => break CloseReason::TunnelClosed,
+        };
+        let current_destination = routed_destination.clone().unwrap_or(current_destination);
+        // Pooled connections are only handed on to clients of the same destination and source
+        let pool = config
+            .pool
+            .as_ref()
+            .map(|pool| (pool, (current_destination.clone(), source_address)));
+        let reused = pool.as_ref().and_then(|(pool, key)| pool.take(key));
+        let dialed = reused.is_none();
+        // Routed connections go where their client asked or nowhere
+        let failover = config
+            .failover
+            .as_ref()
+            .filter(|_| routed_destination.is_none());
+        // Failing over may take the connect timeout of every destination
+        let connect_timeout =
+            config.connect_timeout * failover.map_or(1, |failover| failover.attempts());
+
+        let connect = tokio::time::timeout(connect_timeout, async {
+            let (destination, outbound) = match (reused, failover) {
+                (Some(outbound), _) => (current_destination.clone(), outbound),
+                (None, Some(failover)) => {
+                    failover
+                        .connect(&current_destination, source_address, &config)
+                        .await?
+                }
+                (None, None) => (
+                    current_destination.clone(),
+                    connect(&current_destination, source_address, &config).await?,
+                ),
             };
-        let mut outbound = TcpStream::connect(current_destination.unwrap()).await?;
+            let response = match &config.response_destination {
+                Some(response_destination) => Some(
+                    connect(response_destination, source_address, &config)
+                        .await
+                        .map_err(|err| {
+                            io::Error::new(
+                                err.kind(),
+                                format!("{err} (response destination {response_destination})"),
+                            )
+                        })?,
+                ),
+                None => None,
+            };
+            io::Result::Ok((destination, outbound, response))
+        });
+        tokio::pin!(connect);
+        let dialing = time::Instant::now();
+        let (current_destination, outbound, response) = loop {
+            tokio::select! {
+                result = &mut connect => {
+                    match result {
+                        Ok(Ok(outbound)) => break outbound,
+                        Ok(Err(err)) => {
+                            let error = format!("connecting to {current_destination} failed: {err}");
+                            reject(RejectReason::ConnectFailed, &error);
+                            connections.failed(error);
+                            return CloseReason::Rejected;
+                        }
+                        Err(_) => {
+                            let error = format!(
+                                "connecting to {current_destination} timed out after \
+                                 {connect_timeout:?}"
+                            );
+                            reject(RejectReason::ConnectTimeout, &error);
+                            connections.failed(error);
+                            return CloseReason::Rejected;
+                        }
+                    }
+                }
+                changed = control.changed() => {
+                    if changed.is_err() {
+                        return CloseReason::TunnelClosed;
+                    }
+                    match *control.borrow() {
+                        // Give up on the old destination and connect to the new one instead
+                        ProxyControlMessage::Open { reconnect: true, .. }
+                            if routed_destination.is_none() => continue 'connection,
+                        ProxyControlMessage::Open { .. } | ProxyControlMessage::Drain { .. } => {
+                            continue
+                        }
+                        ProxyControlMessage::Close => return CloseReason::TunnelClosed,
+                    }
+                }
+            }
+        };
 
-        let (mut ri, mut wi) = inbound.split();
-        let (mut ro, mut wo) = outbound.split();
+        if dialed {
+            connections.connect_took(dialing.elapsed());
+        }
+        connections.connected();
+        connection.set_destination(&current_destination);
+        let (ri, mut wi) = inbound.split();
+        let (ro, mut wo) = io::split(outbound);
+        // With a response destination the client reads from there, and the answers of the
+        // destination go nowhere
+        let (ro, mut discarded, mut response_writer) = match response {
+            Some(response) => {
+                let (response_reader, response_writer) = io::split(response);
+                (response_reader, Some(ro), Some(response_writer))
+            }
+            None => (ro, None, None),
+        };
+        let ri = std::io::Cursor::new(std::mem::take(&mut prefix)).chain(ri);
+        let ri = Mirrored::new(ri, mirror.clone(), connections.mirror_stats());
+        let mut ri = BufReader::with_capacity(config.buffer_size, connection.track_sent(ri));
+        let mut ro = BufReader::with_capacity(config.buffer_size, connection.track_received(ro));
 
+        let bounded = config.max_in_flight_bytes.is_some();
+        let pooled = pool.is_some();
+        // Whether everything the destination sent has been passed on to the client
+        let answered = &AtomicBool::new(true);
+        // Whether the connection to the destination can go to the next client, see `pool` of
+        // `Command::Create`
+        let reusable = &AtomicBool::new(true);
+        // A side that resets its connection is done with it, so the shutdown is still passed on
+        // to the other side
         let client_to_server = async {
-            io::copy(&mut ri, &mut wo).await?;
-            wo.shutdown().await
+            if !fanout.is_empty() {
+                note_disconnect(
+                    fanout::copy_buf(&mut ri, &mut wo, &mut fanout).await,
+                    reusable,
+                )?;
+                // Passes the end of the data on to the fan-out destinations
+                fanout.clear();
+            } else if bounded {
+                note_disconnect(copy_bounded(&mut ri, &mut wo).await, reusable)?;
+            } else {
+                note_disconnect(io::copy_buf(&mut ri, &mut wo).await, reusable)?;
+            }
+            if let Some(response_writer) = &mut response_writer {
+                note_disconnect(response_writer.shutdown().await, reusable)?;
+            }
+            if pooled {
+                // The connection to the destination stays open for the next client
+                return note_disconnect(wo.flush().await, reusable);
+            }
+            note_disconnect(wo.shutdown().await, reusable)
         };
 
         let server_to_client = async {
-            io::copy(&mut ro, &mut wi).await?;
-            wi.shutdown().await
+            if pooled {
+                note_disconnect(
+                    copy_tracking_idle(&mut ro, &mut wi, answered).await,
+                    reusable,
+                )?;
+            } else if bounded {
+                note_disconnect(copy_bounded(&mut ro, &mut wi).await, reusable)?;
+            } else {
+                note_disconnect(io::copy_buf(&mut ro, &mut wi).await, reusable)?;
+            }
+            note_disconnect(wi.shutdown().await, reusable)
         };
 
-        // Select between the copy tasks and watch channel
-        tokio::select! {
-            // Join the two copy streams and wait for the connection to clone
-            result = async move { tokio::join!(client_to_server, server_to_client) } => {
-                match result {
-                    (Ok(_), Ok(_)) => {
-                        break Ok(());
+        // Doesn't decide when the connection is done, so it never finishes
+        let discard = async {
+            if let Some(discarded) = &mut discarded {
+                let _ = io::copy(discarded, &mut io::sink()).await;
+            }
+            std::future::pending::<()>().await
+        };
+
+        // Join the two copy streams and wait for the connection to close
+        let received = || connection.bytes_received();
+        let copy = async move {
+            let copying = async {
+                if !pooled {
+                    return tokio::join!(client_to_server, server_to_client);
+                }
+                // The client is done once it stops sending, see `pool` of `Command::Create`
+                tokio::pin!(client_to_server, server_to_client);
+                tokio::select! {
+                    result = &mut client_to_server => {
+                        // The answer to what the client sent last may still be on its way
+                        let stopped = received();
+                        let mut before = stopped;
+                        let result = loop {
+                            match tokio::time::timeout(POOL_SETTLE_TIME, &mut server_to_client).await {
+                                Ok(answer) => break (result, answer),
+                                Err(_) if answered.load(Ordering::Relaxed) && received() == before => {
+                                    break (result, Ok(()));
+                                }
+                                Err(_) => before = received(),
+                            }
+                        };
+                        // There is no telling whether that answer is complete
+                        if received() != stopped {
+                            reusable.store(false, Ordering::Relaxed);
+                        }
+                        result
                     }
-                    (r1, r2) => {
-                        if r1.is_err() {
-                            tracing::error!("error closing client->server of {:?}: {:?}", inbound, &r1);
+                    result = &mut server_to_client => (client_to_server.await, result),
+                }
+            };
+            tokio::select! {
+                result = copying => result,
+                () = discard => unreachable!(),
+            }
+        };
+        let result = {
+            tokio::pin!(copy);
+            let mut sample = tokio::time::interval_at(
+                tokio::time::Instant::now() + THROUGHPUT_SAMPLE_INTERVAL,
+                THROUGHPUT_SAMPLE_INTERVAL,
+            );
+
+            // Select between the copy tasks and watch channel. A drain keeps the copy running,
+            // so the select is repeated until the connection either finishes or has to switch.
+            loop {
+                tokio::select! {
+                    result = &mut copy => break result,
+                    _ = sample.tick() => connection.sample_throughput(),
+                    () = &mut lifetime => return CloseReason::MaxLifetime,
+                    changed = control.changed() => {
+                        if changed.is_err() {
+                            return CloseReason::TunnelClosed;
                         }
-                        if r2.is_err() {
-                            tracing::error!("error closing server->client of {:?}: {:?}", inbound, &r2);
+                        match *control.borrow() {
+                            ProxyControlMessage::Open { ref destination, reconnect: true, .. }
+                                if routed_destination.is_none() => {
+                                if logged {
+                                    tracing::debug!(%client, "switching to new destination {destination}");
+                                }
+                                // Disconnect the current outbound connection and restart the loop
+                                continue 'connection;
+                            },
+                            ProxyControlMessage::Open { .. } | ProxyControlMessage::Drain { .. } => {
+                                // Let the connection finish naturally
+                                continue;
+                            },
+                            ProxyControlMessage::Close => {
+                                return CloseReason::TunnelClosed;
+                            },
                         }
-                        r1?;
-                        r2?;
-                    },
+                    }
                 }
             }
-            _ = control.changed() => {
-                match *control.borrow() {
-                    ProxyControlMessage::Open { destination } => {
-                        eprintln!("Switching to new destination: {destination}");
-                        // Disconnect the current outbound connection and restart the loop
-                        drop(outbound);
-                        continue;
-                    },
-                    ProxyControlMessage::Close => {
-                        break Ok(());
-                    },
-                }
-
+        };
+        if let (Some((pool, key)), (Ok(()), Ok(()))) = (pool, &result) {
+            // Answers that the client didn't get would go to the next one
+            if reusable.load(Ordering::Relaxed) && ro.buffer().is_empty() {
+                pool.put(key, ro.into_inner().into_inner().unsplit(wo));
             }
         }
+        return match result {
+            (Ok(_), Ok(_)) => CloseReason::Completed,
+            (r1, r2) => {
+                if let Err(err) = r1 {
+                    if logged {
+                        tracing::warn!(%client, "copying client->server failed: {err}");
+                    }
+                    connections.failed(format!("copying client->server failed: {err}"));
+                }
+                if let Err(err) = r2 {
+                    if logged {
+                        tracing::warn!(%client, "copying server->client failed: {err}");
+                    }
+                    connections.failed(format!("copying server->client failed: {err}"));
+                }
+                CloseReason::Error
+            }
+        };
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::{
-        net::{IpAddr, Ipv4Addr},
+        collections::{BTreeMap, HashMap},
+        net::{IpAddr, Ipv4Addr, SocketAddr},
         time,
     };
 
-    use crate::{Command, ProxyCommand};
+    use crate::{
+        accept_backoff, execute_command, proxy, validate_label, validate_source_address, Command,
+        Destination, GlobalState, Inconsistency, Nonces, Protocol, ProxyCommand,
+        ProxyControlMessage, TunnelConfig, VerifyError, DEFAULT_BACKLOG, DEFAULT_BUFFER_SIZE,
+        DEFAULT_CONNECT_TIMEOUT, DEFAULT_QUEUE_WORKERS, MAX_ACCEPT_BACKOFF, MAX_COMMAND_AGE,
+        MAX_COMMAND_FUTURE,
+    };
     use p384::{
         ecdsa::{signature::Signer, Signature, SigningKey, VerifyingKey},
         elliptic_curve::rand_core::OsRng,
     };
+    use std::sync::Arc;
+    use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::{mpsc, oneshot, watch};
     use uuid::uuid;
 
+    /// The config of a tunnel created without any options
+    fn tunnel_config() -> TunnelConfig {
+        TunnelConfig {
+            id: uuid::Uuid::nil(),
+            #[cfg(feature = "otel")]
+            created_by: opentelemetry::trace::SpanContext::empty_context(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            max_in_flight_bytes: None,
+            tcp_nodelay: false,
+            tcp_keepalive: None,
+            dscp: None,
+            max_lifetime: None,
+            log_sample_rate: 1.0,
+            quiet: false,
+            reuse_address: true,
+            reuse_port: false,
+            backlog: DEFAULT_BACKLOG,
+            accept_loops: 1,
+            sni_map: BTreeMap::new(),
+            response_destination: None,
+            mirror_to: None,
+            mirror_gzip_level: None,
+            fanout_destinations: Vec::new(),
+            failover: None,
+            overflow_response: None,
+            routes: BTreeMap::new(),
+            queue: None,
+            queue_workers: DEFAULT_QUEUE_WORKERS,
+            pool: None,
+        }
+    }
+
     #[test]
     fn serialize_proxy_command_create() {
         let key = SigningKey::from_slice(&[1; 48]).unwrap();
@@ -355,11 +4489,40 @@ mod tests {
         let proxy_command = ProxyCommand {
             command: Command::Create {
                 incoming_port: 5555,
-                destination_port: 6666,
-                destination_ip: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                destination_port: Some(6666),
+                destination_ip: Some(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))),
+                destination_uds: None,
                 id: uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"),
+                connect_timeout_ms: None,
+                source_address: None,
+                ttl_secs: None,
+                buffer_size: None,
+                max_in_flight_bytes: None,
+                tcp_nodelay: false,
+                tcp_keepalive_secs: None,
+                dscp: None,
+                max_lifetime_secs: None,
+                reuse_address: None,
+                reuse_port: None,
+                log_sample_rate: None,
+                quiet: false,
+                label: None,
+                backlog: None,
+                accept_loops: None,
+                sni_map: BTreeMap::new(),
+                response_destination: None,
+                mirror_to: None,
+                mirror_gzip_level: None,
+                fanout_destinations: Vec::new(),
+                failover_destinations: Vec::new(),
+                overflow_response: None,
+                queue_len: None,
+                overflow_policy: None,
+                queue_workers: None,
+                pool: false,
             },
             timestamp: Some(8888),
+            nonce: None,
             signature: Some(signature),
         };
         let expected = "{\"create\":{\"incoming_port\":5555,\"destination_port\":6666,\"\
@@ -381,8 +4544,10 @@ mod tests {
         let proxy_command = ProxyCommand {
             command: Command::Delete {
                 id: uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"),
+                drain: false,
             },
             timestamp: Some(987654),
+            nonce: None,
             signature: Some(signature),
         };
         let expected = "{\"delete\":{\"id\":\"67e55044-10b1-426f-9247-bb680e5fe0c8\"},\
@@ -396,6 +4561,27 @@ mod tests {
         assert_eq!(serde_json::to_string(&proxy_command).unwrap(), expected);
     }
 
+    #[test]
+    fn redacted_proxy_command() {
+        let key = SigningKey::from_slice(&[1; 48]).unwrap();
+        let signature = key.sign(&[]); // Not a valid signature
+        let proxy_command = ProxyCommand {
+            command: Command::Delete {
+                id: uuid!("67e55044-10b1-426f-9247-bb680e5fe0c8"),
+                drain: false,
+            },
+            timestamp: Some(987654),
+            nonce: None,
+            signature: Some(signature),
+        };
+        let expected = "ProxyCommand { command: \"delete\", \
+                        id: 67e55044-10b1-426f-9247-bb680e5fe0c8, \
+                        timestamp: Some(987654), \
+                        signature: Some(\"5C912C4B...\") }";
+
+        assert_eq!(format!("{:?}", proxy_command.redacted()), expected);
+    }
+
     #[test]
     fn verify_signature() {
         let subscriber = tracing_subscriber::FmtSubscriber::builder()
@@ -405,9 +4591,37 @@ mod tests {
 
         let command = Command::Create {
             incoming_port: 4567,
-            destination_port: 7654,
-            destination_ip: IpAddr::V4(Ipv4Addr::new(123, 23, 76, 21)),
+            destination_port: Some(7654),
+            destination_ip: Some(IpAddr::V4(Ipv4Addr::new(123, 23, 76, 21))),
+            destination_uds: None,
             id: uuid::Uuid::new_v4(),
+            connect_timeout_ms: None,
+            source_address: None,
+            ttl_secs: None,
+            buffer_size: None,
+            max_in_flight_bytes: None,
+            tcp_nodelay: false,
+            tcp_keepalive_secs: None,
+            dscp: None,
+            max_lifetime_secs: None,
+            reuse_address: None,
+            reuse_port: None,
+            log_sample_rate: None,
+            quiet: false,
+            label: None,
+            backlog: None,
+            accept_loops: None,
+            sni_map: BTreeMap::new(),
+            response_destination: None,
+            mirror_to: None,
+            mirror_gzip_level: None,
+            fanout_destinations: Vec::new(),
+            failover_destinations: Vec::new(),
+            overflow_response: None,
+            queue_len: None,
+            overflow_policy: None,
+            queue_workers: None,
+            pool: false,
         };
 
         // Create signed message
@@ -416,19 +4630,554 @@ mod tests {
             .duration_since(time::UNIX_EPOCH)
             .unwrap()
             .as_secs();
+        let nonce = [0xab; 16];
         let mut message = serde_json::to_string(&command).unwrap();
         message.push_str(&timestamp.to_string());
+        message.push_str("abababababababababababababababab");
         let signature: Signature = signing_key.sign(message.as_bytes());
         let bytes = signature.to_bytes();
         assert_eq!(bytes.len(), 96);
         let proxy_command = ProxyCommand {
             command,
             timestamp: Some(timestamp),
+            nonce: Some(nonce),
             signature: Some(signature),
         };
 
         // Verify signed message
-        let verifying_key = VerifyingKey::from(&signing_key);
-        assert!(proxy_command.verify_signature(&Some(verifying_key)));
+        let other_key = VerifyingKey::from(&SigningKey::random(&mut OsRng));
+        let mut verifying_keys = HashMap::from([("other".to_string(), other_key)]);
+        let nonces = Nonces::default();
+        assert_eq!(
+            proxy_command.verify_signature(&verifying_keys, &nonces, true),
+            Err(VerifyError::SignatureMismatch)
+        );
+        verifying_keys.insert("signer".to_string(), VerifyingKey::from(&signing_key));
+        assert_eq!(
+            proxy_command.verify_signature(&verifying_keys, &nonces, true),
+            Ok(Some("signer"))
+        );
+
+        // The same command can't be used twice
+        assert_eq!(
+            proxy_command.verify_signature(&verifying_keys, &nonces, true),
+            Err(VerifyError::ReplayedNonce)
+        );
+    }
+
+    #[test]
+    fn sign_proxy_command() {
+        let signing_key = SigningKey::random(&mut OsRng);
+        let verifying_keys =
+            HashMap::from([("signer".to_string(), VerifyingKey::from(&signing_key))]);
+        let destination = SocketAddr::from((Ipv4Addr::LOCALHOST, 8080));
+        let command = ProxyCommand::create(uuid::Uuid::new_v4(), 5555, destination);
+        assert_eq!(
+            command.verify_signature(&verifying_keys, &Nonces::default(), true),
+            Err(VerifyError::MissingSignature)
+        );
+        let command = command.sign(&signing_key);
+        assert_eq!(
+            command.verify_signature(&verifying_keys, &Nonces::default(), true),
+            Ok(Some("signer"))
+        );
+    }
+
+    #[test]
+    fn verify_signature_time_window() {
+        let signing_key = SigningKey::random(&mut OsRng);
+        let verifying_keys =
+            HashMap::from([("signer".to_string(), VerifyingKey::from(&signing_key))]);
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let verify_at = |timestamp: u64| {
+            let destination = SocketAddr::from((Ipv4Addr::LOCALHOST, 8080));
+            let mut command = ProxyCommand::create(uuid::Uuid::new_v4(), 5555, destination);
+            let nonce: [u8; 16] = rand::random();
+            command.signature =
+                Some(signing_key.sign(command.signed_message(timestamp, &nonce).as_bytes()));
+            command.timestamp = Some(timestamp);
+            command.nonce = Some(nonce);
+            command.verify_signature(&verifying_keys, &Nonces::default(), true)
+        };
+
+        assert_eq!(verify_at(now + 5), Ok(Some("signer")));
+        assert_eq!(verify_at(now + 25), Ok(Some("signer")));
+        assert!(matches!(
+            verify_at(now + 35),
+            Err(VerifyError::FromTheFuture { .. })
+        ));
+        assert_eq!(verify_at(now - 30), Ok(Some("signer")));
+        assert!(matches!(
+            verify_at(now - 90),
+            Err(VerifyError::Stale { .. })
+        ));
+    }
+
+    #[test]
+    fn verify_signature_edge_cases() {
+        let signing_key = SigningKey::random(&mut OsRng);
+        let verifying_keys =
+            HashMap::from([("signer".to_string(), VerifyingKey::from(&signing_key))]);
+        let other_keys = HashMap::from([(
+            "other".to_string(),
+            VerifyingKey::from(&SigningKey::random(&mut OsRng)),
+        )]);
+        let now = time::Duration::from_secs(1_700_000_000);
+        let signed_at = |timestamp: u64| {
+            let destination = SocketAddr::from((Ipv4Addr::LOCALHOST, 8080));
+            let mut command = ProxyCommand::create(uuid::Uuid::new_v4(), 5555, destination);
+            let nonce: [u8; 16] = rand::random();
+            command.signature =
+                Some(signing_key.sign(command.signed_message(timestamp, &nonce).as_bytes()));
+            command.timestamp = Some(timestamp);
+            command.nonce = Some(nonce);
+            command
+        };
+        let signed = || signed_at(now.as_secs());
+
+        let no_timestamp = ProxyCommand {
+            timestamp: None,
+            ..signed()
+        };
+        let no_signature = ProxyCommand {
+            signature: None,
+            ..signed()
+        };
+        let no_nonce = ProxyCommand {
+            nonce: None,
+            ..signed()
+        };
+        let mut tampered_command = signed();
+        if let Command::Create { incoming_port, .. } = &mut tampered_command.command {
+            *incoming_port = 5556;
+        }
+        let mut tampered_timestamp = signed();
+        tampered_timestamp.timestamp = Some(now.as_secs() - 1);
+        let mut tampered_nonce = signed();
+        tampered_nonce.nonce = Some([0; 16]);
+        let now_secs = now.as_secs();
+        let max_age = MAX_COMMAND_AGE.as_secs();
+        let max_future = MAX_COMMAND_FUTURE.as_secs();
+        let cases = [
+            ("valid", signed(), Ok(Some("signer"))),
+            (
+                "no timestamp",
+                no_timestamp,
+                Err(VerifyError::MissingTimestamp),
+            ),
+            (
+                "no signature",
+                no_signature,
+                Err(VerifyError::MissingSignature),
+            ),
+            ("no nonce", no_nonce, Err(VerifyError::MissingNonce)),
+            (
+                "tampered command",
+                tampered_command,
+                Err(VerifyError::SignatureMismatch),
+            ),
+            (
+                "tampered timestamp",
+                tampered_timestamp,
+                Err(VerifyError::SignatureMismatch),
+            ),
+            (
+                "tampered nonce",
+                tampered_nonce,
+                Err(VerifyError::SignatureMismatch),
+            ),
+            ("oldest", signed_at(now_secs - max_age), Ok(Some("signer"))),
+            (
+                "too old",
+                signed_at(now_secs - max_age - 1),
+                Err(VerifyError::Stale { now: now_secs }),
+            ),
+            (
+                "epoch",
+                signed_at(0),
+                Err(VerifyError::Stale { now: now_secs }),
+            ),
+            (
+                "furthest ahead",
+                signed_at(now_secs + max_future),
+                Ok(Some("signer")),
+            ),
+            (
+                "too far ahead",
+                signed_at(now_secs + max_future + 1),
+                Err(VerifyError::FromTheFuture { now: now_secs }),
+            ),
+            (
+                "end of time",
+                signed_at(u64::MAX),
+                Err(VerifyError::FromTheFuture { now: now_secs }),
+            ),
+        ];
+        let no_keys = HashMap::new();
+        for (case, command, expected) in cases {
+            let verified =
+                command.verify_signature_at(&verifying_keys, &Nonces::default(), true, now);
+            assert_eq!(verified, expected, "{case}");
+            // Only the configured keys count, and the signature is checked before the time
+            let verified = command.verify_signature_at(&other_keys, &Nonces::default(), true, now);
+            let expected = match expected {
+                Err(
+                    err @ (VerifyError::MissingSignature
+                    | VerifyError::MissingTimestamp
+                    | VerifyError::MissingNonce),
+                ) => Err(err),
+                _ => Err(VerifyError::SignatureMismatch),
+            };
+            assert_eq!(verified, expected, "{case} with another key");
+            // Without keys there is nothing to check
+            let verified = command.verify_signature_at(&no_keys, &Nonces::default(), true, now);
+            assert_eq!(verified, Ok(None), "{case} without keys");
+        }
+
+        // Only verifying doesn't use up the nonce
+        let command = signed();
+        let nonces = Nonces::default();
+        for _ in 0..2 {
+            assert_eq!(
+                command.verify_signature_at(&verifying_keys, &nonces, false, now),
+                Ok(Some("signer"))
+            );
+        }
+        assert_eq!(
+            command.verify_signature_at(&verifying_keys, &nonces, true, now),
+            Ok(Some("signer"))
+        );
+        assert_eq!(
+            command.verify_signature_at(&verifying_keys, &nonces, false, now),
+            Err(VerifyError::ReplayedNonce)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn back_off_after_accept_errors() {
+        let mut consecutive = 0;
+        let aborted = io::Error::from(io::ErrorKind::ConnectionAborted);
+        let not_listening = io::Error::from(io::ErrorKind::InvalidInput);
+
+        assert_eq!(
+            accept_backoff(&aborted, &mut consecutive),
+            Some(time::Duration::ZERO)
+        );
+        assert_eq!(consecutive, 0);
+        // Running out of something never gives up on the listener, however long it lasts
+        for exhausted in [libc::EMFILE, libc::ENFILE, libc::ENOBUFS, libc::ENOMEM] {
+            let exhausted = io::Error::from_raw_os_error(exhausted);
+            let mut consecutive = 0;
+            let mut previous = time::Duration::ZERO;
+            for _ in 0..10_000 {
+                let backoff = accept_backoff(&exhausted, &mut consecutive).unwrap();
+                assert!(backoff >= previous && backoff <= MAX_ACCEPT_BACKOFF);
+                previous = backoff;
+            }
+            assert_eq!(previous, MAX_ACCEPT_BACKOFF);
+        }
+        assert_eq!(accept_backoff(&not_listening, &mut 0), None);
+        for broken in [libc::EBADF, libc::ENOTSOCK] {
+            let broken = io::Error::from_raw_os_error(broken);
+            assert_eq!(accept_backoff(&broken, &mut 0), None);
+        }
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn link_connections_to_the_creating_command() {
+        use opentelemetry::trace::TracerProvider as _;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder().build();
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("test"));
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(!crate::otel::current_span_context().is_valid());
+            let command = tracing::info_span!("command");
+            let created_by = command.in_scope(crate::otel::current_span_context);
+            assert!(created_by.is_valid());
+            let connection = tracing::info_span!("connection");
+            crate::otel::link(&connection, &created_by);
+            let linked = connection.in_scope(crate::otel::current_span_context);
+            // A link, not a parent: the connection starts a trace of its own
+            assert_ne!(linked.trace_id(), created_by.trace_id());
+        });
+    }
+
+    #[test]
+    fn sample_connection_logs() {
+        let config = |log_sample_rate| TunnelConfig {
+            log_sample_rate,
+            ..tunnel_config()
+        };
+        let clients: Vec<SocketAddr> = (1..=1000)
+            .map(|port| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), port))
+            .collect();
+        let logged = |config: &TunnelConfig| {
+            clients
+                .iter()
+                .filter(|client| config.logs(**client))
+                .count()
+        };
+
+        assert_eq!(logged(&config(1.0)), 1000);
+        assert_eq!(logged(&config(0.0)), 0);
+        let quiet = TunnelConfig {
+            quiet: true,
+            ..config(1.0)
+        };
+        assert_eq!(logged(&quiet), 0);
+        let sampled = logged(&config(0.25));
+        assert!((150..350).contains(&sampled), "{sampled}");
+        // The same client always gets the same decision
+        assert_eq!(logged(&config(0.25)), sampled);
+    }
+
+    #[test]
+    fn label_must_be_short_and_printable() {
+        assert!(validate_label("database primary").is_ok());
+        assert!(validate_label("Überwachung 🚀").is_ok());
+        assert!(validate_label(&"x".repeat(crate::MAX_LABEL_LEN + 1)).is_err());
+        assert!(validate_label("line\nbreak").is_err());
+        assert!(validate_label("\u{1b}[31mred").is_err());
+    }
+
+    #[test]
+    fn source_address_must_be_local() {
+        let destination = Destination::Tcp("203.0.113.7:80".parse().unwrap());
+
+        assert!(validate_source_address(IpAddr::V4(Ipv4Addr::LOCALHOST), &destination).is_ok());
+        assert!(
+            validate_source_address(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), &destination).is_err()
+        );
+        assert!(validate_source_address("::1".parse().unwrap(), &destination).is_err());
+        assert!(validate_source_address(
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            &Destination::Unix("/run/backend.sock".into())
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn configure_tunnel_sockets() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let config = TunnelConfig {
+            tcp_nodelay: true,
+            tcp_keepalive: Some(time::Duration::from_secs(30)),
+            dscp: Some(46),
+            ..tunnel_config()
+        };
+
+        config.configure(&stream).unwrap();
+        assert!(stream.nodelay().unwrap());
+        let socket = socket2::SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        // Windows can set the keepalive time of a socket, but not read it back
+        #[cfg(not(windows))]
+        assert_eq!(
+            socket.keepalive_time().unwrap(),
+            time::Duration::from_secs(30)
+        );
+        #[cfg(target_os = "linux")]
+        assert_eq!(socket.tos().unwrap(), 46 << 2);
+    }
+
+    #[tokio::test]
+    async fn exit_when_the_control_channel_closes() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination = Destination::Tcp(backend.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = backend.accept().await.unwrap();
+            let (mut si, mut so) = socket.split();
+            io::copy(&mut si, &mut so).await
+        });
+
+        let (control, rx) = watch::channel(ProxyControlMessage::Open {
+            destination,
+            source_address: None,
+            reconnect: true,
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (ready_tx, ready_rx) = oneshot::channel();
+        let proxy = tokio::spawn(proxy(
+            listener,
+            rx,
+            mpsc::channel(1).1,
+            Arc::new(tunnel_config()),
+            Arc::default(),
+            Arc::default(),
+            None,
+            ready_tx,
+        ));
+        ready_rx.await.unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+
+        // The tunnel goes away without sending `Close`, in the middle of a transfer
+        drop(control);
+        let timeout = time::Duration::from_secs(1);
+        tokio::time::timeout(timeout, proxy)
+            .await
+            .expect("proxy task didn't exit")
+            .expect("proxy task panicked");
+        let read = tokio::time::timeout(timeout, client.read(&mut buf)).await;
+        assert_eq!(read.expect("transfer didn't exit").unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn undo_create_when_cancelled() {
+        let state = Arc::new(GlobalState::new(None::<&str>));
+        let incoming_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let destination = SocketAddr::from((Ipv4Addr::LOCALHOST, 9));
+        let create = Command::create(uuid::Uuid::new_v4(), incoming_port, destination);
+        let mut command = Box::pin(execute_command(&state, create, None, false));
+
+        // Gives up while the tunnel is starting, like the command timeout does
+        tokio::select! {
+            biased;
+            _ = &mut command => panic!("the tunnel started without waiting for its task"),
+            _ = std::future::ready(()) => {}
+        }
+        assert_eq!(state.proxies.lock().unwrap().len(), 1);
+        drop(command);
+        assert_eq!(state.proxies.lock().unwrap().len(), 0);
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        std::net::TcpListener::bind(("0.0.0.0", incoming_port)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn undo_handover_when_cancelled() {
+        let state = Arc::new(GlobalState::new(None::<&str>));
+        let free_port = || {
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port()
+        };
+        let destination = SocketAddr::from((Ipv4Addr::LOCALHOST, 9));
+        let [from, to] = [uuid::Uuid::new_v4(), uuid::Uuid::new_v4()];
+        let [from_port, to_port] = [free_port(), free_port()];
+        for (id, port) in [(from, from_port), (to, to_port)] {
+            let create = Command::create(id, port, destination);
+            assert!(execute_command(&state, create, None, false).await.is_ok());
+        }
+        let connections = state
+            .proxies
+            .lock()
+            .unwrap()
+            .get(&from)
+            .unwrap()
+            .connections
+            .clone();
+
+        // Gives up before and after `from` sent its listener, like the command timeout does
+        for wait in [time::Duration::ZERO, time::Duration::from_millis(100)] {
+            let handover = Command::Handover {
+                from_id: from,
+                to_id: to,
+            };
+            let mut command = Box::pin(execute_command(&state, handover, None, false));
+            tokio::select! {
+                biased;
+                _ = &mut command => panic!("the handover finished without waiting for `from`"),
+                _ = std::future::ready(()) => {}
+            }
+            assert!(state.proxies.lock().unwrap().get(&from).unwrap().draining);
+            tokio::time::sleep(wait).await;
+            drop(command);
+            {
+                let proxies = state.proxies.lock().unwrap();
+                assert!(!proxies.get(&from).unwrap().draining);
+                assert_eq!(proxies.get(&to).unwrap().incoming_port, to_port);
+            }
+            assert!(state.self_check(false).is_empty());
+
+            // `from` got its listener back and accepts again
+            tokio::time::sleep(time::Duration::from_millis(100)).await;
+            let accepted = connections.accepted_total();
+            let _client = TcpStream::connect(("127.0.0.1", from_port)).await.unwrap();
+            tokio::time::sleep(time::Duration::from_millis(100)).await;
+            assert_eq!(connections.accepted_total(), accepted + 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn repair_leaked_state() {
+        let state = Arc::new(GlobalState::new(None::<&str>));
+        let free_port = || {
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port()
+        };
+        let destination = SocketAddr::from((Ipv4Addr::LOCALHOST, 9));
+        let [dead, unreserved] = [uuid::Uuid::new_v4(), uuid::Uuid::new_v4()];
+        let [dead_port, unreserved_port] = [free_port(), free_port()];
+        for (id, port) in [(dead, dead_port), (unreserved, unreserved_port)] {
+            let create = Command::create(id, port, destination);
+            assert!(execute_command(&state, create, None, false).await.is_ok());
+        }
+        assert!(state.self_check(false).is_empty());
+
+        {
+            let mut proxies = state.proxies.lock().unwrap();
+            // The tasks of a tunnel exit without it being removed, as the channel it holds is
+            // not the one they listened on
+            let proxy = proxies.get_mut(&dead).unwrap();
+            let _ = proxy.control.send(ProxyControlMessage::Close);
+            proxy.control = Arc::new(watch::channel(ProxyControlMessage::Close).0);
+            proxies.ports.remove(&(Protocol::Tcp, unreserved_port));
+            proxies.ports.insert((Protocol::Tcp, 1));
+        }
+
+        let expected = [
+            Inconsistency::DeadTunnel {
+                id: dead,
+                incoming_port: dead_port,
+            },
+            Inconsistency::UnreservedPort {
+                id: unreserved,
+                port: unreserved_port,
+            },
+            Inconsistency::OrphanedPort { port: 1 },
+        ];
+        let found = |repair| {
+            let mut findings = state.self_check(repair);
+            findings.sort_by_key(|finding| {
+                expected
+                    .iter()
+                    .position(|inconsistency| *inconsistency == finding.inconsistency)
+            });
+            findings
+        };
+        let findings = found(false);
+        assert!(findings.iter().all(|finding| !finding.repaired));
+        let findings = found(true);
+        let inconsistencies: Vec<_> = findings.iter().map(|f| f.inconsistency.clone()).collect();
+        assert_eq!(inconsistencies, expected);
+        assert!(findings.iter().all(|finding| finding.repaired));
+
+        assert!(state.self_check(false).is_empty());
+        let proxies = state.proxies.lock().unwrap();
+        assert!(!proxies.contains_key(&dead));
+        assert!(!proxies.port_in_use(Protocol::Tcp, dead_port));
+        assert!(proxies.port_in_use(Protocol::Tcp, unreserved_port));
     }
 }
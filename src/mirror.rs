@@ -0,0 +1,187 @@
+//! Best-effort copies of the data that clients send through a tunnel, for traffic analysis
+
+use crate::connections::Connections;
+use async_compression::tokio::write::GzipEncoder;
+use async_compression::Level;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time;
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// Reads of a connection that may wait for the mirror, anything beyond that is dropped
+const MIRROR_QUEUE_LEN: usize = 64;
+/// Longest time a write to the mirror may take, after which the mirror connection counts as
+/// failed, so a mirror that stops reading doesn't hold on to the queued data forever
+pub const MIRROR_WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+/// Gzip levels of the mirrored data, from fastest to smallest
+pub const MIRROR_GZIP_LEVELS: std::ops::RangeInclusive<u32> = 1..=9;
+
+/// What happened to the mirrored data of a tunnel
+#[derive(Debug, Default)]
+pub(crate) struct MirrorStats {
+    mirrored_bytes: AtomicU64,
+    /// Bytes that weren't mirrored because the mirror was too slow or unreachable
+    dropped_bytes: AtomicU64,
+    /// Mirror connections that couldn't be made or broke off
+    failed_connections: AtomicU64,
+}
+
+impl MirrorStats {
+    fn dropped(&self, len: usize) {
+        self.dropped_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a failed mirror connection and drops the data that waits for it, as well as the
+    /// data sent after it.
+    async fn fail(&self, mut rx: mpsc::Receiver<Vec<u8>>) {
+        self.failed_connections.fetch_add(1, Ordering::Relaxed);
+        rx.close();
+        while let Some(data) = rx.recv().await {
+            self.dropped(data.len());
+        }
+    }
+
+    pub(crate) fn status(&self, to: SocketAddr, gzip_level: Option<u32>) -> MirrorStatus {
+        MirrorStatus {
+            to,
+            gzip_level,
+            mirrored_bytes: self.mirrored_bytes.load(Ordering::Relaxed),
+            dropped_bytes: self.dropped_bytes.load(Ordering::Relaxed),
+            failed_connections: self.failed_connections.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The mirror of a tunnel as reported by the `Status` command
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MirrorStatus {
+    pub to: SocketAddr,
+    /// Level that the mirrored data is gzipped with, `None` when it is sent as is
+    #[serde(default)]
+    pub gzip_level: Option<u32>,
+    /// Bytes read from clients that were sent to the mirror, before they were compressed
+    pub mirrored_bytes: u64,
+    pub dropped_bytes: u64,
+    pub failed_connections: u64,
+}
+
+/// Connects to the mirror `to` for one connection and returns where to send its data.
+///
+/// With a `gzip_level` the mirror gets a single gzip stream per connection, which is flushed
+/// whenever the queue runs empty, so the compression never holds data back for long. It
+/// happens in the task of the mirror, a slow compressor only drops data like a slow mirror.
+///
+/// The mirror connection is shut down once every sender is dropped. Failures are only logged
+/// when the connection is `logged`.
+pub(crate) fn spawn(
+    to: SocketAddr,
+    gzip_level: Option<u32>,
+    connect_timeout: time::Duration,
+    connections: Arc<Connections>,
+    logged: bool,
+) -> mpsc::Sender<Vec<u8>> {
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(MIRROR_QUEUE_LEN);
+    tokio::spawn(async move {
+        let stats = connections.mirror_stats();
+        let stream = match tokio::time::timeout(connect_timeout, TcpStream::connect(to)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(err)) => {
+                if logged {
+                    tracing::debug!("connecting to mirror {to} failed: {err}");
+                }
+                return stats.fail(rx).await;
+            }
+            Err(_) => {
+                if logged {
+                    tracing::debug!("connecting to mirror {to} timed out");
+                }
+                return stats.fail(rx).await;
+            }
+        };
+        let mut stream: Pin<Box<dyn AsyncWrite + Send>> = match gzip_level {
+            Some(level) => Box::pin(GzipEncoder::with_quality(
+                stream,
+                Level::Precise(level as i32),
+            )),
+            None => Box::pin(stream),
+        };
+        while let Some(data) = rx.recv().await {
+            let write = async {
+                stream.write_all(&data).await?;
+                if rx.is_empty() {
+                    stream.flush().await?;
+                }
+                Ok(())
+            };
+            let written = match tokio::time::timeout(MIRROR_WRITE_TIMEOUT, write).await {
+                Ok(written) => written,
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the mirror stopped reading",
+                )),
+            };
+            if let Err(err) = written {
+                if logged {
+                    tracing::debug!("writing to mirror {to} failed: {err}");
+                }
+                stats.dropped(data.len());
+                return stats.fail(rx).await;
+            }
+            stats
+                .mirrored_bytes
+                .fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        // Also writes the end of the gzip stream
+        let _ = tokio::time::timeout(MIRROR_WRITE_TIMEOUT, stream.shutdown()).await;
+    });
+    tx
+}
+
+/// A reader that copies the data read through it to a mirror, without ever waiting for it
+pub(crate) struct Mirrored<'a, R> {
+    inner: R,
+    mirror: Option<mpsc::Sender<Vec<u8>>>,
+    stats: &'a MirrorStats,
+}
+
+impl<'a, R> Mirrored<'a, R> {
+    /// Mirrors the data of `reader` to `mirror`, if there is one.
+    pub(crate) fn new(
+        reader: R,
+        mirror: Option<mpsc::Sender<Vec<u8>>>,
+        stats: &'a MirrorStats,
+    ) -> Self {
+        Self {
+            inner: reader,
+            mirror,
+            stats,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Mirrored<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = &buf.filled()[filled..];
+        if let (Some(mirror), false) = (&self.mirror, read.is_empty()) {
+            // A full queue means the mirror is too slow, a closed one that it failed
+            if let Err(TrySendError::Full(data) | TrySendError::Closed(data)) =
+                mirror.try_send(read.to_vec())
+            {
+                self.stats.dropped(data.len());
+            }
+        }
+        result
+    }
+}
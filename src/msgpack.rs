@@ -0,0 +1,111 @@
+//! Commands and responses in MessagePack, for clients that find JSON too large. They have the
+//! same fields as in JSON, and a command is signed the same way, over its JSON, so a signature
+//! doesn't depend on the format it was sent in.
+
+use crate::{invalid_json, port, ApiError, ErrorCode, ProxyCommand};
+use axum::body::{self, Bytes, Full};
+use axum::extract::FromRequest;
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::{async_trait, Json};
+use hyper::Body;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// The `Content-Type` of commands and responses in MessagePack
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+/// Encodes `value` like the control plane does: structs as maps and everything else as it
+/// would be in JSON, like ids and addresses as strings.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    let mut encoded = Vec::new();
+    let mut serializer = rmp_serde::Serializer::new(&mut encoded)
+        .with_struct_map()
+        .with_human_readable();
+    value.serialize(&mut serializer)?;
+    Ok(encoded)
+}
+
+/// Decodes `bytes` encoded like by [`to_vec`].
+pub fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, rmp_serde::decode::Error> {
+    let mut deserializer = rmp_serde::Deserializer::from_read_ref(bytes).with_human_readable();
+    T::deserialize(&mut deserializer)
+}
+
+/// Whether a request or response has a MessagePack body
+pub(crate) fn is_msgpack(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/msgpack"))
+}
+
+/// Extracts the command in the body of a request, in MessagePack when the `Content-Type` says
+/// so and otherwise in JSON
+#[derive(Debug)]
+pub struct CommandBody(pub ProxyCommand);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequest<S, Body> for CommandBody {
+    type Rejection = ApiError;
+
+    async fn from_request(request: Request<Body>, state: &S) -> Result<Self, ApiError> {
+        if !is_msgpack(request.headers()) {
+            let Json(command) = Json::from_request(request, state)
+                .await
+                .map_err(invalid_json)?;
+            return Ok(Self(command));
+        }
+        let bytes = Bytes::from_request(request, state)
+            .await
+            .map_err(|rejection| {
+                ApiError::new(ErrorCode::InvalidJson, rejection.body_text())
+                    .with_status(rejection.status())
+            })?;
+        from_slice(&bytes).map(Self).map_err(|err| {
+            let message = format!("Failed to deserialize the MessagePack body: {err}");
+            // Like with JSON, the command is fine but a port in it is out of range
+            if message.contains(port::PORT_RANGE) {
+                ApiError::new(ErrorCode::InvalidCommand, message)
+            } else {
+                ApiError::new(ErrorCode::InvalidJson, message)
+                    .with_status(StatusCode::UNPROCESSABLE_ENTITY)
+            }
+        })
+    }
+}
+
+/// Answers requests made in MessagePack with MessagePack, by converting their JSON responses.
+pub(crate) async fn msgpack_responses(request: Request<Body>, next: Next<Body>) -> Response {
+    let msgpack = is_msgpack(request.headers());
+    let response = next.run(request).await;
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/json"));
+    if !msgpack || !is_json {
+        return response;
+    }
+    let (mut parts, response_body) = response.into_parts();
+    let encoded = hyper::body::to_bytes(response_body)
+        .await
+        .map_err(|err| err.to_string())
+        .and_then(|json| {
+            serde_json::from_slice::<serde_json::Value>(&json).map_err(|err| err.to_string())
+        })
+        .and_then(|value| to_vec(&value).map_err(|err| err.to_string()));
+    match encoded {
+        Ok(encoded) => {
+            parts.headers.remove(header::CONTENT_LENGTH);
+            parts.headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(MSGPACK_CONTENT_TYPE),
+            );
+            Response::from_parts(parts, body::boxed(Full::from(encoded)))
+        }
+        Err(err) => {
+            tracing::error!("encoding the response as MessagePack failed: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
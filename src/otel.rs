@@ -0,0 +1,53 @@
+//! Spans of commands and connections exported over OTLP, for platforms that collect traces with
+//! OpenTelemetry
+
+use opentelemetry::trace::{SpanContext, TraceContextExt, TraceError, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Name that the spans of the proxy are exported under
+pub const SERVICE_NAME: &str = "proxima-centauri";
+
+/// A layer that exports every span to the OTLP collector at `endpoint`, over gRPC like
+/// `http://localhost:4317`. Has to be called from within a Tokio runtime, which sends the
+/// spans in batches.
+pub fn layer<S>(
+    endpoint: &str,
+) -> Result<OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>, TraceError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new("service.name", SERVICE_NAME)]))
+        .build();
+    let tracer = provider.tracer(SERVICE_NAME);
+    opentelemetry::global::set_tracer_provider(provider);
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// The span that is being handled, to link the spans of a tunnel back to the command that
+/// created it. Empty when the spans aren't exported.
+pub(crate) fn current_span_context() -> SpanContext {
+    tracing::Span::current()
+        .context()
+        .span()
+        .span_context()
+        .clone()
+}
+
+/// Links `span` to `to`, unless that is empty.
+pub(crate) fn link(span: &tracing::Span, to: &SpanContext) {
+    if to.is_valid() {
+        span.add_link(to.clone());
+    }
+}
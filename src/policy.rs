@@ -0,0 +1,90 @@
+//! The destinations that tunnels may forward to, enforced independently of who signed a command
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// A network and optionally the ports in it that tunnels may forward to, written like
+/// `10.0.0.0/8`, `10.1.2.3/32:5432` or `fd00::/8:8000-8999`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AllowedDestination {
+    network: IpAddr,
+    prefix_len: u8,
+    /// All ports when `None`
+    ports: Option<RangeInclusive<u16>>,
+}
+
+impl AllowedDestination {
+    pub(crate) fn allows(&self, addr: SocketAddr) -> bool {
+        let in_network = match (self.network, addr.ip()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        };
+        in_network
+            && self
+                .ports
+                .as_ref()
+                .is_none_or(|ports| ports.contains(&addr.port()))
+    }
+}
+
+impl FromStr for AllowedDestination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // The prefix length never contains a colon, unlike an IPv6 network
+        let (network, rest) = s
+            .split_once('/')
+            .ok_or_else(|| format!("`{s}` has no prefix length, like `10.0.0.0/8`"))?;
+        let (prefix_len, ports) = match rest.split_once(':') {
+            Some((prefix_len, ports)) => (prefix_len, Some(ports)),
+            None => (rest, None),
+        };
+        let network: IpAddr = network
+            .parse()
+            .map_err(|err| format!("invalid network `{network}`: {err}"))?;
+        let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len
+            .parse::<u8>()
+            .ok()
+            .filter(|len| *len <= max_prefix_len)
+            .ok_or_else(|| format!("invalid prefix length `{prefix_len}`"))?;
+        let ports = ports
+            .map(|ports| {
+                let (first, last) = ports.split_once('-').unwrap_or((ports, ports));
+                match (first.parse::<u16>(), last.parse::<u16>()) {
+                    (Ok(first), Ok(last)) if first <= last => Ok(first..=last),
+                    _ => Err(format!("invalid ports `{ports}`")),
+                }
+            })
+            .transpose()?;
+        Ok(Self {
+            network,
+            prefix_len,
+            ports,
+        })
+    }
+}
+
+impl fmt::Display for AllowedDestination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)?;
+        match &self.ports {
+            Some(ports) if ports.start() == ports.end() => write!(f, ":{}", ports.start()),
+            Some(ports) => write!(f, ":{}-{}", ports.start(), ports.end()),
+            None => Ok(()),
+        }
+    }
+}
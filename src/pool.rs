@@ -0,0 +1,136 @@
+//! Idle connections to the destinations of a tunnel, reused by later clients instead of
+//! connecting again
+
+use crate::{Destination, Outbound};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Waker};
+use std::time;
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Idle connections kept per destination, further ones are closed
+pub const MAX_POOLED_PER_DESTINATION: usize = 16;
+/// Time an idle connection is kept before it is closed
+pub const POOL_IDLE_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+/// Time the destination has to be quiet after a client stopped sending before its connection is
+/// done, so that a client which shuts down its side before reading still gets its answer
+pub const POOL_SETTLE_TIME: time::Duration = time::Duration::from_millis(20);
+
+/// A destination together with the address that connections to it are made from
+pub(crate) type PoolKey = (Destination, Option<IpAddr>);
+
+struct Idle {
+    outbound: Box<dyn Outbound>,
+    since: time::Instant,
+}
+
+/// The idle connections of one tunnel, by where they go
+#[derive(Default)]
+pub(crate) struct OutboundPool {
+    idle: Mutex<HashMap<PoolKey, VecDeque<Idle>>>,
+    /// Connections that were handed to another client instead of connecting again
+    reused: AtomicU64,
+}
+
+/// The connection pool of a tunnel as reported by the `Status` command
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PoolStatus {
+    pub idle_connections: usize,
+    pub reused_connections: u64,
+}
+
+impl std::fmt::Debug for OutboundPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OutboundPool")
+            .field("idle", &self.len())
+            .finish()
+    }
+}
+
+impl OutboundPool {
+    /// Creates a pool whose idle connections are closed after [`POOL_IDLE_TIMEOUT`], until the
+    /// pool is dropped.
+    pub(crate) fn new() -> Arc<Self> {
+        let pool = Arc::new(Self::default());
+        tokio::spawn(evict_idle(Arc::downgrade(&pool)));
+        pool
+    }
+
+    /// The most recently used idle connection to `key` that is still open, if any.
+    pub(crate) fn take(&self, key: &PoolKey) -> Option<Box<dyn Outbound>> {
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.get_mut(key)?;
+        while let Some(mut connection) = connections.pop_back() {
+            if connection.since.elapsed() < POOL_IDLE_TIMEOUT && is_quiet(&mut connection.outbound)
+            {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                return Some(connection.outbound);
+            }
+        }
+        None
+    }
+
+    /// Keeps `outbound` for the next client of `key`, unless the destination closed it or
+    /// already sent something that no client asked for.
+    pub(crate) fn put(&self, key: PoolKey, mut outbound: Box<dyn Outbound>) {
+        if !is_quiet(&mut outbound) {
+            return;
+        }
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.entry(key).or_default();
+        if connections.len() == MAX_POOLED_PER_DESTINATION {
+            connections.pop_front();
+        }
+        connections.push_back(Idle {
+            outbound,
+            since: time::Instant::now(),
+        });
+    }
+
+    /// Idle connections to all destinations together
+    pub(crate) fn len(&self) -> usize {
+        self.idle.lock().unwrap().values().map(VecDeque::len).sum()
+    }
+
+    pub(crate) fn status(&self) -> PoolStatus {
+        PoolStatus {
+            idle_connections: self.len(),
+            reused_connections: self.reused.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Closes the connections that have been idle for too long.
+    fn evict(&self) {
+        let mut idle = self.idle.lock().unwrap();
+        for connections in idle.values_mut() {
+            connections.retain(|connection| connection.since.elapsed() < POOL_IDLE_TIMEOUT);
+        }
+        idle.retain(|_, connections| !connections.is_empty());
+    }
+}
+
+/// Evicts the idle connections of `pool` now and then, while it exists.
+async fn evict_idle(pool: Weak<OutboundPool>) {
+    let mut interval = tokio::time::interval(POOL_IDLE_TIMEOUT / 2);
+    loop {
+        interval.tick().await;
+        let Some(pool) = pool.upgrade() else {
+            return;
+        };
+        pool.evict();
+    }
+}
+
+/// Whether `outbound` is open without anything to read, which is the only state in which a
+/// connection can be handed to another client.
+fn is_quiet(outbound: &mut Box<dyn Outbound>) -> bool {
+    let mut byte = [0];
+    let mut buf = ReadBuf::new(&mut byte);
+    let mut cx = Context::from_waker(Waker::noop());
+    // Ready means closed, failed or unsolicited data
+    Pin::new(outbound).poll_read(&mut cx, &mut buf).is_pending()
+}
@@ -0,0 +1,78 @@
+//! Ports in commands, with errors that name the field and the valid range instead of serde's
+//! error about a `u16`
+
+use serde::de::{self, Deserializer, Visitor};
+use std::fmt;
+
+/// Part of every error about a port, which tells them apart from other errors in the JSON
+pub(crate) const PORT_RANGE: &str = "a port from 1 to 65535";
+
+struct Port(&'static str);
+
+impl<'de> Visitor<'de> for Port {
+    type Value = u16;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}` to be {PORT_RANGE}", self.0)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<u16, E> {
+        // Port 0 would let the OS pick a port, which nothing reports back
+        u16::try_from(v)
+            .ok()
+            .filter(|port| *port != 0)
+            .ok_or_else(|| E::custom(format!("`{}` must be {PORT_RANGE}, not {v}", self.0)))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<u16, E> {
+        match u64::try_from(v) {
+            Ok(v) => self.visit_u64(v),
+            Err(_) => Err(E::custom(format!(
+                "`{}` must be {PORT_RANGE}, not {v}",
+                self.0
+            ))),
+        }
+    }
+}
+
+struct OptionalPort(&'static str);
+
+impl<'de> Visitor<'de> for OptionalPort {
+    type Value = Option<u16>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Port(self.0).expecting(f)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Option<u16>, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Option<u16>, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Option<u16>, D::Error> {
+        deserializer.deserialize_any(Port(self.0)).map(Some)
+    }
+}
+
+pub(crate) fn incoming_port<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    deserializer.deserialize_any(Port("incoming_port"))
+}
+
+pub(crate) fn destination_port<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u16>, D::Error> {
+    deserializer.deserialize_option(OptionalPort("destination_port"))
+}
+
+pub(crate) fn start_port<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    deserializer.deserialize_any(Port("start_port"))
+}
+
+pub(crate) fn destination_port_start<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<u16, D::Error> {
+    deserializer.deserialize_any(Port("destination_port_start"))
+}
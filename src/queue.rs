@@ -0,0 +1,99 @@
+//! A bounded queue of accepted connections that wait for a worker of their tunnel
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+
+/// What happens to a connection that arrives while the queue of its tunnel is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Close the new connection, the queued ones keep their place
+    #[default]
+    RejectNew,
+    /// Close the connection that waited longest and queue the new one, for clients that give
+    /// up on connections that take too long anyway
+    DropOldest,
+}
+
+/// An accepted connection and its client
+pub(crate) type Pending = (TcpStream, SocketAddr);
+
+#[derive(Debug)]
+pub(crate) struct ConnectionQueue {
+    pending: Mutex<VecDeque<Pending>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    /// Woken for every queued connection, and for all workers when the queue closes
+    available: Notify,
+    closed: AtomicBool,
+}
+
+impl ConnectionQueue {
+    pub(crate) fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            pending: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            policy,
+            available: Notify::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub(crate) fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    /// Queues a connection, returning the one that the overflow policy turned away when the
+    /// queue is full.
+    pub(crate) fn push(&self, connection: Pending) -> Option<Pending> {
+        let mut pending = self.pending.lock().unwrap();
+        let turned_away = if pending.len() < self.capacity {
+            None
+        } else {
+            match self.policy {
+                OverflowPolicy::RejectNew => return Some(connection),
+                OverflowPolicy::DropOldest => pending.pop_front(),
+            }
+        };
+        pending.push_back(connection);
+        drop(pending);
+        self.available.notify_one();
+        turned_away
+    }
+
+    /// Waits for the next connection, or `None` once the queue is closed and empty.
+    pub(crate) async fn pop(&self) -> Option<Pending> {
+        loop {
+            // Registered before looking, so a connection queued in between still wakes it
+            let available = self.available.notified();
+            if let Some(connection) = self.pending.lock().unwrap().pop_front() {
+                return Some(connection);
+            }
+            if self.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            available.await;
+        }
+    }
+
+    /// Lets the workers finish the queued connections and stop, once the tunnel no longer
+    /// accepts any.
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.available.notify_waiters();
+    }
+
+    /// Connections waiting for a worker
+    pub(crate) fn len(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+}
@@ -0,0 +1,107 @@
+//! Accounting of connections that are dropped without being proxied
+
+use std::fmt::{self, Write};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Why a connection was dropped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RejectReason {
+    /// Accepting the connection on the listener of a tunnel failed
+    AcceptFailed,
+    /// The socket options of the tunnel couldn't be applied to the connection
+    SocketOptions,
+    /// The destination didn't accept the connection within the connect timeout
+    ConnectTimeout,
+    /// The destination refused the connection
+    ConnectFailed,
+    /// A control plane client failed the TLS handshake
+    TlsHandshake,
+    /// The proxy already has its maximum of connections through all tunnels
+    ConnectionLimit,
+    /// The client of a router didn't send a known route key
+    UnknownRoute,
+    /// The connection queue of the tunnel was full, see `OverflowPolicy`
+    QueueFull,
+}
+
+impl RejectReason {
+    const ALL: [RejectReason; 8] = [
+        RejectReason::AcceptFailed,
+        RejectReason::SocketOptions,
+        RejectReason::ConnectTimeout,
+        RejectReason::ConnectFailed,
+        RejectReason::TlsHandshake,
+        RejectReason::ConnectionLimit,
+        RejectReason::UnknownRoute,
+        RejectReason::QueueFull,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            RejectReason::AcceptFailed => "accept_failed",
+            RejectReason::SocketOptions => "socket_options",
+            RejectReason::ConnectTimeout => "connect_timeout",
+            RejectReason::ConnectFailed => "connect_failed",
+            RejectReason::TlsHandshake => "tls_handshake",
+            RejectReason::ConnectionLimit => "connection_limit",
+            RejectReason::UnknownRoute => "unknown_route",
+            RejectReason::QueueFull => "queue_full",
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Counts the rejected connections per [`RejectReason`]
+#[derive(Debug, Default)]
+pub(crate) struct Rejections {
+    counts: [AtomicU64; RejectReason::ALL.len()],
+}
+
+impl Rejections {
+    /// Records that the connection from `client` is dropped because of `reason`.
+    ///
+    /// Every rejection goes through here, so this is the one event to monitor.
+    pub(crate) fn reject(
+        &self,
+        client: Option<SocketAddr>,
+        reason: RejectReason,
+        detail: &dyn fmt::Display,
+    ) {
+        self.count(reason);
+        match client {
+            Some(client) => {
+                tracing::warn!(%reason, %client, "rejected connection: {detail}")
+            }
+            None => tracing::warn!(%reason, "rejected connection: {detail}"),
+        }
+    }
+
+    /// Records that a connection is dropped because of `reason`, without logging it, for
+    /// tunnels that may not log their connections.
+    pub(crate) fn count(&self, reason: RejectReason) {
+        self.counts[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counts in the Prometheus text format
+    pub(crate) fn metrics(&self) -> String {
+        let mut metrics = String::from(
+            "# HELP proxima_rejected_connections_total Connections dropped without being proxied\n\
+             # TYPE proxima_rejected_connections_total counter\n",
+        );
+        for reason in RejectReason::ALL {
+            let count = self.counts[reason as usize].load(Ordering::Relaxed);
+            // Writing to a string can't fail
+            let _ = writeln!(
+                metrics,
+                "proxima_rejected_connections_total{{reason=\"{reason}\"}} {count}"
+            );
+        }
+        metrics
+    }
+}
@@ -0,0 +1,192 @@
+//! Tunnels declared in a file, which the proxy makes its tunnels match when it starts and
+//! whenever it is asked to reload
+
+use crate::{execute_command, ApiError, Command, ErrorCode, GlobalState};
+use anyhow::{bail, Context};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time;
+use uuid::Uuid;
+
+/// Fields of a declared tunnel that a `Modify` changes in place, a change to any other field
+/// recreates the tunnel
+const MODIFIABLE_FIELDS: [&str; 6] = [
+    "destination_ip",
+    "destination_port",
+    "destination_uds",
+    "source_address",
+    "ttl_secs",
+    "label",
+];
+
+/// Longest wait for the port of a deleted tunnel to be released before it is bound again
+const PORT_RELEASE_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+/// The `create` commands last applied from the tunnels file, by tunnel id
+pub(crate) type Declared = BTreeMap<Uuid, Value>;
+
+/// What reloading the tunnels file changed
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Reconciled {
+    pub created: usize,
+    pub modified: usize,
+    /// Deleted and created again, for changes that `Modify` can't make
+    pub recreated: usize,
+    pub deleted: usize,
+    pub unchanged: usize,
+}
+
+impl fmt::Display for Reconciled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} created, {} modified, {} recreated, {} deleted, {} unchanged",
+            self.created, self.modified, self.recreated, self.deleted, self.unchanged
+        )
+    }
+}
+
+/// Makes the tunnels of the proxy match the tunnels file at `path`, a JSON array of `create`
+/// commands like `[{"create": {"incoming_port": 5555, ...}}]` without signatures.
+///
+/// Tunnels that are declared the same way as at the previous reload are left alone, and
+/// tunnels created through the control plane are never touched. When any change fails, like a
+/// new tunnel whose port can't be bound, the changes made so far are undone and the tunnels are
+/// left as they were.
+pub async fn reload_tunnels(state: &Arc<GlobalState>, path: &Path) -> anyhow::Result<Reconciled> {
+    let file = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("reading {} failed", path.display()))?;
+    let declared = parse(&file).with_context(|| format!("{} is invalid", path.display()))?;
+
+    // Reloads are applied one at a time, so the previous one is always complete
+    let mut applied = state.declared.lock().await;
+    let mut reconciled = Reconciled::default();
+    // Commands that undo the changes made so far, in the order they were made
+    let mut undo: Vec<Vec<Value>> = Vec::new();
+    let result = async {
+        for (id, command) in &declared {
+            let exists = state.proxies.lock().unwrap().contains_key(id);
+            let old = applied.get(id).filter(|_| exists);
+            match old {
+                None => {
+                    run(state, command.clone(), false).await?;
+                    undo.push(vec![delete(*id)]);
+                    reconciled.created += 1;
+                }
+                Some(old) if old == command => reconciled.unchanged += 1,
+                Some(old) if modifiable(&old["create"], &command["create"]) => {
+                    run(state, modify(&old["create"], &command["create"]), false).await?;
+                    undo.push(vec![modify(&command["create"], &old["create"])]);
+                    reconciled.modified += 1;
+                }
+                Some(old) => {
+                    run(state, delete(*id), false).await?;
+                    if let Err(err) = run(state, command.clone(), true).await {
+                        undo.push(vec![old.clone()]);
+                        return Err(err);
+                    }
+                    undo.push(vec![delete(*id), old.clone()]);
+                    reconciled.recreated += 1;
+                }
+            }
+        }
+        for (id, old) in applied.iter() {
+            if declared.contains_key(id) || !state.proxies.lock().unwrap().contains_key(id) {
+                continue;
+            }
+            run(state, delete(*id), false).await?;
+            undo.push(vec![old.clone()]);
+            reconciled.deleted += 1;
+        }
+        Ok(())
+    }
+    .await;
+
+    if let Err(err) = result {
+        for commands in undo.into_iter().rev() {
+            for command in commands {
+                if let Err(err) = run(state, command, true).await {
+                    tracing::error!("undoing a change of the tunnels file failed: {err}");
+                }
+            }
+        }
+        bail!(
+            "applying {} failed, nothing was changed: {err}",
+            path.display()
+        );
+    }
+    *applied = declared;
+    Ok(reconciled)
+}
+
+/// Reads the `create` commands of a tunnels file.
+fn parse(file: &str) -> anyhow::Result<Declared> {
+    let commands: Vec<Value> = serde_json::from_str(file)?;
+    let mut declared = Declared::new();
+    for command in commands {
+        let Command::Create { id, .. } = serde_json::from_value(command.clone())? else {
+            bail!("only `create` commands can be declared");
+        };
+        if declared.insert(id, command).is_some() {
+            bail!("tunnel {id} is declared more than once");
+        }
+    }
+    Ok(declared)
+}
+
+/// Whether a declared tunnel can change from `old` to `new` with a `Modify`.
+fn modifiable(old: &Value, new: &Value) -> bool {
+    let empty = Map::new();
+    let (old, new) = (
+        old.as_object().unwrap_or(&empty),
+        new.as_object().unwrap_or(&empty),
+    );
+    old.keys()
+        .chain(new.keys())
+        .filter(|field| old.get(*field) != new.get(*field))
+        .all(|field| MODIFIABLE_FIELDS.contains(&field.as_str()))
+}
+
+/// The `Modify` that changes a tunnel declared as `old` to the destination and other modifiable
+/// fields of `new`. Fields that `new` leaves out are `null`, so that a removed label or time to
+/// live is cleared instead of kept.
+fn modify(old: &Value, new: &Value) -> Value {
+    let mut modify: Map<String, Value> = MODIFIABLE_FIELDS
+        .iter()
+        .filter_map(|field| match (old.get(*field), new.get(*field)) {
+            (_, Some(value)) => Some((field.to_string(), value.clone())),
+            (Some(_), None) => Some((field.to_string(), Value::Null)),
+            (None, None) => None,
+        })
+        .collect();
+    modify.insert("id".to_string(), new["id"].clone());
+    serde_json::json!({ "modify": modify })
+}
+
+fn delete(id: Uuid) -> Value {
+    serde_json::json!({ "delete": { "id": id } })
+}
+
+/// Carries out a declared command. With `after_delete`, a port that is still held by the
+/// tunnel that was just deleted is waited for.
+async fn run(state: &Arc<GlobalState>, command: Value, after_delete: bool) -> Result<(), ApiError> {
+    let deadline = time::Instant::now() + PORT_RELEASE_TIMEOUT;
+    loop {
+        let parsed: Command = serde_json::from_value(command.clone())
+            .map_err(|err| ApiError::new(ErrorCode::InvalidCommand, err.to_string()))?;
+        match execute_command(state, parsed, None, false).await {
+            Err(err)
+                if after_delete
+                    && err.code() == ErrorCode::PortInUse
+                    && time::Instant::now() < deadline =>
+            {
+                tokio::time::sleep(time::Duration::from_millis(10)).await;
+            }
+            result => return result.map(|_| ()),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! Auditing the tunnels against the ports they reserve, to catch state that leaked, see
+//! `Command::SelfCheck`
+
+use crate::{GlobalState, Protocol, ProxyState, Tunnels};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Something about the tunnels that should never happen
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum Inconsistency {
+    /// The tasks of a tunnel have all exited while it is still listed, where it should have
+    /// been removed once they did
+    DeadTunnel { id: Uuid, incoming_port: u16 },
+    /// A port is reserved without a tunnel that listens on it, so it can't be used again
+    OrphanedPort { port: u16 },
+    /// A tunnel listens on a port that isn't reserved, so another tunnel could be created on it
+    UnreservedPort { id: Uuid, port: u16 },
+    /// Several tunnels that accept connections claim the same port. Tunnels that are draining
+    /// keep their port, like after a `Handover`, and are left out.
+    SharedPort { port: u16, ids: Vec<Uuid> },
+}
+
+/// An [`Inconsistency`] found by a `SelfCheck`, and whether it was repaired
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Finding {
+    #[serde(flatten)]
+    pub inconsistency: Inconsistency,
+    pub repaired: bool,
+}
+
+impl GlobalState {
+    /// Audits the tunnels and the ports they reserve. With `repair`, dead tunnels are removed
+    /// and the reserved ports are made to match the tunnels, but tunnels that share a port are
+    /// only reported, as there is no telling which one should keep it.
+    pub(crate) fn self_check(&self, repair: bool) -> Vec<Finding> {
+        let mut proxies = self.proxies.lock().unwrap();
+        let findings: Vec<Finding> = proxies
+            .audit()
+            .into_iter()
+            .map(|inconsistency| {
+                let repaired = repair && proxies.repair(&inconsistency);
+                tracing::warn!(
+                    ?inconsistency,
+                    repaired,
+                    "self check found an inconsistency"
+                );
+                Finding {
+                    inconsistency,
+                    repaired,
+                }
+            })
+            .collect();
+        if findings.iter().any(|finding| finding.repaired) {
+            self.changed();
+        }
+        findings
+    }
+}
+
+impl Tunnels {
+    fn audit(&self) -> Vec<Inconsistency> {
+        let mut inconsistencies = Vec::new();
+        let is_dead = |proxy: &ProxyState| proxy.control.receiver_count() == 0;
+        let mut claims: HashMap<(Protocol, u16), Vec<Uuid>> = HashMap::new();
+        for (id, proxy) in self.iter() {
+            if is_dead(proxy) {
+                inconsistencies.push(Inconsistency::DeadTunnel {
+                    id: *id,
+                    incoming_port: proxy.incoming_port,
+                });
+                continue;
+            }
+            if !self.port_in_use(proxy.protocol, proxy.incoming_port) {
+                inconsistencies.push(Inconsistency::UnreservedPort {
+                    id: *id,
+                    port: proxy.incoming_port,
+                });
+            }
+            if !proxy.draining {
+                claims
+                    .entry((proxy.protocol, proxy.incoming_port))
+                    .or_default()
+                    .push(*id);
+            }
+        }
+        let mut shared: Vec<((Protocol, u16), Vec<Uuid>)> = claims
+            .into_iter()
+            .filter(|(_, ids)| ids.len() > 1)
+            .collect();
+        shared.sort_unstable_by_key(|((_, port), _)| *port);
+        for ((_, port), mut ids) in shared {
+            ids.sort_unstable();
+            inconsistencies.push(Inconsistency::SharedPort { port, ids });
+        }
+        let mut orphaned: Vec<u16> = self
+            .ports
+            .iter()
+            .filter(|(protocol, port)| {
+                !self
+                    .by_id
+                    .values()
+                    .any(|proxy| (proxy.protocol, proxy.incoming_port) == (*protocol, *port))
+            })
+            .map(|(_, port)| *port)
+            .collect();
+        orphaned.sort_unstable();
+        inconsistencies.extend(
+            orphaned
+                .into_iter()
+                .map(|port| Inconsistency::OrphanedPort { port }),
+        );
+        inconsistencies
+    }
+
+    /// Returns whether `inconsistency` was repaired
+    fn repair(&mut self, inconsistency: &Inconsistency) -> bool {
+        match inconsistency {
+            // Its tasks are gone already, so nothing has to be told to close
+            Inconsistency::DeadTunnel { id, .. } => self.remove(id).is_some(),
+            Inconsistency::OrphanedPort { port } => self.ports.remove(&(Protocol::Tcp, *port)),
+            Inconsistency::UnreservedPort { id, port } => match self.get(id) {
+                Some(proxy) => self.ports.insert((proxy.protocol, *port)),
+                None => false,
+            },
+            Inconsistency::SharedPort { .. } => false,
+        }
+    }
+}
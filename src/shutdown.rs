@@ -0,0 +1,110 @@
+//! Stopping every tunnel when the proxy exits, and the account of what it served
+
+use crate::connections::Connections;
+use crate::{GlobalState, ProxyControlMessage, Served};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time;
+use uuid::Uuid;
+
+/// Time that the connections of a shutdown get to end once their tunnels are closed
+const CLOSE_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+/// What the proxy served from its start to its shutdown, see [`GlobalState::shutdown`]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ShutdownStats {
+    pub uptime_secs: u64,
+    /// Of every tunnel, the ones deleted before the shutdown included
+    #[serde(flatten)]
+    pub served: Served,
+    /// Of the tunnels that were open at the shutdown
+    pub tunnels: BTreeMap<Uuid, TunnelServed>,
+}
+
+/// What one tunnel served, as part of the [`ShutdownStats`]
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TunnelServed {
+    pub incoming_port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(flatten)]
+    pub served: Served,
+}
+
+/// A tunnel of the shutdown, kept after it is removed to count its connections
+struct Stopping {
+    id: Uuid,
+    incoming_port: u16,
+    label: Option<String>,
+    connections: Arc<Connections>,
+}
+
+impl GlobalState {
+    /// Drains every tunnel, like `Delete` with `drain`, for the proxy to exit. Connections that
+    /// are still open after `grace` are closed. Returns what the proxy served once every
+    /// connection has ended.
+    pub async fn shutdown(&self, grace: time::Duration) -> ShutdownStats {
+        let tunnels: Vec<Stopping> = {
+            let mut proxies = self.proxies.lock().unwrap();
+            let ids: Vec<Uuid> = proxies.iter().map(|(id, _)| *id).collect();
+            ids.into_iter()
+                .map(|id| {
+                    let proxy = proxies.get_mut(&id).unwrap();
+                    if !proxy.draining {
+                        proxy.draining = true;
+                        // A tunnel whose listener failed may already have no receivers left
+                        let _ = proxy.control.send(ProxyControlMessage::Drain {
+                            destination: proxy.destination.clone(),
+                            source_address: proxy.source_address,
+                            handover: None,
+                        });
+                    }
+                    Stopping {
+                        id,
+                        incoming_port: proxy.incoming_port,
+                        label: proxy.label.clone(),
+                        connections: proxy.connections.clone(),
+                    }
+                })
+                .collect()
+        };
+        self.changed();
+
+        if !ended(&tunnels, grace).await {
+            let closed = self.close_all_tunnels();
+            tracing::warn!("closing the connections of {closed} tunnels after {grace:?}");
+            if !ended(&tunnels, CLOSE_TIMEOUT).await {
+                tracing::warn!("some connections didn't end within {CLOSE_TIMEOUT:?}");
+            }
+        }
+        self.close_all_tunnels();
+
+        ShutdownStats {
+            uptime_secs: self.started.elapsed().as_secs(),
+            served: self.served.get(),
+            tunnels: tunnels
+                .into_iter()
+                .map(|tunnel| {
+                    let served = TunnelServed {
+                        incoming_port: tunnel.incoming_port,
+                        label: tunnel.label,
+                        served: tunnel.connections.served(),
+                    };
+                    (tunnel.id, served)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Waits at most `timeout` for the connections of `tunnels` to end, returns whether they did.
+async fn ended(tunnels: &[Stopping], timeout: time::Duration) -> bool {
+    let all_ended = async {
+        let mut interval = tokio::time::interval(time::Duration::from_millis(50));
+        while tunnels.iter().any(|tunnel| tunnel.connections.active() > 0) {
+            interval.tick().await;
+        }
+    };
+    tokio::time::timeout(timeout, all_ended).await.is_ok()
+}
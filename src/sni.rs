@@ -0,0 +1,115 @@
+//! Routing of TLS connections by the server name in their ClientHello, without terminating TLS
+
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+
+/// Length of the header of a TLS record
+const RECORD_HEADER_LEN: usize = 5;
+/// Largest payload of a TLS record
+const MAX_RECORD_LEN: usize = 16 * 1024;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// Reads the first TLS record of a connection into `read`, which still has to be passed on to
+/// the destination, and returns the server name the client asked for.
+///
+/// Stops as soon as the connection doesn't look like TLS, so other protocols only lose the
+/// time until their first bytes arrive.
+pub(crate) async fn read_client_hello<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    read: &mut Vec<u8>,
+) -> io::Result<Option<String>> {
+    let mut wanted = RECORD_HEADER_LEN;
+    while read.len() < wanted {
+        let mut chunk = [0; 1024];
+        let max = chunk.len().min(wanted - read.len());
+        let n = reader.read(&mut chunk[..max]).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        read.extend_from_slice(&chunk[..n]);
+        if read[0] != CONTENT_TYPE_HANDSHAKE {
+            return Ok(None);
+        }
+        if wanted == RECORD_HEADER_LEN && read.len() == RECORD_HEADER_LEN {
+            let len = u16::from_be_bytes([read[3], read[4]]) as usize;
+            if len > MAX_RECORD_LEN {
+                return Ok(None);
+            }
+            wanted += len;
+        }
+    }
+    Ok(server_name(&read[RECORD_HEADER_LEN..]))
+}
+
+/// The host name of the server name extension of a ClientHello, lowercased, or `None` if the
+/// handshake message isn't a ClientHello or doesn't have one.
+///
+/// A ClientHello that doesn't fit in the first record isn't parsed.
+fn server_name(handshake: &[u8]) -> Option<String> {
+    let mut message = Reader(handshake);
+    if message.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let len = message.u24()?;
+    let mut hello = Reader(message.take(len)?);
+    // The legacy version and the random
+    hello.take(2 + 32)?;
+    let session_id_len = hello.u8()? as usize;
+    hello.take(session_id_len)?;
+    let cipher_suites_len = hello.u16()? as usize;
+    hello.take(cipher_suites_len)?;
+    let compression_methods_len = hello.u8()? as usize;
+    hello.take(compression_methods_len)?;
+    let extensions_len = hello.u16()? as usize;
+    let mut extensions = Reader(hello.take(extensions_len)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let mut extension = Reader(extensions.take(len)?);
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let list_len = extension.u16()? as usize;
+        let mut names = Reader(extension.take(list_len)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let len = names.u16()? as usize;
+            let name = names.take(len)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return std::str::from_utf8(name).ok().map(str::to_ascii_lowercase);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+/// Reads the big-endian fields of a TLS message
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let bytes = self.take(3)?;
+        Some(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize)
+    }
+}
@@ -0,0 +1,97 @@
+//! The client addresses that open the most connections through a tunnel, for spotting abuse
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// Client addresses counted per tunnel. A flood from more addresses than this, spoofed or not,
+/// replaces the least frequent ones instead of growing the map.
+pub const MAX_TRACKED_CLIENTS: usize = 256;
+
+/// Counts connections per client address with the space-saving algorithm: once the map is
+/// full, a new address takes over the entry with the lowest count and continues from there.
+/// Every address that opened more than `1 / MAX_TRACKED_CLIENTS` of the connections is
+/// guaranteed to be in the map, and counts are never too low.
+#[derive(Debug, Default)]
+pub(crate) struct TopClients {
+    /// Connections and overestimate by address
+    counts: HashMap<IpAddr, (u64, u64)>,
+}
+
+/// A client address and the connections it opened, as answered by
+/// `GET /tunnels/:id/top-clients`
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ClientCount {
+    pub ip: IpAddr,
+    pub connections: u64,
+    /// How much `connections` may be too high, from the addresses that the client replaced.
+    /// Zero means the count is exact.
+    pub overestimate: u64,
+}
+
+impl TopClients {
+    pub(crate) fn record(&mut self, ip: IpAddr) {
+        if let Some((connections, _)) = self.counts.get_mut(&ip) {
+            *connections += 1;
+            return;
+        }
+        if self.counts.len() < MAX_TRACKED_CLIENTS {
+            self.counts.insert(ip, (1, 0));
+            return;
+        }
+        // Full, so there is a least frequent address
+        let (&least, &(lowest, _)) = self
+            .counts
+            .iter()
+            .min_by_key(|(_, (connections, _))| *connections)
+            .unwrap();
+        self.counts.remove(&least);
+        self.counts.insert(ip, (lowest + 1, lowest));
+    }
+
+    /// The `limit` addresses with the most connections, most first
+    pub(crate) fn top(&self, limit: usize) -> Vec<ClientCount> {
+        let mut clients: Vec<_> = self
+            .counts
+            .iter()
+            .map(|(&ip, &(connections, overestimate))| ClientCount {
+                ip,
+                connections,
+                overestimate,
+            })
+            .collect();
+        clients.sort_by(|a, b| b.connections.cmp(&a.connections).then(a.ip.cmp(&b.ip)));
+        clients.truncate(limit);
+        clients
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TopClients, MAX_TRACKED_CLIENTS};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn top_clients_survive_a_flood() {
+        let heavy = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let mut top = TopClients::default();
+        for _ in 0..100 {
+            top.record(heavy);
+        }
+        // Every connection from a different address, like spoofed SYNs
+        for i in 0..10_000u32 {
+            top.record(IpAddr::from((0x0a00_0000 + i).to_be_bytes()));
+        }
+
+        let clients = top.top(MAX_TRACKED_CLIENTS + 1);
+        assert_eq!(clients.len(), MAX_TRACKED_CLIENTS);
+        assert_eq!(clients[0].ip, heavy);
+        assert_eq!(clients[0].connections, 100);
+        assert_eq!(clients[0].overestimate, 0);
+        // The flood only ever replaced its own addresses, whose counts stay upper bounds
+        assert!(clients[1..]
+            .iter()
+            .all(|client| client.connections > client.overestimate && client.connections < 100));
+        assert_eq!(top.top(1).len(), 1);
+    }
+}
@@ -0,0 +1,91 @@
+//! A proxy to run integration tests against, for crates that depend on this one. Only built
+//! with the `testing` feature.
+//!
+//! ```no_run
+//! # async fn example() {
+//! use proxima_centauri::client::CommandBuilder;
+//! use proxima_centauri::testing::TestProxy;
+//!
+//! let proxy = TestProxy::start();
+//! let id = uuid::Uuid::new_v4();
+//! proxy
+//!     .send(CommandBuilder::create(id, 5555, "127.0.0.1:6666".parse().unwrap()))
+//!     .await
+//!     .unwrap();
+//! # }
+//! ```
+
+use crate::client::{ClientError, CommandBuilder};
+use crate::{serve, ControlPlaneConfig, GlobalState, ProxyResponse};
+use p384::ecdsa::{SigningKey, VerifyingKey};
+use p384::elliptic_curve::rand_core::OsRng;
+use p384::pkcs8::{EncodePublicKey, LineEnding};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// The control plane of a proxy on an ephemeral port of `127.0.0.1`, which verifies commands
+/// with a key of its own. It runs until the Tokio runtime it was started in shuts down.
+#[derive(Debug)]
+pub struct TestProxy {
+    addr: SocketAddr,
+    url: String,
+    signing_key: SigningKey,
+    state: Arc<GlobalState>,
+}
+
+impl TestProxy {
+    /// Starts a proxy with the defaults of [`GlobalState::new`]. Has to be called from within a
+    /// Tokio runtime.
+    pub fn start() -> Self {
+        Self::start_with(|state| state)
+    }
+
+    /// Starts a proxy whose state is changed by `configure` first, like
+    /// `TestProxy::start_with(|state| state.with_max_tunnels(Some(1)))`. Has to be called from
+    /// within a Tokio runtime.
+    pub fn start_with(configure: impl FnOnce(GlobalState) -> GlobalState) -> Self {
+        let signing_key = SigningKey::random(&mut OsRng);
+        let verifying_key = VerifyingKey::from(&signing_key)
+            .to_public_key_pem(LineEnding::LF)
+            .expect("encoding the verifying key failed");
+        let state = Arc::new(configure(GlobalState::new(Some(verifying_key))));
+        let server = serve(
+            &SocketAddr::from(([127, 0, 0, 1], 0)),
+            state.clone(),
+            &ControlPlaneConfig::default(),
+        )
+        .expect("binding the control plane failed");
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        Self {
+            addr,
+            url: format!("http://{addr}"),
+            signing_key,
+            state,
+        }
+    }
+
+    /// Where the control plane listens
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The base URL of the control plane, like `http://127.0.0.1:38211`
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The key that the proxy verifies commands with
+    pub fn signing_key(&self) -> &SigningKey {
+        &self.signing_key
+    }
+
+    pub fn state(&self) -> &Arc<GlobalState> {
+        &self.state
+    }
+
+    /// Signs `command` with the key of the proxy and sends it.
+    pub async fn send(&self, command: CommandBuilder) -> Result<ProxyResponse, ClientError> {
+        command.sign(&self.signing_key).send(&self.url).await
+    }
+}
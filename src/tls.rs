@@ -0,0 +1,82 @@
+//! Mutual TLS for the control plane
+
+use crate::rejections::RejectReason;
+use crate::{app, ControlPlaneConfig, GlobalState};
+use hyper::server::conn::Http;
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use socket2::{SockRef, TcpKeepalive};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+/// Builds an acceptor that presents `cert` and only lets clients in that present a certificate
+/// signed by `client_ca`.
+pub fn acceptor(cert: &Path, key: &Path, client_ca: &Path) -> anyhow::Result<TlsAcceptor> {
+    let provider = Arc::new(ring::default_provider());
+
+    let mut roots = RootCertStore::empty();
+    for ca in CertificateDer::pem_file_iter(client_ca)? {
+        roots.add(ca?)?;
+    }
+    let verifier =
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?;
+
+    let certs = CertificateDer::pem_file_iter(cert)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key)?;
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Serves the control plane around `state` on `listener`, over TLS set up by `acceptor`.
+///
+/// Connections that fail the handshake, for example because they lack a valid client
+/// certificate, are closed without reaching the control plane.
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<GlobalState>,
+    config: &ControlPlaneConfig,
+    acceptor: TlsAcceptor,
+) -> io::Result<()> {
+    let rejections = state.rejections.clone();
+    let app = app(state, config);
+    let mut http = Http::new();
+    http.http1_only(!config.http2)
+        .http2_max_concurrent_streams(config.http2_max_concurrent_streams);
+
+    loop {
+        let (stream, client) = listener.accept().await?;
+        if let Some(keepalive) = config.tcp_keepalive {
+            let keepalive = TcpKeepalive::new().with_time(keepalive);
+            if let Err(err) = SockRef::from(&stream).set_tcp_keepalive(&keepalive) {
+                tracing::warn!("failed to enable keepalive for {client}: {err}");
+            }
+        }
+
+        let acceptor = acceptor.clone();
+        let rejections = rejections.clone();
+        let app = app.clone();
+        let http = http.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    rejections.reject(Some(client), RejectReason::TlsHandshake, &err);
+                    return;
+                }
+            };
+            if let Err(err) = http.serve_connection(stream, app).await {
+                tracing::debug!("control plane connection with {client} failed: {err}");
+            }
+        });
+    }
+}
@@ -0,0 +1,43 @@
+//! The control plane on a Unix domain socket
+
+use crate::{app, ControlPlaneConfig, GlobalState};
+use hyper::server::conn::Http;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io;
+use tokio::net::UnixListener;
+
+/// Listens on the socket at `path`, replacing a socket that a previous run left behind.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        // Anything else is left for `bind` to complain about
+        _ => {}
+    }
+    UnixListener::bind(path)
+}
+
+/// Serves the control plane around `state` on `listener`.
+pub async fn serve(
+    listener: UnixListener,
+    state: Arc<GlobalState>,
+    config: &ControlPlaneConfig,
+) -> io::Result<()> {
+    let app = app(state, config);
+    let mut http = Http::new();
+    http.http1_only(!config.http2)
+        .http2_max_concurrent_streams(config.http2_max_concurrent_streams);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        let app = app.clone();
+        let http = http.clone();
+        tokio::spawn(async move {
+            if let Err(err) = http.serve_connection(stream, app).await {
+                tracing::debug!("control plane connection on the unix socket failed: {err}");
+            }
+        });
+    }
+}
@@ -0,0 +1,371 @@
+//! The checks that a command has to pass before it is carried out. Every variant of
+//! [`Command`] has a validator of its own, which reports all problems with the command instead
+//! of only the first, so a client can fix them in one go.
+//!
+//! Checks that depend on the tunnel a command changes, like the destination of a `Modify` that
+//! only gives a port, are made while the command is carried out.
+
+use crate::{
+    validate_label, validate_source_address, ApiError, Command, Destination, ErrorCode,
+    GlobalState, MAX_ACCEPT_LOOPS, MAX_BUFFER_SIZE, MAX_DSCP, MAX_FAILOVER_DESTINATIONS,
+    MAX_FANOUT_DESTINATIONS, MAX_QUEUE_LEN, MAX_QUEUE_WORKERS, MAX_ROUTE_KEY_LEN,
+    MIRROR_GZIP_LEVELS,
+};
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+
+/// The problems found with a command so far
+#[derive(Debug, Default)]
+struct Violations(Vec<ApiError>);
+
+impl Violations {
+    fn check(&mut self, result: Result<(), ApiError>) {
+        if let Err(err) = result {
+            self.0.push(err);
+        }
+    }
+
+    fn invalid(&mut self, message: impl Into<String>) {
+        self.0
+            .push(ApiError::new(ErrorCode::InvalidCommand, message));
+    }
+}
+
+impl Command {
+    /// Runs the validator of the command. A single violation is returned as is, several as one
+    /// `invalid_command` error that lists them all.
+    pub(crate) fn validate(&self, state: &GlobalState) -> Result<(), ApiError> {
+        let mut violations = Violations::default();
+        match self {
+            Command::Create { .. } => create(self, state, &mut violations),
+            Command::CreateRange { .. } => create_range(self, &mut violations),
+            Command::CreateRouter { .. } => create_router(self, state, &mut violations),
+            Command::Modify { .. } => modify(self, state, &mut violations),
+            Command::TemporaryModify { .. } => temporary_modify(self, state, &mut violations),
+            Command::Handover { .. } => handover(self, &mut violations),
+            // Only depend on the tunnels and keys of the proxy
+            Command::Delete { .. }
+            | Command::Status
+            | Command::RotateKey { .. }
+            | Command::SelfCheck { .. } => {}
+        }
+        ApiError::from_violations(violations.0)
+    }
+}
+
+fn create(command: &Command, state: &GlobalState, violations: &mut Violations) {
+    let Command::Create {
+        incoming_port,
+        destination_port,
+        destination_ip,
+        destination_uds,
+        source_address,
+        buffer_size,
+        max_in_flight_bytes,
+        dscp,
+        max_lifetime_secs,
+        reuse_port,
+        log_sample_rate,
+        label,
+        backlog,
+        accept_loops,
+        sni_map,
+        response_destination,
+        mirror_to,
+        mirror_gzip_level,
+        fanout_destinations,
+        failover_destinations,
+        queue_len,
+        overflow_policy,
+        queue_workers,
+        pool,
+        ..
+    } = command
+    else {
+        return;
+    };
+    let destination =
+        Destination::from_fields(*destination_ip, *destination_port, destination_uds.clone())
+            .map_err(|message| violations.invalid(message))
+            .ok();
+    privileged_port(*incoming_port, state, violations);
+    if buffer_size.is_some_and(|size| size == 0 || size > MAX_BUFFER_SIZE) {
+        violations.invalid(format!(
+            "The `buffer_size` must be between 1 and {MAX_BUFFER_SIZE} bytes"
+        ));
+    }
+    if max_in_flight_bytes.is_some_and(|max| max == 0 || max > MAX_BUFFER_SIZE) {
+        violations.invalid(format!(
+            "The `max_in_flight_bytes` must be between 1 and {MAX_BUFFER_SIZE} bytes"
+        ));
+    }
+    if let (Some(source_address), Some(destination)) = (source_address, &destination) {
+        if let Err(message) = validate_source_address(*source_address, destination) {
+            violations.invalid(message);
+        }
+    }
+    if let Some(Err(message)) = label.as_deref().map(validate_label) {
+        violations.invalid(message);
+    }
+    if log_sample_rate.is_some_and(|rate| !(0.0..=1.0).contains(&rate)) {
+        violations.invalid("The `log_sample_rate` must be between 0 and 1");
+    }
+    if sni_map.keys().any(String::is_empty) {
+        violations.invalid("The server names of the `sni_map` may not be empty");
+    }
+    // Server names are matched in lowercase, so names that only differ in case would collide
+    let mut names = BTreeSet::new();
+    if let Some(name) = sni_map
+        .keys()
+        .find(|name| !names.insert(name.to_ascii_lowercase()))
+    {
+        violations.invalid(format!(
+            "The server name {name:?} is in the `sni_map` more than once, ignoring case"
+        ));
+    }
+    if let Some(source_address) = source_address {
+        for addr in sni_map.values() {
+            if let Err(message) = validate_source_address(*source_address, &Destination::Tcp(*addr))
+            {
+                violations.invalid(message);
+            }
+        }
+    }
+    if let Some(response_destination) = response_destination {
+        if !sni_map.is_empty() {
+            violations.invalid("A `response_destination` can't be combined with an `sni_map`");
+        }
+        if destination == Some(Destination::Tcp(*response_destination)) {
+            violations.invalid("The `response_destination` must differ from the destination");
+        }
+        if let Some(source_address) = source_address {
+            let response_destination = Destination::Tcp(*response_destination);
+            if let Err(message) = validate_source_address(*source_address, &response_destination) {
+                violations.invalid(message);
+            }
+        }
+    }
+    if fanout_destinations.len() > MAX_FANOUT_DESTINATIONS {
+        violations.invalid(format!(
+            "A tunnel can have at most {MAX_FANOUT_DESTINATIONS} `fanout_destinations`"
+        ));
+    }
+    if failover_destinations.len() > MAX_FAILOVER_DESTINATIONS {
+        violations.invalid(format!(
+            "A tunnel can have at most {MAX_FAILOVER_DESTINATIONS} `failover_destinations`"
+        ));
+    }
+    if !failover_destinations.is_empty() {
+        if *pool {
+            violations
+                .invalid("A tunnel with `failover_destinations` can't `pool` its connections");
+        }
+        if let Some(source_address) = source_address {
+            let failover = failover_destinations.iter().find_map(|addr| {
+                validate_source_address(*source_address, &Destination::Tcp(*addr)).err()
+            });
+            if let Some(message) = failover {
+                violations.invalid(message);
+            }
+        }
+    }
+    if *pool && response_destination.is_some() {
+        violations.invalid("A tunnel with a `response_destination` can't `pool` its connections");
+    }
+    if queue_len.is_some_and(|len| len == 0 || len > MAX_QUEUE_LEN) {
+        violations.invalid(format!(
+            "The `queue_len` must be between 1 and {MAX_QUEUE_LEN}"
+        ));
+    }
+    if queue_workers.is_some_and(|workers| workers == 0 || workers > MAX_QUEUE_WORKERS) {
+        violations.invalid(format!(
+            "The `queue_workers` must be between 1 and {MAX_QUEUE_WORKERS}"
+        ));
+    }
+    if queue_len.is_none() && (overflow_policy.is_some() || queue_workers.is_some()) {
+        violations
+            .invalid("The `overflow_policy` and `queue_workers` only apply with a `queue_len`");
+    }
+    if mirror_gzip_level.is_some_and(|level| !MIRROR_GZIP_LEVELS.contains(&level)) {
+        violations.invalid(format!(
+            "The `mirror_gzip_level` must be between {} and {}",
+            MIRROR_GZIP_LEVELS.start(),
+            MIRROR_GZIP_LEVELS.end()
+        ));
+    }
+    if mirror_gzip_level.is_some() && mirror_to.is_none() {
+        violations.invalid("The `mirror_gzip_level` only applies with a `mirror_to`");
+    }
+    if dscp.is_some_and(|dscp| dscp > MAX_DSCP) {
+        violations.invalid(format!("The `dscp` must be between 0 and {MAX_DSCP}"));
+    }
+    if *max_lifetime_secs == Some(0) {
+        violations.invalid("The `max_lifetime_secs` must be at least 1");
+    }
+    if *backlog == Some(0) {
+        violations.invalid("The `backlog` must be at least 1");
+    }
+    if accept_loops.is_some_and(|loops| loops == 0 || loops > MAX_ACCEPT_LOOPS) {
+        violations.invalid(format!(
+            "The `accept_loops` must be between 1 and {MAX_ACCEPT_LOOPS}"
+        ));
+    }
+    if reuse_port.unwrap_or(state.reuse_port) && cfg!(not(unix)) {
+        violations.invalid("`reuse_port` is not supported on this platform");
+    }
+
+    let extra: Vec<SocketAddr> = response_destination
+        .iter()
+        .chain(mirror_to)
+        .chain(fanout_destinations)
+        .chain(failover_destinations)
+        .chain(sni_map.values())
+        .copied()
+        .collect();
+    if let Some(destination) = &destination {
+        violations.check(state.check_destination(destination));
+    }
+    for addr in &extra {
+        violations.check(state.check_destination(&Destination::Tcp(*addr)));
+    }
+    let proxies = state.proxies.lock().unwrap();
+    let destination = match destination {
+        Some(Destination::Tcp(addr)) => Some(addr),
+        _ => None,
+    };
+    for addr in destination.into_iter().chain(extra) {
+        violations.check(state.check_self_loop(&proxies, Some(*incoming_port), addr));
+    }
+}
+
+/// Refuses an `incoming_port` below 1024 unless the proxy may bind those.
+fn privileged_port(incoming_port: u16, state: &GlobalState, violations: &mut Violations) {
+    if (1..1024).contains(&incoming_port) && !state.allow_privileged_ports {
+        violations.0.push(ApiError::new(
+            ErrorCode::PrivilegedPort,
+            format!(
+                "The `incoming_port` {incoming_port} is privileged. Start the proxy with \
+                 `--allow-privileged-ports` and the `CAP_NET_BIND_SERVICE` capability to use it"
+            ),
+        ));
+    }
+}
+
+fn create_range(command: &Command, violations: &mut Violations) {
+    let Command::CreateRange {
+        start_port,
+        count,
+        destination_port_start,
+        ..
+    } = command
+    else {
+        return;
+    };
+    let fits = |start: u16| *count > 0 && start.checked_add(count - 1).is_some();
+    if !fits(*start_port) || !fits(*destination_port_start) {
+        violations
+            .invalid("The `count` must be at least 1 and the ranges may not go past port 65535");
+    }
+}
+
+fn create_router(command: &Command, state: &GlobalState, violations: &mut Violations) {
+    let Command::CreateRouter {
+        incoming_port,
+        routes,
+        ..
+    } = command
+    else {
+        return;
+    };
+    privileged_port(*incoming_port, state, violations);
+    if routes.is_empty() {
+        violations.invalid("A router needs at least one route");
+    }
+    if routes
+        .keys()
+        .any(|key| key.is_empty() || key.len() > MAX_ROUTE_KEY_LEN)
+    {
+        violations.invalid(format!(
+            "Route keys must be between 1 and {MAX_ROUTE_KEY_LEN} bytes"
+        ));
+    }
+    for addr in routes.values() {
+        violations.check(state.check_destination(&Destination::Tcp(*addr)));
+    }
+    let proxies = state.proxies.lock().unwrap();
+    for addr in routes.values() {
+        violations.check(state.check_self_loop(&proxies, Some(*incoming_port), *addr));
+    }
+}
+
+fn modify(command: &Command, state: &GlobalState, violations: &mut Violations) {
+    let Command::Modify {
+        destination_port,
+        destination_ip,
+        destination_uds,
+        source_address,
+        label,
+        ..
+    } = command
+    else {
+        return;
+    };
+    if let Some(Err(message)) = label
+        .as_ref()
+        .and_then(Option::as_deref)
+        .map(validate_label)
+    {
+        violations.invalid(message);
+    }
+    let destination = match (destination_ip, destination_port, destination_uds) {
+        (None, None, None) => {
+            violations.invalid(
+                "A `modify` needs a `destination_ip`, a `destination_port` or a \
+                 `destination_uds`",
+            );
+            return;
+        }
+        // The other half comes from the tunnel
+        (ip, port, None) if ip.is_some() != port.is_some() => return,
+        (ip, port, uds) => match Destination::from_fields(*ip, *port, uds.clone()) {
+            Ok(destination) => destination,
+            Err(message) => return violations.invalid(message),
+        },
+    };
+    if let Some(Some(source_address)) = source_address {
+        if let Err(message) = validate_source_address(*source_address, &destination) {
+            violations.invalid(message);
+        }
+    }
+    violations.check(state.check_destination(&destination));
+    if let Destination::Tcp(addr) = destination {
+        let proxies = state.proxies.lock().unwrap();
+        violations.check(state.check_self_loop(&proxies, None, addr));
+    }
+}
+
+fn temporary_modify(command: &Command, state: &GlobalState, violations: &mut Violations) {
+    let Command::TemporaryModify {
+        destination,
+        revert_after_secs,
+        ..
+    } = command
+    else {
+        return;
+    };
+    if *revert_after_secs == 0 {
+        violations.invalid("The `revert_after_secs` must be at least 1");
+    }
+    violations.check(state.check_destination(&Destination::Tcp(*destination)));
+    let proxies = state.proxies.lock().unwrap();
+    violations.check(state.check_self_loop(&proxies, None, *destination));
+}
+
+fn handover(command: &Command, violations: &mut Violations) {
+    let Command::Handover { from_id, to_id } = command else {
+        return;
+    };
+    if from_id == to_id {
+        violations.invalid("A tunnel can't hand its port over to itself");
+    }
+}
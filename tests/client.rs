@@ -0,0 +1,110 @@
+use p384::ecdsa::{SigningKey, VerifyingKey};
+use p384::elliptic_curve::rand_core::OsRng;
+use p384::pkcs8::{EncodePublicKey, LineEnding};
+use proxima_centauri::client::{ClientError, CommandBuilder};
+use proxima_centauri::{serve, ControlPlaneConfig, ErrorCode, GlobalState, ProxyResponse};
+use std::sync::Arc;
+
+/// Starts the control plane on an ephemeral port, verifying commands with `key`, and returns
+/// its URL.
+fn start_proxy(key: &SigningKey) -> String {
+    let verifying_key = VerifyingKey::from(key)
+        .to_public_key_pem(LineEnding::LF)
+        .unwrap();
+    let server = serve(
+        &"127.0.0.1:0".parse().unwrap(),
+        Arc::new(GlobalState::new(Some(verifying_key))),
+        &ControlPlaneConfig::default(),
+    )
+    .unwrap();
+    let url = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+    url
+}
+
+#[tokio::test]
+async fn send_built_commands() {
+    let key = SigningKey::random(&mut OsRng);
+    let url = start_proxy(&key);
+    let incoming_port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let id = uuid::Uuid::new_v4();
+
+    let response = CommandBuilder::create(id, incoming_port, "127.0.0.1:1".parse().unwrap())
+        .label("built")
+        .sign(&key)
+        .send(&url)
+        .await
+        .unwrap();
+    assert!(
+        matches!(response, ProxyResponse::Message(_)),
+        "{response:?}"
+    );
+
+    let response = CommandBuilder::modify(id, "127.0.0.1:2".parse().unwrap())
+        .sign(&key)
+        .send(&url)
+        .await
+        .unwrap();
+    assert!(
+        matches!(response, ProxyResponse::Modified { generation: 1, .. }),
+        "{response:?}"
+    );
+
+    let ProxyResponse::Status { tunnels, .. } = CommandBuilder::status()
+        .sign(&key)
+        .send(&url)
+        .await
+        .unwrap()
+    else {
+        panic!("expected a status");
+    };
+    assert_eq!(tunnels[&id].incoming_port, incoming_port);
+    // The `modify` left the label out
+    assert_eq!(tunnels[&id].label.as_deref(), Some("built"));
+
+    CommandBuilder::modify(id, "127.0.0.1:2".parse().unwrap())
+        .clear_label()
+        .sign(&key)
+        .send(&url)
+        .await
+        .unwrap();
+    let ProxyResponse::Status { tunnels, .. } = CommandBuilder::status()
+        .sign(&key)
+        .send(&url)
+        .await
+        .unwrap()
+    else {
+        panic!("expected a status");
+    };
+    assert_eq!(tunnels[&id].label, None);
+
+    CommandBuilder::delete(id)
+        .sign(&key)
+        .send(&url)
+        .await
+        .unwrap();
+    let err = CommandBuilder::delete(id)
+        .sign(&key)
+        .send(&url)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(&err, ClientError::Api { error, .. } if error.code == ErrorCode::NotFound),
+        "{err}"
+    );
+
+    // Unsigned commands are refused by a proxy with a verifying key
+    let err = CommandBuilder::status()
+        .unsigned()
+        .send(&url)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(&err, ClientError::Api { error, .. } if error.code == ErrorCode::InvalidSignature),
+        "{err}"
+    );
+}
@@ -0,0 +1,40 @@
+#![cfg(feature = "testing")]
+
+use proxima_centauri::client::CommandBuilder;
+use proxima_centauri::testing::TestProxy;
+use proxima_centauri::ProxyResponse;
+
+#[tokio::test]
+async fn send_commands_to_test_proxy() {
+    let proxy = TestProxy::start_with(|state| state.with_max_tunnels(Some(1)));
+    let incoming_port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let id = uuid::Uuid::new_v4();
+
+    let response = proxy
+        .send(CommandBuilder::create(
+            id,
+            incoming_port,
+            "127.0.0.1:1".parse().unwrap(),
+        ))
+        .await
+        .unwrap();
+    assert!(
+        matches!(response, ProxyResponse::Message(_)),
+        "{response:?}"
+    );
+    let ProxyResponse::Status {
+        tunnels,
+        max_tunnels,
+        ..
+    } = proxy.send(CommandBuilder::status()).await.unwrap()
+    else {
+        panic!("not a status");
+    };
+    assert!(tunnels.contains_key(&id));
+    assert_eq!(max_tunnels, Some(1));
+    assert!(proxy.url().ends_with(&proxy.addr().port().to_string()));
+}
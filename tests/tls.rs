@@ -0,0 +1,125 @@
+use proxima_centauri::{tls, ControlPlaneConfig, GlobalState};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+use rustls::crypto::ring;
+use rustls::pki_types::{PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsConnector;
+
+struct Pki {
+    ca: Certificate,
+    ca_key: KeyPair,
+}
+
+impl Pki {
+    fn new() -> Self {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = params.self_signed(&ca_key).unwrap();
+        Self { ca, ca_key }
+    }
+
+    fn issue(&self, name: &str) -> (Certificate, KeyPair) {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![name.to_string()])
+            .unwrap()
+            .signed_by(&key, &self.ca, &self.ca_key)
+            .unwrap();
+        (cert, key)
+    }
+}
+
+/// Starts the control plane over mutual TLS, with certificates issued by `pki`.
+async fn start_tls_proxy(pki: &Pki) -> SocketAddr {
+    let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    std::fs::create_dir(&dir).unwrap();
+    let write = |name: &str, pem: String| -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, pem).unwrap();
+        path
+    };
+    let (cert, key) = pki.issue("localhost");
+    let acceptor = tls::acceptor(
+        &write("cert.pem", cert.pem()),
+        &write("key.pem", key.serialize_pem()),
+        &write("ca.pem", pki.ca.pem()),
+    )
+    .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let state = Arc::new(GlobalState::new(None::<String>));
+    tokio::spawn(async move {
+        tls::serve(listener, state, &ControlPlaneConfig::default(), acceptor).await
+    });
+    addr
+}
+
+/// Requests `GET /time` over TLS, authenticating with a certificate for `client` issued by
+/// `issuer` if given. Returns the raw response, or `None` if the connection failed.
+async fn get_time(proxy: SocketAddr, pki: &Pki, client: Option<(&Pki, &str)>) -> Option<String> {
+    let mut roots = RootCertStore::empty();
+    roots.add(pki.ca.der().clone()).unwrap();
+    let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+    let config = match client {
+        Some((issuer, name)) => {
+            let (cert, key) = issuer.issue(name);
+            let key = PrivateKeyDer::try_from(key.serialize_der()).unwrap();
+            builder
+                .with_client_auth_cert(vec![cert.der().clone()], key)
+                .unwrap()
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let stream = TcpStream::connect(proxy).await.unwrap();
+    let server_name = ServerName::try_from("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(config))
+        .connect(server_name, stream)
+        .await
+        .ok()?;
+    stream
+        .write_all(b"GET /time HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+        .await
+        .ok()?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.ok()?;
+    Some(response)
+}
+
+#[tokio::test]
+async fn accept_client_certificate() {
+    let pki = Pki::new();
+    let proxy = start_tls_proxy(&pki).await;
+
+    let response = get_time(proxy, &pki, Some((&pki, "client"))).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+}
+
+#[tokio::test]
+async fn reject_missing_client_certificate() {
+    let pki = Pki::new();
+    let proxy = start_tls_proxy(&pki).await;
+
+    let response = get_time(proxy, &pki, None).await;
+    assert!(!response.is_some_and(|response| response.starts_with("HTTP")));
+}
+
+#[tokio::test]
+async fn reject_client_certificate_from_other_ca() {
+    let pki = Pki::new();
+    let proxy = start_tls_proxy(&pki).await;
+    let other = Pki::new();
+
+    let response = get_time(proxy, &pki, Some((&other, "client"))).await;
+    assert!(!response.is_some_and(|response| response.starts_with("HTTP")));
+}
@@ -0,0 +1,3997 @@
+use async_compression::tokio::bufread::GzipDecoder;
+use hyper::header::{self, HeaderValue};
+use hyper::{Body, Client, Method, Request, StatusCode};
+use p384::ecdsa::{SigningKey, VerifyingKey};
+use p384::elliptic_curve::rand_core::OsRng;
+use p384::pkcs8::{EncodePublicKey, LineEnding};
+use proxima_centauri::{
+    serve, verify_response, ControlPlaneConfig, GlobalState, Heartbeat, ProxyCommand,
+    ProxyResponse, ResponseVerifyError, UnreachablePolicy, FANOUT_WRITE_TIMEOUT,
+    MIRROR_WRITE_TIMEOUT,
+};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Starts the control plane on an ephemeral port, verifying commands with `key`.
+fn start_proxy(key: &SigningKey) -> SocketAddr {
+    start_proxy_with(proxy_state(key))
+}
+
+/// The state of a proxy that verifies commands with `key`, to customize before serving it.
+fn proxy_state(key: &SigningKey) -> GlobalState {
+    let verifying_key = VerifyingKey::from(key)
+        .to_public_key_pem(LineEnding::LF)
+        .unwrap();
+    GlobalState::new(Some(verifying_key))
+}
+
+/// Starts the control plane for `state` on an ephemeral port.
+fn start_proxy_with(state: GlobalState) -> SocketAddr {
+    let server = serve(
+        &"127.0.0.1:0".parse().unwrap(),
+        Arc::new(state),
+        &ControlPlaneConfig::default(),
+    )
+    .unwrap();
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+/// Starts an echo server in the style of `ping-server` that counts its connections.
+async fn start_echo_server() -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                let (mut si, mut so) = socket.split();
+                io::copy(&mut si, &mut so).await?;
+                so.shutdown().await
+            });
+        }
+    });
+    (addr, connections)
+}
+
+/// Reads the body of a streamed `Status` response into the JSON of a single `Status`.
+fn read_status(body: &[u8]) -> serde_json::Value {
+    serde_json::to_value(ProxyResponse::from_status_lines(body).unwrap()).unwrap()
+}
+
+/// Finds a port that is currently free to use as `incoming_port`.
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Finds `count` consecutive ports that are currently free, and returns the first.
+fn free_ports(count: u16) -> u16 {
+    loop {
+        let start = free_port();
+        let free = (1..count).all(|i| {
+            start
+                .checked_add(i)
+                .is_some_and(|port| std::net::TcpListener::bind(("127.0.0.1", port)).is_ok())
+        });
+        if free {
+            return start;
+        }
+    }
+}
+
+/// Signs `command` the way the proxy expects and posts it to `/command`.
+async fn send_command(proxy: SocketAddr, key: &SigningKey, command: &Value) -> StatusCode {
+    let request = command_request(proxy, key, command);
+    Client::new().request(request).await.unwrap().status()
+}
+
+/// A request that posts `command`, like `json!({"status": null})`, to `/command`, signed the
+/// way the proxy expects. The signature covers the command as the proxy serializes it again, so
+/// it doesn't depend on the order of the fields in `command`.
+fn command_request(proxy: SocketAddr, key: &SigningKey, command: &Value) -> Request<Body> {
+    let command: ProxyCommand = serde_json::from_value(command.clone()).unwrap();
+    post_command(proxy, serde_json::to_string(&command.sign(key)).unwrap())
+}
+
+/// `command` with `fields`, like `json!({"ttl_secs": 1})`, added to the fields of its variant.
+fn with_fields(mut command: Value, fields: Value) -> Value {
+    let (_, variant) = command.as_object_mut().unwrap().iter_mut().next().unwrap();
+    let fields = fields.as_object().unwrap().clone();
+    variant.as_object_mut().unwrap().extend(fields);
+    command
+}
+
+/// A request that posts `body` to `/command` as it is, for bodies that aren't valid commands.
+fn post_command(proxy: SocketAddr, body: impl Into<Body>) -> Request<Body> {
+    Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{proxy}/command"))
+        .header("content-type", "application/json")
+        .body(body.into())
+        .unwrap()
+}
+
+/// A `GET` request for `path`, authenticated with a `status` command signed with `key`.
+fn read_request(proxy: SocketAddr, key: &SigningKey, path: &str) -> Request<Body> {
+    let mut request = Request::get(format!("http://{proxy}{path}"));
+    for (name, value) in proxima_centauri::client::CommandBuilder::status()
+        .sign(key)
+        .read_headers()
+    {
+        request = request.header(name, value);
+    }
+    request.body(Body::empty()).unwrap()
+}
+
+/// Gets `path` from the control plane, signed with `key`, and parses the JSON response.
+async fn get(proxy: SocketAddr, key: &SigningKey, path: &str) -> (StatusCode, serde_json::Value) {
+    let response = Client::new()
+        .request(read_request(proxy, key, path))
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+async fn echo(port: u16, message: &[u8]) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    stream.write_all(message).await.unwrap();
+    let mut buf = vec![0; message.len()];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, message);
+}
+
+/// The first bytes a TLS client sends to `server_name`.
+fn client_hello(server_name: &str) -> Vec<u8> {
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(rustls::RootCertStore::empty())
+    .with_no_client_auth();
+    let server_name = server_name.to_string().try_into().unwrap();
+    let mut connection = rustls::ClientConnection::new(Arc::new(config), server_name).unwrap();
+    let mut hello = Vec::new();
+    connection.write_tls(&mut hello).unwrap();
+    hello
+}
+
+#[tokio::test]
+async fn create_and_modify_tunnel() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (first, first_connections) = start_echo_server().await;
+    let (second, second_connections) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": first.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+    echo(incoming_port, b"hello first").await;
+    assert_eq!(first_connections.load(Ordering::SeqCst), 1);
+
+    let modify = json!({
+        "modify": {"destination_port": second.port(), "destination_ip": "127.0.0.1", "id": id},
+    });
+    let response = Client::new()
+        .request(command_request(proxy, &key, &modify))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["Modified"]["generation"], 1);
+    echo(incoming_port, b"hello second").await;
+    assert_eq!(first_connections.load(Ordering::SeqCst), 1);
+    assert_eq!(second_connections.load(Ordering::SeqCst), 1);
+
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    assert_eq!(body["Status"]["tunnels"][id.to_string()]["generation"], 1);
+
+    let delete = json!({"delete": {"id": id}});
+    assert_eq!(
+        send_command(proxy, &key, &delete).await,
+        StatusCode::ACCEPTED
+    );
+}
+
+#[tokio::test]
+async fn modify_only_the_port() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (first, _) = start_echo_server().await;
+    let (second, second_connections) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": first.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+            "source_address": "127.0.0.1",
+            "ttl_secs": 600,
+            "label": "db",
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+
+    // The address of the destination is kept, like everything else that is left out
+    let modify = json!({"modify": {"destination_port": second.port(), "id": id}});
+    let response = Client::new()
+        .request(command_request(proxy, &key, &modify))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["Modified"]["destination"]["tcp"], second.to_string());
+    echo(incoming_port, b"hello second").await;
+    assert_eq!(second_connections.load(Ordering::SeqCst), 1);
+    let (_, body) = get(proxy, &key, &format!("/tunnels/{id}/config")).await;
+    let config = &body["Config"]["config"];
+    assert_eq!(config["label"], "db");
+    assert_eq!(config["source_address"], "127.0.0.1");
+    assert!(config["ttl_remaining_secs"].as_u64().unwrap() > 590);
+
+    let modify = json!({"modify": {"id": id, "label": "db"}});
+    assert_eq!(
+        send_command(proxy, &key, &modify).await,
+        StatusCode::BAD_REQUEST
+    );
+    let modify = json!({"modify": {"destination_port": second.port(), "id": uuid::Uuid::new_v4()}});
+    assert_eq!(
+        send_command(proxy, &key, &modify).await,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn hand_over_port_to_another_tunnel() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (old_destination, _) = start_echo_server().await;
+    let (new_destination, new_connections) = start_echo_server().await;
+    let [old_port, new_port] = [free_port(), free_port()];
+    let [old_id, new_id] = [uuid::Uuid::new_v4(), uuid::Uuid::new_v4()];
+    for (port, destination, id) in [
+        (old_port, old_destination, old_id),
+        (new_port, new_destination, new_id),
+    ] {
+        let create = json!({
+            "create": {
+                "incoming_port": port,
+                "destination_port": destination.port(),
+                "destination_ip": "127.0.0.1",
+                "id": id,
+            },
+        });
+        assert_eq!(
+            send_command(proxy, &key, &create).await,
+            StatusCode::ACCEPTED
+        );
+    }
+    let mut established = TcpStream::connect(("127.0.0.1", old_port)).await.unwrap();
+    established.write_all(b"old").await.unwrap();
+    let mut buf = [0; 3];
+    established.read_exact(&mut buf).await.unwrap();
+
+    let handover = json!({"handover": {"from_id": old_id, "to_id": new_id}});
+    assert_eq!(
+        send_command(proxy, &key, &handover).await,
+        StatusCode::ACCEPTED
+    );
+    // New connections to the port go to the other tunnel, which no longer listens on its own
+    echo(old_port, b"taken over").await;
+    assert_eq!(new_connections.load(Ordering::SeqCst), 1);
+    tokio::time::sleep(time::Duration::from_millis(100)).await;
+    assert!(TcpStream::connect(("127.0.0.1", new_port)).await.is_err());
+    // The established connection still goes to the old destination
+    established.write_all(b"old").await.unwrap();
+    established.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"old");
+
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    let tunnels = &body["Status"]["tunnels"];
+    assert_eq!(tunnels[old_id.to_string()]["state"], "draining");
+    assert_eq!(tunnels[new_id.to_string()]["incoming_port"], old_port);
+    // Sharing the port with a draining tunnel is fine
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"self_check": {}})))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let ProxyResponse::SelfCheck {
+        findings,
+        tunnel_count,
+    } = serde_json::from_slice(&body).unwrap()
+    else {
+        panic!("not a self check");
+    };
+    assert!(findings.is_empty(), "{findings:?}");
+    assert_eq!(tunnel_count, 2);
+
+    // The drained tunnel goes away without taking the port along
+    drop(established);
+    tokio::time::sleep(time::Duration::from_millis(100)).await;
+    echo(old_port, b"still taken over").await;
+    let create = json!({
+        "create": {
+            "incoming_port": old_port,
+            "destination_port": 1,
+            "destination_ip": "127.0.0.1",
+            "id": uuid::Uuid::new_v4(),
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::CONFLICT
+    );
+    assert_eq!(
+        send_command(proxy, &key, &handover).await,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn reject_unsigned_command() {
+    let key = SigningKey::random(&mut OsRng);
+    let other_key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let create = json!({
+        "create": {
+            "incoming_port": free_port(),
+            "destination_port": 1,
+            "destination_ip": "127.0.0.1",
+            "id": uuid::Uuid::new_v4(),
+        },
+    });
+
+    assert_eq!(
+        send_command(proxy, &other_key, &create).await,
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+#[tokio::test]
+async fn limit_amount_of_tunnels() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy_with(proxy_state(&key).with_max_tunnels(Some(1)));
+    let create = || {
+        json!({
+            "create": {
+                "incoming_port": free_port(),
+                "destination_port": 1,
+                "destination_ip": "127.0.0.1",
+                "id": uuid::Uuid::new_v4(),
+            },
+        })
+    };
+
+    assert_eq!(
+        send_command(proxy, &key, &create()).await,
+        StatusCode::ACCEPTED
+    );
+    assert_eq!(
+        send_command(proxy, &key, &create()).await,
+        StatusCode::INSUFFICIENT_STORAGE
+    );
+}
+
+#[tokio::test]
+async fn limit_connections_across_tunnels() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy_with(proxy_state(&key).with_max_connections(Some(1)));
+    let (destination, _) = start_echo_server().await;
+    let ports = [free_port(), free_port()];
+    for incoming_port in ports {
+        let create = json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": destination.port(),
+                "destination_ip": "127.0.0.1",
+                "id": uuid::Uuid::new_v4(),
+            },
+        });
+        assert_eq!(
+            send_command(proxy, &key, &create).await,
+            StatusCode::ACCEPTED
+        );
+    }
+
+    let mut first = TcpStream::connect(("127.0.0.1", ports[0])).await.unwrap();
+    first.write_all(b"ping").await.unwrap();
+    let mut buf = [0; 4];
+    first.read_exact(&mut buf).await.unwrap();
+
+    // The limit covers the other tunnel as well
+    let mut second = TcpStream::connect(("127.0.0.1", ports[1])).await.unwrap();
+    let _ = second.write_all(b"ping").await;
+    assert!(matches!(second.read(&mut buf).await, Ok(0) | Err(_)));
+
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    assert_eq!(body["Status"]["active_connections"], 1);
+    assert_eq!(body["Status"]["max_connections"], 1);
+
+    let response = Client::new()
+        .request(read_request(proxy, &key, "/metrics"))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        metrics.contains("proxima_rejected_connections_total{reason=\"connection_limit\"} 1\n"),
+        "{metrics}"
+    );
+
+    // Closing the first connection makes room again
+    drop(first);
+    tokio::time::sleep(time::Duration::from_millis(100)).await;
+    echo(ports[1], b"room again").await;
+}
+
+#[tokio::test]
+async fn send_overflow_response_at_capacity() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy_with(proxy_state(&key).with_max_connections(Some(1)));
+    let (destination, _) = start_echo_server().await;
+    let incoming_port = free_port();
+    let overflow_response = b"HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n";
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": destination.port(),
+            "destination_ip": "127.0.0.1",
+            "id": uuid::Uuid::new_v4(),
+            "overflow_response": overflow_response.to_vec(),
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+
+    let mut first = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    first.write_all(b"ping").await.unwrap();
+    let mut buf = [0; 4];
+    first.read_exact(&mut buf).await.unwrap();
+
+    let mut second = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    second.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, overflow_response);
+}
+
+#[tokio::test]
+async fn revert_temporary_modify() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (first, first_connections) = start_echo_server().await;
+    let (second, second_connections) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": first.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+    let temporary_modify = json!({
+        "temporary_modify": {"id": id, "destination": second, "revert_after_secs": 1},
+    });
+    let status = || async {
+        let response = Client::new()
+            .request(command_request(proxy, &key, &json!({"status": null})))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        read_status(&body)["Status"]["tunnels"][id.to_string()].clone()
+    };
+
+    assert_eq!(
+        send_command(proxy, &key, &temporary_modify).await,
+        StatusCode::ACCEPTED
+    );
+    echo(incoming_port, b"canary").await;
+    assert_eq!(second_connections.load(Ordering::SeqCst), 1);
+    let tunnel = status().await;
+    assert_eq!(
+        tunnel["pending_revert"]["destination"]["tcp"],
+        first.to_string()
+    );
+    assert!(tunnel["pending_revert"]["remaining_secs"].as_u64().unwrap() <= 1);
+
+    tokio::time::sleep(time::Duration::from_millis(1200)).await;
+    echo(incoming_port, b"reverted").await;
+    assert_eq!(first_connections.load(Ordering::SeqCst), 1);
+    let tunnel = status().await;
+    assert!(tunnel["pending_revert"].is_null());
+    assert_eq!(tunnel["generation"], 2);
+
+    // A normal modify makes the destination last
+    assert_eq!(
+        send_command(proxy, &key, &temporary_modify).await,
+        StatusCode::ACCEPTED
+    );
+    let modify = json!({
+        "modify": {"destination_port": second.port(), "destination_ip": "127.0.0.1", "id": id},
+    });
+    assert_eq!(
+        send_command(proxy, &key, &modify).await,
+        StatusCode::ACCEPTED
+    );
+    assert!(status().await["pending_revert"].is_null());
+    tokio::time::sleep(time::Duration::from_millis(1200)).await;
+    echo(incoming_port, b"kept").await;
+    assert_eq!(first_connections.load(Ordering::SeqCst), 1);
+    assert_eq!(second_connections.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn expire_tunnel_after_ttl() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let incoming_port = free_port();
+    let create = |ttl_secs: Option<u64>| {
+        json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": 1,
+                "destination_ip": "127.0.0.1",
+                "id": uuid::Uuid::new_v4(),
+                "ttl_secs": ttl_secs,
+            },
+        })
+    };
+
+    assert_eq!(
+        send_command(proxy, &key, &create(Some(1))).await,
+        StatusCode::ACCEPTED
+    );
+    assert_eq!(
+        send_command(proxy, &key, &create(None)).await,
+        StatusCode::CONFLICT
+    );
+
+    // The port is free again once the tunnel has expired
+    tokio::time::sleep(time::Duration::from_millis(1500)).await;
+    assert_eq!(
+        send_command(proxy, &key, &create(None)).await,
+        StatusCode::ACCEPTED
+    );
+}
+
+#[tokio::test]
+async fn keep_ttl_unless_modified() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": 1,
+            "destination_ip": "127.0.0.1",
+            "id": id,
+            "ttl_secs": 600,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+    let ttl_after = |ttl: Value| {
+        let modify = with_fields(json!({"modify": {"destination_port": 2, "id": id}}), ttl);
+        let key = &key;
+        async move {
+            assert_eq!(
+                send_command(proxy, key, &modify).await,
+                StatusCode::ACCEPTED
+            );
+            let response = Client::new()
+                .request(command_request(proxy, key, &json!({"status": null})))
+                .await
+                .unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            read_status(&body)["Status"]["tunnels"][id.to_string()]["ttl_remaining_secs"].as_u64()
+        }
+    };
+
+    let remaining = ttl_after(json!({})).await.unwrap();
+    assert!((590..=600).contains(&remaining), "{remaining}");
+    let remaining = ttl_after(json!({"ttl_secs": 60})).await.unwrap();
+    assert!((50..=60).contains(&remaining), "{remaining}");
+    assert_eq!(ttl_after(json!({"ttl_secs": null})).await, None);
+}
+
+#[tokio::test]
+async fn keep_source_address_unless_modified() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (first, _) = start_echo_server().await;
+    let (second, second_connections) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": first.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+            "source_address": "127.0.0.1",
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+    let modify = |fields: Value| with_fields(json!({"modify": {"id": id}}), fields);
+    let source_address = || async {
+        let (_, body) = get(proxy, &key, &format!("/tunnels/{id}/config")).await;
+        body["Config"]["config"]["source_address"].clone()
+    };
+
+    let port_only = modify(json!({"destination_port": second.port()}));
+    assert_eq!(
+        send_command(proxy, &key, &port_only).await,
+        StatusCode::ACCEPTED
+    );
+    assert_eq!(source_address().await, "127.0.0.1");
+    echo(incoming_port, b"from the same address").await;
+    assert_eq!(second_connections.load(Ordering::SeqCst), 1);
+
+    // The kept address has to fit the new destination
+    let ipv6 = modify(json!({"destination_ip": "::1"}));
+    assert_eq!(
+        send_command(proxy, &key, &ipv6).await,
+        StatusCode::BAD_REQUEST
+    );
+    let cleared = modify(json!({"destination_port": first.port(), "source_address": null}));
+    assert_eq!(
+        send_command(proxy, &key, &cleared).await,
+        StatusCode::ACCEPTED
+    );
+    assert!(source_address().await.is_null());
+}
+
+#[tokio::test]
+async fn keep_connections_on_drain_on_modify() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (first, first_connections) = start_echo_server().await;
+    let (second, second_connections) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": first.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+    let mut established = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    established.write_all(b"before").await.unwrap();
+    let mut buf = [0; 6];
+    established.read_exact(&mut buf).await.unwrap();
+
+    let modify = json!({
+        "modify": {
+            "destination_port": second.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+            "drain_on_modify": true,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &modify).await,
+        StatusCode::ACCEPTED
+    );
+    echo(incoming_port, b"new connection").await;
+
+    // The established connection still goes to the first destination
+    established.write_all(b"after!").await.unwrap();
+    established.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"after!");
+    assert_eq!(first_connections.load(Ordering::SeqCst), 1);
+    assert_eq!(second_connections.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn control_plane_over_http2() {
+    let config = ControlPlaneConfig {
+        http2: true,
+        http2_max_concurrent_streams: Some(16),
+        ..Default::default()
+    };
+    let server = serve(
+        &"127.0.0.1:0".parse().unwrap(),
+        Arc::new(GlobalState::new(None::<String>)),
+        &config,
+    )
+    .unwrap();
+    let addr = server.local_addr();
+    tokio::spawn(server);
+
+    let client = Client::builder().http2_only(true).build_http::<Body>();
+    let response = client
+        .get(format!("http://{addr}/time").parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.version(), hyper::Version::HTTP_2);
+}
+
+#[tokio::test]
+async fn customize_banner() {
+    let root = |banner: Option<&str>| {
+        let config = ControlPlaneConfig {
+            banner: banner.map(str::to_string),
+            ..Default::default()
+        };
+        let server = serve(
+            &"127.0.0.1:0".parse().unwrap(),
+            Arc::new(GlobalState::new(None::<String>)),
+            &config,
+        )
+        .unwrap();
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        async move {
+            let response = Client::new()
+                .get(format!("http://{addr}/").parse().unwrap())
+                .await
+                .unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (status, body)
+        }
+    };
+
+    assert_eq!(
+        root(Some("Hello, World!")).await,
+        (StatusCode::OK, "Hello, World!".into())
+    );
+    assert_eq!(root(Some("")).await, (StatusCode::OK, "".into()));
+    let (status, body) = root(None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"]["code"], "not_found");
+}
+
+#[tokio::test]
+async fn list_tunnel_connections() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": destination.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+            "buffer_size": 4096,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+    let mut stream = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+
+    let (status, body) = get(proxy, &key, &format!("/tunnels/{id}/connections")).await;
+    assert_eq!(status, StatusCode::OK);
+    let connections = body["Connections"]["connections"].as_array().unwrap();
+    assert_eq!(connections.len(), 1);
+    assert_eq!(
+        connections[0]["client"],
+        stream.local_addr().unwrap().to_string()
+    );
+    assert_eq!(
+        connections[0]["destination"]["tcp"],
+        destination.to_string()
+    );
+    assert_eq!(connections[0]["bytes_sent"], 5);
+    assert_eq!(connections[0]["bytes_received"], 5);
+    // Along with the command that created the tunnel
+    assert_eq!(body["Connections"]["created_with"], create);
+
+    let (status, body) = get(proxy, &key, "/diagnostics").await;
+    assert_eq!(status, StatusCode::OK);
+    let diagnostics = &body["Diagnostics"]["tunnels"][id.to_string()];
+    assert_eq!(diagnostics["active_connections"], 1);
+    if cfg!(unix) {
+        assert_eq!(diagnostics["estimated_fds"], 3);
+    }
+
+    // The connection is gone from the list once it is closed
+    drop(stream);
+    tokio::time::sleep(time::Duration::from_millis(100)).await;
+    let (_, body) = get(proxy, &key, &format!("/tunnels/{id}/connections")).await;
+    assert_eq!(body["Connections"]["connections"], serde_json::json!([]));
+
+    let (status, _) = get(
+        proxy,
+        &key,
+        &format!("/tunnels/{}/connections", uuid::Uuid::new_v4()),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn queue_connections_with_overflow_policy() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let metrics = || async {
+        let response = Client::new()
+            .request(read_request(proxy, &key, "/metrics"))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+
+    for (policy, rejected) in [("reject_new", 1), ("drop_oldest", 2)] {
+        let incoming_port = free_port();
+        let id = uuid::Uuid::new_v4();
+        let create = json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": destination.port(),
+                "destination_ip": "127.0.0.1",
+                "id": id,
+                "queue_len": 1,
+                "overflow_policy": policy,
+                "queue_workers": 1,
+            },
+        });
+        assert_eq!(
+            send_command(proxy, &key, &create).await,
+            StatusCode::ACCEPTED
+        );
+
+        // The only worker is busy with the first connection, so the second one is queued
+        let mut first = TcpStream::connect(("127.0.0.1", incoming_port))
+            .await
+            .unwrap();
+        first.write_all(b"first").await.unwrap();
+        let mut buf = [0; 5];
+        first.read_exact(&mut buf).await.unwrap();
+        let mut second = TcpStream::connect(("127.0.0.1", incoming_port))
+            .await
+            .unwrap();
+        second.write_all(b"second").await.unwrap();
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        let mut third = TcpStream::connect(("127.0.0.1", incoming_port))
+            .await
+            .unwrap();
+        third.write_all(b"third").await.unwrap();
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+
+        let metrics = metrics().await;
+        assert!(
+            metrics.contains(&format!(
+                "proxima_queued_connections{{tunnel=\"{id}\"}} 1\n"
+            )),
+            "{metrics}"
+        );
+        assert!(
+            metrics.contains(&format!(
+                "proxima_rejected_connections_total{{reason=\"queue_full\"}} {rejected}\n"
+            )),
+            "{metrics}"
+        );
+
+        let (mut served, mut turned_away, message) = match policy {
+            "reject_new" => (second, third, &b"second"[..]),
+            _ => (third, second, &b"third"[..]),
+        };
+        let mut buf = [0; 8];
+        assert!(matches!(turned_away.read(&mut buf).await, Ok(0) | Err(_)));
+        drop(first);
+        let mut buf = vec![0; message.len()];
+        served.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, message);
+    }
+}
+
+#[tokio::test]
+async fn report_connection_throughput() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": destination.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+    let mut stream = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    let mut buf = vec![0; 64 * 1024];
+    stream.write_all(&buf).await.unwrap();
+    stream.read_exact(&mut buf).await.unwrap();
+    tokio::time::sleep(time::Duration::from_millis(1100)).await;
+
+    let (_, body) = get(proxy, &key, &format!("/tunnels/{id}/connections")).await;
+    let throughput = body["Connections"]["connections"][0]["throughput"]
+        .as_u64()
+        .unwrap();
+    assert!(throughput > 0);
+
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    assert_eq!(
+        body["Status"]["tunnels"][id.to_string()]["throughput"],
+        serde_json::json!({"min": throughput, "avg": throughput, "max": throughput})
+    );
+
+    let response = Client::new()
+        .request(read_request(proxy, &key, "/metrics"))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        metrics.contains(&format!(
+            "proxima_connection_throughput_bytes_per_second{{tunnel=\"{id}\",stat=\"max\"}} {throughput}\n"
+        )),
+        "{metrics}"
+    );
+}
+
+#[tokio::test]
+async fn create_range_of_tunnels() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let start_port = free_ports(3);
+    let create_range = |count: u16| {
+        json!({
+            "create_range": {
+                "start_port": start_port,
+                "count": count,
+                "destination_ip": "127.0.0.1",
+                "destination_port_start": destination.port(),
+            },
+        })
+    };
+
+    let response = Client::new()
+        .request(command_request(proxy, &key, &create_range(2)))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let ids = body["Created"]["ids"].as_array().unwrap();
+    assert_eq!(ids.len(), 2);
+    echo(start_port, b"first of the range").await;
+
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    let second = &body["Status"]["tunnels"][ids[1].as_str().unwrap()];
+    assert_eq!(second["incoming_port"], start_port + 1);
+    assert_eq!(
+        second["destination"]["tcp"],
+        format!("127.0.0.1:{}", destination.port() + 1)
+    );
+
+    // The first two ports are free again, but another tunnel takes the third, so none of the
+    // tunnels of the range are kept
+    for id in ids {
+        let delete = json!({"delete": {"id": id}});
+        assert_eq!(
+            send_command(proxy, &key, &delete).await,
+            StatusCode::ACCEPTED
+        );
+    }
+    let create = json!({
+        "create": {
+            "incoming_port": start_port + 2,
+            "destination_port": destination.port(),
+            "destination_ip": "127.0.0.1",
+            "id": uuid::Uuid::new_v4(),
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+    assert_eq!(
+        send_command(proxy, &key, &create_range(3)).await,
+        StatusCode::CONFLICT
+    );
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    assert_eq!(body["Status"]["tunnel_count"], 1);
+    tokio::time::sleep(time::Duration::from_millis(100)).await;
+    assert!(std::net::TcpListener::bind(("127.0.0.1", start_port)).is_ok());
+}
+
+#[tokio::test]
+async fn report_connect_latency() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": destination.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+
+    let status = || async {
+        let response = Client::new()
+            .request(command_request(proxy, &key, &json!({"status": null})))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = read_status(&body);
+        body["Status"]["tunnels"][id.to_string()]["connect_latency"].clone()
+    };
+    assert_eq!(status().await, serde_json::Value::Null);
+    echo(incoming_port, b"dialed").await;
+    echo(incoming_port, b"dialed again").await;
+    let latency = status().await;
+    assert!(latency["current_us"].is_u64(), "{latency}");
+    assert!(latency["avg_us"].is_u64(), "{latency}");
+    assert!(latency["p99_us"].as_u64() >= latency["current_us"].as_u64());
+
+    let response = Client::new()
+        .request(read_request(proxy, &key, "/metrics"))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        metrics.contains(&format!(
+            "proxima_connect_latency_seconds{{tunnel=\"{id}\",stat=\"p99\"}} "
+        )),
+        "{metrics}"
+    );
+}
+
+#[tokio::test]
+async fn route_by_route_key() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (echo_server, _) = start_echo_server().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = json!({
+        "create_router": {
+            "id": id,
+            "incoming_port": incoming_port,
+            "routes": {"echo": echo_server, "sink": listener.local_addr().unwrap()},
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+    let with_route_key = |route: &str, data: &[u8]| {
+        let mut message = vec![route.len() as u8];
+        message.extend_from_slice(route.as_bytes());
+        message.extend_from_slice(data);
+        message
+    };
+
+    let mut stream = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    stream
+        .write_all(&with_route_key("echo", b"hello"))
+        .await
+        .unwrap();
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // Only the data after the route key reaches the destination
+    let mut stream = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    stream
+        .write_all(&with_route_key("sink", b"data"))
+        .await
+        .unwrap();
+    stream.shutdown().await.unwrap();
+    let (mut sink, _) = listener.accept().await.unwrap();
+    let mut received = Vec::new();
+    sink.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"data");
+
+    let mut stream = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    stream
+        .write_all(&with_route_key("unknown", b"data"))
+        .await
+        .unwrap();
+    assert!(matches!(stream.read(&mut buf).await, Ok(0) | Err(_)));
+    let response = Client::new()
+        .request(read_request(proxy, &key, "/metrics"))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        metrics.contains("proxima_rejected_connections_total{reason=\"unknown_route\"} 1\n"),
+        "{metrics}"
+    );
+
+    // The routes are fixed
+    let modify = json!({
+        "modify": {"destination_port": echo_server.port(), "destination_ip": "127.0.0.1", "id": id},
+    });
+    assert_eq!(
+        send_command(proxy, &key, &modify).await,
+        StatusCode::BAD_REQUEST
+    );
+    let temporary_modify = json!({
+        "temporary_modify": {"id": id, "destination": echo_server, "revert_after_secs": 60},
+    });
+    assert_eq!(
+        send_command(proxy, &key, &temporary_modify).await,
+        StatusCode::BAD_REQUEST
+    );
+
+    // The router keeps the command it was created with
+    let (_, body) = get(proxy, &key, &format!("/tunnels/{id}/connections")).await;
+    assert_eq!(body["Connections"]["created_with"], create);
+    let (_, body) = get(proxy, &key, &format!("/tunnels/{id}/config")).await;
+    assert_eq!(
+        body["Config"]["config"]["routes"]["echo"],
+        echo_server.to_string()
+    );
+}
+
+#[tokio::test]
+async fn reject_oversized_buffer() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let create = json!({
+        "create": {
+            "incoming_port": free_port(),
+            "destination_port": 1,
+            "destination_ip": "127.0.0.1",
+            "id": uuid::Uuid::new_v4(),
+            "buffer_size": proxima_centauri::MAX_BUFFER_SIZE + 1,
+        },
+    });
+
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[tokio::test]
+async fn reject_privileged_port() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let create = json!({
+        "create": {
+            "incoming_port": 80,
+            "destination_port": 1,
+            "destination_ip": "127.0.0.1",
+            "id": uuid::Uuid::new_v4(),
+        },
+    });
+
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[tokio::test]
+async fn reject_destination_outside_of_policy() {
+    let key = SigningKey::random(&mut OsRng);
+    let (allowed, _) = start_echo_server().await;
+    let policy = format!("127.0.0.1/32:{}", allowed.port()).parse().unwrap();
+    let proxy = start_proxy_with(proxy_state(&key).with_allowed_destinations(vec![policy]));
+    let incoming_port = free_ports(2);
+    let other_port = incoming_port + 1;
+    let id = uuid::Uuid::new_v4();
+    let create = |ip: &str, port: u16| {
+        json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": port,
+                "destination_ip": ip,
+                "id": id,
+            },
+        })
+    };
+
+    assert_eq!(
+        send_command(proxy, &key, &create("127.0.0.1", other_port)).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        send_command(proxy, &key, &create("127.0.0.2", allowed.port())).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        send_command(proxy, &key, &create("127.0.0.1", allowed.port())).await,
+        StatusCode::ACCEPTED
+    );
+    echo(incoming_port, b"hello").await;
+
+    let modify = json!({
+        "modify": {"destination_port": other_port, "destination_ip": "127.0.0.1", "id": id},
+    });
+    let response = Client::new()
+        .request(command_request(proxy, &key, &modify))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"]["code"], "destination_not_allowed");
+    echo(incoming_port, b"still allowed").await;
+}
+
+#[tokio::test]
+async fn reject_self_loop() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = |ip: &str, port: u16| {
+        json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": port,
+                "destination_ip": ip,
+                "id": id,
+            },
+        })
+    };
+
+    for ip in ["127.0.0.1", "0.0.0.0", "::1"] {
+        assert_eq!(
+            send_command(proxy, &key, &create(ip, incoming_port)).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+    assert_eq!(
+        send_command(proxy, &key, &create("127.0.0.1", destination.port())).await,
+        StatusCode::ACCEPTED
+    );
+
+    let modify = json!({
+        "modify": {"destination_port": incoming_port, "destination_ip": "127.0.0.1", "id": id},
+    });
+    assert_eq!(
+        send_command(proxy, &key, &modify).await,
+        StatusCode::BAD_REQUEST
+    );
+    echo(incoming_port, b"no loop").await;
+}
+
+#[tokio::test]
+async fn status_not_modified() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let status = |etag: Option<HeaderValue>| {
+        let mut request = command_request(proxy, &key, &json!({"status": null}));
+        if let Some(etag) = etag {
+            request.headers_mut().insert(header::IF_NONE_MATCH, etag);
+        }
+        Client::new().request(request)
+    };
+
+    let response = status(None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response.headers()[header::ETAG].clone();
+    let response = status(Some(etag.clone())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+    let create = json!({
+        "create": {
+            "incoming_port": free_port(),
+            "destination_port": 1,
+            "destination_ip": "127.0.0.1",
+            "id": uuid::Uuid::new_v4(),
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+    let response = status(Some(etag.clone())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(response.headers()[header::ETAG], etag);
+}
+
+#[tokio::test]
+async fn stream_status_lines() {
+    let key = SigningKey::random(&mut OsRng);
+    let streamed = start_proxy(&key);
+    let buffered = start_proxy_with(proxy_state(&key).with_buffered_status(true));
+    for proxy in [streamed, buffered] {
+        for _ in 0..2 {
+            let create = json!({
+                "create": {
+                    "incoming_port": free_port(),
+                    "destination_port": 1,
+                    "destination_ip": "127.0.0.1",
+                    "id": uuid::Uuid::new_v4(),
+                },
+            });
+            assert_eq!(
+                send_command(proxy, &key, &create).await,
+                StatusCode::ACCEPTED
+            );
+        }
+    }
+
+    let response = Client::new()
+        .request(command_request(streamed, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        "application/x-ndjson"
+    );
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let lines: Vec<serde_json::Value> = body
+        .split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0]["Summary"]["tunnel_count"], 2);
+    assert!(lines[1..]
+        .iter()
+        .all(|line| line["Tunnel"]["status"]["state"] == "active"));
+
+    let response = Client::new()
+        .request(command_request(buffered, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["Status"]["tunnels"].as_object().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn count_rejected_connections() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    // Nothing listens on the destination
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": free_port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+
+    let mut stream = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    let mut buf = Vec::new();
+    let _ = stream.read_to_end(&mut buf).await;
+
+    let response = Client::new()
+        .request(read_request(proxy, &key, "/metrics"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        metrics.contains("proxima_rejected_connections_total{reason=\"connect_failed\"} 1\n"),
+        "{metrics}"
+    );
+    assert!(
+        metrics.contains("proxima_rejected_connections_total{reason=\"connect_timeout\"} 0\n"),
+        "{metrics}"
+    );
+    assert!(
+        metrics.contains(&format!(
+            "proxima_closed_connections_total{{tunnel=\"{id}\",reason=\"rejected\"}} 1\n"
+        )),
+        "{metrics}"
+    );
+}
+
+#[tokio::test]
+async fn report_last_error() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": free_port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+    let (_, body) = get(proxy, &key, &format!("/tunnels/{id}/connections")).await;
+    assert_eq!(body["Connections"]["last_error"], serde_json::Value::Null);
+
+    let mut stream = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    let mut buf = Vec::new();
+    let _ = stream.read_to_end(&mut buf).await;
+    let (_, body) = get(proxy, &key, &format!("/tunnels/{id}/connections")).await;
+    let message = body["Connections"]["last_error"]["message"]
+        .as_str()
+        .unwrap();
+    assert!(message.starts_with("connecting to 127.0.0.1:"), "{message}");
+
+    // A connection that reaches the destination clears it
+    let modify = json!({
+        "modify": {"destination_port": destination.port(), "destination_ip": "127.0.0.1", "id": id},
+    });
+    assert_eq!(
+        send_command(proxy, &key, &modify).await,
+        StatusCode::ACCEPTED
+    );
+    echo(incoming_port, b"healthy").await;
+    let (_, body) = get(proxy, &key, &format!("/tunnels/{id}/connections")).await;
+    assert_eq!(body["Connections"]["last_error"], serde_json::Value::Null);
+}
+
+#[tokio::test]
+async fn free_port_after_rapid_commands() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": 1,
+            "destination_ip": "127.0.0.1",
+            "id": id,
+        },
+    });
+    let modify = json!({
+        "modify": {"destination_port": destination.port(), "destination_ip": "127.0.0.1", "id": id},
+    });
+    let delete = json!({"delete": {"id": id}});
+    // The tunnel may only get to see the last of these changes
+    for command in [&create, &modify, &delete] {
+        assert_eq!(
+            send_command(proxy, &key, command).await,
+            StatusCode::ACCEPTED
+        );
+    }
+
+    // The listener of the tunnel goes away shortly after the delete
+    let mut freed = false;
+    for _ in 0..50 {
+        if std::net::TcpListener::bind(("0.0.0.0", incoming_port)).is_ok() {
+            freed = true;
+            break;
+        }
+        tokio::time::sleep(time::Duration::from_millis(10)).await;
+    }
+    assert!(freed, "port {incoming_port} is still in use");
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+}
+
+#[tokio::test]
+async fn label_tunnel() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let id = uuid::Uuid::new_v4();
+    let create = |label: &str| {
+        json!({
+            "create": {
+                "incoming_port": free_port(),
+                "destination_port": 1,
+                "destination_ip": "127.0.0.1",
+                "id": id,
+                "label": label,
+            },
+        })
+    };
+
+    assert_eq!(
+        send_command(proxy, &key, &create("bad\u{7}label")).await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        send_command(proxy, &key, &create("db")).await,
+        StatusCode::ACCEPTED
+    );
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    assert_eq!(body["Status"]["tunnels"][id.to_string()]["label"], "db");
+
+    // Kept by a `Modify` without a label, and removed by one with `null`
+    let label_after = |label: Value| {
+        let modify = with_fields(json!({"modify": {"destination_port": 2, "id": id}}), label);
+        let key = &key;
+        async move {
+            assert_eq!(
+                send_command(proxy, key, &modify).await,
+                StatusCode::ACCEPTED
+            );
+            let response = Client::new()
+                .request(command_request(proxy, key, &json!({"status": null})))
+                .await
+                .unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            read_status(&body)["Status"]["tunnels"][id.to_string()]["label"].clone()
+        }
+    };
+    assert_eq!(label_after(json!({})).await, "db");
+    assert_eq!(label_after(json!({"label": "web"})).await, "web");
+    assert!(label_after(json!({"label": null})).await.is_null());
+}
+
+#[tokio::test]
+async fn configure_backlog_and_count_accepts() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = |backlog: u32| {
+        json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": destination.port(),
+                "destination_ip": "127.0.0.1",
+                "id": id,
+                "backlog": backlog,
+            },
+        })
+    };
+    assert_eq!(
+        send_command(proxy, &key, &create(0)).await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        send_command(proxy, &key, &create(16)).await,
+        StatusCode::ACCEPTED
+    );
+    echo(incoming_port, b"counted").await;
+
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    assert_eq!(body["Status"]["tunnels"][id.to_string()]["backlog"], 16);
+
+    let response = Client::new()
+        .request(read_request(proxy, &key, "/metrics"))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        metrics.contains(&format!(
+            "proxima_accepted_connections_total{{tunnel=\"{id}\"}} 1\n"
+        )),
+        "{metrics}"
+    );
+}
+
+#[tokio::test]
+async fn filter_status() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let ports = [free_port(), free_port()];
+    for (port, label) in ports.iter().zip(["db", "web"]) {
+        let create = json!({
+            "create": {
+                "incoming_port": port,
+                "destination_port": 1,
+                "destination_ip": "127.0.0.1",
+                "id": uuid::Uuid::new_v4(),
+                "label": label,
+            },
+        });
+        assert_eq!(
+            send_command(proxy, &key, &create).await,
+            StatusCode::ACCEPTED
+        );
+    }
+    let status = |query: String| {
+        let mut request = command_request(proxy, &key, &json!({"status": null}));
+        *request.uri_mut() = format!("http://{proxy}/command{query}").parse().unwrap();
+        async {
+            let response = Client::new().request(request).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body = read_status(&body);
+            body["Status"]["tunnels"].as_object().unwrap().clone()
+        }
+    };
+
+    assert_eq!(status(String::new()).await.len(), 2);
+    let tunnels = status(format!("?port={}", ports[1])).await;
+    assert_eq!(tunnels.len(), 1);
+    assert_eq!(tunnels.values().next().unwrap()["label"], "web");
+    assert_eq!(status("?label=db&state=active".to_string()).await.len(), 1);
+    assert!(status("?state=draining".to_string()).await.is_empty());
+
+    // A filter that doesn't parse is an invalid status command, but doesn't bother others
+    let mut request = command_request(proxy, &key, &json!({"status": null}));
+    *request.uri_mut() = format!("http://{proxy}/command?state=asleep")
+        .parse()
+        .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"]["code"], "invalid_command");
+    let create = json!({
+        "create": {
+            "incoming_port": free_port(),
+            "destination_port": 1,
+            "destination_ip": "127.0.0.1",
+            "id": uuid::Uuid::new_v4(),
+        },
+    });
+    let mut request = command_request(proxy, &key, &create);
+    *request.uri_mut() = format!("http://{proxy}/command?state=asleep")
+        .parse()
+        .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+}
+
+#[tokio::test]
+async fn rotate_verifying_key() {
+    let old = SigningKey::random(&mut OsRng);
+    let new = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&old);
+    let status = json!({"status": null});
+    let new_pem = VerifyingKey::from(&new)
+        .to_public_key_pem(LineEnding::LF)
+        .unwrap();
+
+    // Only a key from before the rotation can sign it
+    let rotate = json!({
+        "rotate_key": {"add": [{"name": "next", "key": new_pem}], "remove": ["default"]},
+    });
+    assert_eq!(
+        send_command(proxy, &new, &rotate).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(send_command(proxy, &old, &rotate).await, StatusCode::OK);
+
+    assert_eq!(
+        send_command(proxy, &old, &status).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(send_command(proxy, &new, &status).await, StatusCode::OK);
+
+    let remove_all = json!({"rotate_key": {"remove": ["next"]}});
+    assert_eq!(
+        send_command(proxy, &new, &remove_all).await,
+        StatusCode::CONFLICT
+    );
+    assert_eq!(send_command(proxy, &new, &status).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn reject_oversized_body() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let label = "x".repeat(proxima_centauri::DEFAULT_MAX_BODY_SIZE);
+    let create = json!({
+        "create": {
+            "incoming_port": free_port(),
+            "destination_port": 1,
+            "destination_ip": "127.0.0.1",
+            "id": uuid::Uuid::new_v4(),
+            "label": label,
+        },
+    });
+
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+}
+
+#[tokio::test]
+async fn reject_wrong_method_and_route() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+
+    let response = Client::new()
+        .get(format!("http://{proxy}/command").parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.headers()[header::ALLOW], "POST");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["error"]["code"], "method_not_allowed");
+
+    let (status, body) = get(proxy, &key, "/nowhere").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"]["code"], "not_found");
+}
+
+#[tokio::test]
+async fn verify_without_executing() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": 1,
+            "destination_ip": "127.0.0.1",
+            "id": id,
+        },
+    });
+    let delete = json!({"delete": {"id": id}});
+    let signed = |command: &Value| {
+        let request = command_request(proxy, &key, command);
+        async { hyper::body::to_bytes(request.into_body()).await.unwrap() }
+    };
+    let post = |path: &str, body| {
+        Client::new().request(
+            Request::post(format!("http://{proxy}{path}"))
+                .header("content-type", "application/json")
+                .body(Body::from(body))
+                .unwrap(),
+        )
+    };
+
+    let signed_create = signed(&create).await;
+    let response = post("/verify", signed_create.clone()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    // The dry run neither listens on the port nor creates the tunnel
+    assert!(std::net::TcpListener::bind(("0.0.0.0", incoming_port)).is_ok());
+    let response = post("/verify", signed(&delete).await).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // The same signed command can still be sent for real
+    let response = post("/command", signed_create).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let response = post("/verify", signed(&create).await).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = post("/verify", signed(&delete).await).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+}
+
+#[tokio::test]
+async fn concurrent_creates_on_the_same_port() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let incoming_port = free_port();
+    let create = || {
+        json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": 1,
+                "destination_ip": "127.0.0.1",
+                "id": uuid::Uuid::new_v4(),
+            },
+        })
+    };
+    let (first, second) = (create(), create());
+
+    let (first, second) = tokio::join!(
+        send_command(proxy, &key, &first),
+        send_command(proxy, &key, &second)
+    );
+    let mut statuses = [first, second];
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::ACCEPTED, StatusCode::CONFLICT]);
+}
+
+// Several worker threads, so the commands are handled in parallel rather than interleaved
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn many_concurrent_creates_admit_one_per_port() {
+    const PORTS: u16 = 4;
+    const CREATES_PER_PORT: usize = 16;
+    let key = Arc::new(SigningKey::random(&mut OsRng));
+    let proxy = start_proxy(&key);
+    // Below the ephemeral ports, which the connections that send the commands use meanwhile
+    let start_port = (20000..32000)
+        .step_by(PORTS as usize)
+        .find(|start| {
+            (*start..start + PORTS)
+                .all(|port| std::net::TcpListener::bind(("0.0.0.0", port)).is_ok())
+        })
+        .unwrap();
+    // The same id on every port as well, which only one of them may get
+    let shared_id = uuid::Uuid::new_v4();
+
+    let mut creates = tokio::task::JoinSet::new();
+    for round in 0..CREATES_PER_PORT {
+        for port in start_port..start_port + PORTS {
+            let key = key.clone();
+            let id = if round == 0 {
+                shared_id
+            } else {
+                uuid::Uuid::new_v4()
+            };
+            let create = json!({
+                "create": {
+                    "incoming_port": port,
+                    "destination_port": 1,
+                    "destination_ip": "127.0.0.1",
+                    "id": id,
+                },
+            });
+            creates.spawn(async move { (port, send_command(proxy, &key, &create).await) });
+        }
+    }
+    let mut accepted = std::collections::HashMap::new();
+    while let Some(result) = creates.join_next().await {
+        let (port, status) = result.unwrap();
+        match status {
+            StatusCode::ACCEPTED => *accepted.entry(port).or_insert(0) += 1,
+            StatusCode::CONFLICT => {}
+            status => panic!("unexpected {status} for port {port}"),
+        }
+    }
+    for port in start_port..start_port + PORTS {
+        assert_eq!(accepted.get(&port), Some(&1), "port {port}");
+    }
+
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    assert_eq!(body["Status"]["tunnel_count"], PORTS);
+}
+
+#[tokio::test]
+async fn stream_status_changes() {
+    use hyper::body::HttpBody;
+
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let response = Client::new()
+        .request(read_request(proxy, &key, "/status/stream"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        "text/event-stream"
+    );
+    let mut body = response.into_body();
+    async fn next_event(body: &mut Body) -> String {
+        let chunk = tokio::time::timeout(time::Duration::from_secs(5), body.data())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        String::from_utf8(chunk.to_vec()).unwrap()
+    }
+
+    let event = next_event(&mut body).await;
+    assert!(event.starts_with("event:status\n"), "{event}");
+    assert!(event.contains("\"tunnels\":{}"), "{event}");
+
+    let id = uuid::Uuid::new_v4();
+    let create = json!({
+        "create": {
+            "incoming_port": free_port(),
+            "destination_port": 1,
+            "destination_ip": "127.0.0.1",
+            "id": id,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+    let event = next_event(&mut body).await;
+    assert!(event.contains(&id.to_string()), "{event}");
+}
+
+#[tokio::test]
+async fn error_envelope() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let error = |request: Request<Body>| async {
+        let response = Client::new().request(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(body["error"]["message"].is_string(), "{body}");
+        (status, body["error"]["code"].as_str().unwrap().to_string())
+    };
+    let delete = json!({"delete": {"id": uuid::Uuid::new_v4()}});
+
+    assert_eq!(
+        error(command_request(proxy, &key, &delete)).await,
+        (StatusCode::NOT_FOUND, "not_found".to_string())
+    );
+    let other = SigningKey::random(&mut OsRng);
+    assert_eq!(
+        error(command_request(proxy, &other, &delete)).await,
+        (StatusCode::UNAUTHORIZED, "invalid_signature".to_string())
+    );
+    assert_eq!(
+        error(post_command(proxy, json!({"explode": {}}).to_string())).await,
+        (StatusCode::UNPROCESSABLE_ENTITY, "invalid_json".to_string())
+    );
+}
+
+#[tokio::test]
+async fn list_every_violation() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let violations = |command: Value| {
+        let request = command_request(proxy, &key, &command);
+        async {
+            let response = Client::new().request(request).await.unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let codes: Vec<_> = body["error"]["violations"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|violation| violation["code"].as_str().unwrap().to_string())
+                .collect();
+            (status, body["error"]["code"].clone(), codes)
+        }
+    };
+
+    let (status, code, codes) = violations(json!({
+        "create": {
+            "incoming_port": 80,
+            "destination_port": 1,
+            "destination_ip": "127.0.0.1",
+            "id": uuid::Uuid::new_v4(),
+            "backlog": 0,
+            "queue_workers": 2,
+        },
+    }))
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(code, "invalid_command");
+    assert_eq!(
+        codes,
+        ["privileged_port", "invalid_command", "invalid_command"]
+    );
+
+    // A single violation keeps its own code and status
+    let (status, code, codes) = violations(json!({
+        "create": {
+            "incoming_port": 80,
+            "destination_port": 1,
+            "destination_ip": "127.0.0.1",
+            "id": uuid::Uuid::new_v4(),
+        },
+    }))
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(code, "privileged_port");
+    assert_eq!(codes, ["privileged_port"]);
+}
+
+#[tokio::test]
+async fn accept_commands_in_msgpack() {
+    use proxima_centauri::client::CommandBuilder;
+    use proxima_centauri::{msgpack, ErrorBody, ErrorCode, ProxyCommand};
+
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let send = |body: Vec<u8>| async move {
+        let request = Request::post(format!("http://{proxy}/command"))
+            .header("content-type", msgpack::MSGPACK_CONTENT_TYPE)
+            .body(Body::from(body))
+            .unwrap();
+        let response = Client::new().request(request).await.unwrap();
+        let status = response.status();
+        let content_type = response.headers()[header::CONTENT_TYPE].clone();
+        assert_eq!(content_type, msgpack::MSGPACK_CONTENT_TYPE);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, body)
+    };
+    let encode = |command: ProxyCommand| msgpack::to_vec(&command).unwrap();
+
+    // Signed over the JSON of the command, like when it is sent as JSON
+    let (incoming_port, id) = (free_port(), uuid::Uuid::new_v4());
+    let create = CommandBuilder::create(id, incoming_port, destination).sign(&key);
+    let (status, body) = send(encode(create)).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert!(matches!(
+        msgpack::from_slice(&body).unwrap(),
+        ProxyResponse::Message(_)
+    ));
+    echo(incoming_port, b"msgpack").await;
+
+    // Not streamed, unlike to JSON clients
+    let (status, body) = send(encode(CommandBuilder::status().sign(&key))).await;
+    assert_eq!(status, StatusCode::OK);
+    let ProxyResponse::Status { tunnels, .. } = msgpack::from_slice(&body).unwrap() else {
+        panic!("not a status");
+    };
+    assert_eq!(tunnels[&id].incoming_port, incoming_port);
+
+    let (status, body) = send(encode(CommandBuilder::delete(id).unsigned())).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let ErrorBody { error } = msgpack::from_slice(&body).unwrap();
+    assert_eq!(error.code, ErrorCode::InvalidSignature);
+
+    let (status, body) = send(b"\xc1".to_vec()).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let ErrorBody { error } = msgpack::from_slice(&body).unwrap();
+    assert_eq!(error.code, ErrorCode::InvalidJson);
+}
+
+#[tokio::test]
+async fn reject_out_of_range_ports() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    // Out of range ports don't deserialize into a command, so they can't be signed either
+    let error = |incoming_port: Value, destination_port: Value| {
+        let create = json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": destination_port,
+                "destination_ip": "127.0.0.1",
+                "id": uuid::Uuid::new_v4(),
+            },
+        });
+        let request = post_command(proxy, create.to_string());
+        async {
+            let response = Client::new().request(request).await.unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["error"]["code"], "invalid_command");
+            (
+                status,
+                body["error"]["message"].as_str().unwrap().to_string(),
+            )
+        }
+    };
+
+    for (incoming_port, destination_port, field) in [
+        (json!(70000), json!(1), "`incoming_port`"),
+        (json!(0), json!(1), "`incoming_port`"),
+        (json!(5555), json!(-1), "`destination_port`"),
+        (json!(5555), json!("http"), "`destination_port`"),
+    ] {
+        let (status, message) = error(incoming_port, destination_port).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(message.contains(field), "{message}");
+        assert!(message.contains("1 to 65535"), "{message}");
+    }
+}
+
+#[tokio::test]
+async fn sign_responses() {
+    let key = SigningKey::random(&mut OsRng);
+    let server_key = SigningKey::random(&mut OsRng);
+    let server_verifying_key = VerifyingKey::from(&server_key);
+    let proxy = start_proxy_with(proxy_state(&key).with_signing_key(Some(server_key)));
+    let id = uuid::Uuid::new_v4();
+    let create = json!({
+        "create": {
+            "incoming_port": free_port(),
+            "destination_port": 1,
+            "destination_ip": "127.0.0.1",
+            "id": id,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+
+    // The status isn't streamed, so it is signed as a whole
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await.unwrap();
+    assert_eq!(
+        verify_response(&server_verifying_key, &parts.headers, &body),
+        Ok(())
+    );
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(
+        status["Status"]["tunnels"].get(id.to_string()).is_some(),
+        "{status}"
+    );
+
+    // Errors are signed as well, and a changed body no longer matches
+    let unsigned = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{proxy}/command"))
+        .header("content-type", "application/json")
+        .body(Body::from(json!({"status": null}).to_string()))
+        .unwrap();
+    let response = Client::new().request(unsigned).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await.unwrap();
+    assert_eq!(
+        verify_response(&server_verifying_key, &parts.headers, &body),
+        Ok(())
+    );
+    assert_eq!(
+        verify_response(&server_verifying_key, &parts.headers, b"{}"),
+        Err(ResponseVerifyError::SignatureMismatch)
+    );
+    assert_eq!(
+        verify_response(&server_verifying_key, &hyper::HeaderMap::new(), &body),
+        Err(ResponseVerifyError::MissingSignature)
+    );
+}
+
+#[tokio::test]
+async fn report_version() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (status, body) = get(proxy, &key, "/version").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["Version"]["version"], env!("CARGO_PKG_VERSION"));
+    let features = body["Version"]["features"].as_array().unwrap();
+    assert!(features.contains(&serde_json::json!("tls")), "{features:?}");
+}
+
+#[tokio::test]
+async fn complete_connection_reset_by_backend() {
+    // A backend that answers once the request is complete and then resets the connection
+    let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let destination = backend.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = backend.accept().await.unwrap();
+        let mut request = Vec::new();
+        socket.read_to_end(&mut request).await.unwrap();
+        socket.write_all(b"all of the response").await.unwrap();
+        socket2::SockRef::from(&socket)
+            .set_linger(Some(time::Duration::ZERO))
+            .unwrap();
+    });
+
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": destination.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+
+    let mut stream = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    stream.write_all(b"request").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"all of the response");
+
+    tokio::time::sleep(time::Duration::from_millis(100)).await;
+    let response = Client::new()
+        .request(read_request(proxy, &key, "/metrics"))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        metrics.contains(&format!(
+            "proxima_closed_connections_total{{tunnel=\"{id}\",reason=\"completed\"}} 1\n"
+        )),
+        "{metrics}"
+    );
+    let (_, body) = get(proxy, &key, &format!("/tunnels/{id}/connections")).await;
+    assert_eq!(body["Connections"]["last_error"], serde_json::Value::Null);
+}
+
+#[tokio::test]
+async fn close_connections_after_max_lifetime() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = |max_lifetime_secs: u64| {
+        json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": destination.port(),
+                "destination_ip": "127.0.0.1",
+                "id": id,
+                "max_lifetime_secs": max_lifetime_secs,
+            },
+        })
+    };
+    assert_eq!(
+        send_command(proxy, &key, &create(0)).await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        send_command(proxy, &key, &create(1)).await,
+        StatusCode::ACCEPTED
+    );
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    assert_eq!(
+        body["Status"]["tunnels"][id.to_string()]["max_lifetime_secs"],
+        1
+    );
+
+    // Busy the whole time, but closed anyway
+    let mut stream = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    let started = tokio::time::Instant::now();
+    let mut buf = [0; 4];
+    while started.elapsed() < time::Duration::from_secs(5) {
+        if stream.write_all(b"ping").await.is_err() || stream.read_exact(&mut buf).await.is_err() {
+            break;
+        }
+        assert_eq!(&buf, b"ping");
+        tokio::time::sleep(time::Duration::from_millis(50)).await;
+    }
+    let lifetime = started.elapsed();
+    assert!(
+        lifetime >= time::Duration::from_millis(900) && lifetime < time::Duration::from_secs(5),
+        "{lifetime:?}"
+    );
+
+    tokio::time::sleep(time::Duration::from_millis(100)).await;
+    let response = Client::new()
+        .request(read_request(proxy, &key, "/metrics"))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        metrics.contains(&format!(
+            "proxima_closed_connections_total{{tunnel=\"{id}\",reason=\"max_lifetime\"}} 1\n"
+        )),
+        "{metrics}"
+    );
+}
+
+#[tokio::test]
+async fn route_by_server_name() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (default, default_connections) = start_echo_server().await;
+    let (db, db_connections) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": default.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+            "sni_map": {"DB.example": db},
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+
+    // The ClientHello is passed on as it is
+    echo(incoming_port, &client_hello("db.example")).await;
+    assert_eq!(db_connections.load(Ordering::SeqCst), 1);
+    echo(incoming_port, &client_hello("web.example")).await;
+    echo(incoming_port, b"not tls").await;
+    assert_eq!(default_connections.load(Ordering::SeqCst), 2);
+
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    assert_eq!(
+        body["Status"]["tunnels"][id.to_string()]["sni_map"],
+        serde_json::json!({"db.example": db.to_string()})
+    );
+
+    // Names are matched ignoring case, so they may only be given once
+    let create = json!({
+        "create": {
+            "incoming_port": free_port(),
+            "destination_port": default.port(),
+            "destination_ip": "127.0.0.1",
+            "id": uuid::Uuid::new_v4(),
+            "sni_map": {"DB.example": db, "db.example": default},
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[tokio::test]
+async fn accept_admin_token() {
+    let proxy = start_proxy_with(
+        GlobalState::new(None::<String>).with_admin_token(Some("secret".to_string())),
+    );
+    let status = |token: Option<&str>| {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{proxy}/command"))
+            .header("content-type", "application/json");
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {token}"));
+        }
+        let request = request
+            .body(Body::from(json!({"status": null}).to_string()))
+            .unwrap();
+        async {
+            let response = Client::new().request(request).await.unwrap();
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            (
+                status,
+                serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            )
+        }
+    };
+
+    let (status_code, body) = status(None).await;
+    assert_eq!(status_code, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"]["code"], "invalid_token");
+    assert_eq!(status(Some("guess")).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(status(Some("secret")).await.0, StatusCode::OK);
+    let read = |token: &str| {
+        let request = Request::get(format!("http://{proxy}/diagnostics"))
+            .header("authorization", format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap();
+        async { Client::new().request(request).await.unwrap().status() }
+    };
+    assert_eq!(read("guess").await, StatusCode::UNAUTHORIZED);
+    assert_eq!(read("secret").await, StatusCode::OK);
+
+    // Signatures win when there is a verifying key
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy_with(proxy_state(&key).with_admin_token(Some("secret".to_string())));
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{proxy}/command"))
+        .header("content-type", "application/json")
+        .header("authorization", "Bearer secret")
+        .body(Body::from(json!({"status": null}).to_string()))
+        .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn pretty_print_responses() {
+    let key = SigningKey::random(&mut OsRng);
+    let server_key = SigningKey::random(&mut OsRng);
+    let server_verifying_key = VerifyingKey::from(&server_key);
+    let proxy = start_proxy_with(proxy_state(&key).with_signing_key(Some(server_key)));
+
+    let body = |response: hyper::Response<Body>| async {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    };
+    let time =
+        |query: &str| Client::new().get(format!("http://{proxy}/time{query}").parse().unwrap());
+    assert!(!body(time("").await.unwrap()).await.contains('\n'));
+    let pretty = body(time("?pretty=true").await.unwrap()).await;
+    assert!(pretty.contains("\n  "), "{pretty}");
+
+    // The signature covers the indented body
+    let mut request = command_request(proxy, &key, &json!({"status": null}));
+    *request.uri_mut() = format!("http://{proxy}/command?pretty=true")
+        .parse()
+        .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+    let headers = response.headers().clone();
+    let pretty = body(response).await;
+    assert!(pretty.contains('\n'), "{pretty}");
+    assert_eq!(
+        verify_response(&server_verifying_key, &headers, pretty.as_bytes()),
+        Ok(())
+    );
+}
+
+#[tokio::test]
+async fn read_responses_from_response_destination() {
+    // The destination only receives, its answer is discarded
+    let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let destination_addr = destination.local_addr().unwrap();
+    let (received_tx, received_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (mut socket, _) = destination.accept().await.unwrap();
+        socket.write_all(b"discarded").await.unwrap();
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.unwrap();
+        received_tx.send(received).unwrap();
+    });
+    let response = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let response_addr = response.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut socket, _) = response.accept().await.unwrap();
+        socket.write_all(b"answer").await.unwrap();
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+    });
+
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let incoming_port = free_port();
+    let create = |response_destination: SocketAddr| {
+        json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": destination_addr.port(),
+                "destination_ip": "127.0.0.1",
+                "id": uuid::Uuid::new_v4(),
+                "response_destination": response_destination,
+            },
+        })
+    };
+    assert_eq!(
+        send_command(proxy, &key, &create(destination_addr)).await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        send_command(proxy, &key, &create(response_addr)).await,
+        StatusCode::ACCEPTED
+    );
+
+    let mut stream = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    stream.write_all(b"request").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut answer = Vec::new();
+    stream.read_to_end(&mut answer).await.unwrap();
+    assert_eq!(answer, b"answer");
+    assert_eq!(received_rx.await.unwrap(), b"request");
+}
+
+#[tokio::test]
+async fn mirror_client_data() {
+    let mirror = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mirror_addr = mirror.local_addr().unwrap();
+    let (mirrored_tx, mirrored_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (mut socket, _) = mirror.accept().await.unwrap();
+        let mut mirrored = Vec::new();
+        socket.read_to_end(&mut mirrored).await.unwrap();
+        mirrored_tx.send(mirrored).unwrap();
+    });
+
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let create = |incoming_port: u16, id: uuid::Uuid, mirror_to: SocketAddr| {
+        json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": destination.port(),
+                "destination_ip": "127.0.0.1",
+                "id": id,
+                "mirror_to": mirror_to,
+            },
+        })
+    };
+    let (incoming_port, id) = (free_port(), uuid::Uuid::new_v4());
+    assert_eq!(
+        send_command(proxy, &key, &create(incoming_port, id, mirror_addr)).await,
+        StatusCode::ACCEPTED
+    );
+    echo(incoming_port, b"mirrored").await;
+    assert_eq!(mirrored_rx.await.unwrap(), b"mirrored");
+
+    // A mirror that can't be reached doesn't affect the tunnel
+    let unreachable = SocketAddr::from(([127, 0, 0, 1], free_port()));
+    let (other_port, other_id) = (free_port(), uuid::Uuid::new_v4());
+    assert_eq!(
+        send_command(proxy, &key, &create(other_port, other_id, unreachable)).await,
+        StatusCode::ACCEPTED
+    );
+    echo(other_port, b"not mirrored").await;
+    tokio::time::sleep(time::Duration::from_millis(100)).await;
+
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    let tunnels = &body["Status"]["tunnels"];
+    assert_eq!(tunnels[id.to_string()]["mirror"]["mirrored_bytes"], 8);
+    assert_eq!(
+        tunnels[other_id.to_string()]["mirror"]["failed_connections"],
+        1
+    );
+    assert_eq!(tunnels[other_id.to_string()]["mirror"]["dropped_bytes"], 12);
+}
+
+#[tokio::test]
+async fn give_up_on_a_mirror_that_stops_reading() {
+    // Accepts, but never reads
+    let mirror = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mirror_addr = mirror.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = mirror.accept().await.unwrap();
+        std::future::pending::<()>().await;
+        drop(socket);
+    });
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let (incoming_port, id) = (free_port(), uuid::Uuid::new_v4());
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": destination.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+            "mirror_to": mirror_addr,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+
+    // More than the socket buffers of the mirror connection hold
+    let data = vec![7u8; 32 << 20];
+    let stream = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    let (mut reader, mut writer) = stream.into_split();
+    let written = async {
+        writer.write_all(&data).await.unwrap();
+        writer.shutdown().await.unwrap();
+    };
+    let mut echoed = Vec::new();
+    let (_, read) = tokio::join!(written, reader.read_to_end(&mut echoed));
+    assert_eq!(read.unwrap(), data.len());
+
+    tokio::time::sleep(MIRROR_WRITE_TIMEOUT + time::Duration::from_millis(500)).await;
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    let mirror = &body["Status"]["tunnels"][id.to_string()]["mirror"];
+    assert_eq!(mirror["failed_connections"], 1, "{mirror}");
+    let (mirrored, dropped) = (
+        mirror["mirrored_bytes"].as_u64().unwrap(),
+        mirror["dropped_bytes"].as_u64().unwrap(),
+    );
+    assert_eq!(mirrored + dropped, data.len() as u64);
+}
+
+#[tokio::test]
+async fn gzip_mirrored_data() {
+    let mirror = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mirror_addr = mirror.local_addr().unwrap();
+    let (mirrored_tx, mirrored_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (socket, _) = mirror.accept().await.unwrap();
+        let mut decoder = GzipDecoder::new(io::BufReader::new(socket));
+        let mut mirrored = Vec::new();
+        decoder.read_to_end(&mut mirrored).await.unwrap();
+        mirrored_tx.send(mirrored).unwrap();
+    });
+
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let (incoming_port, id) = (free_port(), uuid::Uuid::new_v4());
+    let create = |mirror_gzip_level: u32| {
+        json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": destination.port(),
+                "destination_ip": "127.0.0.1",
+                "id": id,
+                "mirror_to": mirror_addr,
+                "mirror_gzip_level": mirror_gzip_level,
+            },
+        })
+    };
+    assert_eq!(
+        send_command(proxy, &key, &create(10)).await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        send_command(proxy, &key, &create(6)).await,
+        StatusCode::ACCEPTED
+    );
+    // The destination gets the data as it was sent
+    let message = b"compressed for the mirror only ".repeat(64);
+    echo(incoming_port, &message).await;
+    assert_eq!(mirrored_rx.await.unwrap(), message);
+
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    let mirror = &body["Status"]["tunnels"][id.to_string()]["mirror"];
+    assert_eq!(mirror["gzip_level"], 6);
+    assert_eq!(mirror["mirrored_bytes"], message.len());
+}
+
+#[tokio::test]
+async fn fan_out_client_data() {
+    let mut received = Vec::new();
+    let mut fanout = Vec::new();
+    for _ in 0..2 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        fanout.push(listener.local_addr().unwrap());
+        let (received_tx, received_rx) = tokio::sync::oneshot::channel();
+        received.push(received_rx);
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            // Answers that must not reach the client
+            socket.write_all(b"ignored").await.unwrap();
+            let mut data = Vec::new();
+            socket.read_to_end(&mut data).await.unwrap();
+            received_tx.send(data).unwrap();
+        });
+    }
+    // One that can't be reached doesn't affect the others
+    fanout.push(SocketAddr::from(([127, 0, 0, 1], free_port())));
+
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let (incoming_port, id) = (free_port(), uuid::Uuid::new_v4());
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": destination.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+            "fanout_destinations": fanout,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+
+    let mut stream = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    stream.write_all(b"fanned out").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut answer = Vec::new();
+    stream.read_to_end(&mut answer).await.unwrap();
+    assert_eq!(answer, b"fanned out");
+    for received in received {
+        assert_eq!(received.await.unwrap(), b"fanned out");
+    }
+
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    let status = &body["Status"]["tunnels"][id.to_string()]["fanout"];
+    assert_eq!(status["destinations"].as_array().unwrap().len(), 3);
+    assert_eq!(status["sent_bytes"], 20);
+    assert_eq!(status["failed_connections"], 1);
+}
+
+#[tokio::test]
+async fn leave_out_fan_out_destinations_that_stop_reading() {
+    // Accepts, but never reads
+    let stuck = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stuck_addr = stuck.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = stuck.accept().await.unwrap();
+        std::future::pending::<()>().await;
+        drop(socket);
+    });
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let (incoming_port, id) = (free_port(), uuid::Uuid::new_v4());
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": destination.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+            "fanout_destinations": [stuck_addr],
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+
+    // More than the socket buffers of the fan-out connection hold, which still all reaches the
+    // destination once the stuck one is left out
+    let data = vec![7u8; 32 << 20];
+    let stream = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    let (mut reader, mut writer) = stream.into_split();
+    let written = async {
+        writer.write_all(&data).await.unwrap();
+        writer.shutdown().await.unwrap();
+    };
+    let mut echoed = Vec::new();
+    let copied = async { tokio::join!(written, reader.read_to_end(&mut echoed)).1 };
+    let deadline = FANOUT_WRITE_TIMEOUT * 5;
+    let read = tokio::time::timeout(deadline, copied).await.unwrap();
+    assert_eq!(read.unwrap(), data.len());
+
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    let status = &body["Status"]["tunnels"][id.to_string()]["fanout"];
+    assert_eq!(status["failed_connections"], 1, "{status}");
+}
+
+#[tokio::test]
+async fn limit_tunnels_per_key() {
+    let operator = SigningKey::random(&mut OsRng);
+    let tenant = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy_with(
+        proxy_state(&operator)
+            .with_key_quotas(std::collections::HashMap::from([("tenant".to_string(), 1)])),
+    );
+    let tenant_pem = VerifyingKey::from(&tenant)
+        .to_public_key_pem(LineEnding::LF)
+        .unwrap();
+    let rotate = json!({"rotate_key": {"add": [{"name": "tenant", "key": tenant_pem}]}});
+    assert_eq!(
+        send_command(proxy, &operator, &rotate).await,
+        StatusCode::OK
+    );
+    let (destination, _) = start_echo_server().await;
+    let create = |id: uuid::Uuid| {
+        json!({
+            "create": {
+                "incoming_port": free_port(),
+                "destination_port": destination.port(),
+                "destination_ip": "127.0.0.1",
+                "id": id,
+            },
+        })
+    };
+    let delete = |id: uuid::Uuid| json!({"delete": {"id": id}});
+    let listed = |key: &SigningKey| {
+        let request = command_request(proxy, key, &json!({"status": null}));
+        async move {
+            let response = Client::new().request(request).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body = read_status(&body);
+            let mut ids: Vec<String> = body["Status"]["tunnels"]
+                .as_object()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect();
+            ids.sort();
+            ids
+        }
+    };
+
+    let (operators, tenants) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+    assert_eq!(
+        send_command(proxy, &operator, &create(operators)).await,
+        StatusCode::ACCEPTED
+    );
+    assert_eq!(
+        send_command(proxy, &tenant, &create(tenants)).await,
+        StatusCode::ACCEPTED
+    );
+    assert_eq!(
+        send_command(proxy, &tenant, &create(uuid::Uuid::new_v4())).await,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+
+    // The tenant only sees and changes its own tunnel, the operator every tunnel
+    assert_eq!(listed(&tenant).await, vec![tenants.to_string()]);
+    let mut all = vec![operators.to_string(), tenants.to_string()];
+    all.sort();
+    assert_eq!(listed(&operator).await, all);
+    assert_eq!(
+        send_command(proxy, &tenant, &delete(operators)).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        send_command(
+            proxy,
+            &tenant,
+            &json!({"rotate_key": {"remove": ["default"]}})
+        )
+        .await,
+        StatusCode::FORBIDDEN
+    );
+
+    // Deleting its tunnel frees up the quota
+    assert_eq!(
+        send_command(proxy, &tenant, &delete(tenants)).await,
+        StatusCode::ACCEPTED
+    );
+    assert_eq!(
+        send_command(proxy, &tenant, &create(uuid::Uuid::new_v4())).await,
+        StatusCode::ACCEPTED
+    );
+}
+
+#[tokio::test]
+async fn hide_other_tenants_tunnels_from_reads() {
+    use hyper::body::HttpBody;
+
+    let operator = SigningKey::random(&mut OsRng);
+    let (alice, bob) = (
+        SigningKey::random(&mut OsRng),
+        SigningKey::random(&mut OsRng),
+    );
+    let proxy = start_proxy_with(proxy_state(&operator).with_key_quotas(
+        std::collections::HashMap::from([("alice".to_string(), 1), ("bob".to_string(), 1)]),
+    ));
+    for (name, key) in [("alice", &alice), ("bob", &bob)] {
+        let pem = VerifyingKey::from(key)
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+        let rotate = json!({"rotate_key": {"add": [{"name": name, "key": pem}]}});
+        assert_eq!(
+            send_command(proxy, &operator, &rotate).await,
+            StatusCode::OK
+        );
+    }
+    let (destination, _) = start_echo_server().await;
+    let (alices, bobs) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+    for (key, id) in [(&alice, alices), (&bob, bobs)] {
+        let create = json!({
+            "create": {
+                "incoming_port": free_port(),
+                "destination_port": destination.port(),
+                "destination_ip": "127.0.0.1",
+                "id": id,
+            },
+        });
+        assert_eq!(
+            send_command(proxy, key, &create).await,
+            StatusCode::ACCEPTED
+        );
+    }
+
+    // Reads need a signature, like a `status` command
+    let response = Client::new()
+        .get(format!("http://{proxy}/diagnostics").parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    for path in ["config", "connections", "top-clients"] {
+        let (status, _) = get(proxy, &alice, &format!("/tunnels/{alices}/{path}")).await;
+        assert_eq!(status, StatusCode::OK, "{path}");
+        let (status, body) = get(proxy, &alice, &format!("/tunnels/{bobs}/{path}")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{path}");
+        assert_eq!(body["error"]["code"], "not_permitted", "{path}");
+        let (status, _) = get(proxy, &operator, &format!("/tunnels/{bobs}/{path}")).await;
+        assert_eq!(status, StatusCode::OK, "{path}");
+    }
+
+    let (_, body) = get(proxy, &alice, "/diagnostics").await;
+    let tunnels = body["Diagnostics"]["tunnels"].as_object().unwrap();
+    assert_eq!(
+        tunnels.keys().collect::<Vec<_>>(),
+        vec![&alices.to_string()]
+    );
+    let (_, body) = get(proxy, &operator, "/diagnostics").await;
+    assert_eq!(body["Diagnostics"]["tunnels"].as_object().unwrap().len(), 2);
+
+    let metrics = |key: &SigningKey| {
+        let request = read_request(proxy, key, "/metrics");
+        async {
+            let response = Client::new().request(request).await.unwrap();
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        }
+    };
+    let alices_metrics = metrics(&alice).await;
+    assert!(
+        alices_metrics.contains(&alices.to_string()),
+        "{alices_metrics}"
+    );
+    assert!(
+        !alices_metrics.contains(&bobs.to_string()),
+        "{alices_metrics}"
+    );
+    let operators_metrics = metrics(&operator).await;
+    assert!(
+        operators_metrics.contains(&bobs.to_string()),
+        "{operators_metrics}"
+    );
+
+    let response = Client::new()
+        .request(read_request(proxy, &alice, "/status/stream"))
+        .await
+        .unwrap();
+    let event = tokio::time::timeout(time::Duration::from_secs(5), response.into_body().data())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let event = String::from_utf8(event.to_vec()).unwrap();
+    assert!(event.contains(&alices.to_string()), "{event}");
+    assert!(!event.contains(&bobs.to_string()), "{event}");
+}
+
+#[tokio::test]
+async fn reuse_pooled_connections() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, connections) = start_echo_server().await;
+    let (incoming_port, id) = (free_port(), uuid::Uuid::new_v4());
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": destination.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+            "pool": true,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+
+    // Clients that read their answer before they stop sending, one after the other
+    for i in 0..3u32 {
+        let mut stream = TcpStream::connect(("127.0.0.1", incoming_port))
+            .await
+            .unwrap();
+        stream.write_u32(i).await.unwrap();
+        assert_eq!(stream.read_u32().await.unwrap(), i);
+        stream.shutdown().await.unwrap();
+        drop(stream);
+        tokio::time::sleep(time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    let pool = &body["Status"]["tunnels"][id.to_string()]["pool"];
+    assert_eq!(pool["idle_connections"], 1);
+    assert_eq!(pool["reused_connections"], 2);
+}
+
+#[tokio::test]
+async fn answer_pooled_clients_that_stop_sending_first() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, connections) = start_echo_server().await;
+    let (incoming_port, id) = (free_port(), uuid::Uuid::new_v4());
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": destination.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+            "pool": true,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+
+    // Clients that shut down their side before they read the answer, which must neither be
+    // lost nor go to the next client, so their connections aren't reused
+    for i in 0..3u32 {
+        let mut stream = TcpStream::connect(("127.0.0.1", incoming_port))
+            .await
+            .unwrap();
+        stream.write_u32(i).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut answer = Vec::new();
+        stream.read_to_end(&mut answer).await.unwrap();
+        assert_eq!(answer, i.to_be_bytes());
+        tokio::time::sleep(time::Duration::from_millis(50)).await;
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn dont_pool_connections_cut_off_mid_request() {
+    // Answers every request of four bytes, which it has to read in full first
+    let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let destination_addr = destination.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = destination.accept().await.unwrap();
+            tokio::spawn(async move {
+                while let Ok(request) = socket.read_u32().await {
+                    socket.write_u32(request).await.unwrap();
+                }
+            });
+        }
+    });
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (incoming_port, id) = (free_port(), uuid::Uuid::new_v4());
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": destination_addr.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+            "pool": true,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+
+    // Resets its connection with half a request sent
+    let mut stream = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    socket2::SockRef::from(&stream)
+        .set_linger(Some(time::Duration::ZERO))
+        .unwrap();
+    stream.write_all(&[0xff, 0xff]).await.unwrap();
+    tokio::time::sleep(time::Duration::from_millis(50)).await;
+    drop(stream);
+    tokio::time::sleep(time::Duration::from_millis(100)).await;
+
+    let mut stream = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    stream.write_u32(7).await.unwrap();
+    assert_eq!(stream.read_u32().await.unwrap(), 7);
+}
+
+#[tokio::test]
+async fn list_top_clients() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let (incoming_port, id) = (free_port(), uuid::Uuid::new_v4());
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": destination.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+    for _ in 0..3 {
+        echo(incoming_port, b"hello").await;
+    }
+
+    let (status, body) = get(proxy, &key, &format!("/tunnels/{id}/top-clients?limit=5")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        body["TopClients"],
+        serde_json::json!({
+            "clients": [{"ip": "127.0.0.1", "connections": 3, "overestimate": 0}],
+            "accepted": 3,
+        })
+    );
+
+    let (status, _) = get(proxy, &key, &format!("/tunnels/{id}/top-clients?limit=0")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = get(
+        proxy,
+        &key,
+        &format!("/tunnels/{}/top-clients", uuid::Uuid::new_v4()),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn reconcile_tunnels_file() {
+    use proxima_centauri::{reload_tunnels, Reconciled};
+
+    let state = Arc::new(GlobalState::new(None::<String>));
+    let (first, first_connections) = start_echo_server().await;
+    let (second, second_connections) = start_echo_server().await;
+    let [kept, removed, added, taken] = [0; 4].map(|_| uuid::Uuid::new_v4());
+    let ports = free_ports(4);
+    let path = std::env::temp_dir().join(format!("tunnels-{}.json", uuid::Uuid::new_v4()));
+    let create = |id: uuid::Uuid, port: u16, destination: SocketAddr, extra: Value| {
+        let create = json!({
+            "create": {
+                "incoming_port": port,
+                "destination_port": destination.port(),
+                "destination_ip": "127.0.0.1",
+                "id": id,
+            },
+        });
+        with_fields(create, extra)
+    };
+    let reload = |tunnels: Vec<Value>| {
+        std::fs::write(&path, serde_json::to_string(&tunnels).unwrap()).unwrap();
+        let state = state.clone();
+        let path = path.clone();
+        async move { reload_tunnels(&state, &path).await }
+    };
+
+    let reconciled = reload(vec![
+        create(kept, ports, first, json!({})),
+        create(removed, ports + 1, first, json!({})),
+    ])
+    .await
+    .unwrap();
+    assert_eq!(reconciled.created, 2);
+    echo(ports, b"kept").await;
+    echo(ports + 1, b"removed").await;
+    assert_eq!(first_connections.load(Ordering::SeqCst), 2);
+
+    // A new destination is a `Modify`, so the established connection keeps its tunnel
+    let mut established = TcpStream::connect(("127.0.0.1", ports)).await.unwrap();
+    let reconciled = reload(vec![
+        create(kept, ports, second, json!({})),
+        create(added, ports + 2, first, json!({})),
+    ])
+    .await
+    .unwrap();
+    assert_eq!(
+        reconciled,
+        Reconciled {
+            created: 1,
+            modified: 1,
+            deleted: 1,
+            ..Default::default()
+        }
+    );
+    established.write_all(b"still").await.unwrap();
+    let mut buf = [0; 5];
+    established.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"still");
+    drop(established);
+    assert!(TcpStream::connect(("127.0.0.1", ports + 1)).await.is_err());
+    echo(ports + 2, b"added").await;
+
+    // A port that can't be bound undoes everything
+    let _blocker = std::net::TcpListener::bind(("0.0.0.0", ports + 3)).unwrap();
+    let second_before = second_connections.load(Ordering::SeqCst);
+    let err = reload(vec![
+        create(kept, ports, first, json!({})),
+        create(taken, ports + 3, first, json!({})),
+    ])
+    .await
+    .unwrap_err();
+    assert!(err.to_string().contains("nothing was changed"), "{err}");
+    echo(ports, b"kept").await;
+    echo(ports + 2, b"added").await;
+    assert_eq!(second_connections.load(Ordering::SeqCst), second_before + 1);
+
+    // Other changes recreate the tunnel
+    let reconciled = reload(vec![
+        create(kept, ports, second, json!({"buffer_size": 4096})),
+        create(added, ports + 2, first, json!({})),
+    ])
+    .await
+    .unwrap();
+    assert_eq!(
+        reconciled,
+        Reconciled {
+            recreated: 1,
+            unchanged: 1,
+            ..Default::default()
+        }
+    );
+    echo(ports, b"kept").await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn clear_fields_removed_from_tunnels_file() {
+    use proxima_centauri::reload_tunnels;
+
+    let state = Arc::new(GlobalState::new(None::<String>));
+    let server = serve(
+        &"127.0.0.1:0".parse().unwrap(),
+        state.clone(),
+        &ControlPlaneConfig::default(),
+    )
+    .unwrap();
+    let proxy = server.local_addr();
+    tokio::spawn(server);
+    let key = SigningKey::random(&mut OsRng);
+    let (destination, _) = start_echo_server().await;
+    let (id, port) = (uuid::Uuid::new_v4(), free_port());
+    let path = std::env::temp_dir().join(format!("tunnels-{}.json", uuid::Uuid::new_v4()));
+    let reload = |extra: Value| {
+        let create = json!({
+            "create": {
+                "incoming_port": port,
+                "destination_port": destination.port(),
+                "destination_ip": "127.0.0.1",
+                "id": id,
+            },
+        });
+        let tunnels = json!([with_fields(create, extra)]);
+        std::fs::write(&path, tunnels.to_string()).unwrap();
+        let state = state.clone();
+        let path = path.clone();
+        async move { reload_tunnels(&state, &path).await.unwrap() }
+    };
+    let config = || async {
+        let (_, body) = get(proxy, &key, &format!("/tunnels/{id}/config")).await;
+        body["Config"]["config"].clone()
+    };
+
+    reload(json!({"label": "web", "ttl_secs": 3600})).await;
+    let created = config().await;
+    assert_eq!(created["label"], "web", "{created}");
+    assert!(created["ttl_remaining_secs"].is_u64(), "{created}");
+
+    // Left out of the file, the label and time to live are cleared by the `Modify`
+    assert_eq!(reload(json!({})).await.modified, 1);
+    let modified = config().await;
+    assert!(modified["label"].is_null(), "{modified}");
+    assert!(modified["ttl_remaining_secs"].is_null(), "{modified}");
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Breaks the listener of the tunnel on `port`, after which accepting fails with `EINVAL`.
+#[cfg(target_os = "linux")]
+fn break_listener(port: u16) {
+    use std::os::fd::{AsRawFd, BorrowedFd};
+
+    for entry in std::fs::read_dir("/proc/self/fd").unwrap() {
+        let Ok(fd) = entry.unwrap().file_name().to_string_lossy().parse() else {
+            continue;
+        };
+        // SAFETY: only used while the proxy keeps its sockets open, and `read_dir` holds the
+        // one other descriptor that might be closed in the meantime
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+        let socket = socket2::SockRef::from(&fd);
+        let local = socket.local_addr().ok().and_then(|addr| addr.as_socket());
+        // The accepted connections have the same local port, but also a peer
+        if local.is_some_and(|addr| addr.port() == port) && socket.peer_addr().is_err() {
+            socket.shutdown(std::net::Shutdown::Read).unwrap();
+            assert!(fd.as_raw_fd() > 2);
+            return;
+        }
+    }
+    panic!("no listener on port {port}");
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn remove_tunnel_after_its_listener_failed() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let [busy, idle] = [uuid::Uuid::new_v4(), uuid::Uuid::new_v4()];
+    let [busy_port, idle_port] = [free_port(), free_port()];
+    let create = |id, incoming_port| {
+        json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": destination.port(),
+                "destination_ip": "127.0.0.1",
+                "id": id,
+            },
+        })
+    };
+    for (id, port) in [(busy, busy_port), (idle, idle_port)] {
+        assert_eq!(
+            send_command(proxy, &key, &create(id, port)).await,
+            StatusCode::ACCEPTED
+        );
+    }
+    let mut established = TcpStream::connect(("127.0.0.1", busy_port)).await.unwrap();
+    established.write_all(b"ping").await.unwrap();
+    let mut buf = [0; 4];
+    established.read_exact(&mut buf).await.unwrap();
+
+    break_listener(busy_port);
+    break_listener(idle_port);
+    tokio::time::sleep(time::Duration::from_millis(100)).await;
+    let key = &key;
+    let status = |id: uuid::Uuid| async move {
+        let response = Client::new()
+            .request(command_request(proxy, key, &json!({"status": null})))
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        read_status(&body)["Status"]["tunnels"][id.to_string()].clone()
+    };
+    // Without connections nothing is left of the tunnel
+    assert!(status(idle).await.is_null());
+    let modify = |id| {
+        json!({
+            "modify": {"destination_port": destination.port(), "id": id, "drain_on_modify": true},
+        })
+    };
+    assert_eq!(
+        send_command(proxy, key, &modify(idle)).await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        send_command(proxy, key, &create(uuid::Uuid::new_v4(), idle_port)).await,
+        StatusCode::ACCEPTED
+    );
+    echo(idle_port, b"listening again").await;
+
+    // A connection still holds on to the tunnel, which can be changed like any other
+    assert_eq!(status(busy).await["state"], "failed");
+    let temporary_modify = json!({
+        "temporary_modify": {"id": busy, "destination": destination, "revert_after_secs": 60},
+    });
+    let drain = json!({"delete": {"id": busy, "drain": true}});
+    for command in [modify(busy), temporary_modify, drain] {
+        assert_eq!(
+            send_command(proxy, key, &command).await,
+            StatusCode::ACCEPTED,
+            "{command}"
+        );
+    }
+    established.write_all(b"pong").await.unwrap();
+    established.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"pong");
+    drop(established);
+    tokio::time::sleep(time::Duration::from_millis(100)).await;
+    assert!(status(busy).await.is_null());
+}
+
+#[tokio::test]
+async fn accept_with_several_loops() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, accepted) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = |accept_loops: usize| {
+        json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": destination.port(),
+                "destination_ip": "127.0.0.1",
+                "id": id,
+                "accept_loops": accept_loops,
+            },
+        })
+    };
+    for invalid in [0, proxima_centauri::MAX_ACCEPT_LOOPS + 1] {
+        assert_eq!(
+            send_command(proxy, &key, &create(invalid)).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+    assert_eq!(
+        send_command(proxy, &key, &create(4)).await,
+        StatusCode::ACCEPTED
+    );
+    let clients: Vec<_> = (0..32u8)
+        .map(|i| tokio::spawn(async move { echo(incoming_port, &[i; 8]).await }))
+        .collect();
+    for client in clients {
+        client.await.unwrap();
+    }
+    assert_eq!(accepted.load(Ordering::SeqCst), 32);
+
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    assert_eq!(body["Status"]["tunnels"][id.to_string()]["accept_loops"], 4);
+
+    // Every loop lets go of the port when the tunnel is deleted
+    let delete = json!({"delete": {"id": id}});
+    assert_eq!(
+        send_command(proxy, &key, &delete).await,
+        StatusCode::ACCEPTED
+    );
+    tokio::time::sleep(time::Duration::from_millis(100)).await;
+    std::net::TcpListener::bind(("0.0.0.0", incoming_port)).unwrap();
+}
+
+#[tokio::test]
+async fn fail_over_to_fallback_destination() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let primary = free_port();
+    let (fallback, fallback_connections) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": primary,
+            "destination_ip": "127.0.0.1",
+            "id": id,
+            "failover_destinations": [fallback],
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+
+    // The destination isn't listening, so the connections go to the fallback and stay there
+    echo(incoming_port, b"first").await;
+    echo(incoming_port, b"second").await;
+    assert_eq!(fallback_connections.load(Ordering::SeqCst), 2);
+    let response = Client::new()
+        .request(command_request(proxy, &key, &json!({"status": null})))
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    let status = &body["Status"]["tunnels"][id.to_string()]["failover"];
+    assert_eq!(status["active_destination"]["tcp"], fallback.to_string());
+    assert_eq!(status["switches"], 1);
+
+    // Once the destination is back, the probe moves new connections back to it
+    let listener = TcpListener::bind(("127.0.0.1", primary)).await.unwrap();
+    tokio::time::sleep(
+        proxima_centauri::FAILOVER_PROBE_INTERVAL + time::Duration::from_millis(500),
+    )
+    .await;
+    let client = tokio::spawn(echo(incoming_port, b"third"));
+    let (mut socket, _) = loop {
+        let (socket, addr) = listener.accept().await.unwrap();
+        // Skips the probe, which closes its connection right away
+        let mut first = [0; 1];
+        if socket.peek(&mut first).await.unwrap() > 0 {
+            break (socket, addr);
+        }
+    };
+    let (mut si, mut so) = socket.split();
+    let mut buf = [0; 5];
+    si.read_exact(&mut buf).await.unwrap();
+    so.write_all(&buf).await.unwrap();
+    client.await.unwrap();
+    assert_eq!(fallback_connections.load(Ordering::SeqCst), 2);
+
+    let invalid = json!({
+        "create": {
+            "incoming_port": free_port(),
+            "destination_port": primary,
+            "destination_ip": "127.0.0.1",
+            "id": uuid::Uuid::new_v4(),
+            "failover_destinations":
+                vec![fallback; proxima_centauri::MAX_FAILOVER_DESTINATIONS + 1],
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &invalid).await,
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[tokio::test]
+async fn fail_closed_without_heartbeats() {
+    // Answers every heartbeat until it is aborted
+    let orchestrator = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url: hyper::Uri = format!("http://{}/beat", orchestrator.local_addr().unwrap())
+        .parse()
+        .unwrap();
+    let beats = Arc::new(AtomicUsize::new(0));
+    let counter = beats.clone();
+    let orchestrator = tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = orchestrator.accept().await.unwrap();
+            let mut request = [0; 1024];
+            assert!(socket.read(&mut request).await.unwrap() > 0);
+            counter.fetch_add(1, Ordering::SeqCst);
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+        }
+    });
+
+    let key = SigningKey::random(&mut OsRng);
+    let (destination, _) = start_echo_server().await;
+    let mut tunnels = Vec::new();
+    for policy in [UnreachablePolicy::FailClosed, UnreachablePolicy::FailOpen] {
+        let state = Arc::new(proxy_state(&key));
+        let server = serve(
+            &"127.0.0.1:0".parse().unwrap(),
+            state.clone(),
+            &ControlPlaneConfig::default(),
+        )
+        .unwrap();
+        let proxy = server.local_addr();
+        tokio::spawn(server);
+        let heartbeat = Heartbeat {
+            url: url.clone(),
+            interval: time::Duration::from_millis(50),
+            max_missed: 3,
+            policy,
+        };
+        tokio::spawn(heartbeat.run(state));
+        let incoming_port = free_port();
+        let create = json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": destination.port(),
+                "destination_ip": "127.0.0.1",
+                "id": uuid::Uuid::new_v4(),
+            },
+        });
+        assert_eq!(
+            send_command(proxy, &key, &create).await,
+            StatusCode::ACCEPTED
+        );
+        tunnels.push(incoming_port);
+    }
+    tokio::time::sleep(time::Duration::from_millis(300)).await;
+    assert!(beats.load(Ordering::SeqCst) > 0);
+    for incoming_port in &tunnels {
+        echo(*incoming_port, b"beating").await;
+    }
+
+    orchestrator.abort();
+    tokio::time::sleep(time::Duration::from_millis(500)).await;
+    let [closed, open] = tunnels[..] else {
+        unreachable!()
+    };
+    assert!(TcpStream::connect(("127.0.0.1", closed)).await.is_err());
+    echo(open, b"still forwarding").await;
+}
+
+#[tokio::test]
+async fn account_for_connections_on_shutdown() {
+    let key = SigningKey::random(&mut OsRng);
+    let state = Arc::new(proxy_state(&key));
+    let server = serve(
+        &"127.0.0.1:0".parse().unwrap(),
+        state.clone(),
+        &ControlPlaneConfig::default(),
+    )
+    .unwrap();
+    let proxy = server.local_addr();
+    tokio::spawn(server);
+    let (destination, _) = start_echo_server().await;
+    let create = |incoming_port: u16, id: uuid::Uuid| {
+        json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": destination.port(),
+                "destination_ip": "127.0.0.1",
+                "id": id,
+            },
+        })
+    };
+    let (incoming_port, id) = (free_port(), uuid::Uuid::new_v4());
+    let (deleted_port, deleted_id) = (free_port(), uuid::Uuid::new_v4());
+    for (port, id) in [(incoming_port, id), (deleted_port, deleted_id)] {
+        assert_eq!(
+            send_command(proxy, &key, &create(port, id)).await,
+            StatusCode::ACCEPTED
+        );
+    }
+    echo(deleted_port, b"deleted").await;
+    tokio::time::sleep(time::Duration::from_millis(50)).await;
+    let delete = json!({"delete": {"id": deleted_id}});
+    assert_eq!(
+        send_command(proxy, &key, &delete).await,
+        StatusCode::ACCEPTED
+    );
+    echo(incoming_port, b"done").await;
+    // Still open at the shutdown, so it is closed after the grace period
+    let mut open = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    open.write_all(b"open").await.unwrap();
+    let mut buf = [0; 4];
+    open.read_exact(&mut buf).await.unwrap();
+
+    let stats = state.shutdown(time::Duration::from_millis(200)).await;
+    assert_eq!(stats.served.connections, 3);
+    assert_eq!(stats.served.bytes_sent, 15);
+    assert_eq!(stats.served.bytes_received, 15);
+    assert_eq!(stats.tunnels.len(), 1);
+    let tunnel = &stats.tunnels[&id];
+    assert_eq!(tunnel.incoming_port, incoming_port);
+    assert_eq!(tunnel.served.connections, 2);
+    assert_eq!(tunnel.served.bytes_sent, 8);
+    assert_eq!(open.read(&mut buf).await.unwrap_or(0), 0);
+    assert!(TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn mark_connections_with_dscp() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (destination, _) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = |dscp: u8| {
+        json!({
+            "create": {
+                "incoming_port": incoming_port,
+                "destination_port": destination.port(),
+                "destination_ip": "127.0.0.1",
+                "id": id,
+                "dscp": dscp,
+            },
+        })
+    };
+    assert_eq!(
+        send_command(proxy, &key, &create(64)).await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        send_command(proxy, &key, &create(46)).await,
+        StatusCode::ACCEPTED
+    );
+    echo(incoming_port, b"expedited").await;
+
+    let (_, body) = get(proxy, &key, &format!("/tunnels/{id}/config")).await;
+    assert_eq!(body["Config"]["config"]["dscp"], 46);
+}
+
+#[tokio::test]
+async fn read_tunnel_config() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    let (first, _) = start_echo_server().await;
+    let (second, _) = start_echo_server().await;
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": first.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+            "ttl_secs": 600,
+            "buffer_size": 4096,
+            "label": "db",
+            "queue_len": 8,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+    let modify = json!({
+        "modify": {"destination_port": second.port(), "destination_ip": "127.0.0.1", "id": id},
+    });
+    assert_eq!(
+        send_command(proxy, &key, &modify).await,
+        StatusCode::ACCEPTED
+    );
+
+    let (status, body) = get(proxy, &key, &format!("/tunnels/{id}/config")).await;
+    assert_eq!(status, StatusCode::OK);
+    let config = &body["Config"]["config"];
+    assert_eq!(config["destination"]["tcp"], second.to_string());
+    assert_eq!(config["bind_address"], format!("0.0.0.0:{incoming_port}"));
+    assert_eq!(config["generation"], 1);
+    // A `Modify` keeps the label and time to live that it leaves out
+    assert_eq!(config["label"], "db");
+    assert!(config["ttl_remaining_secs"].as_u64().unwrap() > 590);
+    assert_eq!(config["buffer_size"], 4096);
+    assert_eq!(config["queue_len"], 8);
+    // Defaults are filled in
+    assert_eq!(
+        config["connect_timeout_ms"],
+        proxima_centauri::DEFAULT_CONNECT_TIMEOUT.as_millis() as u64
+    );
+    assert_eq!(
+        config["queue_workers"],
+        proxima_centauri::DEFAULT_QUEUE_WORKERS
+    );
+    assert_eq!(config["overflow_policy"], "reject_new");
+    assert_eq!(config["accept_loops"], 1);
+    assert_eq!(config["pool"], false);
+
+    let (status, _) = get(
+        proxy,
+        &key,
+        &format!("/tunnels/{}/config", uuid::Uuid::new_v4()),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn observer_keys_only_read() {
+    let operator = SigningKey::random(&mut OsRng);
+    let observer = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy_with(proxy_state(&operator).with_observer_keys(
+        std::collections::HashMap::from([("dashboard".to_string(), VerifyingKey::from(&observer))]),
+    ));
+    let (destination, _) = start_echo_server().await;
+    let id = uuid::Uuid::new_v4();
+    let create = json!({
+        "create": {
+            "incoming_port": free_port(),
+            "destination_port": destination.port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+        },
+    });
+    let delete = json!({"delete": {"id": id}});
+
+    assert_eq!(
+        send_command(proxy, &observer, &create).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        send_command(proxy, &operator, &create).await,
+        StatusCode::ACCEPTED
+    );
+    let response = Client::new()
+        .request(command_request(proxy, &observer, &json!({"status": null})))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = read_status(&body);
+    assert!(body["Status"]["tunnels"].get(id.to_string()).is_some());
+    for path in ["/diagnostics", "/metrics", &format!("/tunnels/{id}/config")] {
+        let response = Client::new()
+            .request(read_request(proxy, &observer, path))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{path}");
+    }
+    assert_eq!(
+        send_command(proxy, &observer, &delete).await,
+        StatusCode::FORBIDDEN
+    );
+    let rotate = json!({"rotate_key": {"remove": ["dashboard"]}});
+    assert_eq!(
+        send_command(proxy, &observer, &rotate).await,
+        StatusCode::FORBIDDEN
+    );
+
+    // Keys can be added as observers, but the last admin key stays
+    let auditor = SigningKey::random(&mut OsRng);
+    let auditor_pem = VerifyingKey::from(&auditor)
+        .to_public_key_pem(LineEnding::LF)
+        .unwrap();
+    let rotate = json!({
+        "rotate_key": {"add": [{"name": "audit", "key": auditor_pem, "role": "observer"}]},
+    });
+    assert_eq!(
+        send_command(proxy, &operator, &rotate).await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        send_command(proxy, &auditor, &delete).await,
+        StatusCode::FORBIDDEN
+    );
+    let rotate = json!({"rotate_key": {"remove": ["default"]}});
+    assert_eq!(
+        send_command(proxy, &operator, &rotate).await,
+        StatusCode::CONFLICT
+    );
+    assert_eq!(
+        send_command(proxy, &operator, &delete).await,
+        StatusCode::ACCEPTED
+    );
+}
+
+#[tokio::test]
+async fn bound_data_in_flight_to_slow_destination() {
+    let key = SigningKey::random(&mut OsRng);
+    let proxy = start_proxy(&key);
+    // A destination that doesn't read, with small buffers on both ends so that only the proxy
+    // can hold on to much data
+    let destination = tokio::net::TcpSocket::new_v4().unwrap();
+    destination.set_recv_buffer_size(4096).unwrap();
+    destination.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let destination = destination.listen(1).unwrap();
+    let incoming_port = free_port();
+    let id = uuid::Uuid::new_v4();
+    let create = json!({
+        "create": {
+            "incoming_port": incoming_port,
+            "destination_port": destination.local_addr().unwrap().port(),
+            "destination_ip": "127.0.0.1",
+            "id": id,
+            "max_in_flight_bytes": 16384,
+        },
+    });
+    assert_eq!(
+        send_command(proxy, &key, &create).await,
+        StatusCode::ACCEPTED
+    );
+
+    let client = tokio::net::TcpSocket::new_v4().unwrap();
+    client.set_send_buffer_size(4096).unwrap();
+    let mut client = client
+        .connect(([127, 0, 0, 1], incoming_port).into())
+        .await
+        .unwrap();
+    let (mut server, _) = destination.accept().await.unwrap();
+    let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let mut written = 0;
+    while let Ok(Ok(n)) = tokio::time::timeout(
+        time::Duration::from_millis(300),
+        client.write(&data[written..]),
+    )
+    .await
+    {
+        written += n;
+    }
+    // Without a bound the kernel grows the buffers of the proxy to megabytes
+    assert!(written < 256 * 1024, "{written} bytes were taken in");
+
+    // Everything arrives once the destination reads again
+    let mut received = vec![0; written];
+    server.read_exact(&mut received).await.unwrap();
+    assert!(received == data[..written]);
+}
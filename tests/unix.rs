@@ -0,0 +1,128 @@
+#![cfg(unix)]
+
+use hyper::{Body, Client, Method, Request, StatusCode};
+use proxima_centauri::{serve, unix, ControlPlaneConfig, GlobalState};
+use socket2::{Domain, Socket, Type};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixListener, UnixStream};
+
+fn socket_path() -> PathBuf {
+    std::env::temp_dir().join(format!("{}.sock", uuid::Uuid::new_v4()))
+}
+
+#[tokio::test]
+async fn control_plane_over_unix_socket() {
+    let path = socket_path();
+    let listener = unix::bind(&path).unwrap();
+    let state = Arc::new(GlobalState::new(None::<String>));
+    tokio::spawn(async move { unix::serve(listener, state, &ControlPlaneConfig::default()).await });
+
+    let mut stream = UnixStream::connect(&path).await.unwrap();
+    stream
+        .write_all(b"GET /time HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+
+    // A socket left behind by a previous run is replaced
+    assert!(unix::bind(&path).is_ok());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn tunnel_to_unix_socket() {
+    // An echo server on a unix socket
+    let path = socket_path();
+    let backend = UnixListener::bind(&path).unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = backend.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut si, mut so) = socket.split();
+                io::copy(&mut si, &mut so).await?;
+                so.shutdown().await
+            });
+        }
+    });
+
+    let server = serve(
+        &"127.0.0.1:0".parse().unwrap(),
+        Arc::new(GlobalState::new(None::<String>)),
+        &ControlPlaneConfig::default(),
+    )
+    .unwrap();
+    let proxy = server.local_addr();
+    tokio::spawn(server);
+
+    let incoming_port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let create = format!(
+        "{{\"create\":{{\"incoming_port\":{incoming_port},\"destination_uds\":{:?},\
+         \"id\":\"{}\"}}}}",
+        path.display().to_string(),
+        uuid::Uuid::new_v4()
+    );
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{proxy}/command"))
+        .header("content-type", "application/json")
+        .body(Body::from(create))
+        .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    let mut stream = TcpStream::connect(("127.0.0.1", incoming_port))
+        .await
+        .unwrap();
+    stream.write_all(b"over a unix socket").await.unwrap();
+    let mut buf = [0; 18];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"over a unix socket");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn share_port_with_reuse_port() {
+    let server = serve(
+        &"127.0.0.1:0".parse().unwrap(),
+        Arc::new(GlobalState::new(None::<String>)),
+        &ControlPlaneConfig::default(),
+    )
+    .unwrap();
+    let proxy = server.local_addr();
+    tokio::spawn(server);
+    let incoming_port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let create = format!(
+        "{{\"create\":{{\"incoming_port\":{incoming_port},\"destination_port\":1,\
+         \"destination_ip\":\"127.0.0.1\",\"id\":\"{}\",\"reuse_port\":true}}}}",
+        uuid::Uuid::new_v4()
+    );
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://{proxy}/command"))
+        .header("content-type", "application/json")
+        .body(Body::from(create))
+        .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    // Another process could listen on the port as well
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+    socket.set_reuse_address(true).unwrap();
+    socket.set_reuse_port(true).unwrap();
+    let addr = SocketAddr::from(([0, 0, 0, 0], incoming_port));
+    assert!(socket.bind(&addr.into()).is_ok());
+}
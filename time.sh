@@ -0,0 +1 @@
+curl http://localhost:14000/time